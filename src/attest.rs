@@ -0,0 +1,182 @@
+//! Attaching SBOMs and attestations to manifests.
+//!
+//! Each document is stored both as an OCI 1.1 referrer, as used by `oras discover`,
+//! and as a layer of the manifest with the cosign tag (`sha256-<digest>.sbom` or
+//! `.att`) as used by `cosign download`. Both share the same blob.
+
+use std::collections::HashMap;
+
+use anyhow::{anyhow, Result};
+use fn_error_context::context;
+use oci_spec::image::{Descriptor, MediaType};
+
+use crate::referrers::cosign_tag;
+use crate::OciDir;
+
+/// The media type of a DSSE envelope, which wraps in-toto attestations.
+pub const DSSE_ENVELOPE_MEDIA_TYPE: &str = "application/vnd.dsse.envelope.v1+json";
+/// The artifact type of in-toto attestations stored as referrers.
+pub const IN_TOTO_ARTIFACT_TYPE: &str = "application/vnd.in-toto+json";
+/// The layer annotation holding the in-toto predicate type.
+pub const PREDICATE_TYPE_ANNOTATION: &str = "in-toto.io/predicate-type";
+/// The layer annotation used by cosign for the predicate type.
+const COSIGN_PREDICATE_TYPE_ANNOTATION: &str = "predicateType";
+
+/// The format of a software bill of materials.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum SbomFormat {
+    /// SPDX in JSON format.
+    SpdxJson,
+    /// CycloneDX in JSON format.
+    CycloneDxJson,
+}
+
+impl SbomFormat {
+    /// The media type used for the artifact type and layer of the referrer.
+    pub fn media_type(&self) -> &'static str {
+        match self {
+            Self::SpdxJson => "application/spdx+json",
+            Self::CycloneDxJson => "application/vnd.cyclonedx+json",
+        }
+    }
+
+    /// The layer media type used by cosign.
+    fn cosign_media_type(&self) -> &'static str {
+        match self {
+            Self::SpdxJson => "text/spdx+json",
+            Self::CycloneDxJson => self.media_type(),
+        }
+    }
+
+    /// Check that the document looks like this format.
+    fn validate(&self, doc: &serde_json::Value) -> Result<()> {
+        let ok = match self {
+            Self::SpdxJson => doc.get("spdxVersion").is_some(),
+            Self::CycloneDxJson => {
+                doc.get("bomFormat").and_then(|v| v.as_str()) == Some("CycloneDX")
+            }
+        };
+        if !ok {
+            anyhow::bail!("Document is not in {self:?} format");
+        }
+        Ok(())
+    }
+}
+
+impl OciDir {
+    /// Attach an SBOM document to the manifest `subject`, returning the descriptor of
+    /// the referrer artifact.
+    #[context("Attaching SBOM to {}", subject.digest())]
+    pub fn attach_sbom(
+        &self,
+        subject: &Descriptor,
+        sbom: &[u8],
+        format: SbomFormat,
+    ) -> Result<Descriptor> {
+        let doc: serde_json::Value = serde_json::from_slice(sbom)?;
+        format.validate(&doc)?;
+        let media_type = MediaType::Other(format.media_type().to_owned());
+        let layer = self
+            .write_blob_with_type(sbom, media_type.clone())?
+            .build()?;
+        let mut cosign_layer = layer.clone();
+        cosign_layer.set_media_type(MediaType::Other(format.cosign_media_type().to_owned()));
+        self.append_to_cosign_tag(&cosign_tag(subject.digest(), "sbom")?, cosign_layer)?;
+        self.attach_referrer(subject, media_type, vec![layer], None)
+    }
+
+    /// Attach an in-toto attestation, as a serialized DSSE envelope, to the manifest
+    /// `subject`, returning the descriptor of the referrer artifact. The envelope is
+    /// stored as is; it is not signed or verified.
+    #[context("Attaching attestation to {}", subject.digest())]
+    pub fn attach_attestation(
+        &self,
+        subject: &Descriptor,
+        envelope: &[u8],
+        predicate_type: &str,
+    ) -> Result<Descriptor> {
+        let doc: serde_json::Value = serde_json::from_slice(envelope)?;
+        for field in ["payloadType", "payload"] {
+            doc.get(field)
+                .ok_or_else(|| anyhow!("DSSE envelope is missing {field}"))?;
+        }
+        let mut layer = self
+            .write_blob_with_type(envelope, MediaType::Other(DSSE_ENVELOPE_MEDIA_TYPE.into()))?
+            .build()?;
+        layer.set_annotations(Some(HashMap::from([
+            (
+                PREDICATE_TYPE_ANNOTATION.to_owned(),
+                predicate_type.to_owned(),
+            ),
+            (
+                COSIGN_PREDICATE_TYPE_ANNOTATION.to_owned(),
+                predicate_type.to_owned(),
+            ),
+        ])));
+        self.append_to_cosign_tag(&cosign_tag(subject.digest(), "att")?, layer.clone())?;
+        self.attach_referrer(
+            subject,
+            MediaType::Other(IN_TOTO_ARTIFACT_TYPE.to_owned()),
+            vec![layer],
+            None,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use oci_spec::image::ImageManifest;
+
+    #[test]
+    fn attach() -> Result<()> {
+        let w = OciDir::new_in_memory()?;
+        let manifest = crate::new_empty_manifest().build().unwrap();
+        let subject = w.insert_manifest(manifest, Some("app"), Default::default())?;
+
+        let spdx = br#"{"spdxVersion":"SPDX-2.3","packages":[]}"#;
+        let sbom = w.attach_sbom(&subject, spdx, SbomFormat::SpdxJson)?;
+        assert!(w
+            .attach_sbom(&subject, spdx, SbomFormat::CycloneDxJson)
+            .is_err());
+        let cdx = br#"{"bomFormat":"CycloneDX","specVersion":"1.5"}"#;
+        w.attach_sbom(&subject, cdx, SbomFormat::CycloneDxJson)?;
+        let spdx_type = MediaType::Other("application/spdx+json".into());
+        assert_eq!(
+            w.referrers(&subject, Some(&spdx_type))?,
+            std::slice::from_ref(&sbom)
+        );
+        let sbom_manifest: ImageManifest = w.read_json_blob(&sbom)?;
+        assert_eq!(sbom_manifest.layers()[0].media_type(), &spdx_type);
+        let tagged = w
+            .find_manifest_with_tag(&cosign_tag(subject.digest(), "sbom")?)?
+            .unwrap();
+        assert_eq!(tagged.layers().len(), 2);
+        assert_eq!(
+            tagged.layers()[0].media_type(),
+            &MediaType::Other("text/spdx+json".into())
+        );
+        assert_eq!(
+            tagged.layers()[0].digest(),
+            sbom_manifest.layers()[0].digest()
+        );
+
+        let envelope =
+            br#"{"payloadType":"application/vnd.in-toto+json","payload":"e30=","signatures":[]}"#;
+        let predicate = "https://slsa.dev/provenance/v1";
+        let att = w.attach_attestation(&subject, envelope, predicate)?;
+        assert!(w.attach_attestation(&subject, b"{}", predicate).is_err());
+        let att_manifest: ImageManifest = w.read_json_blob(&att)?;
+        let layer = &att_manifest.layers()[0];
+        assert_eq!(
+            layer.annotations().as_ref().unwrap()[PREDICATE_TYPE_ANNOTATION],
+            predicate
+        );
+        assert!(w
+            .find_manifest_with_tag(&cosign_tag(subject.digest(), "att")?)?
+            .is_some());
+        assert_eq!(w.referrers(&subject, None)?.len(), 3);
+        Ok(())
+    }
+}
@@ -0,0 +1,183 @@
+//! Concurrent execution of batch operations.
+//!
+//! Rather than failing fast, batch operations run every item and collect the
+//! per-item results into a [`BatchReport`], so that a single bad image or blob
+//! does not hide the state of all the others.
+
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use anyhow::Result;
+
+use crate::OciDir;
+
+/// Options for batch operations.
+#[derive(Debug, Clone, Default)]
+pub struct BatchOptions {
+    /// The maximum number of items processed at once. If unset,
+    /// [`std::thread::available_parallelism`] is used.
+    pub concurrency: Option<NonZeroUsize>,
+}
+
+/// The result of processing one item of a batch.
+#[derive(Debug)]
+pub struct BatchItem<I, T> {
+    /// The input item.
+    pub item: I,
+    /// The result of processing it.
+    pub result: Result<T>,
+}
+
+/// The per-item results of a batch operation, in the order of the input items.
+#[derive(Debug)]
+pub struct BatchReport<I, T> {
+    /// All items with their results.
+    pub items: Vec<BatchItem<I, T>>,
+}
+
+impl<I, T> BatchReport<I, T> {
+    /// Returns true if all items succeeded.
+    pub fn is_success(&self) -> bool {
+        self.items.iter().all(|i| i.result.is_ok())
+    }
+
+    /// Iterate over the items which succeeded.
+    pub fn succeeded(&self) -> impl Iterator<Item = (&I, &T)> {
+        self.items
+            .iter()
+            .filter_map(|i| i.result.as_ref().ok().map(|v| (&i.item, v)))
+    }
+
+    /// Iterate over the items which failed.
+    pub fn failed(&self) -> impl Iterator<Item = (&I, &anyhow::Error)> {
+        self.items
+            .iter()
+            .filter_map(|i| i.result.as_ref().err().map(|e| (&i.item, e)))
+    }
+}
+
+impl<I: std::fmt::Display, T> BatchReport<I, T> {
+    /// Convert into a single result, with an error describing all failed items.
+    pub fn into_result(self) -> Result<Vec<T>> {
+        let failed: Vec<String> = self
+            .failed()
+            .map(|(item, e)| format!("{item}: {e:#}"))
+            .collect();
+        if !failed.is_empty() {
+            anyhow::bail!(
+                "{} of {} items failed:\n{}",
+                failed.len(),
+                self.items.len(),
+                failed.join("\n")
+            );
+        }
+        Ok(self
+            .items
+            .into_iter()
+            .filter_map(|i| i.result.ok())
+            .collect())
+    }
+}
+
+/// Run `f` on every item, processing up to the configured number of items concurrently.
+pub fn run<I, T, F>(items: Vec<I>, opts: &BatchOptions, f: F) -> BatchReport<I, T>
+where
+    I: Sync,
+    T: Send,
+    F: Fn(&I) -> Result<T> + Sync,
+{
+    let concurrency = opts
+        .concurrency
+        .or_else(|| std::thread::available_parallelism().ok())
+        .map_or(1, NonZeroUsize::get)
+        .min(items.len());
+    if concurrency <= 1 {
+        // Run on the calling thread, which is also the only option on wasm.
+        let items = items
+            .into_iter()
+            .map(|item| {
+                let result = f(&item);
+                BatchItem { item, result }
+            })
+            .collect();
+        return BatchReport { items };
+    }
+    let results: Vec<Mutex<Option<Result<T>>>> = items.iter().map(|_| Mutex::new(None)).collect();
+    let next = AtomicUsize::new(0);
+    std::thread::scope(|s| {
+        for _ in 0..concurrency {
+            s.spawn(|| loop {
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                let Some(item) = items.get(i) else {
+                    break;
+                };
+                *results[i].lock().unwrap() = Some(f(item));
+            });
+        }
+    });
+    let items = items
+        .into_iter()
+        .zip(results)
+        .map(|(item, result)| BatchItem {
+            item,
+            result: result
+                .into_inner()
+                .unwrap()
+                .expect("batch item was not processed"),
+        })
+        .collect();
+    BatchReport { items }
+}
+
+impl OciDir {
+    /// Verify the digests of the provided blobs concurrently; see [`OciDir::fsck`].
+    ///
+    /// Blobs with an unsupported digest algorithm are reported as `false`.
+    pub fn verify_blobs(
+        &self,
+        digests: Vec<String>,
+        opts: &BatchOptions,
+    ) -> BatchReport<String, bool> {
+        run(digests, opts, |digest| self.verify_blob(digest))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn batch() -> Result<()> {
+        let opts = BatchOptions {
+            concurrency: NonZeroUsize::new(3),
+        };
+        let report = run((0..10).collect(), &opts, |&i: &u32| {
+            if i % 4 == 0 {
+                anyhow::bail!("divisible by four");
+            }
+            Ok(i * 2)
+        });
+        assert!(!report.is_success());
+        let failed: Vec<_> = report.failed().map(|(i, _)| *i).collect();
+        assert_eq!(failed, [0, 4, 8]);
+        assert_eq!(report.succeeded().count(), 7);
+        let err = report.into_result().unwrap_err().to_string();
+        assert!(err.starts_with("3 of 10 items failed"));
+        assert!(run(Vec::<u32>::new(), &opts, |_| Ok(())).is_success());
+
+        let w = OciDir::new_in_memory()?;
+        let mut digests = Vec::new();
+        for i in 0..5 {
+            let mut layerw = w.create_uncompressed_layer()?;
+            write!(layerw, "layer {i}")?;
+            digests.push(layerw.complete()?.blob.digest_id());
+        }
+        digests.push(format!("sha256:{}", "0".repeat(64)));
+        let report = w.verify_blobs(digests, &BatchOptions::default());
+        assert_eq!(report.succeeded().count(), 5);
+        assert_eq!(report.failed().count(), 1);
+        Ok(())
+    }
+}
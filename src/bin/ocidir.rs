@@ -0,0 +1,342 @@
+//! Command line interface to OCI image layout directories, built on the
+//! `ocidir` library. This requires the `cli` feature.
+
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+use anyhow::{anyhow, Context, Result};
+use clap::{Parser, Subcommand, ValueEnum};
+use ocidir::cap_std::{self, fs::Dir};
+use ocidir::oci_spec::image::ImageManifest;
+use ocidir::{
+    CloneMode, FsckAction, FsckCache, FsckOptions, OciDir, PruneOptions, ValidationLevel,
+};
+
+#[derive(Debug, Parser)]
+#[command(version, about = "Inspect and manipulate OCI image layout directories")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum Mode {
+    Copy,
+    Reflink,
+    Hardlink,
+    Auto,
+}
+
+impl From<Mode> for CloneMode {
+    fn from(m: Mode) -> Self {
+        match m {
+            Mode::Copy => CloneMode::Copy,
+            Mode::Reflink => CloneMode::Reflink,
+            Mode::Hardlink => CloneMode::Hardlink,
+            Mode::Auto => CloneMode::Auto,
+        }
+    }
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Describe the layout as JSON, or summarize one image.
+    Inspect {
+        layout: PathBuf,
+        /// A tag or manifest digest.
+        image: Option<String>,
+    },
+    /// List the tags in the index.
+    Tags { layout: PathBuf },
+    /// Verify all blobs and index entries.
+    Fsck {
+        layout: PathBuf,
+        /// Drop index entries with missing content.
+        #[arg(long)]
+        repair: bool,
+        /// Delete blobs whose content does not match their digest.
+        #[arg(long)]
+        remove_corrupt: bool,
+        /// Only report what would be repaired.
+        #[arg(long)]
+        dry_run: bool,
+        /// Skip blobs which are unchanged since they were last verified.
+        #[arg(long)]
+        incremental: bool,
+        /// Print the report as JSON.
+        #[arg(long)]
+        json: bool,
+    },
+    /// Delete blobs which are not reachable from the index.
+    Gc {
+        layout: PathBuf,
+        /// Only report what would be deleted.
+        #[arg(long)]
+        dry_run: bool,
+        /// Print the report as JSON.
+        #[arg(long)]
+        json: bool,
+    },
+    /// Check the layout against the OCI image specification.
+    Validate {
+        layout: PathBuf,
+        /// Also check annotations, platforms, consistency and blob digests.
+        #[arg(long)]
+        strict: bool,
+    },
+    /// Copy a layout to a new directory.
+    Cp {
+        src: PathBuf,
+        dest: PathBuf,
+        /// How blobs are transferred.
+        #[arg(long, value_enum, default_value = "auto")]
+        mode: Mode,
+    },
+    /// Write the layout as an uncompressed tarball, or `-` for stdout.
+    Export {
+        layout: PathBuf,
+        output: PathBuf,
+        /// Only export this image, given as a tag or manifest digest.
+        #[arg(long)]
+        image: Option<String>,
+    },
+    /// Extract the layers of an image, applying whiteouts, into a directory.
+    Unpack {
+        layout: PathBuf,
+        /// A tag or manifest digest.
+        image: String,
+        dest: PathBuf,
+    },
+}
+
+fn open_dir(path: &Path) -> Result<Dir> {
+    Dir::open_ambient_dir(path, cap_std::ambient_authority())
+        .with_context(|| format!("Opening {}", path.display()))
+}
+
+fn open_layout(path: &Path) -> Result<OciDir> {
+    OciDir::open(&open_dir(path)?)
+}
+
+/// Open a layout for commands which only read it, which also works on read-only media.
+fn open_layout_readonly(path: &Path) -> Result<OciDir> {
+    OciDir::open_readonly(&open_dir(path)?)
+}
+
+fn find_manifest(d: &OciDir, image: &str) -> Result<ImageManifest> {
+    let manifest = if image.contains(':') {
+        d.read_manifest_by_digest(image)?
+    } else {
+        d.find_manifest_with_tag(image)?
+    };
+    manifest.ok_or_else(|| anyhow!("Image {image} not found"))
+}
+
+fn print_json(v: &impl serde::Serialize) -> Result<()> {
+    let mut stdout = std::io::stdout().lock();
+    serde_json::to_writer_pretty(&mut stdout, v)?;
+    writeln!(stdout)?;
+    Ok(())
+}
+
+fn print_actions(actions: &[FsckAction], dry_run: bool) {
+    let (remove, drop) = if dry_run {
+        ("Would remove", "Would drop")
+    } else {
+        ("Removed", "Dropped")
+    };
+    for action in actions {
+        match action {
+            FsckAction::RemovedCorruptBlob(d) => println!("{remove} corrupt blob {d}"),
+            FsckAction::DroppedIndexEntry(desc) => println!("{drop} {}", desc.digest()),
+            FsckAction::RemovedOrphanBlob(d) => println!("{remove} {d}"),
+            _ => println!("{action:?}"),
+        }
+    }
+}
+
+fn unpack(d: &OciDir, manifest: &ImageManifest, dest: &Dir) -> Result<()> {
+    d.unpack_image(manifest, dest, &Default::default())?;
+    Ok(())
+}
+
+fn run(cli: Cli) -> Result<ExitCode> {
+    match cli.command {
+        Command::Inspect { layout, image } => {
+            let d = open_layout_readonly(&layout)?;
+            match image {
+                Some(image) => print_json(&d.inspect(&image)?)?,
+                None => print_json(&d.describe()?)?,
+            }
+        }
+        Command::Tags { layout } => {
+            for entry in open_layout_readonly(&layout)?.manifests()? {
+                if let Some(tag) = entry.tag {
+                    println!("{tag}");
+                }
+            }
+        }
+        Command::Fsck {
+            layout,
+            repair,
+            remove_corrupt,
+            dry_run,
+            incremental,
+            json,
+        } => {
+            let opts = FsckOptions {
+                repair,
+                remove_corrupt,
+                dry_run,
+                cache: if incremental {
+                    FsckCache::Incremental
+                } else {
+                    FsckCache::Off
+                },
+                ..Default::default()
+            };
+            let report = open_layout(&layout)?.fsck_with(&opts)?;
+            if json {
+                print_json(&report)?;
+            } else {
+                println!("Verified {} blobs", report.verified);
+                if incremental {
+                    println!("Skipped {} unchanged blobs", report.unchanged);
+                }
+                for (digest, err) in &report.corrupt {
+                    println!("Corrupt blob {digest}: {err}");
+                }
+                for digest in &report.unsupported {
+                    println!("Unverified blob {digest}: unsupported digest algorithm");
+                }
+                for desc in &report.incomplete {
+                    println!("Incomplete index entry {}", desc.digest());
+                }
+                print_actions(&report.actions, dry_run);
+            }
+            if !report.is_clean() {
+                return Ok(ExitCode::FAILURE);
+            }
+        }
+        Command::Gc {
+            layout,
+            dry_run,
+            json,
+        } => {
+            let report = open_layout(&layout)?.prune(&PruneOptions { dry_run })?;
+            if json {
+                print_json(&report)?;
+            } else {
+                let remove = if dry_run { "Would remove" } else { "Removed" };
+                for blob in &report.removed {
+                    println!("{remove} {} ({} bytes)", blob.digest, blob.size);
+                }
+            }
+        }
+        Command::Validate { layout, strict } => {
+            let level = if strict {
+                ValidationLevel::Strict
+            } else {
+                ValidationLevel::Basic
+            };
+            let violations = open_layout_readonly(&layout)?.validate(level)?;
+            for v in &violations {
+                println!("{v}");
+            }
+            if !violations.is_empty() {
+                return Ok(ExitCode::FAILURE);
+            }
+        }
+        Command::Cp { src, dest, mode } => {
+            let parent = match dest.parent() {
+                Some(p) if !p.as_os_str().is_empty() => p,
+                _ => Path::new("."),
+            };
+            let name = dest
+                .file_name()
+                .and_then(|n| n.to_str())
+                .ok_or_else(|| anyhow!("Invalid destination {}", dest.display()))?;
+            open_layout(&src)?.clone_to_with(&open_dir(parent)?, name, mode.into())?;
+        }
+        Command::Export {
+            layout,
+            output,
+            image,
+        } => {
+            // Ensure this is a layout before archiving it.
+            let d = open_layout_readonly(&layout)?;
+            let out: Box<dyn Write> = if output.as_os_str() == "-" {
+                Box::new(std::io::stdout().lock())
+            } else {
+                Box::new(std::fs::File::create(&output)?)
+            };
+            let mut out = std::io::BufWriter::new(out);
+            if let Some(image) = image {
+                d.export_to_tar(&image, &mut out)?;
+            } else {
+                let mut builder = tar::Builder::new(&mut out);
+                builder.follow_symlinks(false);
+                builder.append_dir_all(".", &layout)?;
+                builder.finish()?;
+            }
+            out.flush()?;
+        }
+        Command::Unpack {
+            layout,
+            image,
+            dest,
+        } => {
+            let d = open_layout_readonly(&layout)?;
+            let manifest = find_manifest(&d, &image)?;
+            std::fs::create_dir_all(&dest)?;
+            unpack(&d, &manifest, &open_dir(&dest)?)?;
+        }
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+fn main() -> ExitCode {
+    match run(Cli::parse()) {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("error: {e:#}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cap_std_ext::cap_tempfile;
+
+    #[test]
+    fn unpack_whiteouts() -> Result<()> {
+        let td = cap_tempfile::tempdir(cap_std::ambient_authority())?;
+        let w = OciDir::new_in_memory()?;
+        let mut manifest = ocidir::new_empty_manifest().build()?;
+        let mut config = ocidir::oci_spec::image::ImageConfigurationBuilder::default().build()?;
+        let layer = |files: &[(&str, &str)]| -> Result<ocidir::Layer> {
+            let mut b = tar::Builder::new(w.create_gzip_layer(None)?);
+            for (path, content) in files {
+                let mut h = tar::Header::new_gnu();
+                h.set_size(content.len() as u64);
+                h.set_mode(0o644);
+                b.append_data(&mut h, path, content.as_bytes())?;
+            }
+            b.into_inner()?.complete()
+        };
+        let base = layer(&[("a/one", "1"), ("a/two", "2"), ("b/three", "3")])?;
+        w.push_layer(&mut manifest, &mut config, base, "base", None);
+        let upper = layer(&[("a/.wh.one", ""), ("b/.wh..wh..opq", ""), ("b/four", "4")])?;
+        w.push_layer(&mut manifest, &mut config, upper, "upper", None);
+
+        unpack(&w, &manifest, &td)?;
+        assert!(!td.try_exists("a/one")?);
+        assert_eq!(td.read_to_string("a/two")?, "2");
+        assert!(!td.try_exists("b/three")?);
+        assert_eq!(td.read_to_string("b/four")?, "4");
+        Ok(())
+    }
+}
@@ -0,0 +1,126 @@
+//! Reuse of previously built layers, keyed on caller-supplied cache keys.
+
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::sync::Mutex;
+
+use anyhow::Result;
+use cap_std_ext::cap_std::fs::Dir;
+use fn_error_context::context;
+
+use crate::{Layer, LayerTarOptions, OciDir};
+
+/// A cache of layer metadata, see [`crate::OciDirOptions::layer_cache`].
+///
+/// Keys are chosen by the caller and should identify the inputs of a layer, such as
+/// a hash of a source tree or a build step. Only metadata is cached; the blobs
+/// themselves stay in the layout.
+pub trait LayerCache: Send + Sync + Debug {
+    /// Look up the layer previously stored for `key`.
+    fn get(&self, key: &str) -> Result<Option<Layer>>;
+
+    /// Store the layer built for `key`.
+    fn put(&self, key: &str, layer: &Layer) -> Result<()>;
+}
+
+/// A [`LayerCache`] held in memory, for reuse within a process.
+#[derive(Debug, Default)]
+pub struct MemoryLayerCache {
+    layers: Mutex<HashMap<String, Layer>>,
+}
+
+impl LayerCache for MemoryLayerCache {
+    fn get(&self, key: &str) -> Result<Option<Layer>> {
+        Ok(self.layers.lock().unwrap().get(key).cloned())
+    }
+
+    fn put(&self, key: &str, layer: &Layer) -> Result<()> {
+        self.layers
+            .lock()
+            .unwrap()
+            .insert(key.to_owned(), layer.clone());
+        Ok(())
+    }
+}
+
+impl OciDir {
+    /// Return the layer cached for `key` if its blob is present in this layout,
+    /// and otherwise build it with `build` and add it to the cache.
+    ///
+    /// Without a configured [`crate::OciDirOptions::layer_cache`], this always builds.
+    /// The result can be added to an image with [`OciDir::push_layer`] as usual.
+    #[context("Building cached layer {key}")]
+    pub fn cached_layer(
+        &self,
+        key: &str,
+        build: impl FnOnce(&OciDir) -> Result<Layer>,
+    ) -> Result<Layer> {
+        let Some(cache) = self.opts.layer_cache.as_ref() else {
+            return build(self);
+        };
+        if let Some(layer) = cache.get(key)? {
+            if self.store.has(&layer.blob.digest_id())? {
+                return Ok(layer);
+            }
+        }
+        let layer = build(self)?;
+        cache.put(key, &layer)?;
+        Ok(layer)
+    }
+
+    /// Like [`OciDir::create_layer_from_dir`], but reusing the layer cached for `key`.
+    pub fn create_layer_from_dir_cached(
+        &self,
+        src: &Dir,
+        opts: &LayerTarOptions,
+        key: &str,
+    ) -> Result<Layer> {
+        self.cached_layer(key, |w| w.create_layer_from_dir(src, opts))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::BlobStore;
+    use cap_std_ext::{cap_std, cap_tempfile};
+    use std::sync::Arc;
+
+    #[test]
+    fn layer_cache() -> Result<()> {
+        let td = cap_tempfile::tempdir(cap_std::ambient_authority())?;
+        td.write("f", "contents")?;
+        let cache = Arc::new(MemoryLayerCache::default());
+        let opts = crate::OciDirOptions {
+            layer_cache: Some(cache.clone()),
+            ..Default::default()
+        };
+        let in_memory = || -> Result<OciDir> {
+            let store = crate::store::MemoryStore::default();
+            store.write_meta("oci-layout", crate::OCI_LAYOUT_DEFAULT.as_bytes())?;
+            OciDir::with_store(Arc::new(store), &opts)
+        };
+        let w = in_memory()?;
+        let a = w.create_layer_from_dir_cached(&td, &Default::default(), "tree-v1")?;
+        let mut builds = 0;
+        let b = w.cached_layer("tree-v1", |_| {
+            builds += 1;
+            anyhow::bail!("not rebuilt")
+        })?;
+        assert_eq!(builds, 0);
+        assert_eq!(a.blob.digest_id(), b.blob.digest_id());
+        assert_eq!(a.uncompressed_sha256, b.uncompressed_sha256);
+
+        // Entries whose blob is gone, e.g. from another layout, are rebuilt
+        let other = in_memory()?;
+        let c = other.create_layer_from_dir_cached(&td, &Default::default(), "tree-v1")?;
+        assert!(other.store.has(&c.blob.digest_id())?);
+
+        // Without a cache, layers are always built
+        let uncached = OciDir::new_in_memory()?;
+        assert!(uncached
+            .cached_layer("tree-v1", |_| anyhow::bail!("built"))
+            .is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,232 @@
+//! Canonical JSON serialization, for digests which do not depend on serde or
+//! map ordering; see [`to_canonical_json`].
+
+use std::io::Write;
+
+use anyhow::{Context, Result};
+use serde_json::Value;
+
+/// Serialize a value as canonical JSON: without whitespace, with object keys sorted
+/// by their UTF-16 code units and with only `"`, `\` and control characters
+/// escaped, and with numbers formatted as ECMAScript does, as in [RFC 8785].
+/// Integers beyond ±2<sup>53</sup>, which cannot be represented exactly as a
+/// double, are rejected.
+///
+/// This is used for manifests, configs and the index with
+/// [`crate::OciDirOptions::canonical_json`] and [`crate::JsonBlobOptions::canonical`].
+///
+/// [RFC 8785]: https://www.rfc-editor.org/rfc/rfc8785
+pub fn to_canonical_json<S: serde::Serialize + ?Sized>(v: &S) -> Result<Vec<u8>> {
+    let v = serde_json::to_value(v).context("Failed to serialize")?;
+    let mut buf = Vec::new();
+    write_value(&mut buf, &v)?;
+    Ok(buf)
+}
+
+fn write_string(w: &mut Vec<u8>, s: &str) -> Result<()> {
+    w.push(b'"');
+    for c in s.chars() {
+        match c {
+            '"' => w.extend_from_slice(b"\\\""),
+            '\\' => w.extend_from_slice(b"\\\\"),
+            '\u{8}' => w.extend_from_slice(b"\\b"),
+            '\t' => w.extend_from_slice(b"\\t"),
+            '\n' => w.extend_from_slice(b"\\n"),
+            '\u{c}' => w.extend_from_slice(b"\\f"),
+            '\r' => w.extend_from_slice(b"\\r"),
+            c if c < ' ' => write!(w, "\\u{:04x}", c as u32)?,
+            c => {
+                let mut b = [0u8; 4];
+                w.extend_from_slice(c.encode_utf8(&mut b).as_bytes());
+            }
+        }
+    }
+    w.push(b'"');
+    Ok(())
+}
+
+/// The largest integer below which all integers are exactly representable as a double.
+const MAX_SAFE_INTEGER: u64 = 1 << 53;
+
+/// Write a number in the ECMAScript format required by RFC 8785 §3.2.2.3.
+fn write_number(w: &mut Vec<u8>, n: &serde_json::Number) -> Result<()> {
+    let exact = match (n.as_u64(), n.as_i64()) {
+        (Some(u), _) => u <= MAX_SAFE_INTEGER,
+        (None, Some(i)) => i.unsigned_abs() <= MAX_SAFE_INTEGER,
+        (None, None) => true,
+    };
+    let Some(x) = n.as_f64().filter(|_| exact) else {
+        anyhow::bail!("Number {n} cannot be represented exactly in canonical JSON");
+    };
+    if x == 0.0 {
+        w.push(b'0');
+        return Ok(());
+    }
+    if x < 0.0 {
+        w.push(b'-');
+    }
+    // The shortest digits which round trip, as `d.ddde<exp>`.
+    let sci = format!("{:e}", x.abs());
+    let (mantissa, exp) = sci.split_once('e').unwrap();
+    let digits = mantissa.replace('.', "");
+    let k = digits.len() as i32;
+    // The value is 0.<digits> * 10^n
+    let n = exp.parse::<i32>()? + 1;
+    if k <= n && n <= 21 {
+        w.extend_from_slice(digits.as_bytes());
+        w.resize(w.len() + (n - k) as usize, b'0');
+    } else if 0 < n && n <= 21 {
+        let (int, frac) = digits.split_at(n as usize);
+        write!(w, "{int}.{frac}")?;
+    } else if -6 < n && n <= 0 {
+        w.extend_from_slice(b"0.");
+        w.resize(w.len() + (-n) as usize, b'0');
+        w.extend_from_slice(digits.as_bytes());
+    } else {
+        let (first, rest) = digits.split_at(1);
+        w.extend_from_slice(first.as_bytes());
+        if !rest.is_empty() {
+            write!(w, ".{rest}")?;
+        }
+        let sign = if n > 0 { '+' } else { '-' };
+        write!(w, "e{sign}{}", (n - 1).abs())?;
+    }
+    Ok(())
+}
+
+fn write_value(w: &mut Vec<u8>, v: &Value) -> Result<()> {
+    match v {
+        Value::Null => w.extend_from_slice(b"null"),
+        Value::Bool(b) => write!(w, "{b}")?,
+        Value::Number(n) => write_number(w, n)?,
+        Value::String(s) => write_string(w, s)?,
+        Value::Array(a) => {
+            w.push(b'[');
+            for (i, v) in a.iter().enumerate() {
+                if i > 0 {
+                    w.push(b',');
+                }
+                write_value(w, v)?;
+            }
+            w.push(b']');
+        }
+        Value::Object(o) => {
+            let mut entries: Vec<_> = o.iter().collect();
+            entries.sort_by(|(a, _), (b, _)| a.encode_utf16().cmp(b.encode_utf16()));
+            w.push(b'{');
+            for (i, (k, v)) in entries.into_iter().enumerate() {
+                if i > 0 {
+                    w.push(b',');
+                }
+                write_string(w, k)?;
+                w.push(b':');
+                write_value(w, v)?;
+            }
+            w.push(b'}');
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::BlobStore;
+    use crate::OciDir;
+    use cap_std_ext::{cap_std, cap_tempfile};
+    use oci_spec::image::{ImageConfigurationBuilder, MediaType};
+    use serde_json::json;
+
+    #[test]
+    fn canonical() -> Result<()> {
+        let v = json!({
+            "z": [1, -2, true, null],
+            "a": {"\u{e9}": "caf\u{e9}", "b": "line\nbreak \"quoted\" \\ \u{1}/"},
+            "\u{1f600}": 0,
+            "\u{ff61}": 1,
+        });
+        let buf = to_canonical_json(&v)?;
+        // U+1F600 is a surrogate pair in UTF-16, which sorts before U+FF61.
+        let expected = "{\"a\":{\"b\":\"line\\nbreak \\\"quoted\\\" \\\\ \\u0001/\",\"\u{e9}\":\"caf\u{e9}\"},\
+                        \"z\":[1,-2,true,null],\"\u{1f600}\":0,\"\u{ff61}\":1}";
+        assert_eq!(std::str::from_utf8(&buf)?, expected);
+        let parsed: Value = serde_json::from_slice(&buf)?;
+        assert_eq!(parsed, v);
+
+        let td = cap_tempfile::tempdir(cap_std::ambient_authority())?;
+        let opts = crate::JsonBlobOptions {
+            canonical: true,
+            ..Default::default()
+        };
+        let desc = crate::write_json_blob_with(&td, &v, MediaType::EmptyJSON, &opts)?.build()?;
+        let blob = td.read(crate::store::blob_path(desc.digest())?)?;
+        assert_eq!(std::str::from_utf8(&blob)?, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn numbers() -> Result<()> {
+        // From RFC 8785 appendix B, and the ECMAScript formatting boundaries
+        for (v, expected) in [
+            (json!(0.0), "0"),
+            (json!(-0.0), "0"),
+            (json!(1.0), "1"),
+            (json!(-1.5), "-1.5"),
+            (json!(5e-324), "5e-324"),
+            (json!(-1.7976931348623157e308), "-1.7976931348623157e+308"),
+            (json!(9007199254740992u64), "9007199254740992"),
+            (json!(-9007199254740992i64), "-9007199254740992"),
+            (json!(295147905179352830000.0), "295147905179352830000"),
+            (json!(1e20), "100000000000000000000"),
+            (json!(1e21), "1e+21"),
+            (json!(1.5e22), "1.5e+22"),
+            (json!(333333333.3333333), "333333333.3333333"),
+            (json!(0.000001), "0.000001"),
+            (json!(0.0000012), "0.0000012"),
+            (json!(1e-7), "1e-7"),
+            (json!(-1.25e-10), "-1.25e-10"),
+        ] {
+            assert_eq!(std::str::from_utf8(&to_canonical_json(&v)?)?, expected);
+        }
+        assert!(to_canonical_json(&json!(9007199254740993u64)).is_err());
+        assert!(to_canonical_json(&json!(i64::MIN)).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn pinned_digests() -> Result<()> {
+        let opts = crate::OciDirOptions {
+            canonical_json: true,
+            ..Default::default()
+        };
+        let store = crate::store::MemoryStore::default();
+        store.write_meta("oci-layout", crate::OCI_LAYOUT_DEFAULT.as_bytes())?;
+        let w = OciDir::with_store(std::sync::Arc::new(store), &opts)?;
+        let mut config = ImageConfigurationBuilder::default()
+            .architecture("amd64")
+            .os("linux")
+            .build()?;
+        config.set_created(Some("2024-01-01T00:00:00Z".into()));
+        let config = w.write_config(config)?;
+        assert_eq!(
+            config.digest().as_str(),
+            "sha256:da58ca918ad699b999365720ddda9257cc8cbbec5c6fe6201e1c526e2da842d3"
+        );
+        let mut manifest = crate::new_empty_manifest().build()?;
+        manifest.set_media_type(Some(MediaType::ImageManifest));
+        manifest.set_config(config);
+        let opts = crate::InsertOptions {
+            tag: Some("latest".into()),
+            ..Default::default()
+        };
+        let desc = w.insert_manifest_with(manifest, &opts)?;
+        assert_eq!(
+            desc.digest().as_str(),
+            "sha256:e9f57fafa8c9ad7f7928fd268c1e594191fe165703e344abba0a1ae540eece5d"
+        );
+        let index = w.store.read_meta("index.json")?.unwrap();
+        let expected = r#"{"manifests":[{"annotations":{"org.opencontainers.image.ref.name":"latest"},"digest":"sha256:e9f57fafa8c9ad7f7928fd268c1e594191fe165703e344abba0a1ae540eece5d","mediaType":"application/vnd.oci.image.manifest.v1+json","size":248}],"schemaVersion":2}"#;
+        assert_eq!(std::str::from_utf8(&index)?, expected);
+        Ok(())
+    }
+}
@@ -0,0 +1,490 @@
+//! Content-defined chunking of layers for deduplicated storage.
+//!
+//! [`OciDir::chunk_layer`] splits the uncompressed content of a layer into chunks
+//! with FastCDC, so that similar layers share most of their chunk blobs. The chunks
+//! and a [`ChunkRecipe`] are stored as a referrer artifact of the image manifest,
+//! after which the layer blob itself can be removed and later reconstructed bit for
+//! bit with [`OciDir::reconstruct_layer`].
+
+use std::io::{Read, Write};
+
+use anyhow::{anyhow, Result};
+use fn_error_context::context;
+use oci_spec::image::{Descriptor, DescriptorBuilder, ImageManifest, MediaType};
+use serde::{Deserialize, Serialize};
+
+use crate::hash::Sha256;
+use crate::{CompressionFormat, OciDir};
+
+/// The artifact type of referrers holding chunked layers.
+pub const CHUNKED_LAYER_ARTIFACT_TYPE: &str = "application/vnd.containers.ocidir.chunked-layer.v1";
+/// The media type of a serialized [`ChunkRecipe`].
+pub const RECIPE_MEDIA_TYPE: &str = "application/vnd.containers.ocidir.chunk-recipe.v1+json";
+/// The media type of chunk blobs.
+pub const CHUNK_MEDIA_TYPE: &str = "application/vnd.containers.ocidir.chunk.v1";
+/// Annotation on the recipe descriptor holding the digest of the chunked layer.
+pub const CHUNKED_LAYER_ANNOTATION: &str = "io.containers.ocidir.chunked-layer";
+
+/// Options for [`OciDir::chunk_layer`].
+#[derive(Debug, Clone)]
+pub struct ChunkOptions {
+    /// The minimum chunk size, except for the last chunk.
+    pub min_size: usize,
+    /// The target average chunk size, which must be a power of two.
+    pub avg_size: usize,
+    /// The maximum chunk size.
+    pub max_size: usize,
+    /// Delete the layer blob after chunking it. Until it is reconstructed, the image
+    /// is incomplete and [`OciDir::fsck`] reports the layer as missing.
+    pub remove_layer: bool,
+}
+
+impl Default for ChunkOptions {
+    fn default() -> Self {
+        Self {
+            min_size: 16 * 1024,
+            avg_size: 64 * 1024,
+            max_size: 256 * 1024,
+            remove_layer: false,
+        }
+    }
+}
+
+/// How the concatenated chunks of a [`ChunkRecipe`] are turned back into the layer blob.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase", tag = "type")]
+#[non_exhaustive]
+pub enum ChunkEncoding {
+    /// The chunks are the layer blob itself.
+    Raw,
+    /// The chunks are the uncompressed layer, which is gzip compressed with
+    /// [`flate2`] at this level and default headers.
+    Gzip {
+        /// The compression level.
+        level: u32,
+    },
+}
+
+/// A chunk of a [`ChunkRecipe`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RecipeChunk {
+    /// The digest of the chunk blob.
+    pub digest: String,
+    /// The size of the chunk.
+    pub size: u64,
+}
+
+/// The description of how a layer blob is reconstructed from chunks.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChunkRecipe {
+    /// The descriptor of the layer.
+    pub layer: Descriptor,
+    /// How the chunks are encoded into the layer blob.
+    pub encoding: ChunkEncoding,
+    /// The chunks, in order; the same chunk may appear more than once.
+    pub chunks: Vec<RecipeChunk>,
+}
+
+/// The result of [`OciDir::chunk_layer`].
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct ChunkedLayer {
+    /// The referrer artifact holding the recipe and chunks.
+    pub referrer: Descriptor,
+    /// The recipe.
+    pub recipe: ChunkRecipe,
+    /// The number of chunk blobs which were not already present.
+    pub added_chunks: u64,
+    /// The total size of the added chunk blobs.
+    pub added_bytes: u64,
+}
+
+/// Random values per byte for the gear rolling hash, generated with splitmix64
+/// so that chunk boundaries are stable across releases.
+const GEAR: [u64; 256] = {
+    let mut r = [0u64; 256];
+    let mut state: u64 = 0x6f63_6964_6972_6364;
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        r[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    r
+};
+
+/// A mask of the `bits` most significant bits, which depend on the most bytes
+/// of the gear hash.
+fn high_mask(bits: u32) -> u64 {
+    !0u64 << (64 - bits)
+}
+
+/// FastCDC with normalized chunking: the length of the first chunk of `data`,
+/// which must hold at least `max_size` bytes unless it is the end of the input.
+fn cut_point(data: &[u8], opts: &ChunkOptions) -> usize {
+    if data.len() <= opts.min_size {
+        return data.len();
+    }
+    let n = data.len().min(opts.max_size);
+    let normal = opts.avg_size.min(n);
+    let bits = opts.avg_size.ilog2();
+    // Harder to cut before the average size, and easier after it.
+    let (mask_s, mask_l) = (
+        high_mask(bits + 2),
+        high_mask(bits.saturating_sub(2).max(1)),
+    );
+    let mut hash = 0u64;
+    for (i, &b) in data.iter().enumerate().take(n).skip(opts.min_size) {
+        hash = (hash << 1).wrapping_add(GEAR[b as usize]);
+        let mask = if i < normal { mask_s } else { mask_l };
+        if hash & mask == 0 {
+            return i + 1;
+        }
+    }
+    n
+}
+
+/// Split a stream into content-defined chunks, calling `f` for each.
+fn for_each_chunk(
+    mut r: impl Read,
+    opts: &ChunkOptions,
+    mut f: impl FnMut(&[u8]) -> Result<()>,
+) -> Result<()> {
+    let mut buf = vec![0u8; opts.max_size];
+    let mut len = 0;
+    let mut eof = false;
+    loop {
+        while !eof && len < buf.len() {
+            match r.read(&mut buf[len..])? {
+                0 => eof = true,
+                n => len += n,
+            }
+        }
+        if len == 0 {
+            return Ok(());
+        }
+        let cut = cut_point(&buf[..len], opts);
+        f(&buf[..cut])?;
+        buf.copy_within(cut..len, 0);
+        len -= cut;
+    }
+}
+
+fn chunk_descriptor(c: &RecipeChunk) -> Result<Descriptor> {
+    Ok(DescriptorBuilder::default()
+        .media_type(MediaType::Other(CHUNK_MEDIA_TYPE.into()))
+        .digest(c.digest.as_str())
+        .size(i64::try_from(c.size)?)
+        .build()?)
+}
+
+impl ChunkOptions {
+    fn validate(&self) -> Result<()> {
+        if !self.avg_size.is_power_of_two() {
+            anyhow::bail!("Average chunk size {} is not a power of two", self.avg_size);
+        }
+        if !(0 < self.min_size && self.min_size < self.avg_size && self.avg_size < self.max_size) {
+            anyhow::bail!(
+                "Invalid chunk sizes: min {}, average {}, max {}",
+                self.min_size,
+                self.avg_size,
+                self.max_size
+            );
+        }
+        Ok(())
+    }
+}
+
+impl OciDir {
+    /// Returns true if recompressing the content of a gzip layer at the default
+    /// level reproduces it exactly.
+    fn gzip_reproducible(&self, layer: &Descriptor, level: flate2::Compression) -> Result<bool> {
+        let (_, mut r) = self.open_blob_decompressed(layer)?;
+        let mut enc = flate2::write::GzEncoder::new(Sha256::new()?, level);
+        std::io::copy(&mut r, &mut enc)?;
+        let digest = format!("sha256:{}", enc.finish()?.finish_hex()?);
+        Ok(digest == layer.digest().as_str())
+    }
+
+    /// Split the layer `layer` of the image manifest `manifest` into content-defined
+    /// chunks, and attach them along with their [`ChunkRecipe`] to the manifest as a
+    /// referrer artifact. Chunks which are already present, for example from a
+    /// similar layer, are not written again.
+    ///
+    /// Gzip layers whose compressed form can be reproduced are chunked by their
+    /// uncompressed content; all other layers by their blob, which deduplicates less.
+    #[context("Chunking layer {}", layer.digest())]
+    pub fn chunk_layer(
+        &self,
+        manifest: &Descriptor,
+        layer: &Descriptor,
+        opts: &ChunkOptions,
+    ) -> Result<ChunkedLayer> {
+        opts.validate()?;
+        let m: ImageManifest = self.read_json_blob(manifest)?;
+        if !m.layers().iter().any(|l| l.digest() == layer.digest()) {
+            anyhow::bail!("Layer is not part of manifest {}", manifest.digest());
+        }
+        let level = flate2::Compression::default();
+        let gzip = CompressionFormat::from_media_type(layer.media_type())
+            == Some(CompressionFormat::Gzip)
+            && self.gzip_reproducible(layer, level)?;
+        let (encoding, r): (_, Box<dyn Read>) = if gzip {
+            let encoding = ChunkEncoding::Gzip {
+                level: level.level(),
+            };
+            (encoding, self.open_blob_decompressed(layer)?.1)
+        } else {
+            (ChunkEncoding::Raw, Box::new(self.read_blob(layer)?))
+        };
+
+        let mut chunks = Vec::new();
+        let mut layers = Vec::new();
+        let (mut added_chunks, mut added_bytes) = (0, 0);
+        for_each_chunk(r, opts, |buf| {
+            let (blob, added) = self.write_blob_dedup(buf)?;
+            let chunk = RecipeChunk {
+                digest: blob.digest_id(),
+                size: blob.size,
+            };
+            if added {
+                added_chunks += 1;
+                added_bytes += blob.size;
+            }
+            if !layers
+                .iter()
+                .any(|d: &Descriptor| d.digest() == &chunk.digest)
+            {
+                layers.push(chunk_descriptor(&chunk)?);
+            }
+            chunks.push(chunk);
+            Ok(())
+        })?;
+
+        let recipe = ChunkRecipe {
+            layer: layer.clone(),
+            encoding,
+            chunks,
+        };
+        let mut recipe_desc = self
+            .write_blob_with_type(
+                &serde_json::to_vec(&recipe)?,
+                MediaType::Other(RECIPE_MEDIA_TYPE.into()),
+            )?
+            .build()?;
+        recipe_desc.set_annotations(Some(
+            [(
+                CHUNKED_LAYER_ANNOTATION.to_owned(),
+                layer.digest().to_string(),
+            )]
+            .into(),
+        ));
+        layers.insert(0, recipe_desc);
+        let referrer = self.attach_referrer(
+            manifest,
+            MediaType::Other(CHUNKED_LAYER_ARTIFACT_TYPE.into()),
+            layers,
+            None,
+        )?;
+        // A small raw layer may be its own only chunk.
+        let is_chunk = recipe
+            .chunks
+            .iter()
+            .any(|c| c.digest == layer.digest().as_str());
+        if opts.remove_layer && !is_chunk {
+            self.writable_dir()?;
+            self.store.delete(layer.digest())?;
+        }
+        Ok(ChunkedLayer {
+            referrer,
+            recipe,
+            added_chunks,
+            added_bytes,
+        })
+    }
+
+    /// Find the recipe for the layer with the provided digest among the chunked
+    /// layers attached to `manifest`.
+    #[context("Finding recipe for layer {layer_digest}")]
+    pub fn find_layer_recipe(
+        &self,
+        manifest: &Descriptor,
+        layer_digest: &str,
+    ) -> Result<Option<ChunkRecipe>> {
+        let artifact_type = MediaType::Other(CHUNKED_LAYER_ARTIFACT_TYPE.into());
+        for referrer in self.referrers(manifest, Some(&artifact_type))? {
+            let m: ImageManifest = self.read_json_blob(&referrer)?;
+            let Some(recipe) = m.layers().first() else {
+                continue;
+            };
+            let chunked = recipe
+                .annotations()
+                .as_ref()
+                .and_then(|a| a.get(CHUNKED_LAYER_ANNOTATION));
+            if chunked.map(|s| s.as_str()) == Some(layer_digest) {
+                return Ok(Some(self.read_json_blob(recipe)?));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Write the layer blob described by `recipe` from its chunks, unless it is already
+    /// present, and return its descriptor. The result is verified against the
+    /// digest and size of the original layer.
+    #[context("Reconstructing layer {}", recipe.layer.digest())]
+    pub fn reconstruct_layer(&self, recipe: &ChunkRecipe) -> Result<Descriptor> {
+        let layer = &recipe.layer;
+        if self.store.has(layer.digest())? {
+            return Ok(layer.clone());
+        }
+        let w = self.create_blob_with_expected(layer.digest(), u64::try_from(layer.size())?)?;
+        let copy_chunks = |w: &mut dyn Write| -> Result<()> {
+            for c in &recipe.chunks {
+                let mut r = self.read_blob(&chunk_descriptor(c)?)?;
+                std::io::copy(&mut r, w)?;
+            }
+            Ok(())
+        };
+        let w = match recipe.encoding {
+            ChunkEncoding::Raw => {
+                let mut w = w;
+                copy_chunks(&mut w)?;
+                w
+            }
+            ChunkEncoding::Gzip { level } => {
+                if level > 9 {
+                    return Err(anyhow!("Invalid gzip level {level}"));
+                }
+                let mut enc = flate2::write::GzEncoder::new(w, flate2::Compression::new(level));
+                copy_chunks(&mut enc)?;
+                enc.finish()?
+            }
+        };
+        w.complete()?;
+        Ok(layer.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Deterministic pseudo-random content.
+    fn noise(seed: u64, len: usize) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    fn push_image(w: &OciDir, contents: &[u8], tag: &str) -> Result<(Descriptor, Descriptor)> {
+        let mut manifest = crate::new_empty_manifest().build()?;
+        let mut config = oci_spec::image::ImageConfigurationBuilder::default().build()?;
+        let mut layer = w.create_gzip_layer(None)?;
+        layer.write_all(contents)?;
+        let layer = layer.complete()?;
+        let desc = layer.descriptor().build()?;
+        w.push_layer(&mut manifest, &mut config, layer, "layer", None);
+        let m = w.insert_manifest_and_config(manifest, config, Some(tag), Default::default())?;
+        Ok((m, desc))
+    }
+
+    #[test]
+    fn cut_points() {
+        let opts = ChunkOptions {
+            min_size: 64,
+            avg_size: 256,
+            max_size: 1024,
+            ..Default::default()
+        };
+        let data = noise(1, 64 * 1024);
+        let mut sizes = Vec::new();
+        for_each_chunk(data.as_slice(), &opts, |c| {
+            sizes.push(c.len());
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(sizes.iter().sum::<usize>(), data.len());
+        assert!(sizes[..sizes.len() - 1]
+            .iter()
+            .all(|&n| (64..=1024).contains(&n)));
+        let avg = data.len() / sizes.len();
+        assert!((128..=512).contains(&avg), "{avg}");
+        // An insertion only changes the chunks around it
+        let mut shifted = b"inserted".to_vec();
+        shifted.extend_from_slice(&data);
+        let mut shifted_sizes = Vec::new();
+        for_each_chunk(shifted.as_slice(), &opts, |c| {
+            shifted_sizes.push(c.len());
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(
+            sizes[sizes.len() - 20..],
+            shifted_sizes[shifted_sizes.len() - 20..]
+        );
+        assert!(ChunkOptions {
+            avg_size: 1000,
+            ..Default::default()
+        }
+        .validate()
+        .is_err());
+    }
+
+    #[test]
+    fn chunk_layer() -> Result<()> {
+        let w = OciDir::new_in_memory()?;
+        let base = noise(2, 1024 * 1024);
+        let mut updated = base.clone();
+        updated[512 * 1024..512 * 1024 + 8].copy_from_slice(b"modified");
+        let (m1, l1) = push_image(&w, &base, "v1")?;
+        let (m2, l2) = push_image(&w, &updated, "v2")?;
+        let opts = ChunkOptions {
+            remove_layer: true,
+            ..Default::default()
+        };
+
+        let c1 = w.chunk_layer(&m1, &l1, &opts)?;
+        assert_eq!(c1.recipe.encoding, ChunkEncoding::Gzip { level: 6 });
+        assert_eq!(c1.added_bytes, base.len() as u64);
+        let c2 = w.chunk_layer(&m2, &l2, &opts)?;
+        // Only the chunk containing the change is new
+        assert_eq!(c2.added_chunks, 1);
+        assert!(c2.added_bytes < c1.added_bytes / 4);
+        assert!(!w.store.has(l1.digest())? && !w.store.has(l2.digest())?);
+
+        let recipe = w.find_layer_recipe(&m2, l2.digest())?.unwrap();
+        assert_eq!(recipe, c2.recipe);
+        assert!(w.find_layer_recipe(&m1, l2.digest())?.is_none());
+        assert_eq!(w.reconstruct_layer(&recipe)?, l2);
+        let (_, mut r) = w.open_blob_decompressed(&l2)?;
+        let mut buf = Vec::new();
+        r.read_to_end(&mut buf)?;
+        assert_eq!(buf, updated);
+        w.reconstruct_layer(&c1.recipe)?;
+        w.fsck()?;
+
+        // Layers which cannot be recompressed identically are chunked as is
+        let mut raw = w.create_gzip_layer(Some(flate2::Compression::best()))?;
+        raw.write_all(&noise(3, 4096))?;
+        let raw = raw.complete()?;
+        let raw_desc = raw.descriptor().build()?;
+        let mut manifest = crate::new_empty_manifest().build()?;
+        let mut config = oci_spec::image::ImageConfigurationBuilder::default().build()?;
+        w.push_layer(&mut manifest, &mut config, raw, "layer", None);
+        let m3 = w.insert_manifest_and_config(manifest, config, None, Default::default())?;
+        let c3 = w.chunk_layer(&m3, &raw_desc, &opts)?;
+        assert_eq!(c3.recipe.encoding, ChunkEncoding::Raw);
+        w.reconstruct_layer(&c3.recipe)?;
+        assert!(w.chunk_layer(&m3, &l1, &opts).is_err());
+        Ok(())
+    }
+}
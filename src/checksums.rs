@@ -0,0 +1,128 @@
+//! A `SHA256SUMS` style integrity manifest covering a whole layout, which can
+//! also be checked with standard tools via `sha256sum -c SHA256SUMS`.
+
+use std::io::Read;
+
+use anyhow::{anyhow, Context, Result};
+use fn_error_context::context;
+
+use crate::hash::{sha256_hex, Sha256};
+use crate::store::{blob_path, find_blob_path, split_digest};
+use crate::OciDir;
+
+/// The name of the checksums file at the root of the layout.
+pub const CHECKSUMS_FILE: &str = "SHA256SUMS";
+
+fn sha256_reader(mut r: impl Read) -> Result<String> {
+    let mut hasher = Sha256::new()?;
+    std::io::copy(&mut r, &mut hasher)?;
+    Ok(hasher.finish_hex()?)
+}
+
+impl OciDir {
+    /// Write [`CHECKSUMS_FILE`] listing the SHA-256 of every blob and of `index.json`,
+    /// returning the number of entries.
+    ///
+    /// Changes to the layout after this call are not reflected; the file should be
+    /// regenerated once the layout is complete.
+    #[context("Writing checksums manifest")]
+    pub fn write_checksums_manifest(&self) -> Result<usize> {
+        let mut entries = Vec::new();
+        for digest in self.store.list()? {
+            let (alg, encoded) = split_digest(&digest)?;
+            let sum = if alg == "sha256" {
+                encoded.to_owned()
+            } else {
+                let (f, _) = self.open_blob_sized(&digest)?;
+                sha256_reader(f)?
+            };
+            let path = match self.store.as_dir() {
+                Some(dir) => find_blob_path(dir, &digest)?,
+                None => None,
+            };
+            let path = path.map_or_else(|| blob_path(&digest), Ok)?;
+            entries.push((sum, path.into_string()));
+        }
+        if let Some(index) = self.store.read_meta("index.json")? {
+            entries.push((sha256_hex(&index)?, "index.json".to_owned()));
+        }
+        let contents: String = entries
+            .iter()
+            .map(|(sum, path)| format!("{sum}  {path}\n"))
+            .collect();
+        self.store.write_meta(CHECKSUMS_FILE, contents.as_bytes())?;
+        Ok(entries.len())
+    }
+
+    /// Verify all entries of [`CHECKSUMS_FILE`], returning the number of verified files.
+    ///
+    /// An error is returned if the file is missing, or if any listed file is missing
+    /// or does not match.
+    #[context("Verifying checksums manifest")]
+    pub fn verify_checksums_manifest(&self) -> Result<usize> {
+        let contents = self
+            .store
+            .read_meta(CHECKSUMS_FILE)?
+            .ok_or_else(|| anyhow!("Missing {CHECKSUMS_FILE}"))?;
+        let contents = String::from_utf8(contents).context("Parsing checksums")?;
+        let mut n = 0;
+        for line in contents.lines().filter(|l| !l.is_empty()) {
+            let (expected, path) = line
+                .split_once(' ')
+                .ok_or_else(|| anyhow!("Invalid checksum line: {line}"))?;
+            // Both the text (` `) and binary (`*`) markers of sha256sum are accepted.
+            let path = path
+                .strip_prefix(' ')
+                .or_else(|| path.strip_prefix('*'))
+                .ok_or_else(|| anyhow!("Invalid checksum line: {line}"))?;
+            let found = if let Some(blob) = path.strip_prefix("blobs/") {
+                // Sharded blobs have a prefix directory before the encoded digest.
+                let (alg, encoded) = blob
+                    .split_once('/')
+                    .map(|(alg, rest)| (alg, rest.rsplit('/').next().unwrap_or(rest)))
+                    .ok_or_else(|| anyhow!("Invalid blob path: {path}"))?;
+                let digest = format!("{alg}:{encoded}");
+                let f = self
+                    .store
+                    .get(&digest)?
+                    .ok_or_else(|| anyhow!("Missing blob {digest}"))?;
+                sha256_reader(f)?
+            } else {
+                let contents = self
+                    .store
+                    .read_meta(path)?
+                    .ok_or_else(|| anyhow!("Missing {path}"))?;
+                sha256_hex(&contents)?
+            };
+            if found != expected {
+                anyhow::bail!(
+                    "Checksum mismatch for {path}: expected {expected} but found {found}"
+                );
+            }
+            n += 1;
+        }
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::insert_test_image;
+
+    #[test]
+    fn checksums() -> Result<()> {
+        let w = OciDir::new_in_memory()?;
+        assert!(w.verify_checksums_manifest().is_err());
+        insert_test_image(&w)?;
+        // layer, config, manifest and index.json
+        assert_eq!(w.write_checksums_manifest()?, 4);
+        assert_eq!(w.verify_checksums_manifest()?, 4);
+        let contents = w.store.read_meta(CHECKSUMS_FILE)?.unwrap();
+        assert!(String::from_utf8(contents)?.ends_with("  index.json\n"));
+
+        w.store.write_meta("index.json", b"{}")?;
+        assert!(w.verify_checksums_manifest().is_err());
+        Ok(())
+    }
+}
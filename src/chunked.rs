@@ -0,0 +1,158 @@
+//! Splitting blobs into independently verifiable byte ranges, e.g. for
+//! parallel chunked uploads to a registry.
+
+use std::io::{Read, Seek, SeekFrom};
+
+use anyhow::{anyhow, Context, Result};
+use fn_error_context::context;
+use oci_spec::image::Descriptor;
+use serde::{Deserialize, Serialize};
+
+use crate::hash::Sha256;
+use crate::{BlobReader, OciDir};
+
+/// Descriptor annotation holding the JSON-serialized list of [`BlobChunk`]s.
+pub const CHUNKS_ANNOTATION: &str = "io.containers.ocidir.chunks";
+
+/// A byte range of a blob, along with the digest of its contents.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlobChunk {
+    /// Offset of this chunk in the blob.
+    pub offset: u64,
+    /// Length of this chunk.
+    pub size: u64,
+    /// Digest of the chunk contents, in `sha256:<hex>` form.
+    pub digest: String,
+}
+
+impl BlobChunk {
+    /// Open a reader which yields exactly the bytes of this chunk.
+    ///
+    /// Readers for different chunks are independent and can be driven from separate threads.
+    pub fn open(&self, ocidir: &OciDir, desc: &Descriptor) -> Result<std::io::Take<BlobReader>> {
+        let mut f = ocidir.read_blob(desc)?;
+        f.seek(SeekFrom::Start(self.offset))?;
+        Ok(f.take(self.size))
+    }
+}
+
+/// Compute the chunk boundaries for splitting `size` bytes into at most `n` parts.
+fn chunk_ranges(size: u64, n: u64) -> impl Iterator<Item = (u64, u64)> {
+    let chunk_size = size.div_ceil(n).max(1);
+    (0..size)
+        .step_by(chunk_size as usize)
+        .map(move |offset| (offset, chunk_size.min(size - offset)))
+}
+
+/// Record the provided chunks as an annotation on the descriptor.
+pub fn set_chunks_annotation(desc: &mut Descriptor, chunks: &[BlobChunk]) -> Result<()> {
+    let v = serde_json::to_string(chunks)?;
+    let mut annotations = desc.annotations().clone().unwrap_or_default();
+    annotations.insert(CHUNKS_ANNOTATION.to_string(), v);
+    desc.set_annotations(Some(annotations));
+    Ok(())
+}
+
+/// Parse chunks previously recorded via [`set_chunks_annotation`].
+pub fn chunks_from_annotation(desc: &Descriptor) -> Result<Option<Vec<BlobChunk>>> {
+    let Some(v) = desc
+        .annotations()
+        .as_ref()
+        .and_then(|a| a.get(CHUNKS_ANNOTATION))
+    else {
+        return Ok(None);
+    };
+    let chunks: Vec<BlobChunk> =
+        serde_json::from_str(v).with_context(|| format!("Parsing {CHUNKS_ANNOTATION}"))?;
+    let mut expected_offset = 0;
+    for chunk in chunks.iter() {
+        if chunk.offset != expected_offset {
+            anyhow::bail!("Non-contiguous chunk at offset {}", chunk.offset);
+        }
+        expected_offset += chunk.size;
+    }
+    if expected_offset != u64::try_from(desc.size())? {
+        anyhow::bail!(
+            "Chunks cover {expected_offset} bytes, but blob size is {}",
+            desc.size()
+        );
+    }
+    Ok(Some(chunks))
+}
+
+impl OciDir {
+    /// Split a blob into at most `n` contiguous byte ranges, computing the digest of each.
+    ///
+    /// The result can be stored on the descriptor with [`set_chunks_annotation`], and
+    /// each chunk read independently via [`BlobChunk::open`].
+    #[context("Splitting blob {}", desc.digest())]
+    pub fn split_blob(&self, desc: &Descriptor, n: usize) -> Result<Vec<BlobChunk>> {
+        let n = u64::try_from(n)?;
+        if n == 0 {
+            return Err(anyhow!("Invalid chunk count 0"));
+        }
+        let size = u64::try_from(desc.size())?;
+        let mut f = std::io::BufReader::new(self.read_blob(desc)?);
+        let mut r = Vec::new();
+        for (offset, len) in chunk_ranges(size, n) {
+            let mut hasher = Sha256::new()?;
+            let copied = std::io::copy(&mut (&mut f).take(len), &mut hasher)?;
+            if copied != len {
+                anyhow::bail!("Blob is truncated; expected {size} bytes");
+            }
+            r.push(BlobChunk {
+                offset,
+                size: len,
+                digest: format!("sha256:{}", hasher.finish_hex()?),
+            });
+        }
+        Ok(r)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cap_std;
+    use crate::tests::write_test_layer;
+    use cap_std_ext::cap_tempfile;
+
+    #[test]
+    fn ranges() {
+        let r: Vec<_> = chunk_ranges(10, 3).collect();
+        assert_eq!(r, [(0, 4), (4, 4), (8, 2)]);
+        let r: Vec<_> = chunk_ranges(2, 5).collect();
+        assert_eq!(r, [(0, 1), (1, 1)]);
+        assert_eq!(chunk_ranges(0, 5).count(), 0);
+    }
+
+    #[test]
+    fn split() -> Result<()> {
+        let td = cap_tempfile::tempdir(cap_std::ambient_authority())?;
+        let w = OciDir::ensure(&td)?;
+        let layer = write_test_layer(&w, crate::CompressionFormat::Gzip)?;
+        let mut desc = layer.descriptor().build()?;
+
+        let chunks = w.split_blob(&desc, 3)?;
+        assert_eq!(chunks.len(), 3);
+        let mut buf = Vec::new();
+        for chunk in chunks.iter() {
+            let mut chunkbuf = Vec::new();
+            chunk.open(&w, &desc)?.read_to_end(&mut chunkbuf)?;
+            assert_eq!(chunkbuf.len() as u64, chunk.size);
+            assert_eq!(
+                chunk.digest,
+                format!("sha256:{}", crate::hash::sha256_hex(&chunkbuf)?)
+            );
+            buf.extend(chunkbuf);
+        }
+        let mut expected = Vec::new();
+        w.read_blob(&desc)?.read_to_end(&mut expected)?;
+        assert_eq!(buf, expected);
+
+        assert!(chunks_from_annotation(&desc)?.is_none());
+        set_chunks_annotation(&mut desc, &chunks)?;
+        assert_eq!(chunks_from_annotation(&desc)?.unwrap(), chunks);
+        Ok(())
+    }
+}
@@ -0,0 +1,119 @@
+//! The source of timestamps written into image configurations.
+
+use std::fmt::Debug;
+
+use chrono::{DateTime, Utc};
+
+use crate::{source_date_epoch, OciDir, OciDirOptions};
+
+/// A source of the current time, see [`crate::OciDirOptions::clock`].
+///
+/// This is used for the `created` fields of history entries and configs, and for
+/// journal entries. Pinning it makes the resulting digests reproducible.
+pub trait Clock: Send + Sync + Debug {
+    /// Return the current time.
+    fn now(&self) -> DateTime<Utc>;
+}
+
+/// A [`Clock`] returning the wall clock time.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+/// A [`Clock`] which always returns the same time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FixedClock(pub DateTime<Utc>);
+
+impl Clock for FixedClock {
+    fn now(&self) -> DateTime<Utc> {
+        self.0
+    }
+}
+
+impl OciDir {
+    /// The time to use for new image metadata: the configured clock, or otherwise
+    /// [`source_date_epoch`] if it is set and valid, or otherwise the current time.
+    pub(crate) fn now(&self) -> DateTime<Utc> {
+        if let Some(clock) = self.opts.clock.as_ref() {
+            return clock.now();
+        }
+        source_date_epoch().ok().flatten().unwrap_or_else(Utc::now)
+    }
+}
+
+impl OciDirOptions {
+    /// The time to record in the journal; unlike [`OciDir::now`] this ignores
+    /// [`crate::SOURCE_DATE_EPOCH`], as the journal records when changes happened.
+    pub(crate) fn journal_now(&self) -> DateTime<Utc> {
+        self.clock
+            .as_ref()
+            .map(|c| c.now())
+            .unwrap_or_else(Utc::now)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::sync::Arc;
+
+    use anyhow::Result;
+    use cap_std_ext::{cap_std, cap_tempfile};
+    use chrono::TimeZone;
+    use oci_spec::image as oci_image;
+
+    use super::*;
+    use crate::new_empty_manifest;
+
+    fn build(w: &OciDir) -> Result<(oci_image::ImageManifest, oci_image::ImageConfiguration)> {
+        let mut manifest = new_empty_manifest().build()?;
+        let mut config = oci_image::ImageConfigurationBuilder::default().build()?;
+        let mut layer = w.create_gzip_layer(None)?;
+        layer.write_all(b"contents")?;
+        let layer = layer.complete()?;
+        w.push_layer(&mut manifest, &mut config, layer, "layer", None);
+        w.push_empty_history(&mut config, "ENV FOO=bar", None);
+        Ok((manifest, config))
+    }
+
+    #[test]
+    fn fixed_clock() -> Result<()> {
+        let t = Utc.timestamp_opt(1_700_000_000, 0).unwrap();
+        let opts = OciDirOptions {
+            clock: Some(Arc::new(FixedClock(t))),
+            journal: true,
+            ..Default::default()
+        };
+        let td = cap_tempfile::tempdir(cap_std::ambient_authority())?;
+        let w = OciDir::ensure_with(&td, &opts)?;
+        let (manifest, config) = build(&w)?;
+        assert_eq!(config.created().as_deref(), Some("2023-11-14T22:13:20Z"));
+        assert!(config
+            .history()
+            .iter()
+            .all(|h| h.created().as_deref() == Some("2023-11-14T22:13:20Z")));
+        let (_, again) = build(&w)?;
+        assert_eq!(config, again);
+
+        w.insert_manifest_and_config(manifest, config, Some("latest"), Default::default())?;
+        let journal = w.read_journal()?;
+        assert_eq!(journal[0].timestamp, "2023-11-14T22:13:20.000Z");
+
+        let later = Utc.timestamp_opt(1_800_000_000, 0).unwrap();
+        let mut manifest = new_empty_manifest().build()?;
+        let mut config = oci_image::ImageConfigurationBuilder::default().build()?;
+        let layer = w.create_gzip_layer(None)?.complete()?;
+        w.push_layer_with_time(&mut manifest, &mut config, layer, "layer", None, later);
+        assert_eq!(config.created().as_deref(), Some("2027-01-15T08:00:00Z"));
+        assert_eq!(
+            config.history()[0].created().as_deref(),
+            Some("2027-01-15T08:00:00Z")
+        );
+        Ok(())
+    }
+}
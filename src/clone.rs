@@ -0,0 +1,252 @@
+//! Cloning layouts, optionally sharing blob storage with the source.
+
+use anyhow::{anyhow, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use cap_std::fs::Dir;
+use cap_std_ext::cap_std;
+use fn_error_context::context;
+
+use crate::store::{blob_path, ensure_blob_parent, find_blob_path, sharded_blob_path};
+use crate::OciDir;
+
+/// How blobs are transferred by [`OciDir::clone_to_with`].
+///
+/// Modes other than [`CloneMode::Copy`] fall back to copying a blob if sharing
+/// it fails, for example because the destination is on another filesystem or
+/// the filesystem has no reflink support. Either layout being in memory also
+/// implies copying.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum CloneMode {
+    /// Always copy blob contents.
+    Copy,
+    /// Create copy-on-write clones of blobs.
+    Reflink,
+    /// Hard link blobs. Note that modifying a blob in place (which should never
+    /// happen for content-addressed storage) would then affect both layouts.
+    Hardlink,
+    /// Use reflinks where supported, and copy otherwise. Hard links are never
+    /// used automatically.
+    #[default]
+    Auto,
+}
+
+#[cfg(target_os = "linux")]
+fn reflink_blob(src: &Dir, src_path: &Utf8Path, dest: &Dir, path: &Utf8Path) -> Result<bool> {
+    let srcf = src.open(src_path)?;
+    let tmpf = cap_std_ext::cap_tempfile::TempFile::new(dest)?;
+    if rustix::fs::ioctl_ficlone(tmpf.as_file(), &srcf).is_err() {
+        return Ok(false);
+    }
+    tmpf.replace(path)?;
+    Ok(true)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn reflink_blob(_src: &Dir, _src_path: &Utf8Path, _dest: &Dir, _path: &Utf8Path) -> Result<bool> {
+    Ok(false)
+}
+
+/// Copy a blob within the kernel using `copy_file_range`, which filesystems may
+/// implement as a reflink or a server-side copy. Returns false if this is not
+/// supported between the two files.
+///
+/// With `verify`, the copy is checked against its SHA-256 digest before it is
+/// put in place.
+#[cfg(target_os = "linux")]
+fn copy_range_blob(
+    src: &Dir,
+    src_path: &Utf8Path,
+    dest: &Dir,
+    digest: &str,
+    path: &Utf8Path,
+    verify: bool,
+) -> Result<bool> {
+    use rustix::io::Errno;
+    use std::io::{Seek, SeekFrom};
+
+    let srcf = src.open(src_path)?;
+    let size = srcf.metadata()?.len();
+    let tmpf = cap_std_ext::cap_tempfile::TempFile::new(dest)?;
+    let mut remaining = size;
+    while remaining > 0 {
+        let len = usize::try_from(remaining).unwrap_or(usize::MAX);
+        match rustix::fs::copy_file_range(&srcf, None, tmpf.as_file(), None, len) {
+            Ok(0) => anyhow::bail!("Blob {digest} was truncated while copying"),
+            Ok(n) => remaining -= n as u64,
+            Err(Errno::INTR) => continue,
+            // Nothing was copied yet, so the caller can fall back cleanly
+            Err(Errno::XDEV | Errno::NOSYS | Errno::OPNOTSUPP | Errno::INVAL)
+                if remaining == size =>
+            {
+                return Ok(false)
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+    if verify {
+        let mut f = tmpf.as_file();
+        f.seek(SeekFrom::Start(0))?;
+        let mut hasher = crate::hash::Sha256::new()?;
+        std::io::copy(&mut f, &mut hasher)?;
+        let found = hasher.finish_hex()?;
+        if Some(found.as_str()) != digest.strip_prefix("sha256:") {
+            anyhow::bail!("Corrupted blob {digest}: found sha256:{found}");
+        }
+    }
+    tmpf.replace(path)?;
+    Ok(true)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn copy_range_blob(
+    _src: &Dir,
+    _src_path: &Utf8Path,
+    _dest: &Dir,
+    _digest: &str,
+    _path: &Utf8Path,
+    _verify: bool,
+) -> Result<bool> {
+    Ok(false)
+}
+
+/// Find the path of a blob in `src`, and return it along with the path for the
+/// copy after creating its parent directory in `dest`, which stores blobs sharded
+/// if `dest_sharded` is set.
+fn prepare_blob_paths(
+    src: &Dir,
+    dest: &Dir,
+    digest: &str,
+    dest_sharded: bool,
+) -> Result<(Utf8PathBuf, Utf8PathBuf)> {
+    let src_path =
+        find_blob_path(src, digest)?.ok_or_else(|| anyhow!("Blob {digest} disappeared"))?;
+    let path = if dest_sharded {
+        sharded_blob_path(digest)?
+    } else {
+        blob_path(digest)?
+    };
+    ensure_blob_parent(dest, &path)?;
+    Ok((src_path, path))
+}
+
+/// Copy the blob with the given digest between two layout directories without
+/// passing its contents through userspace, returning false if it needs to be
+/// copied by other means. With `verify` only SHA-256 blobs are copied, and the
+/// copy is checked against its digest.
+pub(crate) fn copy_blob_fast(
+    src: &Dir,
+    dest: &Dir,
+    digest: &str,
+    dest_sharded: bool,
+    verify: bool,
+) -> Result<bool> {
+    if verify && !digest.starts_with("sha256:") {
+        return Ok(false);
+    }
+    let (src_path, path) = prepare_blob_paths(src, dest, digest, dest_sharded)?;
+    copy_range_blob(src, &src_path, dest, digest, &path, verify)
+}
+
+/// Try to share the blob with the given digest between two layout directories
+/// according to `mode`, returning false if it needs to be copied instead.
+pub(crate) fn share_blob(
+    src: &Dir,
+    dest: &Dir,
+    digest: &str,
+    dest_sharded: bool,
+    mode: CloneMode,
+) -> Result<bool> {
+    if mode == CloneMode::Copy {
+        return Ok(false);
+    }
+    let (src_path, path) = prepare_blob_paths(src, dest, digest, dest_sharded)?;
+    match mode {
+        CloneMode::Copy => Ok(false),
+        CloneMode::Hardlink => Ok(src.hard_link(&src_path, dest, &path).is_ok()),
+        CloneMode::Reflink | CloneMode::Auto => reflink_blob(src, &src_path, dest, &path),
+    }
+}
+
+impl OciDir {
+    /// Clone an OCI directory into the new subdirectory `p` of `destdir`,
+    /// transferring blobs as specified by `mode`.
+    ///
+    /// On Linux, blobs which are copied between directories are copied within the
+    /// kernel using `copy_file_range` where the filesystems support it.
+    #[context("Cloning OCI dir")]
+    pub fn clone_to_with(
+        &self,
+        destdir: &Dir,
+        p: impl AsRef<Utf8Path>,
+        mode: CloneMode,
+    ) -> Result<Self> {
+        let p = p.as_ref();
+        destdir.create_dir(p)?;
+        let cloned = Self::ensure(&destdir.open_dir(p)?)?;
+        self.copy_contents_to(&cloned, mode)?;
+        Ok(cloned)
+    }
+}
+
+// Link counts are only available on Unix.
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::tests::insert_test_image;
+    use cap_std::fs::MetadataExt;
+    use cap_std_ext::cap_tempfile;
+
+    #[test]
+    fn clone_modes() -> Result<()> {
+        let td = cap_tempfile::tempdir(cap_std::ambient_authority())?;
+        td.create_dir("src")?;
+        let w = OciDir::ensure(&td.open_dir("src")?)?;
+        let (_, layer) = insert_test_image(&w)?;
+        let path = format!("blobs/sha256/{}", layer.blob.sha256);
+
+        for (name, mode) in [
+            ("copy", CloneMode::Copy),
+            ("hardlink", CloneMode::Hardlink),
+            ("reflink", CloneMode::Reflink),
+            ("auto", CloneMode::Auto),
+        ] {
+            let cloned = w.clone_to_with(&td, name, mode)?;
+            assert_eq!(cloned.fsck()?, w.fsck()?);
+            assert!(cloned.find_manifest_with_tag("latest")?.is_some());
+            let nlink = cloned.dir().unwrap().metadata(&path)?.nlink();
+            assert_eq!(nlink > 1, mode == CloneMode::Hardlink);
+        }
+        assert_eq!(w.dir().unwrap().metadata(&path)?.nlink(), 2);
+
+        // In-memory layouts are always copied
+        let m = OciDir::new_in_memory()?;
+        let cloned = m.clone_to_with(&td, "memory", CloneMode::Hardlink)?;
+        assert_eq!(cloned.fsck()?, 0);
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn copy_fast() -> Result<()> {
+        let td = cap_tempfile::tempdir(cap_std::ambient_authority())?;
+        let w = OciDir::ensure(&td)?;
+        let blob = w.write_blob_dedup(&b"some blob contents"[..])?.0;
+        let digest = format!("sha256:{}", blob.sha256);
+        td.create_dir("dest")?;
+        let dest = OciDir::ensure(&td.open_dir("dest")?)?;
+        let (srcdir, destdir) = (w.dir().unwrap(), dest.dir().unwrap());
+        // At worst the kernel reports that this is unsupported
+        if copy_blob_fast(srcdir, destdir, &digest, false, true)? {
+            assert_eq!(dest.fsck()?, 1);
+            // A corrupted source is detected before anything is put in place
+            let path = blob_path(&digest)?;
+            srcdir.write(&path, b"some blob c0ntents")?;
+            destdir.remove_file(&path)?;
+            assert!(copy_blob_fast(srcdir, destdir, &digest, false, true).is_err());
+            assert!(!destdir.try_exists(&path)?);
+        }
+        assert!(!copy_blob_fast(srcdir, destdir, "sha512:abc", false, true)?);
+        Ok(())
+    }
+}
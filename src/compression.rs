@@ -0,0 +1,364 @@
+//! Detection and decompression of layer compression formats.
+
+use std::io::{BufRead, BufReader, Read};
+
+use anyhow::{anyhow, Result};
+use fn_error_context::context;
+use oci_spec::image::{Descriptor, ImageManifest, MediaType, Platform};
+
+use crate::chunked::CHUNKS_ANNOTATION;
+use crate::hash::Sha256;
+use crate::{BlobReader, BlobWriter, OciDir};
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+const XZ_MAGIC: &[u8] = &[0xfd, b'7', b'z', b'X', b'Z', 0x00];
+const BZIP2_MAGIC: &[u8] = b"BZh";
+
+/// A compression format for layer content.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CompressionFormat {
+    /// No compression.
+    None,
+    /// gzip compression.
+    Gzip,
+    /// zstd compression.
+    Zstd,
+}
+
+impl CompressionFormat {
+    /// Detect the compression format from the leading bytes of a blob.
+    ///
+    /// Anything which is not recognized as a supported compression format is
+    /// assumed to be uncompressed; an error is returned for known but unsupported
+    /// formats such as xz and bzip2.
+    pub fn detect(buf: &[u8]) -> Result<Self> {
+        if buf.starts_with(GZIP_MAGIC) {
+            Ok(Self::Gzip)
+        } else if buf.starts_with(ZSTD_MAGIC) {
+            Ok(Self::Zstd)
+        } else if buf.starts_with(XZ_MAGIC) {
+            Err(anyhow!("Unsupported compression format: xz"))
+        } else if buf.starts_with(BZIP2_MAGIC) {
+            Err(anyhow!("Unsupported compression format: bzip2"))
+        } else {
+            Ok(Self::None)
+        }
+    }
+
+    /// The compression format implied by a layer media type, if it is a known layer type.
+    pub fn from_media_type(media_type: &MediaType) -> Option<Self> {
+        match media_type {
+            MediaType::ImageLayer | MediaType::ImageLayerNonDistributable => Some(Self::None),
+            MediaType::ImageLayerGzip | MediaType::ImageLayerNonDistributableGzip => {
+                Some(Self::Gzip)
+            }
+            MediaType::ImageLayerZstd | MediaType::ImageLayerNonDistributableZstd => {
+                Some(Self::Zstd)
+            }
+            MediaType::Other(o) => match o.as_str() {
+                "application/vnd.docker.image.rootfs.diff.tar.gzip" => Some(Self::Gzip),
+                "application/vnd.docker.image.rootfs.diff.tar" => Some(Self::None),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// The standard OCI layer media type for this compression format.
+    pub fn layer_media_type(self) -> MediaType {
+        match self {
+            Self::None => MediaType::ImageLayer,
+            Self::Gzip => MediaType::ImageLayerGzip,
+            Self::Zstd => MediaType::ImageLayerZstd,
+        }
+    }
+
+    /// Wrap a reader with a decompressor for this format.
+    pub fn decompress<'a, R: BufRead + Send + 'a>(self, r: R) -> Result<Box<dyn Read + Send + 'a>> {
+        match self {
+            Self::None => Ok(Box::new(r)),
+            Self::Gzip => Ok(Box::new(flate2::bufread::MultiGzDecoder::new(r))),
+            #[cfg(feature = "zstd")]
+            Self::Zstd => Ok(Box::new(zstd::stream::read::Decoder::with_buffer(r)?)),
+            #[cfg(not(feature = "zstd"))]
+            Self::Zstd => Err(anyhow!("zstd support is not enabled")),
+        }
+    }
+}
+
+/// How [`OciDir::open_layer`] handles layers whose content does not match the
+/// compression format of their media type, see [`crate::OciDirOptions::layer_format`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum LayerFormatPolicy {
+    /// Decompress the layer according to the format detected from its content.
+    #[default]
+    Detect,
+    /// Fail with a [`MediaTypeMismatch`] error.
+    Strict,
+}
+
+/// The error returned by [`OciDir::open_layer`] with [`LayerFormatPolicy::Strict`]
+/// when a layer is mislabeled, for example a zstd layer with a gzip media type.
+/// It can be retrieved with [`anyhow::Error::downcast_ref`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MediaTypeMismatch {
+    /// The digest of the layer.
+    pub digest: String,
+    /// The media type from the descriptor.
+    pub media_type: MediaType,
+    /// The compression format implied by the media type.
+    pub expected: CompressionFormat,
+    /// The compression format detected from the content.
+    pub detected: CompressionFormat,
+}
+
+impl std::fmt::Display for MediaTypeMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Layer {} has media type {} but its content is {:?}",
+            self.digest, self.media_type, self.detected
+        )
+    }
+}
+
+impl std::error::Error for MediaTypeMismatch {}
+
+impl OciDir {
+    /// Open a layer blob and decompress it. The compression format is detected
+    /// from its content, and a mismatch with the media type of a known layer type
+    /// is handled according to [`crate::OciDirOptions::layer_format`].
+    #[context("Opening layer {}", desc.digest())]
+    pub fn open_layer(
+        &self,
+        desc: &Descriptor,
+    ) -> Result<(CompressionFormat, Box<dyn Read + Send>)> {
+        let mut r: BufReader<BlobReader> = BufReader::new(self.read_blob(desc)?);
+        let detected = CompressionFormat::detect(r.fill_buf()?)?;
+        if let Some(expected) = CompressionFormat::from_media_type(desc.media_type()) {
+            if expected != detected && self.opts.layer_format == LayerFormatPolicy::Strict {
+                return Err(MediaTypeMismatch {
+                    digest: desc.digest().to_string(),
+                    media_type: desc.media_type().clone(),
+                    expected,
+                    detected,
+                }
+                .into());
+            }
+        }
+        Ok((detected, detected.decompress(r)?))
+    }
+
+    /// Open a blob and decompress it, detecting the compression format from its
+    /// content rather than trusting the descriptor media type.
+    pub fn open_blob_decompressed(
+        &self,
+        desc: &Descriptor,
+    ) -> Result<(CompressionFormat, Box<dyn Read + Send>)> {
+        let mut r: BufReader<BlobReader> = BufReader::new(self.read_blob(desc)?);
+        let format = CompressionFormat::detect(r.fill_buf()?)?;
+        Ok((format, format.decompress(r)?))
+    }
+
+    /// Compute the uncompressed digest ("diff_id") of a layer blob, detecting
+    /// its compression format by content.
+    #[context("Computing diff_id for {}", desc.digest())]
+    pub fn compute_diffid(&self, desc: &Descriptor) -> Result<String> {
+        let (_, mut r) = self.open_blob_decompressed(desc)?;
+        let mut hasher = Sha256::new()?;
+        std::io::copy(&mut r, &mut hasher)?;
+        Ok(format!("sha256:{}", hasher.finish_hex()?))
+    }
+
+    /// Recompress a single layer, returning the descriptor for the new blob.
+    /// Layers which are already in the target format are returned unchanged.
+    #[context("Transcoding layer {}", desc.digest())]
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, fields(digest = %desc.digest(), ?target))
+    )]
+    fn transcode_layer(&self, desc: &Descriptor, target: CompressionFormat) -> Result<Descriptor> {
+        let (format, mut r) = self.open_blob_decompressed(desc)?;
+        if format == target {
+            return Ok(desc.clone());
+        }
+        let bw = BlobWriter::new(&*self.store, &self.progress)?;
+        let blob = match target {
+            CompressionFormat::None => {
+                let mut bw = bw;
+                std::io::copy(&mut r, &mut bw)?;
+                bw.complete()?
+            }
+            CompressionFormat::Gzip => {
+                let mut enc = flate2::write::GzEncoder::new(bw, flate2::Compression::default());
+                std::io::copy(&mut r, &mut enc)?;
+                enc.finish()?.complete()?
+            }
+            #[cfg(feature = "zstd")]
+            CompressionFormat::Zstd => {
+                let mut enc = zstd::stream::write::Encoder::new(bw, 0)?;
+                std::io::copy(&mut r, &mut enc)?;
+                enc.finish()?.complete()?
+            }
+            #[cfg(not(feature = "zstd"))]
+            CompressionFormat::Zstd => return Err(anyhow!("zstd support is not enabled")),
+        };
+        trace_event!(digest = %blob.digest_id(), size = blob.size, "Transcoded layer");
+        let mut r = desc.clone();
+        r.set_media_type(target.layer_media_type());
+        r.set_digest(blob.digest_id());
+        r.set_size(blob.size.try_into()?);
+        r.set_data(None);
+        // Chunk offsets refer to the old blob.
+        if let Some(mut annotations) = r.annotations().clone() {
+            annotations.remove(CHUNKS_ANNOTATION);
+            r.set_annotations(Some(annotations).filter(|a| !a.is_empty()));
+        }
+        Ok(r)
+    }
+
+    /// Recompress all layers of a manifest into the target format, and insert the
+    /// updated manifest into the index with the provided tag and platform.
+    ///
+    /// The config is unchanged, as diff_ids refer to the uncompressed content.
+    /// The original layer blobs are left in place.
+    #[context("Transcoding layers")]
+    pub fn transcode_layers(
+        &self,
+        manifest: &ImageManifest,
+        target: CompressionFormat,
+        tag: Option<&str>,
+        platform: Option<Platform>,
+    ) -> Result<Descriptor> {
+        let mut manifest = manifest.clone();
+        let layers = manifest
+            .layers()
+            .iter()
+            .map(|l| self.transcode_layer(l, target))
+            .collect::<Result<Vec<_>>>()?;
+        manifest.set_layers(layers);
+        let opts = crate::InsertOptions {
+            tag: tag.map(ToOwned::to_owned),
+            platform,
+            ..Default::default()
+        };
+        self.insert_manifest_with(manifest, &opts)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{insert_test_image, write_test_layer};
+
+    #[test]
+    fn detect() -> Result<()> {
+        assert_eq!(
+            CompressionFormat::detect(&[0x1f, 0x8b, 8])?,
+            CompressionFormat::Gzip
+        );
+        assert_eq!(
+            CompressionFormat::detect(&[0x28, 0xb5, 0x2f, 0xfd])?,
+            CompressionFormat::Zstd
+        );
+        assert_eq!(CompressionFormat::detect(b"")?, CompressionFormat::None);
+        assert_eq!(
+            CompressionFormat::detect(b"etc/passwd")?,
+            CompressionFormat::None
+        );
+        assert!(CompressionFormat::detect(b"BZh91AY").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn diffid() -> Result<()> {
+        let w = OciDir::new_in_memory()?;
+        let layer = write_test_layer(&w, CompressionFormat::Gzip)?;
+        // Deliberately mislabel it
+        let desc = layer
+            .descriptor()
+            .media_type(MediaType::ImageLayer)
+            .build()?;
+        assert_eq!(w.compute_diffid(&desc)?, layer.diff_id());
+
+        let uncompressed = write_test_layer(&w, CompressionFormat::None)?;
+        assert_eq!(uncompressed.diff_id(), layer.diff_id());
+        let desc = uncompressed.descriptor().build()?;
+        assert_eq!(w.compute_diffid(&desc)?, layer.diff_id());
+        Ok(())
+    }
+
+    #[test]
+    fn open_layer() -> Result<()> {
+        let td = cap_std_ext::cap_tempfile::tempdir(cap_std_ext::cap_std::ambient_authority())?;
+        let w = OciDir::ensure(&td)?;
+        let layer = write_test_layer(&w, CompressionFormat::Gzip)?;
+        let mislabeled = layer
+            .descriptor()
+            .media_type(MediaType::ImageLayerZstd)
+            .build()?;
+        let (format, mut r) = w.open_layer(&mislabeled)?;
+        assert_eq!(format, CompressionFormat::Gzip);
+        let mut buf = String::new();
+        r.read_to_string(&mut buf)?;
+        assert_eq!(buf, "pretend this is a tarball");
+
+        let opts = crate::OciDirOptions {
+            layer_format: LayerFormatPolicy::Strict,
+            ..Default::default()
+        };
+        let strict = OciDir::open_with(&td, &opts)?;
+        let err = strict.open_layer(&mislabeled).err().unwrap();
+        let mismatch = err.downcast_ref::<MediaTypeMismatch>().unwrap();
+        assert_eq!(mismatch.expected, CompressionFormat::Zstd);
+        assert_eq!(mismatch.detected, CompressionFormat::Gzip);
+        assert!(strict.open_layer(&layer.descriptor().build()?).is_ok());
+        // Unknown media types are not checked
+        let other = layer
+            .descriptor()
+            .media_type(MediaType::Other("application/octet-stream".into()))
+            .build()?;
+        assert!(strict.open_layer(&other).is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn transcode() -> Result<()> {
+        let w = OciDir::new_in_memory()?;
+        let (desc, _) = insert_test_image(&w)?;
+        let orig: ImageManifest = w.read_json_blob(&desc)?;
+        let orig_diffid = w.compute_diffid(&orig.layers()[0])?;
+
+        let desc = w.transcode_layers(&orig, CompressionFormat::None, Some("plain"), None)?;
+        let plain: ImageManifest = w.read_json_blob(&desc)?;
+        assert_eq!(plain.config(), orig.config());
+        let l = &plain.layers()[0];
+        assert_eq!(l.media_type(), &MediaType::ImageLayer);
+        assert_ne!(l.digest(), orig.layers()[0].digest());
+        // For uncompressed layers, the blob digest is the diff_id
+        assert_eq!(l.digest(), &orig_diffid);
+        assert!(w.find_manifest_with_tag("latest")?.is_some());
+
+        // Already in the target format
+        let desc = w.transcode_layers(&orig, CompressionFormat::Gzip, None, None)?;
+        let same: ImageManifest = w.read_json_blob(&desc)?;
+        assert_eq!(same.layers(), orig.layers());
+
+        #[cfg(feature = "zstd")]
+        {
+            let desc = w.transcode_layers(&plain, CompressionFormat::Zstd, None, None)?;
+            let zstd: ImageManifest = w.read_json_blob(&desc)?;
+            let l = &zstd.layers()[0];
+            assert_eq!(l.media_type(), &MediaType::ImageLayerZstd);
+            assert_eq!(w.compute_diffid(l)?, orig_diffid);
+        }
+        #[cfg(not(feature = "zstd"))]
+        assert!(w
+            .transcode_layers(&plain, CompressionFormat::Zstd, None, None)
+            .is_err());
+        assert_eq!(w.fsck()?, w.store.list()?.len() as u32);
+        Ok(())
+    }
+}
@@ -0,0 +1,120 @@
+//! Concise helpers for mutating image configurations.
+
+use oci_spec::image::{Config, ImageConfiguration};
+
+/// Extension methods for [`ImageConfiguration`], operating on the execution
+/// parameters in its `config` field, which is created if necessary.
+///
+/// All methods return `&mut Self` so that calls can be chained.
+pub trait ImageConfigExt {
+    /// Set an environment variable, replacing any existing value for `key`.
+    fn set_env(&mut self, key: &str, value: &str) -> &mut Self;
+    /// Add a label, replacing any existing value for `key`.
+    fn add_label(&mut self, key: impl Into<String>, value: impl Into<String>) -> &mut Self;
+    /// Set the entrypoint.
+    fn set_entrypoint<S: Into<String>>(&mut self, args: impl IntoIterator<Item = S>) -> &mut Self;
+    /// Set the default arguments.
+    fn set_cmd<S: Into<String>>(&mut self, args: impl IntoIterator<Item = S>) -> &mut Self;
+    /// Set the working directory.
+    fn set_working_dir(&mut self, dir: impl Into<String>) -> &mut Self;
+    /// Add an exposed port, in the form `port`, `port/tcp` or `port/udp`.
+    /// Ports which are already exposed are not duplicated.
+    fn add_exposed_port(&mut self, port: impl Into<String>) -> &mut Self;
+}
+
+fn update_config(config: &mut ImageConfiguration, f: impl FnOnce(&mut Config)) {
+    let mut c = config.config().clone().unwrap_or_default();
+    f(&mut c);
+    config.set_config(Some(c));
+}
+
+impl ImageConfigExt for ImageConfiguration {
+    fn set_env(&mut self, key: &str, value: &str) -> &mut Self {
+        update_config(self, |c| {
+            let mut env = c.env().clone().unwrap_or_default();
+            let prefix = format!("{key}=");
+            let v = format!("{key}={value}");
+            match env.iter_mut().find(|e| e.starts_with(&prefix)) {
+                Some(e) => *e = v,
+                None => env.push(v),
+            }
+            c.set_env(Some(env));
+        });
+        self
+    }
+
+    fn add_label(&mut self, key: impl Into<String>, value: impl Into<String>) -> &mut Self {
+        update_config(self, |c| {
+            c.labels_mut()
+                .get_or_insert_with(Default::default)
+                .insert(key.into(), value.into());
+        });
+        self
+    }
+
+    fn set_entrypoint<S: Into<String>>(&mut self, args: impl IntoIterator<Item = S>) -> &mut Self {
+        let args = args.into_iter().map(Into::into).collect();
+        update_config(self, |c| {
+            c.set_entrypoint(Some(args));
+        });
+        self
+    }
+
+    fn set_cmd<S: Into<String>>(&mut self, args: impl IntoIterator<Item = S>) -> &mut Self {
+        let args = args.into_iter().map(Into::into).collect();
+        update_config(self, |c| {
+            c.set_cmd(Some(args));
+        });
+        self
+    }
+
+    fn set_working_dir(&mut self, dir: impl Into<String>) -> &mut Self {
+        update_config(self, |c| {
+            c.set_working_dir(Some(dir.into()));
+        });
+        self
+    }
+
+    fn add_exposed_port(&mut self, port: impl Into<String>) -> &mut Self {
+        let port = port.into();
+        update_config(self, |c| {
+            let mut ports = c.exposed_ports().clone().unwrap_or_default();
+            if !ports.contains(&port) {
+                ports.push(port);
+            }
+            c.set_exposed_ports(Some(ports));
+        });
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use oci_spec::image::ImageConfigurationBuilder;
+
+    #[test]
+    fn config_ext() {
+        let mut config = ImageConfigurationBuilder::default().build().unwrap();
+        config
+            .set_env("PATH", "/bin")
+            .set_env("LANG", "C")
+            .set_env("PATH", "/usr/bin")
+            .add_label("org.example.version", "1")
+            .set_entrypoint(["/usr/bin/app"])
+            .set_cmd(["--verbose"])
+            .set_working_dir("/srv")
+            .add_exposed_port("80/tcp")
+            .add_exposed_port("80/tcp");
+        let c = config.config().as_ref().unwrap();
+        assert_eq!(c.env().as_deref().unwrap(), ["PATH=/usr/bin", "LANG=C"]);
+        assert_eq!(
+            c.labels().as_ref().unwrap()["org.example.version"].as_str(),
+            "1"
+        );
+        assert_eq!(c.entrypoint().as_deref().unwrap(), ["/usr/bin/app"]);
+        assert_eq!(c.cmd().as_deref().unwrap(), ["--verbose"]);
+        assert_eq!(c.working_dir().as_deref(), Some("/srv"));
+        assert_eq!(c.exposed_ports().as_deref().unwrap(), ["80/tcp"]);
+    }
+}
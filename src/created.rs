@@ -0,0 +1,127 @@
+//! Effective creation timestamps of images.
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use fn_error_context::context;
+use oci_spec::image::{Descriptor, ImageConfiguration, ImageManifest, MediaType};
+
+use crate::{OciDir, OCI_TAG_ANNOTATION};
+
+/// A tagged image along with its effective creation time.
+#[derive(Debug, Clone)]
+pub struct TaggedImage {
+    /// The tag, from the `org.opencontainers.image.ref.name` annotation.
+    pub tag: String,
+    /// The index descriptor for the manifest.
+    pub descriptor: Descriptor,
+    /// The effective creation time, see [`effective_created`].
+    pub created: Option<DateTime<Utc>>,
+}
+
+fn parse_timestamp(s: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(s)
+        .ok()
+        .map(|t| t.with_timezone(&Utc))
+}
+
+/// The effective creation time of an image: the config `created` field if it is present
+/// and valid, otherwise the newest timestamp in the history.
+pub fn effective_created(config: &ImageConfiguration) -> Option<DateTime<Utc>> {
+    config
+        .created()
+        .as_deref()
+        .and_then(parse_timestamp)
+        .or_else(|| {
+            config
+                .history()
+                .iter()
+                .filter_map(|h| h.created().as_deref().and_then(parse_timestamp))
+                .max()
+        })
+}
+
+impl OciDir {
+    /// Return the effective creation time of the image referenced by this manifest.
+    pub fn image_created(&self, manifest: &ImageManifest) -> Result<Option<DateTime<Utc>>> {
+        if manifest.config().media_type() != &MediaType::ImageConfig {
+            return Ok(None);
+        }
+        let config: ImageConfiguration = self.read_json_blob(manifest.config())?;
+        Ok(effective_created(&config))
+    }
+
+    /// Return all tagged images, sorted by effective creation time with the oldest first.
+    ///
+    /// Images without a known creation time sort before all others; ties are broken by tag.
+    #[context("Listing images by creation time")]
+    pub fn images_sorted_by_created(&self) -> Result<Vec<TaggedImage>> {
+        let mut r = Vec::new();
+        let Some(index) = self.read_index()? else {
+            return Ok(r);
+        };
+        for desc in index.manifests() {
+            if desc.media_type() != &MediaType::ImageManifest {
+                continue;
+            }
+            let Some(tag) = desc
+                .annotations()
+                .as_ref()
+                .and_then(|a| a.get(OCI_TAG_ANNOTATION))
+            else {
+                continue;
+            };
+            let manifest: ImageManifest = self.read_json_blob(desc)?;
+            r.push(TaggedImage {
+                tag: tag.clone(),
+                descriptor: desc.clone(),
+                created: self.image_created(&manifest)?,
+            });
+        }
+        r.sort_by(|a, b| a.created.cmp(&b.created).then_with(|| a.tag.cmp(&b.tag)));
+        Ok(r)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use oci_spec::image::{HistoryBuilder, ImageConfigurationBuilder};
+
+    #[test]
+    fn created() -> Result<()> {
+        let mut config = ImageConfigurationBuilder::default().build().unwrap();
+        assert!(effective_created(&config).is_none());
+        for ts in ["2023-01-01T00:00:00Z", "2024-06-01T00:00:00Z", "garbage"] {
+            config
+                .history_mut()
+                .push(HistoryBuilder::default().created(ts).build().unwrap());
+        }
+        assert_eq!(
+            effective_created(&config),
+            parse_timestamp("2024-06-01T00:00:00Z")
+        );
+        config.set_created(Some("2022-01-01T00:00:00+01:00".into()));
+        assert_eq!(
+            effective_created(&config),
+            parse_timestamp("2021-12-31T23:00:00Z")
+        );
+
+        let w = OciDir::new_in_memory()?;
+        for (tag, ts) in [
+            ("new", "2024-01-01T00:00:00Z"),
+            ("old", "2020-01-01T00:00:00Z"),
+        ] {
+            let mut config = ImageConfigurationBuilder::default().build().unwrap();
+            config.set_created(Some(ts.into()));
+            let manifest = crate::new_empty_manifest().build().unwrap();
+            w.insert_manifest_and_config(manifest, config, Some(tag), Default::default())?;
+        }
+        let tags: Vec<_> = w
+            .images_sorted_by_created()?
+            .into_iter()
+            .map(|i| i.tag)
+            .collect();
+        assert_eq!(tags, ["old", "new"]);
+        Ok(())
+    }
+}
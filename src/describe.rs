@@ -0,0 +1,203 @@
+//! Self-description of an OCI layout, see [`OciDir::describe`].
+
+use std::collections::BTreeSet;
+
+use anyhow::Result;
+use cap_std_ext::dirext::CapStdExtDirExt;
+use fn_error_context::context;
+use oci_spec::image::{self as oci_image, MediaType};
+use serde::Serialize;
+
+use crate::store::STAGING_DIR;
+use crate::{OciDir, BLOBS};
+
+/// Optional features of a layout which go beyond the basic image-spec layout.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LayoutExtension {
+    /// At least one manifest refers to another via the `subject` field.
+    Referrers,
+    /// Blobs are stored in two-character prefix subdirectories.
+    ShardedBlobs,
+    /// The `blobs` directory is a symbolic link, likely shared with other layouts.
+    SharedStore,
+}
+
+/// A summary of the contents and capabilities of an OCI layout.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+#[non_exhaustive]
+pub struct LayoutDescription {
+    /// The `imageLayoutVersion` from the `oci-layout` file, if present.
+    pub layout_version: Option<String>,
+    /// Digest algorithms of the blobs in the layout.
+    pub digest_algorithms: BTreeSet<String>,
+    /// Number of container images referenced directly from `index.json`; the
+    /// manifests of nested indexes are not counted.
+    pub images: u64,
+    /// Number of non-image artifacts referenced directly from `index.json`.
+    pub artifacts: u64,
+    /// Total number of blobs across all digest algorithms.
+    pub blobs: u64,
+    /// Number of blobs whose digest algorithm is not supported for verification.
+    pub unsupported_blobs: u64,
+    /// Detected layout extensions.
+    pub extensions: BTreeSet<LayoutExtension>,
+    /// Cargo features this crate was compiled with.
+    pub features: BTreeSet<&'static str>,
+}
+
+/// The set of cargo features enabled in this build.
+pub(crate) fn enabled_features() -> BTreeSet<&'static str> {
+    let mut r = BTreeSet::new();
+    if cfg!(feature = "cdc") {
+        r.insert("cdc");
+    }
+    if cfg!(feature = "cli") {
+        r.insert("cli");
+    }
+    if cfg!(feature = "encrypt") {
+        r.insert("encrypt");
+    }
+    if cfg!(feature = "mmap") {
+        r.insert("mmap");
+    }
+    if cfg!(feature = "openssl") {
+        r.insert("openssl");
+    }
+    if cfg!(feature = "registry") {
+        r.insert("registry");
+    }
+    if cfg!(feature = "rust-crypto") {
+        r.insert("rust-crypto");
+    }
+    if cfg!(feature = "sign") {
+        r.insert("sign");
+    }
+    if cfg!(feature = "tracing") {
+        r.insert("tracing");
+    }
+    if cfg!(feature = "zstd") {
+        r.insert("zstd");
+    }
+    r
+}
+
+/// Returns true if this index entry is known to refer to a non-image artifact
+/// from the descriptor alone.
+fn descriptor_is_artifact(desc: &oci_image::Descriptor) -> bool {
+    desc.artifact_type().is_some()
+        || !matches!(
+            desc.media_type(),
+            MediaType::ImageManifest | MediaType::ImageIndex
+        )
+}
+
+impl OciDir {
+    /// Inspect the layout and return a summary of what it contains and which
+    /// extensions it uses, so callers can detect capabilities before operating on it.
+    #[context("Describing OCI dir")]
+    pub fn describe(&self) -> Result<LayoutDescription> {
+        let layout_version = self
+            .store
+            .read_meta("oci-layout")?
+            .map(|buf| oci_image::OciLayout::from_reader(buf.as_slice()))
+            .transpose()?
+            .map(|l| l.image_layout_version().to_owned());
+
+        let mut extensions = BTreeSet::new();
+        if let Some(dir) = self.dir() {
+            if dir
+                .symlink_metadata_optional(BLOBS)?
+                .map(|m| m.is_symlink())
+                .unwrap_or_default()
+            {
+                extensions.insert(LayoutExtension::SharedStore);
+            }
+            if let Some(blobdir) = dir.open_dir_optional(BLOBS)? {
+                for algdir in blobdir.entries()? {
+                    let algdir = algdir?;
+                    if !algdir.file_type()?.is_dir() || algdir.file_name() == STAGING_DIR {
+                        continue;
+                    }
+                    for ent in algdir.open_dir()?.entries()? {
+                        let ent = ent?;
+                        if ent.file_type()?.is_dir() && ent.file_name().len() == 2 {
+                            extensions.insert(LayoutExtension::ShardedBlobs);
+                        }
+                    }
+                }
+            }
+        }
+
+        let blob_digests = self.store.list()?;
+        let blobs = blob_digests.len() as u64;
+        let unsupported_blobs = blob_digests
+            .iter()
+            .filter(|d| !crate::store::is_supported_digest(d))
+            .count() as u64;
+        let digest_algorithms = blob_digests
+            .iter()
+            .filter_map(|d| d.split_once(':').map(|(alg, _)| alg.to_owned()))
+            .collect();
+
+        let mut images = 0u64;
+        let mut artifacts = 0u64;
+        if let Some(index) = self.read_index()? {
+            for desc in index.manifests() {
+                let is_artifact = descriptor_is_artifact(desc);
+                if desc.media_type() == &MediaType::ImageManifest {
+                    let manifest: oci_image::ImageManifest = self.read_json_blob_lenient(desc)?;
+                    if is_artifact
+                        || manifest.artifact_type().is_some()
+                        || manifest.config().media_type() != &MediaType::ImageConfig
+                    {
+                        artifacts += 1;
+                    } else {
+                        images += 1;
+                    }
+                    if manifest.subject().is_some() {
+                        extensions.insert(LayoutExtension::Referrers);
+                    }
+                } else if is_artifact {
+                    artifacts += 1;
+                }
+            }
+        }
+
+        Ok(LayoutDescription {
+            layout_version,
+            digest_algorithms,
+            images,
+            artifacts,
+            blobs,
+            unsupported_blobs,
+            extensions,
+            features: enabled_features(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cap_std_ext::{cap_std, cap_tempfile};
+
+    #[test]
+    fn describe() -> Result<()> {
+        let td = cap_tempfile::tempdir(cap_std::ambient_authority())?;
+        let w = OciDir::ensure(&td)?;
+        let manifest = crate::new_empty_manifest().build()?;
+        let config = oci_image::ImageConfigurationBuilder::default().build()?;
+        w.insert_manifest_and_config(manifest, config, Some("latest"), Default::default())?;
+        let desc = w.describe()?;
+        assert_eq!(desc.layout_version.as_deref(), Some("1.0.0"));
+        assert_eq!(desc.images, 1);
+        assert_eq!(desc.artifacts, 0);
+        assert_eq!(desc.blobs, 2);
+        assert_eq!(desc.unsupported_blobs, 0);
+        assert!(desc.digest_algorithms.contains("sha256"));
+        assert!(desc.extensions.is_empty());
+        Ok(())
+    }
+}
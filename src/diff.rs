@@ -0,0 +1,274 @@
+//! Comparison of two layouts.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use anyhow::Result;
+use fn_error_context::context;
+use oci_spec::image::{Descriptor, ImageManifest};
+use serde_json::Value;
+
+use crate::{ManifestEntry, OciDir};
+
+/// A tag which refers to different manifests in two layouts.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChangedTag {
+    /// The tag.
+    pub tag: String,
+    /// The index descriptor in the first layout.
+    pub a: Descriptor,
+    /// The index descriptor in the second layout.
+    pub b: Descriptor,
+}
+
+/// The differences between two layouts, see [`diff_layouts`].
+///
+/// Tagged manifests are matched by tag, and untagged manifests by digest.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct LayoutDiff {
+    /// Manifests which are only present in the first layout.
+    pub only_in_a: Vec<ManifestEntry>,
+    /// Manifests which are only present in the second layout.
+    pub only_in_b: Vec<ManifestEntry>,
+    /// Tags which refer to a different digest in each layout.
+    pub changed: Vec<ChangedTag>,
+    /// Digests of blobs which are only present in the first layout.
+    pub blobs_only_in_a: Vec<String>,
+    /// Digests of blobs which are only present in the second layout.
+    pub blobs_only_in_b: Vec<String>,
+}
+
+impl LayoutDiff {
+    /// Returns true if no differences were found.
+    pub fn is_empty(&self) -> bool {
+        self.only_in_a.is_empty()
+            && self.only_in_b.is_empty()
+            && self.changed.is_empty()
+            && self.blobs_only_in_a.is_empty()
+            && self.blobs_only_in_b.is_empty()
+    }
+}
+
+/// The differences between two image manifests, see [`OciDir::diff_manifests`].
+///
+/// Layers are matched by digest and listed in the order of their manifest.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct ManifestDiff {
+    /// Layers which are present in both manifests.
+    pub shared_layers: Vec<Descriptor>,
+    /// Layers which are only present in the first manifest.
+    pub layers_only_in_a: Vec<Descriptor>,
+    /// Layers which are only present in the second manifest.
+    pub layers_only_in_b: Vec<Descriptor>,
+    /// The total size of the shared layers.
+    pub shared_bytes: u64,
+    /// The total size of the layers only in the first manifest.
+    pub removed_bytes: u64,
+    /// The total size of the layers only in the second manifest, which is what
+    /// needs to be transferred in addition to the config and manifest when
+    /// updating from the first image to the second.
+    pub added_bytes: u64,
+    /// The JSON paths of the config fields which differ, such as `config.Env`
+    /// or `history`, sorted. Objects are compared field by field and any other
+    /// values as a whole.
+    pub changed_config: Vec<String>,
+}
+
+impl ManifestDiff {
+    /// The size of the second image minus the size of the first, counting layers only.
+    pub fn layer_delta(&self) -> i64 {
+        self.added_bytes as i64 - self.removed_bytes as i64
+    }
+}
+
+/// Collect the paths of the differing fields of two JSON values.
+fn diff_json(prefix: &str, a: &Value, b: &Value, r: &mut Vec<String>) {
+    match (a, b) {
+        (Value::Object(a), Value::Object(b)) => {
+            let keys: BTreeSet<&String> = a.keys().chain(b.keys()).collect();
+            for key in keys {
+                let path = if prefix.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{prefix}.{key}")
+                };
+                match (a.get(key), b.get(key)) {
+                    (Some(a), Some(b)) => diff_json(&path, a, b, r),
+                    _ => r.push(path),
+                }
+            }
+        }
+        (a, b) if a != b => r.push(prefix.to_owned()),
+        _ => {}
+    }
+}
+
+/// Split the layers of `a` by whether they are present in `b`.
+fn partition_layers(a: &ImageManifest, b: &ImageManifest) -> (Vec<Descriptor>, Vec<Descriptor>) {
+    let b_digests: BTreeSet<&str> = b.layers().iter().map(|l| l.digest().as_str()).collect();
+    let mut seen = BTreeSet::new();
+    a.layers()
+        .iter()
+        .filter(|l| seen.insert(l.digest().as_str()))
+        .cloned()
+        .partition(|l| b_digests.contains(l.digest().as_str()))
+}
+
+fn total_size(layers: &[Descriptor]) -> u64 {
+    layers.iter().map(|l| l.size().max(0) as u64).sum()
+}
+
+impl OciDir {
+    /// Compare the layers and configs of two images in this layout, each given by
+    /// tag or manifest digest.
+    #[context("Comparing manifests {a} and {b}")]
+    pub fn diff_manifests(&self, a: &str, b: &str) -> Result<ManifestDiff> {
+        let a: ImageManifest = self.read_json_blob(&self.resolve(a)?)?;
+        let b: ImageManifest = self.read_json_blob(&self.resolve(b)?)?;
+        let (shared_layers, layers_only_in_a) = partition_layers(&a, &b);
+        let (_, layers_only_in_b) = partition_layers(&b, &a);
+        let mut changed_config = Vec::new();
+        if a.config().digest() != b.config().digest() {
+            let ac: Value = self.read_json_blob(a.config())?;
+            let bc: Value = self.read_json_blob(b.config())?;
+            diff_json("", &ac, &bc, &mut changed_config);
+        }
+        Ok(ManifestDiff {
+            shared_bytes: total_size(&shared_layers),
+            removed_bytes: total_size(&layers_only_in_a),
+            added_bytes: total_size(&layers_only_in_b),
+            shared_layers,
+            layers_only_in_a,
+            layers_only_in_b,
+            changed_config,
+        })
+    }
+}
+
+/// The key used to match manifests between layouts.
+fn entry_key(e: &ManifestEntry) -> (bool, String) {
+    match e.tag.as_ref() {
+        Some(tag) => (true, tag.clone()),
+        None => (false, e.descriptor.digest().to_string()),
+    }
+}
+
+fn entries_by_key(d: &OciDir) -> Result<BTreeMap<(bool, String), ManifestEntry>> {
+    Ok(d.manifests()?
+        .into_iter()
+        .map(|e| (entry_key(&e), e))
+        .collect())
+}
+
+/// Compare the manifests and blobs of two layouts.
+#[context("Comparing layouts")]
+pub fn diff_layouts(a: &OciDir, b: &OciDir) -> Result<LayoutDiff> {
+    let mut r = LayoutDiff::default();
+    let mut b_entries = entries_by_key(b)?;
+    for (key, ea) in entries_by_key(a)? {
+        match b_entries.remove(&key) {
+            None => r.only_in_a.push(ea),
+            Some(eb) if ea.descriptor.digest() != eb.descriptor.digest() => {
+                r.changed.push(ChangedTag {
+                    tag: key.1,
+                    a: ea.descriptor,
+                    b: eb.descriptor,
+                })
+            }
+            Some(_) => {}
+        }
+    }
+    r.only_in_b = b_entries.into_values().collect();
+
+    let a_blobs: BTreeSet<String> = a.store.list()?.into_iter().collect();
+    let b_blobs: BTreeSet<String> = b.store.list()?.into_iter().collect();
+    r.blobs_only_in_a = a_blobs.difference(&b_blobs).cloned().collect();
+    r.blobs_only_in_b = b_blobs.difference(&a_blobs).cloned().collect();
+    Ok(r)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use oci_spec::image::ImageConfigurationBuilder;
+    use std::io::Write;
+
+    fn insert(d: &OciDir, tag: Option<&str>, author: &str) -> Result<Descriptor> {
+        let mut config = ImageConfigurationBuilder::default().build().unwrap();
+        config.set_author(Some(author.into()));
+        let manifest = crate::new_empty_manifest().build().unwrap();
+        d.insert_manifest_and_config(manifest, config, tag, Default::default())
+    }
+
+    #[test]
+    fn diff() -> Result<()> {
+        let a = OciDir::new_in_memory()?;
+        let b = OciDir::new_in_memory()?;
+        assert!(diff_layouts(&a, &b)?.is_empty());
+
+        insert(&a, Some("same"), "same")?;
+        insert(&b, Some("same"), "same")?;
+        insert(&a, Some("changed"), "a")?;
+        insert(&b, Some("changed"), "b")?;
+        insert(&a, Some("a"), "only a")?;
+        let b_untagged = insert(&b, None, "only b")?;
+        let d = diff_layouts(&a, &b)?;
+        assert!(!d.is_empty());
+        let only_a: Vec<_> = d.only_in_a.iter().map(|e| e.tag.as_deref()).collect();
+        assert_eq!(only_a, [Some("a")]);
+        assert_eq!(d.only_in_b.len(), 1);
+        assert_eq!(d.only_in_b[0].descriptor.digest(), b_untagged.digest());
+        assert_eq!(d.changed.len(), 1);
+        assert_eq!(d.changed[0].tag, "changed");
+        // Each differing image has a unique config and manifest
+        assert_eq!(d.blobs_only_in_a.len(), 4);
+        assert_eq!(d.blobs_only_in_b.len(), 4);
+        assert!(diff_layouts(&a, &a)?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn diff_manifests() -> Result<()> {
+        let w = OciDir::new_in_memory()?;
+        let insert = |tag, layers: &[&str], env: &str| -> Result<ImageManifest> {
+            let mut manifest = crate::new_empty_manifest().build()?;
+            let mut config = ImageConfigurationBuilder::default().build()?;
+            config.set_config(Some(
+                oci_spec::image::ConfigBuilder::default()
+                    .env(vec![env.to_owned()])
+                    .user("root")
+                    .build()?,
+            ));
+            for contents in layers {
+                let mut layer = w.create_gzip_layer(None)?;
+                layer.write_all(contents.as_bytes())?;
+                let layer = layer.complete()?;
+                w.push_layer(&mut manifest, &mut config, layer, "", None);
+            }
+            w.insert_manifest_and_config(manifest.clone(), config, Some(tag), Default::default())?;
+            Ok(manifest)
+        };
+        let v1 = insert("v1", &["base", "old app"], "V=1")?;
+        let v2 = insert("v2", &["base", "new app"], "V=2")?;
+        let (base, old, new) = (&v1.layers()[0], &v1.layers()[1], &v2.layers()[1]);
+        assert_eq!(base, &v2.layers()[0]);
+
+        let d = w.diff_manifests("v1", "v2")?;
+        assert_eq!(d.shared_layers, std::slice::from_ref(base));
+        assert_eq!(d.layers_only_in_a, std::slice::from_ref(old));
+        assert_eq!(d.layers_only_in_b, std::slice::from_ref(new));
+        assert_eq!(d.shared_bytes, base.size() as u64);
+        assert_eq!(d.removed_bytes, old.size() as u64);
+        assert_eq!(d.added_bytes, new.size() as u64);
+        assert_eq!(d.layer_delta(), new.size() - old.size());
+        assert_eq!(d.changed_config, ["config.Env", "rootfs.diff_ids"]);
+
+        let d = w.diff_manifests("v1", "v1")?;
+        assert_eq!(d.shared_layers.len(), 2);
+        assert!(d.layers_only_in_a.is_empty() && d.layers_only_in_b.is_empty());
+        assert!(d.changed_config.is_empty());
+        assert!(w.diff_manifests("v1", "missing").is_err());
+        Ok(())
+    }
+}
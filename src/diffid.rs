@@ -0,0 +1,171 @@
+//! Computing missing diff_ids of existing layers.
+
+use std::collections::BTreeMap;
+
+use anyhow::Result;
+use fn_error_context::context;
+use oci_spec::image::{Descriptor, ImageConfiguration, ImageManifest};
+use serde::{Deserialize, Serialize};
+
+use crate::OciDir;
+
+/// The file at the root of the layout recording the diff_ids computed by
+/// [`OciDir::compute_diffid_cached`], keyed by layer digest.
+pub const DIFFID_CACHE_FILE: &str = "ocidir-diffids.json";
+
+/// The contents of [`DIFFID_CACHE_FILE`].
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct DiffIdCache {
+    diff_ids: BTreeMap<String, String>,
+}
+
+/// Returns true if `s` is a `sha256:` digest with 64 lowercase hex digits.
+fn is_sha256_digest(s: &str) -> bool {
+    s.strip_prefix("sha256:")
+        .is_some_and(|h| h.len() == 64 && h.bytes().all(|c| matches!(c, b'0'..=b'9' | b'a'..=b'f')))
+}
+
+impl OciDir {
+    /// Read [`DIFFID_CACHE_FILE`]; a missing or unreadable cache is empty.
+    fn read_diffid_cache(&self) -> Result<DiffIdCache> {
+        let Some(buf) = self.store.read_meta(DIFFID_CACHE_FILE)? else {
+            return Ok(DiffIdCache::default());
+        };
+        Ok(serde_json::from_slice(&buf).unwrap_or_default())
+    }
+
+    /// Remove the entries of [`DIFFID_CACHE_FILE`] for layers which are no longer
+    /// present, after blobs were deleted.
+    pub(crate) fn prune_diffid_cache(&self) -> Result<()> {
+        if self.store.is_read_only() {
+            return Ok(());
+        }
+        let mut cache = self.read_diffid_cache()?;
+        let n = cache.diff_ids.len();
+        let mut present = BTreeMap::new();
+        for (digest, diff_id) in std::mem::take(&mut cache.diff_ids) {
+            if self.store.has(&digest)? {
+                present.insert(digest, diff_id);
+            }
+        }
+        cache.diff_ids = present;
+        if cache.diff_ids.len() != n {
+            self.store
+                .write_meta(DIFFID_CACHE_FILE, &serde_json::to_vec(&cache)?)?;
+        }
+        Ok(())
+    }
+
+    /// Like [`Self::compute_diffid`], but the result is recorded in
+    /// [`DIFFID_CACHE_FILE`] and reused by later calls without reading the layer.
+    ///
+    /// The cache is advisory: entries which are not a well-formed sha256 digest
+    /// are recomputed, but well-formed ones are trusted, so the layout directory
+    /// must not be writable by untrusted parties. Concurrent writers may lose each
+    /// other's entries, which are then recomputed when needed. Entries are dropped
+    /// when their layer is pruned. Nothing is recorded for read-only layouts.
+    #[context("Computing cached diff_id for {}", desc.digest())]
+    pub fn compute_diffid_cached(&self, desc: &Descriptor) -> Result<String> {
+        let mut cache = self.read_diffid_cache()?;
+        if let Some(diff_id) = cache.diff_ids.get(desc.digest().as_str()) {
+            if is_sha256_digest(diff_id) {
+                return Ok(diff_id.clone());
+            }
+        }
+        let diff_id = self.compute_diffid(desc)?;
+        if !self.store.is_read_only() {
+            cache
+                .diff_ids
+                .insert(desc.digest().to_string(), diff_id.clone());
+            self.store
+                .write_meta(DIFFID_CACHE_FILE, &serde_json::to_vec(&cache)?)?;
+        }
+        Ok(diff_id)
+    }
+
+    /// Add the diff_ids missing from the end of the config rootfs, such as after
+    /// importing compressed layers, using [`Self::compute_diffid_cached`]. Returns
+    /// the number of diff_ids added; existing ones are not checked.
+    ///
+    /// The config must then be written again, which changes its digest.
+    #[context("Filling in diff_ids")]
+    pub fn fill_diffids(
+        &self,
+        manifest: &ImageManifest,
+        config: &mut ImageConfiguration,
+    ) -> Result<usize> {
+        let layers = manifest.layers();
+        let mut rootfs = config.rootfs().clone();
+        let known = rootfs.diff_ids().len();
+        if known > layers.len() {
+            anyhow::bail!(
+                "Config has {known} diff_ids but manifest has {} layers",
+                layers.len()
+            );
+        }
+        for layer in &layers[known..] {
+            let diff_id = self.compute_diffid_cached(layer)?;
+            rootfs.diff_ids_mut().push(diff_id);
+        }
+        if rootfs.typ().is_empty() {
+            rootfs.set_typ("layers".to_owned());
+        }
+        config.set_rootfs(rootfs);
+        Ok(layers.len() - known)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cap_std_ext::{cap_std, cap_tempfile};
+    use oci_spec::image::ImageConfigurationBuilder;
+    use std::io::Write;
+
+    #[test]
+    fn fill_diffids() -> Result<()> {
+        let td = cap_tempfile::tempdir(cap_std::ambient_authority())?;
+        let w = OciDir::ensure(&td)?;
+        let mut manifest = crate::new_empty_manifest().build()?;
+        let mut config = ImageConfigurationBuilder::default().build()?;
+        let mut expected = Vec::new();
+        for contents in ["one", "two", "three"] {
+            let mut lw = w.create_gzip_layer(None)?;
+            lw.write_all(contents.as_bytes())?;
+            let layer = lw.complete()?;
+            expected.push(layer.diff_id());
+            w.push_layer(&mut manifest, &mut config, layer, contents, None);
+        }
+        // As if imported without knowing the last two diff_ids
+        let mut incomplete = config.clone();
+        incomplete.rootfs_mut().diff_ids_mut().truncate(1);
+        assert_eq!(w.fill_diffids(&manifest, &mut incomplete)?, 2);
+        assert_eq!(incomplete.rootfs().diff_ids(), &expected);
+        assert_eq!(w.fill_diffids(&manifest, &mut incomplete)?, 0);
+
+        // Malformed cache entries are recomputed and replaced
+        let read_cache =
+            || -> Result<DiffIdCache> { Ok(serde_json::from_slice(&td.read(DIFFID_CACHE_FILE)?)?) };
+        let mut cache = read_cache()?;
+        assert_eq!(cache.diff_ids.len(), 2);
+        let layer = &manifest.layers()[2];
+        cache
+            .diff_ids
+            .insert(layer.digest().to_string(), "sha256:cached".into());
+        td.write(DIFFID_CACHE_FILE, serde_json::to_vec(&cache)?)?;
+        assert_eq!(w.compute_diffid_cached(layer)?, expected[2]);
+        assert_eq!(read_cache()?.diff_ids[layer.digest().as_str()], expected[2]);
+        td.write(DIFFID_CACHE_FILE, "garbage")?;
+        assert_eq!(w.compute_diffid_cached(layer)?, expected[2]);
+
+        // Entries of pruned layers are dropped
+        w.compute_diffid_cached(&manifest.layers()[1])?;
+        assert_eq!(read_cache()?.diff_ids.len(), 2);
+        w.prune(&Default::default())?;
+        assert!(read_cache()?.diff_ids.is_empty());
+
+        manifest.layers_mut().truncate(1);
+        assert!(w.fill_diffids(&manifest, &mut incomplete).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,183 @@
+//! Editing an image in place: rewriting its config and manifest and retagging it.
+
+use anyhow::{anyhow, Result};
+use fn_error_context::context;
+use oci_spec::image::{Descriptor, ImageConfiguration, ImageManifest, MediaType};
+use serde::Serialize;
+
+use crate::hash::sha256_hex;
+use crate::{serialize_json, write_json_blob_to_store, HistoryExt, OciDir};
+
+/// The blobs written by an edit which were not present before; they are removed
+/// again unless the edit completes.
+struct NewBlobs<'a> {
+    dir: &'a OciDir,
+    digests: Vec<String>,
+    committed: bool,
+}
+
+impl<'a> NewBlobs<'a> {
+    fn write_json<S: Serialize>(&mut self, v: &S, media_type: MediaType) -> Result<Descriptor> {
+        let opts = self.dir.json_options();
+        let digest = format!(
+            "sha256:{}",
+            sha256_hex(&serialize_json(v, opts.canonical)?)?
+        );
+        let existed = self.dir.store.has(&digest)?;
+        let desc =
+            write_json_blob_to_store(&*self.dir.store, &self.dir.progress, v, media_type, &opts)?
+                .build()?;
+        if !existed && desc.digest() == &digest {
+            self.digests.push(digest);
+        }
+        Ok(desc)
+    }
+}
+
+impl<'a> Drop for NewBlobs<'a> {
+    fn drop(&mut self) {
+        if self.committed {
+            return;
+        }
+        for digest in &self.digests {
+            let _ = self.dir.remove_blob(digest);
+        }
+    }
+}
+
+impl OciDir {
+    /// Modify the manifest and config of the image tagged `tag` with `f`, then
+    /// write both and point the tag at the new manifest. The platform and
+    /// annotations of the index entry are kept, and the config descriptor of the
+    /// manifest is replaced.
+    ///
+    /// Nothing changes if any step fails, including `f`: new blobs are removed
+    /// again, and the index is only updated if the tag still refers to the
+    /// original manifest. The original manifest and config are left in place for
+    /// [`OciDir::prune`].
+    #[context("Editing image {tag}")]
+    pub fn edit_image(
+        &self,
+        tag: &str,
+        f: impl FnOnce(&mut ImageManifest, &mut ImageConfiguration) -> Result<()>,
+    ) -> Result<Descriptor> {
+        let (mut manifest, orig) = self
+            .find_manifest_and_descriptor_with_tag(tag)?
+            .ok_or_else(|| anyhow!("No image with tag {tag}"))?;
+        if manifest.config().media_type() != &MediaType::ImageConfig {
+            anyhow::bail!(
+                "Not an image: config has media type {}",
+                manifest.config().media_type()
+            );
+        }
+        let mut config: ImageConfiguration = self.read_json_blob(manifest.config())?;
+        f(&mut manifest, &mut config)?;
+
+        let mut new_blobs = NewBlobs {
+            dir: self,
+            digests: Vec::new(),
+            committed: false,
+        };
+        if self.opts.strict_manifests {
+            config.validate_history()?;
+        }
+        manifest.set_config(new_blobs.write_json(&config, MediaType::ImageConfig)?);
+        if self.opts.strict_manifests {
+            self.verify_manifest(&manifest)?;
+        }
+        let written = new_blobs.write_json(&manifest, MediaType::ImageManifest)?;
+
+        let _lock = self.lock_index()?;
+        let mut index = self.read_index_required()?;
+        let mut manifests = index.manifests().clone();
+        let entry = manifests
+            .iter_mut()
+            .find(|d| Self::descriptor_is_tagged(d, tag))
+            .ok_or_else(|| anyhow!("Tag {tag} was removed concurrently"))?;
+        if entry.digest() != orig.digest() {
+            anyhow::bail!("Tag {tag} was changed concurrently to {}", entry.digest());
+        }
+        entry.set_digest(written.digest().clone());
+        entry.set_size(written.size());
+        entry.set_data(written.data().clone());
+        let desc = entry.clone();
+        index.set_manifests(manifests);
+        self.write_index_retagged(
+            &index,
+            "edit",
+            Some(desc.digest()),
+            Some(tag),
+            Some(orig.digest()),
+        )?;
+        new_blobs.committed = true;
+        Ok(desc)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ImageConfigExt;
+    use oci_spec::image::{Arch, ImageConfigurationBuilder, Os, PlatformBuilder};
+
+    #[test]
+    fn edit_image() -> Result<()> {
+        let w = OciDir::new_in_memory()?;
+        let platform = PlatformBuilder::default()
+            .os(Os::Linux)
+            .architecture(Arch::ARM64)
+            .build()?;
+        let config = ImageConfigurationBuilder::default().build()?;
+        let orig = w.insert_manifest_and_config(
+            crate::new_empty_manifest().build()?,
+            config,
+            Some("app"),
+            platform.clone(),
+        )?;
+        assert!(w.edit_image("missing", |_, _| Ok(())).is_err());
+
+        let desc = w.edit_image("app", |_, config| {
+            config.add_label("org.example.edited", "yes");
+            Ok(())
+        })?;
+        assert_ne!(desc.digest(), orig.digest());
+        assert_eq!(desc.platform().as_ref(), Some(&platform));
+        let (manifest, found) = w.find_manifest_and_descriptor_with_tag("app")?.unwrap();
+        assert_eq!(found, desc);
+        let config: ImageConfiguration = w.read_json_blob(manifest.config())?;
+        assert_eq!(
+            config.config().as_ref().unwrap().labels().as_ref().unwrap()["org.example.edited"],
+            "yes"
+        );
+        assert_eq!(w.read_index()?.unwrap().manifests().len(), 1);
+
+        // A failing edit changes nothing
+        let blobs = w.store.list()?.len();
+        let r = w.edit_image("app", |_, config| {
+            config.add_label("org.example.edited", "no");
+            anyhow::bail!("oops")
+        });
+        assert!(r.is_err());
+        assert_eq!(w.store.list()?.len(), blobs);
+
+        // Neither does one which races with another writer
+        let r = w.edit_image("app", |_, config| {
+            config.add_label("org.example.edited", "again");
+            let other = ImageConfigurationBuilder::default()
+                .os(Os::Windows)
+                .build()?;
+            w.insert_manifest_and_config(
+                crate::new_empty_manifest().build()?,
+                other,
+                Some("app"),
+                Default::default(),
+            )?;
+            Ok(())
+        });
+        assert!(r.is_err());
+        assert_eq!(w.store.list()?.len(), blobs + 2);
+        let (_, found) = w.find_manifest_and_descriptor_with_tag("app")?.unwrap();
+        assert_ne!(found.digest(), desc.digest());
+        Ok(())
+    }
+}
@@ -0,0 +1,590 @@
+//! Encrypted layers following the [ocicrypt] specification.
+//!
+//! This requires the `encrypt` feature. Layer content is compressed, then
+//! encrypted with `AES_256_CTR_HMAC_SHA256` using a random key; the key is
+//! wrapped for each recipient in a JWE (RSA-OAEP key encryption with A256GCM
+//! content encryption) stored in the [`KEYS_JWE_ANNOTATION`] annotation. The
+//! `diff_id` of the layer remains the digest of the plain tarball.
+//!
+//! Recipient and decryption keys are RSA keys in PEM format.
+//!
+//! [ocicrypt]: https://github.com/containers/ocicrypt/blob/main/docs/spec.md
+
+use std::collections::{BTreeMap, HashMap};
+use std::io::{BufReader, Read, Write};
+
+use anyhow::{anyhow, Context, Result};
+use base64::prelude::*;
+use flate2::write::GzEncoder;
+use fn_error_context::context;
+use oci_spec::image::{Descriptor, DescriptorBuilder, MediaType};
+use openssl::encrypt::{Decrypter, Encrypter};
+use openssl::hash::MessageDigest;
+use openssl::md::Md;
+use openssl::md_ctx::MdCtx;
+use openssl::pkey::{PKey, Private, Public};
+use openssl::rsa::Padding;
+use openssl::symm::{Cipher, Crypter, Mode};
+use serde::{Deserialize, Serialize};
+
+use crate::hash::Sha256;
+use crate::{BlobReader, BlobWriter, CompressionFormat, Layer, OciDir};
+
+/// Layer annotation holding the base64 encoded JWEs wrapping the layer key,
+/// separated by commas.
+pub const KEYS_JWE_ANNOTATION: &str = "org.opencontainers.image.enc.keys.jwe";
+/// Layer annotation holding the base64 encoded public cipher options.
+pub const PUBOPTS_ANNOTATION: &str = "org.opencontainers.image.enc.pubopts";
+/// The suffix appended to the media type of encrypted layers.
+pub const ENCRYPTED_SUFFIX: &str = "+encrypted";
+const CIPHER: &str = "AES_256_CTR_HMAC_SHA256";
+const KEY_SIZE: usize = 32;
+const NONCE_SIZE: usize = 16;
+const GCM_IV_SIZE: usize = 12;
+const GCM_TAG_SIZE: usize = 16;
+const BUF_SIZE: usize = 64 * 1024;
+
+/// Return true if the media type is an encrypted layer type.
+pub fn is_encrypted(media_type: &MediaType) -> bool {
+    media_type.to_string().ends_with(ENCRYPTED_SUFFIX)
+}
+
+/// The media type of the encrypted form of a layer media type.
+pub fn encrypted_media_type(media_type: &MediaType) -> MediaType {
+    MediaType::Other(format!("{media_type}{ENCRYPTED_SUFFIX}"))
+}
+
+/// The media type of the decrypted form of an encrypted layer media type.
+pub fn decrypted_media_type(media_type: &MediaType) -> Option<MediaType> {
+    let media_type = media_type.to_string();
+    media_type
+        .strip_suffix(ENCRYPTED_SUFFIX)
+        .map(MediaType::from)
+}
+
+/// Binary data, serialized as standard base64 like Go's `[]byte`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Bytes(Vec<u8>);
+
+impl Serialize for Bytes {
+    fn serialize<S: serde::Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(&BASE64_STANDARD.encode(&self.0))
+    }
+}
+
+impl<'de> Deserialize<'de> for Bytes {
+    fn deserialize<D: serde::Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(d)?;
+        BASE64_STANDARD
+            .decode(s)
+            .map(Self)
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// The options wrapped for each recipient.
+#[derive(Debug, Serialize, Deserialize)]
+struct PrivateOptions {
+    symkey: Bytes,
+    /// The digest of the unencrypted (compressed) layer.
+    digest: String,
+    #[serde(default)]
+    cipheroptions: BTreeMap<String, Bytes>,
+}
+
+/// The options stored in the clear in [`PUBOPTS_ANNOTATION`].
+#[derive(Debug, Serialize, Deserialize)]
+struct PublicOptions {
+    cipher: String,
+    hmac: Bytes,
+    #[serde(default)]
+    cipheroptions: BTreeMap<String, Bytes>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct JweHeader {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    alg: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    enc: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct JweRecipient {
+    header: JweHeader,
+    encrypted_key: String,
+}
+
+/// A JWE in the general or flattened JSON serialization.
+#[derive(Debug, Serialize, Deserialize)]
+struct Jwe {
+    protected: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    recipients: Vec<JweRecipient>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    header: Option<JweHeader>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    encrypted_key: Option<String>,
+    iv: String,
+    ciphertext: String,
+    tag: String,
+}
+
+fn random<const N: usize>() -> Result<[u8; N]> {
+    let mut r = [0u8; N];
+    openssl::rand::rand_bytes(&mut r)?;
+    Ok(r)
+}
+
+fn oaep_digest(alg: &str) -> Option<MessageDigest> {
+    match alg {
+        "RSA-OAEP" => Some(MessageDigest::sha1()),
+        "RSA-OAEP-256" => Some(MessageDigest::sha256()),
+        _ => None,
+    }
+}
+
+fn jwe_encrypt(payload: &[u8], recipients: &[PKey<Public>]) -> Result<Vec<u8>> {
+    let cek = random::<KEY_SIZE>()?;
+    let iv = random::<GCM_IV_SIZE>()?;
+    let protected = serde_json::to_vec(&JweHeader {
+        alg: None,
+        enc: Some("A256GCM".into()),
+    })?;
+    let protected = BASE64_URL_SAFE_NO_PAD.encode(protected);
+    let mut tag = [0u8; GCM_TAG_SIZE];
+    let ciphertext = openssl::symm::encrypt_aead(
+        Cipher::aes_256_gcm(),
+        &cek,
+        Some(&iv),
+        protected.as_bytes(),
+        payload,
+        &mut tag,
+    )?;
+    let recipients = recipients
+        .iter()
+        .map(|key| {
+            let mut e = Encrypter::new(key)?;
+            e.set_rsa_padding(Padding::PKCS1_OAEP)?;
+            let mut buf = vec![0u8; e.encrypt_len(&cek)?];
+            let n = e.encrypt(&cek, &mut buf)?;
+            buf.truncate(n);
+            Ok(JweRecipient {
+                header: JweHeader {
+                    alg: Some("RSA-OAEP".into()),
+                    enc: None,
+                },
+                encrypted_key: BASE64_URL_SAFE_NO_PAD.encode(buf),
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let jwe = Jwe {
+        protected,
+        recipients,
+        header: None,
+        encrypted_key: None,
+        iv: BASE64_URL_SAFE_NO_PAD.encode(iv),
+        ciphertext: BASE64_URL_SAFE_NO_PAD.encode(ciphertext),
+        tag: BASE64_URL_SAFE_NO_PAD.encode(tag),
+    };
+    Ok(serde_json::to_vec(&jwe)?)
+}
+
+/// Decrypt a JWE, returning `None` if it is not addressed to the key.
+fn jwe_decrypt(buf: &[u8], key: &PKey<Private>) -> Result<Option<Vec<u8>>> {
+    let jwe: Jwe = serde_json::from_slice(buf).context("Parsing JWE")?;
+    let protected: JweHeader =
+        serde_json::from_slice(&BASE64_URL_SAFE_NO_PAD.decode(&jwe.protected)?)?;
+    let flattened = jwe
+        .encrypted_key
+        .map(|encrypted_key| JweRecipient {
+            header: jwe.header.unwrap_or(JweHeader {
+                alg: None,
+                enc: None,
+            }),
+            encrypted_key,
+        })
+        .into_iter();
+    for recipient in jwe.recipients.into_iter().chain(flattened) {
+        let alg = recipient.header.alg.as_ref().or(protected.alg.as_ref());
+        let Some(md) = alg.and_then(|alg| oaep_digest(alg)) else {
+            continue;
+        };
+        let encrypted_key = BASE64_URL_SAFE_NO_PAD.decode(&recipient.encrypted_key)?;
+        let mut d = Decrypter::new(key)?;
+        d.set_rsa_padding(Padding::PKCS1_OAEP)?;
+        d.set_rsa_oaep_md(md)?;
+        let mut cek = vec![0u8; d.decrypt_len(&encrypted_key)?];
+        // Failure means the key was wrapped for another recipient.
+        let Ok(n) = d.decrypt(&encrypted_key, &mut cek) else {
+            continue;
+        };
+        cek.truncate(n);
+        let enc = recipient.header.enc.as_ref().or(protected.enc.as_ref());
+        if enc.map(|s| s.as_str()) != Some("A256GCM") || cek.len() != KEY_SIZE {
+            anyhow::bail!("Unsupported JWE content encryption {enc:?}");
+        }
+        let payload = openssl::symm::decrypt_aead(
+            Cipher::aes_256_gcm(),
+            &cek,
+            Some(&BASE64_URL_SAFE_NO_PAD.decode(&jwe.iv)?),
+            jwe.protected.as_bytes(),
+            &BASE64_URL_SAFE_NO_PAD.decode(&jwe.ciphertext)?,
+            &BASE64_URL_SAFE_NO_PAD.decode(&jwe.tag)?,
+        )
+        .context("Decrypting JWE")?;
+        return Ok(Some(payload));
+    }
+    Ok(None)
+}
+
+fn hmac(key: &[u8]) -> Result<MdCtx> {
+    let key = PKey::hmac(key)?;
+    let mut ctx = MdCtx::new()?;
+    // The context holds its own reference to the key.
+    ctx.digest_sign_init(Some(Md::sha256()), &key)?;
+    Ok(ctx)
+}
+
+/// Encrypts compressed layer content on its way to the blob.
+struct EncryptWriter<'a> {
+    bw: BlobWriter<'a>,
+    crypter: Crypter,
+    mac: MdCtx,
+    /// The digest of the unencrypted content.
+    hash: Sha256,
+    buf: Vec<u8>,
+}
+
+impl<'a> Write for EncryptWriter<'a> {
+    fn write(&mut self, srcbuf: &[u8]) -> std::io::Result<usize> {
+        self.hash.update(srcbuf)?;
+        self.buf
+            .resize(srcbuf.len() + Cipher::aes_256_ctr().block_size(), 0);
+        let n = self.crypter.update(srcbuf, &mut self.buf)?;
+        self.mac.digest_sign_update(&self.buf[..n])?;
+        self.bw.write_all(&self.buf[..n])?;
+        Ok(srcbuf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.bw.flush()
+    }
+}
+
+/// A writer for a gzip compressed and encrypted layer, see
+/// [`OciDir::create_encrypted_gzip_layer`].
+pub struct EncryptingLayerWriter<'a> {
+    uncompressed_hash: Sha256,
+    compressor: GzEncoder<EncryptWriter<'a>>,
+    recipients: Vec<PKey<Public>>,
+    key: [u8; KEY_SIZE],
+    nonce: [u8; NONCE_SIZE],
+}
+
+impl<'a> std::fmt::Debug for EncryptingLayerWriter<'a> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EncryptingLayerWriter")
+            .field("bw", &self.compressor.get_ref().bw)
+            .field("recipients", &self.recipients.len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl<'a> Write for EncryptingLayerWriter<'a> {
+    fn write(&mut self, srcbuf: &[u8]) -> std::io::Result<usize> {
+        self.uncompressed_hash.update(srcbuf)?;
+        self.compressor.write_all(srcbuf)?;
+        Ok(srcbuf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.compressor.flush()
+    }
+}
+
+/// A completed encrypted layer, see [`EncryptingLayerWriter::complete`].
+#[derive(Debug)]
+pub struct EncryptedLayer {
+    /// The encrypted layer blob.
+    pub layer: Layer,
+    /// The annotations which must be set on the layer descriptor for decryption.
+    pub annotations: HashMap<String, String>,
+}
+
+impl EncryptedLayer {
+    /// Return the descriptor for this layer, including the annotations.
+    pub fn descriptor(&self) -> DescriptorBuilder {
+        self.layer
+            .descriptor()
+            .annotations(self.annotations.clone())
+    }
+}
+
+impl<'a> EncryptingLayerWriter<'a> {
+    /// Consume this writer, encrypting the key for all recipients and putting
+    /// the blob in place.
+    #[context("Completing encrypted layer")]
+    pub fn complete(self) -> Result<EncryptedLayer> {
+        let mut uncompressed_hash = self.uncompressed_hash;
+        let mut w = self.compressor.finish()?;
+        let mut buf = vec![0u8; Cipher::aes_256_ctr().block_size()];
+        let n = w.crypter.finalize(&mut buf)?;
+        w.mac.digest_sign_update(&buf[..n])?;
+        w.bw.write_all(&buf[..n])?;
+        let mut mac = Vec::new();
+        w.mac.digest_sign_final_to_vec(&mut mac)?;
+        let blob = w.bw.complete()?;
+
+        let private = PrivateOptions {
+            symkey: Bytes(self.key.to_vec()),
+            digest: format!("sha256:{}", w.hash.finish_hex()?),
+            cipheroptions: BTreeMap::from([("nonce".to_owned(), Bytes(self.nonce.to_vec()))]),
+        };
+        let public = PublicOptions {
+            cipher: CIPHER.to_owned(),
+            hmac: Bytes(mac),
+            cipheroptions: Default::default(),
+        };
+        let jwe = jwe_encrypt(&serde_json::to_vec(&private)?, &self.recipients)?;
+        let annotations = HashMap::from([
+            (KEYS_JWE_ANNOTATION.to_owned(), BASE64_STANDARD.encode(jwe)),
+            (
+                PUBOPTS_ANNOTATION.to_owned(),
+                BASE64_STANDARD.encode(serde_json::to_vec(&public)?),
+            ),
+        ]);
+        Ok(EncryptedLayer {
+            layer: Layer {
+                blob,
+                uncompressed_sha256: uncompressed_hash.finish_hex()?,
+                media_type: encrypted_media_type(&MediaType::ImageLayerGzip),
+            },
+            annotations,
+        })
+    }
+}
+
+/// Decrypts a layer blob, verifying the HMAC and digest of the decrypted
+/// content at the end of the stream.
+struct DecryptReader {
+    inner: BlobReader,
+    crypter: Crypter,
+    mac: MdCtx,
+    hash: Sha256,
+    expected_mac: Vec<u8>,
+    expected_digest: String,
+    buf: Vec<u8>,
+    done: bool,
+}
+
+impl DecryptReader {
+    fn verify(&mut self) -> Result<()> {
+        let mut mac = Vec::new();
+        self.mac.digest_sign_final_to_vec(&mut mac)?;
+        if !openssl::memcmp::eq(&mac, &self.expected_mac) {
+            anyhow::bail!("Layer HMAC mismatch");
+        }
+        let digest = format!("sha256:{}", self.hash.finish_hex()?);
+        if digest != self.expected_digest {
+            anyhow::bail!(
+                "Decrypted layer digest mismatch; expected {} found {digest}",
+                self.expected_digest
+            );
+        }
+        Ok(())
+    }
+}
+
+impl Read for DecryptReader {
+    fn read(&mut self, out: &mut [u8]) -> std::io::Result<usize> {
+        if self.done || out.is_empty() {
+            return Ok(0);
+        }
+        let len = out.len().min(BUF_SIZE);
+        self.buf.resize(len, 0);
+        let n = self.inner.read(&mut self.buf[..len])?;
+        if n == 0 {
+            self.done = true;
+            self.verify().map_err(std::io::Error::other)?;
+            return Ok(0);
+        }
+        self.mac.digest_sign_update(&self.buf[..n])?;
+        // AES-CTR is a stream cipher, so the output is the same size as the input.
+        let mut plain = vec![0u8; n + Cipher::aes_256_ctr().block_size()];
+        let n = self.crypter.update(&self.buf[..n], &mut plain)?;
+        out[..n].copy_from_slice(&plain[..n]);
+        self.hash.update(&out[..n])?;
+        Ok(n)
+    }
+}
+
+impl OciDir {
+    /// Create a writer for a gzip compressed layer which is encrypted for the
+    /// provided RSA public keys in PEM format.
+    #[context("Creating encrypted layer")]
+    pub fn create_encrypted_gzip_layer(
+        &self,
+        c: Option<flate2::Compression>,
+        recipients: &[&[u8]],
+    ) -> Result<EncryptingLayerWriter<'_>> {
+        if recipients.is_empty() {
+            anyhow::bail!("At least one recipient is required");
+        }
+        let recipients = recipients
+            .iter()
+            .map(|pem| {
+                let key = PKey::public_key_from_pem(pem).context("Parsing public key")?;
+                if key.rsa().is_err() {
+                    anyhow::bail!("Only RSA recipient keys are supported");
+                }
+                Ok(key)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let key = random::<KEY_SIZE>()?;
+        let nonce = random::<NONCE_SIZE>()?;
+        let inner = EncryptWriter {
+            bw: BlobWriter::new(&*self.store, &self.progress)?,
+            crypter: Crypter::new(Cipher::aes_256_ctr(), Mode::Encrypt, &key, Some(&nonce))?,
+            mac: hmac(&key)?,
+            hash: Sha256::new()?,
+            buf: Vec::new(),
+        };
+        Ok(EncryptingLayerWriter {
+            uncompressed_hash: Sha256::new()?,
+            compressor: GzEncoder::new(inner, c.unwrap_or_default()),
+            recipients,
+            key,
+            nonce,
+        })
+    }
+
+    /// Open an encrypted layer with an RSA private key in PEM format, returning
+    /// the decrypted and decompressed tarball.
+    ///
+    /// The integrity of the layer is verified when the end of the stream is
+    /// reached, so all data must be read before it can be trusted.
+    #[context("Opening encrypted layer {}", desc.digest())]
+    pub fn open_encrypted_layer(
+        &self,
+        desc: &Descriptor,
+        private_key_pem: &[u8],
+    ) -> Result<Box<dyn Read + Send>> {
+        let media_type = decrypted_media_type(desc.media_type())
+            .ok_or_else(|| anyhow!("Not an encrypted layer: {}", desc.media_type()))?;
+        let format = CompressionFormat::from_media_type(&media_type)
+            .ok_or_else(|| anyhow!("Unsupported layer media type {media_type}"))?;
+        let key = PKey::private_key_from_pem(private_key_pem).context("Parsing private key")?;
+        let annotation = |k: &str| {
+            desc.annotations()
+                .as_ref()
+                .and_then(|a| a.get(k))
+                .ok_or_else(|| anyhow!("Missing annotation {k}"))
+        };
+        let public: PublicOptions =
+            serde_json::from_slice(&BASE64_STANDARD.decode(annotation(PUBOPTS_ANNOTATION)?)?)
+                .context("Parsing public options")?;
+        if public.cipher != CIPHER {
+            anyhow::bail!("Unsupported layer cipher {}", public.cipher);
+        }
+        let mut private = None;
+        for jwe in annotation(KEYS_JWE_ANNOTATION)?.split(',') {
+            if let Some(payload) = jwe_decrypt(&BASE64_STANDARD.decode(jwe)?, &key)? {
+                private = Some(payload);
+                break;
+            }
+        }
+        let private = private.ok_or_else(|| anyhow!("No key for this layer"))?;
+        let private: PrivateOptions =
+            serde_json::from_slice(&private).context("Parsing private options")?;
+        let nonce = private
+            .cipheroptions
+            .get("nonce")
+            .filter(|n| n.0.len() == NONCE_SIZE)
+            .ok_or_else(|| anyhow!("Missing or invalid nonce"))?;
+        if private.symkey.0.len() != KEY_SIZE {
+            anyhow::bail!("Invalid key size {}", private.symkey.0.len());
+        }
+        let r = DecryptReader {
+            inner: self.read_blob(desc)?,
+            crypter: Crypter::new(
+                Cipher::aes_256_ctr(),
+                Mode::Decrypt,
+                &private.symkey.0,
+                Some(&nonce.0),
+            )?,
+            mac: hmac(&private.symkey.0)?,
+            hash: Sha256::new()?,
+            expected_mac: public.hmac.0,
+            expected_digest: private.digest,
+            buf: Vec::new(),
+            done: false,
+        };
+        format.decompress(BufReader::new(r))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::rsa::Rsa;
+
+    fn keypair() -> Result<(Vec<u8>, Vec<u8>)> {
+        let key = PKey::from_rsa(Rsa::generate(2048)?)?;
+        Ok((key.public_key_to_pem()?, key.private_key_to_pem_pkcs8()?))
+    }
+
+    #[test]
+    fn encrypted_layer() -> Result<()> {
+        let w = OciDir::new_in_memory()?;
+        let (public, private) = keypair()?;
+        let (other_public, other_private) = keypair()?;
+        let (_, unrelated) = keypair()?;
+        assert!(w.create_encrypted_gzip_layer(None, &[]).is_err());
+
+        let contents = b"some layer contents ".repeat(1000);
+        let mut layer =
+            w.create_encrypted_gzip_layer(None, &[public.as_slice(), other_public.as_slice()])?;
+        layer.write_all(&contents)?;
+        let layer = layer.complete()?;
+        assert_eq!(
+            layer.layer.media_type.to_string(),
+            "application/vnd.oci.image.layer.v1.tar+gzip+encrypted"
+        );
+        assert_eq!(
+            layer.layer.uncompressed_sha256,
+            crate::hash::sha256_hex(&contents)?
+        );
+        let desc = layer.descriptor().build()?;
+        assert!(is_encrypted(desc.media_type()));
+        let mut ciphertext = Vec::new();
+        w.read_blob(&desc)?.read_to_end(&mut ciphertext)?;
+        assert!(!ciphertext.starts_with(&[0x1f, 0x8b]));
+
+        for key in [&private, &other_private] {
+            let mut buf = Vec::new();
+            w.open_encrypted_layer(&desc, key)?.read_to_end(&mut buf)?;
+            assert_eq!(buf, contents);
+        }
+        assert!(w.open_encrypted_layer(&desc, &unrelated).is_err());
+
+        // A modified HMAC is detected at the end of the stream
+        let mut public_opts: PublicOptions = serde_json::from_slice(
+            &BASE64_STANDARD.decode(&layer.annotations[PUBOPTS_ANNOTATION])?,
+        )?;
+        public_opts.hmac.0[0] ^= 1;
+        let mut annotations = layer.annotations.clone();
+        annotations.insert(
+            PUBOPTS_ANNOTATION.to_owned(),
+            BASE64_STANDARD.encode(serde_json::to_vec(&public_opts)?),
+        );
+        let tampered = layer.layer.descriptor().annotations(annotations).build()?;
+        let mut buf = Vec::new();
+        let err = w
+            .open_encrypted_layer(&tampered, &private)?
+            .read_to_end(&mut buf)
+            .unwrap_err();
+        assert!(err.to_string().contains("HMAC"), "{err}");
+        Ok(())
+    }
+}
@@ -0,0 +1,180 @@
+//! Listing the entries of a layer without extracting it.
+
+use std::io::Read;
+use std::path::PathBuf;
+#[cfg(not(target_family = "wasm"))]
+use std::sync::mpsc::sync_channel;
+use std::sync::mpsc::Receiver;
+
+use anyhow::{Context, Result};
+use fn_error_context::context;
+use oci_spec::image::Descriptor;
+
+use crate::extract::normalize;
+use crate::{OciDir, WHITEOUT_PREFIX};
+
+/// The number of entries read ahead of the consumer.
+#[cfg(not(target_family = "wasm"))]
+const READ_AHEAD: usize = 64;
+
+/// The metadata of an entry in a layer tarball, see [`OciDir::layer_entries`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct LayerEntry {
+    /// The relative path, without any leading `/` or `./`. Unlike paths within
+    /// the layout, this is not a `Utf8PathBuf` since tar names need not be UTF-8.
+    pub path: PathBuf,
+    /// The size of the entry contents.
+    pub size: u64,
+    /// The permission bits.
+    pub mode: u32,
+    /// The type of the entry.
+    pub entry_type: tar::EntryType,
+    /// The target of a symbolic or hard link.
+    pub link_name: Option<PathBuf>,
+    /// True if the entry is a whiteout, including opaque whiteouts.
+    pub whiteout: bool,
+}
+
+/// An iterator over the entries of a layer, see [`OciDir::layer_entries`].
+///
+/// The layer is read on a separate thread, which stops when the iterator is dropped;
+/// on wasm, where there are no threads, it is read when the iterator is created.
+#[derive(Debug)]
+pub struct LayerEntries {
+    rx: Receiver<Result<LayerEntry>>,
+}
+
+impl Iterator for LayerEntries {
+    type Item = Result<LayerEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.rx.recv().ok()
+    }
+}
+
+fn entry_metadata<R: Read>(entry: &tar::Entry<R>) -> Result<LayerEntry> {
+    let path = normalize(&entry.path()?)?;
+    let whiteout = path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .is_some_and(|n| n.starts_with(WHITEOUT_PREFIX));
+    let header = entry.header();
+    Ok(LayerEntry {
+        path,
+        size: entry.size(),
+        mode: header.mode()? & 0o7777,
+        entry_type: header.entry_type(),
+        link_name: entry.link_name()?.map(|p| p.into_owned()),
+        whiteout,
+    })
+}
+
+/// Send the entries of a tarball, returning early if `send` returns false
+/// because the receiver is gone.
+fn send_entries(r: impl Read, mut send: impl FnMut(Result<LayerEntry>) -> bool) -> Result<()> {
+    let mut archive = tar::Archive::new(r);
+    for entry in archive.entries()? {
+        if !send(entry_metadata(&entry?)) {
+            break;
+        }
+    }
+    Ok(())
+}
+
+impl OciDir {
+    /// Iterate over the metadata of the entries of a layer, in archive order,
+    /// by streaming the decompressed layer. Nothing is written to disk.
+    ///
+    /// The compression format is detected from the blob content.
+    #[context("Listing entries of {}", desc.digest())]
+    pub fn layer_entries(&self, desc: &Descriptor) -> Result<LayerEntries> {
+        let (_, r) = self.open_layer(desc)?;
+        let digest = desc.digest().to_string();
+        // Without threads, the entries are read up front.
+        #[cfg(target_family = "wasm")]
+        let (tx, rx) = std::sync::mpsc::channel();
+        #[cfg(not(target_family = "wasm"))]
+        let (tx, rx) = sync_channel(READ_AHEAD);
+        let read = move || {
+            if let Err(e) = send_entries(r, |e| tx.send(e).is_ok()) {
+                let _ = tx.send(Err(e).context(format!("Reading entries of {digest}")));
+            }
+        };
+        #[cfg(target_family = "wasm")]
+        read();
+        #[cfg(not(target_family = "wasm"))]
+        std::thread::spawn(read);
+        Ok(LayerEntries { rx })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    #[test]
+    fn layer_entries() -> Result<()> {
+        let w = OciDir::new_in_memory()?;
+        let mut builder = w.create_layer(None)?;
+        let mut h = tar::Header::new_gnu();
+        h.set_entry_type(tar::EntryType::Directory);
+        h.set_mode(0o755);
+        h.set_size(0);
+        builder.append_data(&mut h, "./etc/", std::io::empty())?;
+        for (path, contents) in [
+            ("./etc/os-release", "ID=test"),
+            ("etc/.wh.removed", ""),
+            ("usr/.wh..wh..opq", ""),
+        ] {
+            let mut h = tar::Header::new_gnu();
+            h.set_mode(0o644);
+            h.set_size(contents.len() as u64);
+            builder.append_data(&mut h, path, contents.as_bytes())?;
+        }
+        let mut h = tar::Header::new_gnu();
+        h.set_entry_type(tar::EntryType::Symlink);
+        h.set_mode(0o777);
+        h.set_size(0);
+        builder.append_link(&mut h, "etc/release", "os-release")?;
+        let layer = builder.into_inner()?.complete()?;
+        let desc = layer.descriptor().build()?;
+
+        let entries = w.layer_entries(&desc)?.collect::<Result<Vec<_>>>()?;
+        let paths: Vec<_> = entries.iter().map(|e| e.path.to_str().unwrap()).collect();
+        assert_eq!(
+            paths,
+            [
+                "etc",
+                "etc/os-release",
+                "etc/.wh.removed",
+                "usr/.wh..wh..opq",
+                "etc/release"
+            ]
+        );
+        assert_eq!(entries[0].entry_type, tar::EntryType::Directory);
+        assert_eq!(entries[1].size, 7);
+        assert_eq!(entries[1].mode, 0o644);
+        let whiteouts: Vec<_> = entries.iter().map(|e| e.whiteout).collect();
+        assert_eq!(whiteouts, [false, false, true, true, false]);
+        assert_eq!(entries[4].entry_type, tar::EntryType::Symlink);
+        assert_eq!(
+            entries[4].link_name.as_deref(),
+            Some(Path::new("os-release"))
+        );
+
+        // Stopping early does not wait for the rest of the layer
+        let first = w.layer_entries(&desc)?.next().unwrap()?;
+        assert_eq!(first, entries[0]);
+
+        let broken = w
+            .write_blob_with_type(&[0x1f, 0x8b, 0], oci_spec::image::MediaType::ImageLayerGzip)?
+            .build()?;
+        assert!(w
+            .layer_entries(&broken)?
+            .collect::<Result<Vec<_>>>()
+            .is_err());
+        Ok(())
+    }
+}
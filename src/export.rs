@@ -0,0 +1,190 @@
+//! Export of a single image as an OCI layout tarball, with its size known up front.
+
+use std::collections::BTreeSet;
+use std::io::{Read, Write};
+
+use anyhow::{Context, Result};
+use fn_error_context::context;
+use oci_spec::image as oci_image;
+
+use crate::{OciDir, OCI_LAYOUT_DEFAULT};
+
+const BLOCK_SIZE: u64 = 512;
+
+/// The files of an export, in the order they are written.
+struct ExportPlan {
+    index: Vec<u8>,
+    /// Blob digests and sizes, sorted by digest.
+    blobs: Vec<(String, u64)>,
+}
+
+fn padded(size: u64) -> u64 {
+    size.div_ceil(BLOCK_SIZE) * BLOCK_SIZE
+}
+
+fn blob_tar_path(digest: &str) -> Result<String> {
+    let (alg, encoded) = crate::store::split_digest(digest)?;
+    Ok(format!("blobs/{alg}/{encoded}"))
+}
+
+fn header(path: &str, entry_type: tar::EntryType, size: u64) -> Result<tar::Header> {
+    let mut h = tar::Header::new_ustar();
+    h.set_path(path)
+        .with_context(|| format!("Path {path} does not fit in a tar header"))?;
+    h.set_entry_type(entry_type);
+    h.set_size(size);
+    h.set_mode(if entry_type.is_dir() { 0o755 } else { 0o644 });
+    h.set_uid(0);
+    h.set_gid(0);
+    h.set_mtime(0);
+    h.set_cksum();
+    Ok(h)
+}
+
+impl ExportPlan {
+    /// The directories to create, with trailing slashes.
+    fn dirs(&self) -> Result<BTreeSet<String>> {
+        let mut r = BTreeSet::from(["blobs/".to_owned()]);
+        for (digest, _) in &self.blobs {
+            let (alg, _) = crate::store::split_digest(digest)?;
+            r.insert(format!("blobs/{alg}/"));
+        }
+        Ok(r)
+    }
+
+    fn size(&self) -> Result<u64> {
+        let files = [OCI_LAYOUT_DEFAULT.len() as u64, self.index.len() as u64]
+            .into_iter()
+            .chain(self.blobs.iter().map(|(_, size)| *size));
+        let dirs = self.dirs()?.len() as u64;
+        Ok(files.map(|size| BLOCK_SIZE + padded(size)).sum::<u64>()
+            + dirs * BLOCK_SIZE
+            + 2 * BLOCK_SIZE)
+    }
+}
+
+impl OciDir {
+    #[context("Planning export of {tag_or_digest}")]
+    fn export_plan(&self, tag_or_digest: &str) -> Result<ExportPlan> {
+        let desc = self.resolve(tag_or_digest)?;
+        let mut digests = BTreeSet::new();
+        self.collect_reachable(&desc, &mut digests)?;
+        let blobs = digests
+            .into_iter()
+            .map(|digest| {
+                let (_, size) = self.open_blob_sized(&digest)?;
+                // Ensure the path is valid before anything is written.
+                header(&blob_tar_path(&digest)?, tar::EntryType::Regular, size)?;
+                Ok((digest, size))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let index = oci_image::ImageIndexBuilder::default()
+            .schema_version(oci_image::SCHEMA_VERSION)
+            .manifests(vec![desc])
+            .build()?;
+        Ok(ExportPlan {
+            index: serde_json::to_vec(&index)?,
+            blobs,
+        })
+    }
+
+    /// The exact size in bytes of the tarball written by [`Self::export_to_tar`] for
+    /// the same image, for example to set a `Content-Length` header before streaming.
+    pub fn export_size(&self, tag_or_digest: &str) -> Result<u64> {
+        self.export_plan(tag_or_digest)?.size()
+    }
+
+    /// Write a tarball of an OCI layout containing only the image with the provided
+    /// tag or manifest digest, returning the number of bytes written.
+    ///
+    /// The output is deterministic: `oci-layout`, `index.json`, then the `blobs`
+    /// directories and all blobs reachable from the image sorted by digest, with
+    /// fixed ownership, permissions and timestamps. Every blob must be present.
+    #[context("Exporting {tag_or_digest}")]
+    pub fn export_to_tar(&self, tag_or_digest: &str, w: impl Write) -> Result<u64> {
+        let plan = self.export_plan(tag_or_digest)?;
+        let size = plan.size()?;
+        let mut builder = tar::Builder::new(w);
+        for (path, contents) in [
+            ("oci-layout", OCI_LAYOUT_DEFAULT.as_bytes()),
+            ("index.json", plan.index.as_slice()),
+        ] {
+            let h = header(path, tar::EntryType::Regular, contents.len() as u64)?;
+            builder.append(&h, contents)?;
+        }
+        for dir in plan.dirs()? {
+            builder.append(
+                &header(&dir, tar::EntryType::Directory, 0)?,
+                std::io::empty(),
+            )?;
+        }
+        for (digest, size) in &plan.blobs {
+            let h = header(&blob_tar_path(digest)?, tar::EntryType::Regular, *size)?;
+            let (f, found) = self.open_blob_sized(digest)?;
+            if found != *size {
+                anyhow::bail!("Blob {digest} changed size during export");
+            }
+            builder.append(&h, f.take(*size))?;
+        }
+        builder.into_inner()?.flush()?;
+        Ok(size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+
+    #[test]
+    fn export() -> Result<()> {
+        let w = OciDir::new_in_memory()?;
+        let mut manifest = crate::new_empty_manifest().build()?;
+        let mut config = oci_image::ImageConfigurationBuilder::default().build()?;
+        let mut layer = w.create_gzip_layer(None)?;
+        layer.write_all(b"not actually a tarball")?;
+        let layer = layer.complete()?;
+        w.push_layer(&mut manifest, &mut config, layer, "layer", None);
+        let desc =
+            w.insert_manifest_and_config(manifest, config, Some("app"), Default::default())?;
+        let other = crate::new_empty_manifest().build()?;
+        let other_config = oci_image::ImageConfigurationBuilder::default()
+            .architecture(oci_image::Arch::ARM64)
+            .build()?;
+        w.insert_manifest_and_config(other, other_config, Some("other"), Default::default())?;
+
+        let mut buf = Vec::new();
+        let written = w.export_to_tar("app", &mut buf)?;
+        assert_eq!(written, buf.len() as u64);
+        assert_eq!(w.export_size("app")?, written);
+        assert_eq!(w.export_size(desc.digest())?, written);
+        let mut again = Vec::new();
+        w.export_to_tar("app", &mut again)?;
+        assert_eq!(buf, again);
+
+        let mut archive = tar::Archive::new(buf.as_slice());
+        let mut names = Vec::new();
+        let mut index = None;
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            let name = entry.path()?.to_string_lossy().into_owned();
+            if name == "index.json" {
+                let mut s = String::new();
+                entry.read_to_string(&mut s)?;
+                index = Some(s);
+            }
+            names.push(name);
+        }
+        // oci-layout, index.json, two directories, manifest, config and layer
+        assert_eq!(names.len(), 7);
+        assert_eq!(
+            names[..4],
+            ["oci-layout", "index.json", "blobs/", "blobs/sha256/"]
+        );
+        let index: oci_image::ImageIndex =
+            serde_json::from_str(&index.ok_or_else(|| anyhow!("Missing index"))?)?;
+        assert_eq!(index.manifests(), &[desc]);
+        assert!(w.export_size("missing").is_err());
+        Ok(())
+    }
+}
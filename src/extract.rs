@@ -0,0 +1,606 @@
+//! Extraction of layers, or selected paths from them, into a directory.
+
+use std::collections::HashSet;
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+use cap_std::fs::Dir;
+use cap_std_ext::cap_std;
+use cap_std_ext::dirext::CapStdExtDirExt;
+use fn_error_context::context;
+use oci_spec::image::{Descriptor, ImageManifest};
+
+use crate::{fsmeta, DevicePolicy, OciDir, OPAQUE_WHITEOUT, WHITEOUT_PREFIX};
+
+/// What to do with a link entry which is restricted by [`UnpackOptions`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkPolicy {
+    /// Create the link.
+    Allow,
+    /// Silently omit the link.
+    Skip,
+    /// Fail the extraction.
+    Error,
+}
+
+/// What to do with setuid and setgid bits of files, see [`UnpackOptions::setuid`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SetuidPolicy {
+    /// Apply the bits as they are.
+    #[default]
+    Preserve,
+    /// Clear the bits.
+    Strip,
+    /// Fail the extraction.
+    Error,
+}
+
+/// A range of IDs mapped from the image to the host, in the format of
+/// `/proc/<pid>/uid_map`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IdMapping {
+    /// The first ID in the image.
+    pub container: u32,
+    /// The host ID which `container` is mapped to.
+    pub host: u32,
+    /// The number of IDs in the range.
+    pub size: u32,
+}
+
+/// How the ownership of entries is applied, see [`UnpackOptions::ownership`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum Ownership {
+    /// Leave extracted files owned by the current user.
+    #[default]
+    Ignore,
+    /// Apply the uid and gid from the layer, which usually requires privileges.
+    Preserve,
+    /// Apply the uid and gid from the layer mapped through these ranges, as for a
+    /// user namespace. IDs outside the ranges are an error.
+    Map {
+        /// The ranges for user IDs.
+        uids: Vec<IdMapping>,
+        /// The ranges for group IDs.
+        gids: Vec<IdMapping>,
+    },
+}
+
+/// Options for [`OciDir::unpack_image`] and [`OciDir::extract_paths_with`].
+///
+/// The defaults extract what an unprivileged user can: device nodes and FIFOs are
+/// skipped and ownership is ignored.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnpackOptions {
+    /// Symbolic links with an absolute target. These are resolved relative to the
+    /// root of the image at runtime, but not by tools operating on the extracted tree.
+    pub absolute_symlinks: LinkPolicy,
+    /// Hard links to a path which was not extracted by the same call, including
+    /// files which already existed in the destination.
+    pub external_hardlinks: LinkPolicy,
+    /// How character and block device nodes are handled. FIFOs are created only
+    /// with [`DevicePolicy::Include`], and skipped otherwise.
+    pub devices: DevicePolicy,
+    /// How setuid and setgid bits of non-directories are handled.
+    pub setuid: SetuidPolicy,
+    /// How the ownership of entries is applied.
+    pub ownership: Ownership,
+}
+
+impl Default for UnpackOptions {
+    fn default() -> Self {
+        Self {
+            absolute_symlinks: LinkPolicy::Allow,
+            external_hardlinks: LinkPolicy::Error,
+            devices: DevicePolicy::Skip,
+            setuid: SetuidPolicy::default(),
+            ownership: Ownership::default(),
+        }
+    }
+}
+
+/// The setuid and setgid bits.
+const SETID_BITS: u32 = 0o6000;
+
+/// Normalize a path from a tar entry or a caller to a relative path, rejecting
+/// any `..` components.
+pub(crate) fn normalize(p: &Path) -> Result<PathBuf> {
+    let mut r = PathBuf::new();
+    for c in p.components() {
+        match c {
+            Component::Normal(c) => r.push(c),
+            Component::CurDir | Component::RootDir => {}
+            Component::ParentDir | Component::Prefix(_) => {
+                return Err(anyhow!("Invalid path {}", p.display()))
+            }
+        }
+    }
+    Ok(r)
+}
+
+/// The path hidden by a whiteout `.wh.<target>` in `parent`. The target must be
+/// a single path component, so a whiteout cannot remove anything outside `parent`.
+pub(crate) fn whiteout_target(parent: &Path, target: &str) -> Result<PathBuf> {
+    if target.is_empty() || target == "." || target == ".." || target.contains(['/', '\\']) {
+        return Err(anyhow!("Invalid whiteout {WHITEOUT_PREFIX}{target}"));
+    }
+    normalize(&parent.join(target))
+}
+
+fn ensure_parent(dest: &Dir, path: &Path) -> Result<()> {
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        let db = crate::dir_builder();
+        dest.ensure_dir_with(parent, &db)?;
+    }
+    Ok(())
+}
+
+/// Apply the permission bits of a tar entry; there are none on Windows.
+#[cfg(unix)]
+fn set_mode(dest: &Dir, path: &Path, mode: u32) -> Result<()> {
+    use cap_std::fs::{Permissions, PermissionsExt};
+    dest.set_permissions(path, Permissions::from_mode(mode))?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn set_mode(_dest: &Dir, _path: &Path, _mode: u32) -> Result<()> {
+    Ok(())
+}
+
+/// Map an ID through `ranges`.
+fn map_id(ranges: &[IdMapping], id: u32, kind: &str) -> Result<u32> {
+    ranges
+        .iter()
+        .find(|r| id >= r.container && id - r.container < r.size)
+        .map(|r| r.host + (id - r.container))
+        .ok_or_else(|| anyhow!("{kind} {id} is not mapped"))
+}
+
+/// Returns false if the link should be skipped according to `policy`.
+fn check_link(policy: LinkPolicy, what: &str, path: &Path) -> Result<bool> {
+    match policy {
+        LinkPolicy::Allow => Ok(true),
+        LinkPolicy::Skip => Ok(false),
+        LinkPolicy::Error => anyhow::bail!("Refusing {what} {}", path.display()),
+    }
+}
+
+/// Remove a non-directory at `path`, if any, so that it can be replaced.
+fn remove_existing(dest: &Dir, path: &Path) -> Result<()> {
+    match dest.symlink_metadata_optional(path)? {
+        Some(m) if !m.is_dir() => dest.remove_file(path)?,
+        _ => {}
+    }
+    Ok(())
+}
+
+/// State shared by the layers extracted in one call.
+struct Extractor<'a> {
+    dest: &'a Dir,
+    opts: &'a UnpackOptions,
+    /// The paths extracted so far, which hard links may refer to.
+    extracted: HashSet<PathBuf>,
+}
+
+impl<'a> Extractor<'a> {
+    fn new(dest: &'a Dir, opts: &'a UnpackOptions) -> Self {
+        Self {
+            dest,
+            opts,
+            extracted: HashSet::new(),
+        }
+    }
+
+    /// Apply the ownership of a tar entry according to [`UnpackOptions::ownership`].
+    fn set_owner(&self, path: &Path, header: &tar::Header) -> Result<()> {
+        if self.opts.ownership == Ownership::Ignore {
+            return Ok(());
+        }
+        let uid = u32::try_from(header.uid()?)?;
+        let gid = u32::try_from(header.gid()?)?;
+        let (uid, gid) = match &self.opts.ownership {
+            Ownership::Map { uids, gids } => (map_id(uids, uid, "uid")?, map_id(gids, gid, "gid")?),
+            _ => (uid, gid),
+        };
+        fsmeta::chown(self.dest, path, uid, gid)
+    }
+
+    /// Apply the whiteouts of a layer, before its content is extracted.
+    fn apply_whiteouts(&mut self, d: &OciDir, desc: &Descriptor) -> Result<()> {
+        let (_, r) = d.open_layer(desc)?;
+        let mut archive = tar::Archive::new(r);
+        for entry in archive.entries()? {
+            let path = normalize(&entry?.path()?)?;
+            let Some(name) = path.file_name().and_then(|n| n.to_str()) else {
+                continue;
+            };
+            let parent = path.parent().unwrap_or(Path::new(""));
+            if name == OPAQUE_WHITEOUT {
+                let dir = if parent.as_os_str().is_empty() {
+                    Some(self.dest.open_dir(".")?)
+                } else {
+                    self.dest.open_dir_optional(parent)?
+                };
+                if let Some(dir) = dir {
+                    for child in dir.entries()? {
+                        let child = child?;
+                        if child.file_type()?.is_dir() {
+                            dir.remove_dir_all(child.file_name())?;
+                        } else {
+                            dir.remove_file(child.file_name())?;
+                        }
+                    }
+                }
+                self.extracted
+                    .retain(|p| p == parent || !p.starts_with(parent));
+            } else if let Some(target) = name.strip_prefix(WHITEOUT_PREFIX) {
+                let target = whiteout_target(parent, target)?;
+                match self.dest.symlink_metadata_optional(&target)? {
+                    Some(m) if m.is_dir() => self.dest.remove_dir_all(&target)?,
+                    Some(_) => self.dest.remove_file(&target)?,
+                    None => {}
+                }
+                self.extracted.retain(|p| !p.starts_with(&target));
+            }
+        }
+        Ok(())
+    }
+
+    /// Extract the entries of a layer at or below one of `prefixes`, skipping whiteouts.
+    fn extract(&mut self, d: &OciDir, desc: &Descriptor, prefixes: &[PathBuf]) -> Result<u64> {
+        let dest = self.dest;
+        let (_, r) = d.open_layer(desc)?;
+        let mut archive = tar::Archive::new(r);
+        let mut n = 0;
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            let path = normalize(&entry.path()?)?;
+            if path.as_os_str().is_empty() || !prefixes.iter().any(|p| path.starts_with(p)) {
+                continue;
+            }
+            if path
+                .file_name()
+                .and_then(|n| n.to_str())
+                .is_some_and(|n| n.starts_with(WHITEOUT_PREFIX))
+            {
+                continue;
+            }
+            let header = entry.header().clone();
+            let entry_type = header.entry_type();
+            let mut mode = header.mode()? & 0o7777;
+            if !entry_type.is_dir() && mode & SETID_BITS != 0 {
+                match self.opts.setuid {
+                    SetuidPolicy::Preserve => {}
+                    SetuidPolicy::Strip => mode &= !SETID_BITS,
+                    SetuidPolicy::Error => {
+                        anyhow::bail!("Refusing setuid or setgid file {}", path.display())
+                    }
+                }
+            }
+            match entry_type {
+                tar::EntryType::Directory => {
+                    ensure_parent(dest, &path)?;
+                    let db = crate::dir_builder();
+                    dest.ensure_dir_with(&path, &db)?;
+                    self.set_owner(&path, &header)?;
+                    set_mode(dest, &path, mode)?;
+                }
+                tar::EntryType::Regular | tar::EntryType::Continuous => {
+                    ensure_parent(dest, &path)?;
+                    remove_existing(dest, &path)?;
+                    let mut f = dest.create(&path)?;
+                    std::io::copy(&mut entry, &mut f)?;
+                    drop(f);
+                    self.set_owner(&path, &header)?;
+                    set_mode(dest, &path, mode)?;
+                }
+                #[cfg(unix)]
+                tar::EntryType::Symlink => {
+                    let target = entry
+                        .link_name()?
+                        .ok_or_else(|| anyhow!("Missing symlink target"))?;
+                    if target.has_root()
+                        && !check_link(self.opts.absolute_symlinks, "absolute symlink", &path)?
+                    {
+                        continue;
+                    }
+                    ensure_parent(dest, &path)?;
+                    remove_existing(dest, &path)?;
+                    dest.symlink_contents(target, &path)?;
+                    self.set_owner(&path, &header)?;
+                }
+                tar::EntryType::Link => {
+                    let target = entry
+                        .link_name()?
+                        .ok_or_else(|| anyhow!("Missing hard link target"))?;
+                    let target = normalize(&target)?;
+                    if !self.extracted.contains(&target)
+                        && !check_link(
+                            self.opts.external_hardlinks,
+                            "hard link to unextracted path",
+                            &path,
+                        )?
+                    {
+                        continue;
+                    }
+                    ensure_parent(dest, &path)?;
+                    remove_existing(dest, &path)?;
+                    dest.hard_link(&target, dest, &path)?;
+                }
+                tar::EntryType::Char | tar::EntryType::Block | tar::EntryType::Fifo => {
+                    match self.opts.devices {
+                        DevicePolicy::Include => {}
+                        DevicePolicy::Skip => continue,
+                        DevicePolicy::Error if entry_type == tar::EntryType::Fifo => continue,
+                        DevicePolicy::Error => {
+                            anyhow::bail!("Refusing device node {}", path.display())
+                        }
+                    }
+                    let (major, minor) = if entry_type == tar::EntryType::Fifo {
+                        (0, 0)
+                    } else {
+                        let major = header.device_major()?.unwrap_or_default();
+                        (major, header.device_minor()?.unwrap_or_default())
+                    };
+                    ensure_parent(dest, &path)?;
+                    remove_existing(dest, &path)?;
+                    fsmeta::mknod(dest, &path, entry_type, mode, major, minor)?;
+                    self.set_owner(&path, &header)?;
+                    set_mode(dest, &path, mode)?;
+                }
+                _ => continue,
+            }
+            self.extracted.insert(path);
+            n += 1;
+        }
+        Ok(n)
+    }
+}
+
+impl OciDir {
+    /// Extract only the entries of a layer which are at or below one of the
+    /// provided path prefixes into `dest`, returning the number of extracted
+    /// entries. Leading `/` in prefixes and entry paths are ignored.
+    ///
+    /// The layer is streamed and all other entries are skipped. Regular files,
+    /// directories, symbolic links and hard links (to previously extracted entries)
+    /// are supported; whiteouts and other entry types are ignored. File modes are
+    /// preserved, but not ownership or timestamps.
+    ///
+    /// On Windows, file modes are not applied and symbolic links are skipped.
+    #[context("Extracting paths from {}", desc.digest())]
+    pub fn extract_paths<P: AsRef<Path>>(
+        &self,
+        desc: &Descriptor,
+        prefixes: &[P],
+        dest: &Dir,
+    ) -> Result<u64> {
+        self.extract_paths_with(desc, prefixes, dest, &Default::default())
+    }
+
+    /// Like [`Self::extract_paths`], but with the provided options; see [`UnpackOptions`].
+    ///
+    /// Device nodes, FIFOs and ownership can only be applied on Linux.
+    #[context("Extracting paths from {}", desc.digest())]
+    pub fn extract_paths_with<P: AsRef<Path>>(
+        &self,
+        desc: &Descriptor,
+        prefixes: &[P],
+        dest: &Dir,
+        opts: &UnpackOptions,
+    ) -> Result<u64> {
+        let prefixes = prefixes
+            .iter()
+            .map(|p| normalize(p.as_ref()))
+            .collect::<Result<Vec<_>>>()?;
+        Extractor::new(dest, opts).extract(self, desc, &prefixes)
+    }
+
+    /// Extract all layers of an image into `dest`, bottom to top, applying the
+    /// whiteouts of each layer to the content below it. Returns the number of
+    /// extracted entries.
+    ///
+    /// Hard links may refer to entries of any earlier layer of the image.
+    #[context("Unpacking image")]
+    pub fn unpack_image(
+        &self,
+        manifest: &ImageManifest,
+        dest: &Dir,
+        opts: &UnpackOptions,
+    ) -> Result<u64> {
+        let mut extractor = Extractor::new(dest, opts);
+        let all = [PathBuf::new()];
+        let mut n = 0;
+        for layer in manifest.layers() {
+            extractor.apply_whiteouts(self, layer)?;
+            n += extractor.extract(self, layer, &all)?;
+        }
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cap_std_ext::cap_tempfile;
+
+    #[test]
+    fn extract() -> Result<()> {
+        let w = OciDir::new_in_memory()?;
+        let mut builder = w.create_layer(None)?;
+        for (path, contents) in [
+            ("etc/os-release", "ID=test"),
+            ("etc/.wh.removed", ""),
+            ("usr/share/app/data", "data"),
+            ("usr/share/other/data", "other"),
+            ("usr/bin/app", "binary"),
+        ] {
+            let mut h = tar::Header::new_gnu();
+            h.set_mode(0o600);
+            h.set_size(contents.len() as u64);
+            builder.append_data(&mut h, path, contents.as_bytes())?;
+        }
+        let mut h = tar::Header::new_gnu();
+        h.set_entry_type(tar::EntryType::Symlink);
+        h.set_mode(0o777);
+        h.set_size(0);
+        builder.append_link(&mut h, "etc/release", "os-release")?;
+        let layer = builder.into_inner()?.complete()?;
+        let desc = layer.descriptor().build()?;
+
+        let td = cap_tempfile::tempdir(cap_std::ambient_authority())?;
+        let n = w.extract_paths(&desc, &["/etc", "/usr/share/app"], &td)?;
+        // Symbolic links are skipped on Windows
+        assert_eq!(n, if cfg!(unix) { 3 } else { 2 });
+        assert_eq!(td.read_to_string("etc/os-release")?, "ID=test");
+        #[cfg(unix)]
+        assert_eq!(
+            td.read_link_contents("etc/release")?,
+            Path::new("os-release")
+        );
+        assert_eq!(td.read_to_string("usr/share/app/data")?, "data");
+        assert!(!td.try_exists("etc/.wh.removed")?);
+        assert!(!td.try_exists("usr/share/other")?);
+        assert!(!td.try_exists("usr/bin")?);
+        assert!(w.extract_paths(&desc, &["../etc"], &td).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_whiteouts() -> Result<()> {
+        let w = OciDir::new_in_memory()?;
+        let td = cap_tempfile::tempdir(cap_std::ambient_authority())?;
+        td.write("keep", "keep")?;
+        td.create_dir("x")?;
+        for path in ["x/.wh...", "x/.wh..", ".wh.."] {
+            let mut manifest = crate::new_empty_manifest().build()?;
+            let mut config = oci_spec::image::ImageConfigurationBuilder::default().build()?;
+            let mut builder = w.create_layer(None)?;
+            let mut h = tar::Header::new_gnu();
+            h.set_mode(0o600);
+            h.set_size(0);
+            builder.append_data(&mut h, path, std::io::empty())?;
+            let layer = builder.into_inner()?.complete()?;
+            w.push_layer(&mut manifest, &mut config, layer, "layer", None);
+            let r = w.unpack_image(&manifest, &td, &Default::default());
+            assert!(r.is_err(), "{path}");
+            assert_eq!(td.read_to_string("keep")?, "keep");
+            assert!(td.try_exists("x")?);
+        }
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn unpack_policies() -> Result<()> {
+        use cap_std::fs::{FileTypeExt, MetadataExt, PermissionsExt};
+
+        let w = OciDir::new_in_memory()?;
+        let mut manifest = crate::new_empty_manifest().build()?;
+        let mut config = oci_spec::image::ImageConfigurationBuilder::default().build()?;
+        let mut push =
+            |entries: &[(&str, tar::EntryType, u32, &str)]| -> Result<oci_spec::image::Descriptor> {
+                let mut builder = w.create_layer(None)?;
+                for &(path, entry_type, mode, data) in entries {
+                    let mut h = tar::Header::new_gnu();
+                    h.set_entry_type(entry_type);
+                    h.set_mode(mode);
+                    h.set_uid(0);
+                    h.set_gid(0);
+                    if entry_type.is_symlink() || entry_type.is_hard_link() {
+                        h.set_size(0);
+                        builder.append_link(&mut h, path, data)?;
+                    } else {
+                        h.set_size(data.len() as u64);
+                        builder.append_data(&mut h, path, data.as_bytes())?;
+                    }
+                }
+                let layer = builder.into_inner()?.complete()?;
+                let desc = layer.descriptor().build()?;
+                w.push_layer(&mut manifest, &mut config, layer, "layer", None);
+                Ok(desc)
+            };
+        use tar::EntryType::*;
+        push(&[
+            ("bin/su", Regular, 0o4755, "su"),
+            ("a", Regular, 0o644, "a"),
+            ("b", Link, 0o644, "a"),
+            ("abs", Symlink, 0o777, "/etc/passwd"),
+            ("fifo", Fifo, 0o600, ""),
+        ])?;
+        let upper = push(&[("c", Link, 0o644, "a"), ("d", Link, 0o644, "missing")])?;
+
+        let td = cap_tempfile::tempdir(cap_std::ambient_authority())?;
+        // By default, hard links must refer to entries from the same call
+        assert!(w.unpack_image(&manifest, &td, &Default::default()).is_err());
+        assert!(w.extract_paths(&upper, &["/"], &td).is_err());
+
+        let td = cap_tempfile::tempdir(cap_std::ambient_authority())?;
+        let opts = UnpackOptions {
+            external_hardlinks: LinkPolicy::Skip,
+            ..Default::default()
+        };
+        assert_eq!(w.unpack_image(&manifest, &td, &opts)?, 5);
+        assert_eq!(td.metadata("bin/su")?.permissions().mode() & 0o7777, 0o4755);
+        assert_eq!(td.metadata("c")?.ino(), td.metadata("a")?.ino());
+        assert!(!td.try_exists("d")?);
+        assert!(td.symlink_metadata_optional("fifo")?.is_none());
+        assert_eq!(td.read_link_contents("abs")?, Path::new("/etc/passwd"));
+
+        let me = td.metadata("a")?;
+        let (uid, gid) = (me.uid(), me.gid());
+        let td = cap_tempfile::tempdir(cap_std::ambient_authority())?;
+        let opts = UnpackOptions {
+            absolute_symlinks: LinkPolicy::Skip,
+            external_hardlinks: LinkPolicy::Skip,
+            devices: DevicePolicy::Include,
+            setuid: SetuidPolicy::Strip,
+            ownership: Ownership::Map {
+                uids: vec![IdMapping {
+                    container: 0,
+                    host: uid,
+                    size: 1,
+                }],
+                gids: vec![IdMapping {
+                    container: 0,
+                    host: gid,
+                    size: 1,
+                }],
+            },
+        };
+        w.unpack_image(&manifest, &td, &opts)?;
+        assert_eq!(td.metadata("bin/su")?.permissions().mode() & 0o7777, 0o755);
+        assert!(td.symlink_metadata_optional("abs")?.is_none());
+        assert!(td.symlink_metadata("fifo")?.file_type().is_fifo());
+        assert_eq!(td.metadata("bin/su")?.uid(), uid);
+
+        for opts in [
+            UnpackOptions {
+                absolute_symlinks: LinkPolicy::Error,
+                external_hardlinks: LinkPolicy::Skip,
+                ..Default::default()
+            },
+            UnpackOptions {
+                setuid: SetuidPolicy::Error,
+                external_hardlinks: LinkPolicy::Skip,
+                ..Default::default()
+            },
+            UnpackOptions {
+                external_hardlinks: LinkPolicy::Skip,
+                ownership: Ownership::Map {
+                    uids: vec![IdMapping {
+                        container: 1000,
+                        host: uid,
+                        size: 1,
+                    }],
+                    gids: Vec::new(),
+                },
+                ..Default::default()
+            },
+        ] {
+            let td = cap_tempfile::tempdir(cap_std::ambient_authority())?;
+            assert!(w.unpack_image(&manifest, &td, &opts).is_err());
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,185 @@
+//! Rewriting layers by streaming their entries through a filter.
+
+use std::path::PathBuf;
+
+use anyhow::{anyhow, Result};
+use fn_error_context::context;
+use oci_spec::image::Descriptor;
+
+use crate::{Layer, OciDir};
+
+/// PAX keys which are derived from the entry itself when writing it.
+const DERIVED_PAX_KEYS: &[&str] = &["path", "linkpath", "size"];
+
+/// The decision for an entry from the callback of [`OciDir::filter_layer`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum FilterAction {
+    /// Write the entry, including any changes made to it.
+    Keep,
+    /// Omit the entry from the new layer.
+    Skip,
+    /// Write the entry with new contents; the size in the header is updated.
+    Replace(Vec<u8>),
+}
+
+/// A tar entry passed to the callback of [`OciDir::filter_layer`], which may be
+/// modified to rewrite it.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct FilterEntry {
+    /// The path of the entry.
+    pub path: PathBuf,
+    /// The target of a symbolic or hard link.
+    pub link_name: Option<PathBuf>,
+    /// The header, such as for changing mode or ownership. The path, link name
+    /// and size are set from the other fields when writing.
+    pub header: tar::Header,
+    /// PAX extended headers other than `path`, `linkpath` and `size`, such as
+    /// `SCHILY.xattr.*` entries for extended attributes.
+    pub pax: Vec<(String, Vec<u8>)>,
+}
+
+impl OciDir {
+    /// Create a new gzip compressed layer from the entries of an existing layer, passing
+    /// each entry through `f` which may modify it, skip it or replace its contents.
+    ///
+    /// The source layer is streamed, and the contents of kept entries are
+    /// copied directly. The caller is responsible for updating the manifest and
+    /// config to reference the returned layer.
+    #[context("Filtering layer {}", src.digest())]
+    pub fn filter_layer<F>(&self, src: &Descriptor, mut f: F) -> Result<Layer>
+    where
+        F: FnMut(&mut FilterEntry) -> Result<FilterAction>,
+    {
+        let (_, r) = self.open_layer(src)?;
+        let mut archive = tar::Archive::new(r);
+        let mut builder = self.create_layer(None)?;
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            let mut pax = Vec::new();
+            if let Some(exts) = entry.pax_extensions()? {
+                for ext in exts {
+                    let ext = ext?;
+                    let key = ext.key()?;
+                    if !DERIVED_PAX_KEYS.contains(&key) {
+                        pax.push((key.to_owned(), ext.value_bytes().to_vec()));
+                    }
+                }
+            }
+            let mut fe = FilterEntry {
+                path: entry.path()?.into_owned(),
+                link_name: entry.link_name()?.map(|l| l.into_owned()),
+                header: entry.header().clone(),
+                pax,
+            };
+            let action = f(&mut fe)?;
+            let FilterEntry {
+                path,
+                link_name,
+                mut header,
+                pax,
+            } = fe;
+            if action == FilterAction::Skip {
+                continue;
+            }
+            if !pax.is_empty() {
+                builder
+                    .append_pax_extensions(pax.iter().map(|(k, v)| (k.as_str(), v.as_slice())))?;
+            }
+            let et = header.entry_type();
+            if et.is_symlink() || et.is_hard_link() {
+                let target = link_name
+                    .ok_or_else(|| anyhow!("Missing link target for {}", path.display()))?;
+                builder.append_link(&mut header, &path, target)?;
+                continue;
+            }
+            match action {
+                FilterAction::Replace(data) => {
+                    header.set_size(data.len() as u64);
+                    builder.append_data(&mut header, &path, data.as_slice())?;
+                }
+                _ => {
+                    header.set_size(entry.size());
+                    builder.append_data(&mut header, &path, &mut entry)?;
+                }
+            }
+        }
+        builder.into_inner()?.complete()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn filter() -> Result<()> {
+        let w = OciDir::new_in_memory()?;
+        let mut builder = w.create_layer(None)?;
+        for (path, contents) in [
+            ("etc/config", "x"),
+            ("usr/bin/app", "binary"),
+            ("tmp/junk", ""),
+        ] {
+            let mut h = tar::Header::new_ustar();
+            h.set_mode(0o644);
+            h.set_uid(1000);
+            h.set_size(contents.len() as u64);
+            if path == "usr/bin/app" {
+                builder.append_pax_extensions([
+                    ("SCHILY.xattr.security.capability", b"cap".as_slice()),
+                    ("SCHILY.xattr.user.keep", b"1".as_slice()),
+                ])?;
+            }
+            builder.append_data(&mut h, path, contents.as_bytes())?;
+        }
+        let src = builder.into_inner()?.complete()?;
+        let src = src.descriptor().build()?;
+
+        let layer = w.filter_layer(&src, |e| {
+            if e.path.starts_with("tmp") {
+                return Ok(FilterAction::Skip);
+            }
+            e.header.set_uid(0);
+            e.pax
+                .retain(|(k, _)| !k.starts_with("SCHILY.xattr.security."));
+            if e.path.ends_with("config") {
+                return Ok(FilterAction::Replace(b"rewritten".to_vec()));
+            }
+            Ok(FilterAction::Keep)
+        })?;
+        assert_ne!(layer.blob.digest_id(), src.digest().to_string());
+
+        let (_, r) = w.open_blob_decompressed(&layer.descriptor().build()?)?;
+        let mut archive = tar::Archive::new(r);
+        let mut seen = Vec::new();
+        for e in archive.entries()? {
+            let mut e = e?;
+            assert_eq!(e.header().uid()?, 0);
+            let mut xattrs = Vec::new();
+            if let Some(exts) = e.pax_extensions()? {
+                for ext in exts {
+                    xattrs.push(ext?.key()?.to_owned());
+                }
+            }
+            let path = e.path()?.to_string_lossy().into_owned();
+            let mut buf = String::new();
+            e.read_to_string(&mut buf)?;
+            seen.push((path, buf, xattrs));
+        }
+        assert_eq!(
+            seen,
+            [
+                ("etc/config".into(), "rewritten".into(), vec![]),
+                (
+                    "usr/bin/app".into(),
+                    "binary".into(),
+                    vec!["SCHILY.xattr.user.keep".to_owned()]
+                ),
+            ]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,608 @@
+//! Verification with optional repair of a layout.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use anyhow::Result;
+use fn_error_context::context;
+use oci_spec::image::{Descriptor, ImageIndex, ImageManifest, MediaType};
+use serde::{Deserialize, Serialize};
+
+use crate::progress::ProgressOp;
+use crate::{OciDir, WalkControl};
+
+/// The name of the file at the root of the layout recording verified blobs,
+/// see [`FsckCache`].
+pub const FSCK_STATE_FILE: &str = "ocidir-fsck-state.json";
+
+/// Whether [`OciDir::fsck_with`] uses [`FSCK_STATE_FILE`] to skip blobs.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FsckCache {
+    /// Verify all blobs, without reading or writing the state.
+    #[default]
+    Off,
+    /// Skip blobs whose size and modification time are unchanged since they were
+    /// last verified, and record the blobs verified now.
+    Incremental,
+    /// Verify all blobs, and replace the state with the blobs verified now.
+    Refresh,
+}
+
+/// The size and modification time of a blob when it was verified.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+struct BlobStamp {
+    size: u64,
+    /// Nanoseconds since the epoch.
+    mtime: u64,
+}
+
+/// The contents of [`FSCK_STATE_FILE`].
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct FsckState {
+    blobs: BTreeMap<String, BlobStamp>,
+}
+
+/// Options for [`OciDir::fsck_with`]. With all options disabled, problems are
+/// only reported.
+#[derive(Debug, Clone, Default)]
+pub struct FsckOptions {
+    /// Drop index entries whose manifest, or any blob it references, is missing.
+    pub repair: bool,
+    /// Delete blobs whose content does not match their digest.
+    pub remove_corrupt: bool,
+    /// Delete blobs which are not reachable from the index.
+    pub remove_orphans: bool,
+    /// Only report the actions which would be taken, without changing anything.
+    pub dry_run: bool,
+    /// Skip blobs which were verified before; see [`FsckCache`].
+    pub cache: FsckCache,
+}
+
+/// An action taken by [`OciDir::fsck_with`]. In JSON, this is an object with the
+/// kebab-case `action` and its `target`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case", tag = "action", content = "target")]
+#[non_exhaustive]
+pub enum FsckAction {
+    /// A blob with mismatched content was deleted.
+    RemovedCorruptBlob(String),
+    /// An incomplete entry was dropped from the index.
+    DroppedIndexEntry(Box<Descriptor>),
+    /// A blob not reachable from the index was deleted.
+    RemovedOrphanBlob(String),
+}
+
+/// The result of [`OciDir::fsck_with`].
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "kebab-case")]
+#[non_exhaustive]
+pub struct FsckReport {
+    /// True if the actions were not actually taken, see [`FsckOptions::dry_run`].
+    pub dry_run: bool,
+    /// The number of blobs whose digest was verified successfully.
+    pub verified: u32,
+    /// The number of blobs skipped as unchanged, see [`FsckCache::Incremental`].
+    pub unchanged: u32,
+    /// Blobs which failed verification, with the error.
+    pub corrupt: Vec<(String, String)>,
+    /// Blobs whose digest algorithm is not supported, such as `sha512`, which
+    /// could not be verified. They are still followed and kept when pruning.
+    pub unsupported: Vec<String>,
+    /// Index entries whose manifest or referenced blobs are missing.
+    pub incomplete: Vec<Descriptor>,
+    /// All actions taken, in order.
+    pub actions: Vec<FsckAction>,
+}
+
+/// Options for [`OciDir::prune`].
+#[derive(Debug, Clone, Default)]
+pub struct PruneOptions {
+    /// Only report the blobs which would be deleted, without deleting them.
+    pub dry_run: bool,
+}
+
+/// A blob deleted by [`OciDir::prune`] because it is not reachable from the index.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+#[non_exhaustive]
+pub struct PrunedBlob {
+    /// The digest of the blob.
+    pub digest: String,
+    /// The size of the blob.
+    pub size: u64,
+}
+
+/// The result of [`OciDir::prune`].
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "kebab-case")]
+#[non_exhaustive]
+pub struct PruneReport {
+    /// True if nothing was deleted, see [`PruneOptions::dry_run`].
+    pub dry_run: bool,
+    /// The unreachable blobs, sorted by digest.
+    pub removed: Vec<PrunedBlob>,
+    /// The total size of the unreachable blobs.
+    pub removed_bytes: u64,
+}
+
+impl FsckReport {
+    /// Returns true if no problems were found; problems which were repaired
+    /// are still included.
+    pub fn is_clean(&self) -> bool {
+        self.corrupt.is_empty() && self.incomplete.is_empty()
+    }
+}
+
+impl OciDir {
+    /// Returns true if the blob for this descriptor and everything it references are
+    /// present, treating blobs in `removed` as missing.
+    fn is_complete(&self, desc: &Descriptor, removed: &BTreeSet<&str>) -> Result<bool> {
+        let has = |d: &Descriptor| -> Result<bool> {
+            Ok(!removed.contains(d.digest().as_str()) && self.has_blob(d)?)
+        };
+        if !has(desc)? {
+            return Ok(false);
+        }
+        match desc.media_type() {
+            MediaType::ImageManifest => {
+                let Ok(manifest) = self.read_json_blob_lenient::<ImageManifest>(desc) else {
+                    return Ok(false);
+                };
+                for d in std::iter::once(manifest.config()).chain(manifest.layers()) {
+                    if !has(d)? {
+                        return Ok(false);
+                    }
+                }
+                Ok(true)
+            }
+            MediaType::ImageIndex => {
+                let Ok(index) = self.read_json_blob_lenient::<ImageIndex>(desc) else {
+                    return Ok(false);
+                };
+                for d in index.manifests() {
+                    if !self.is_complete(d, removed)? {
+                        return Ok(false);
+                    }
+                }
+                Ok(true)
+            }
+            _ => Ok(true),
+        }
+    }
+
+    /// Collect the digests of all blobs referenced by this descriptor, recursively.
+    pub(crate) fn collect_reachable(
+        &self,
+        desc: &Descriptor,
+        r: &mut BTreeSet<String>,
+    ) -> Result<()> {
+        self.walk_from(desc, |d, _| {
+            Ok(if r.insert(d.digest().to_string()) {
+                WalkControl::Continue
+            } else {
+                WalkControl::SkipChildren
+            })
+        })
+    }
+
+    /// Return the digests of all blobs which are not reachable from `index`.
+    fn find_orphans(&self, index: Option<&ImageIndex>) -> Result<Vec<String>> {
+        let mut reachable = BTreeSet::new();
+        for desc in index.iter().flat_map(|i| i.manifests()) {
+            self.collect_reachable(desc, &mut reachable)?;
+        }
+        let mut orphans = self.store.list()?;
+        orphans.retain(|d| !reachable.contains(d));
+        Ok(orphans)
+    }
+
+    /// Delete all blobs which are not reachable from `index`, returning their digests.
+    pub(crate) fn remove_orphans(&self, index: Option<&ImageIndex>) -> Result<Vec<String>> {
+        let orphans = self.find_orphans(index)?;
+        for digest in &orphans {
+            self.remove_blob(digest)?;
+        }
+        if !orphans.is_empty() {
+            self.prune_diffid_cache()?;
+        }
+        Ok(orphans)
+    }
+
+    /// Delete all blobs which are not reachable from the index; see [`PruneOptions`].
+    #[context("Pruning blobs")]
+    pub fn prune(&self, opts: &PruneOptions) -> Result<PruneReport> {
+        let _lock = self.lock_index()?;
+        let index = self.read_index()?;
+        self.prune_unreachable(index.as_ref(), opts)
+    }
+
+    /// Delete all blobs which are not reachable from `index`, which need not be
+    /// the current index; the caller must hold the index lock.
+    pub(crate) fn prune_unreachable(
+        &self,
+        index: Option<&ImageIndex>,
+        opts: &PruneOptions,
+    ) -> Result<PruneReport> {
+        let mut r = PruneReport {
+            dry_run: opts.dry_run,
+            ..Default::default()
+        };
+        let mut orphans = self.find_orphans(index)?;
+        orphans.sort();
+        for digest in orphans {
+            let (_, size) = self.open_blob_sized(&digest)?;
+            if !opts.dry_run {
+                self.remove_blob(&digest)?;
+            }
+            r.removed_bytes += size;
+            r.removed.push(PrunedBlob { digest, size });
+        }
+        if !opts.dry_run && !r.removed.is_empty() {
+            self.prune_diffid_cache()?;
+        }
+        Ok(r)
+    }
+
+    pub(crate) fn remove_blob(&self, digest: &str) -> Result<()> {
+        let progress = self.progress.begin(ProgressOp::Remove, Some(digest), None);
+        self.store.delete(digest)?;
+        progress.end(digest);
+        trace_event!(digest, "Removed blob");
+        Ok(())
+    }
+
+    /// The size and modification time of a blob, if it is stored in a directory.
+    fn blob_stamp(&self, digest: &str) -> Result<Option<BlobStamp>> {
+        let Some(dir) = self.store.as_dir() else {
+            return Ok(None);
+        };
+        let Some(path) = crate::store::find_blob_path(dir, digest)? else {
+            return Ok(None);
+        };
+        let meta = dir.metadata(path)?;
+        let Ok(mtime) = meta
+            .modified()?
+            .into_std()
+            .duration_since(std::time::UNIX_EPOCH)
+        else {
+            return Ok(None);
+        };
+        Ok(Some(BlobStamp {
+            size: meta.len(),
+            mtime: mtime.as_nanos().try_into()?,
+        }))
+    }
+
+    /// Read [`FSCK_STATE_FILE`]; a missing or unreadable state is empty.
+    fn read_fsck_state(&self) -> Result<FsckState> {
+        let Some(buf) = self.store.read_meta(FSCK_STATE_FILE)? else {
+            return Ok(FsckState::default());
+        };
+        Ok(serde_json::from_slice(&buf).unwrap_or_default())
+    }
+
+    /// Verify all blobs and index entries like [`Self::fsck_with`], but only re-hash
+    /// blobs whose size or modification time changed since they were last verified;
+    /// see [`FsckCache::Incremental`]. If `force_full` is set, all blobs are verified
+    /// and the state is rebuilt.
+    ///
+    /// Blobs are only skipped for layouts stored in a directory. Corruption which
+    /// preserves the size and modification time of a blob is not detected.
+    pub fn fsck_incremental(&self, force_full: bool) -> Result<FsckReport> {
+        let cache = if force_full {
+            FsckCache::Refresh
+        } else {
+            FsckCache::Incremental
+        };
+        self.fsck_with(&FsckOptions {
+            cache,
+            ..Default::default()
+        })
+    }
+
+    /// Verify all blobs and index entries, optionally repairing problems; see [`FsckOptions`].
+    ///
+    /// Unlike [`Self::fsck`], problems are collected into the returned report rather
+    /// than causing an error.
+    #[context("Checking OCI dir")]
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, fields(repair = opts.repair))
+    )]
+    pub fn fsck_with(&self, opts: &FsckOptions) -> Result<FsckReport> {
+        let mut r = FsckReport {
+            dry_run: opts.dry_run,
+            ..Default::default()
+        };
+        let state = match opts.cache {
+            FsckCache::Off => None,
+            FsckCache::Incremental => Some(self.read_fsck_state()?),
+            FsckCache::Refresh => Some(FsckState::default()),
+        };
+        let mut new_state = FsckState::default();
+        for digest in self.store.list()? {
+            let stamp = match state {
+                Some(_) => self.blob_stamp(&digest)?,
+                None => None,
+            };
+            let known = state.as_ref().and_then(|s| s.blobs.get(&digest));
+            if let Some(stamp) = stamp.filter(|s| Some(s) == known) {
+                r.unchanged += 1;
+                new_state.blobs.insert(digest, stamp);
+                continue;
+            }
+            match self.verify_blob(&digest) {
+                Ok(true) => {
+                    r.verified += 1;
+                    if let Some(stamp) = stamp {
+                        new_state.blobs.insert(digest, stamp);
+                    }
+                }
+                Ok(false) => r.unsupported.push(digest),
+                Err(e) => r.corrupt.push((digest, format!("{e:#}"))),
+            }
+        }
+        if state.is_some() && !opts.dry_run && !self.store.is_read_only() {
+            let buf = serde_json::to_vec(&new_state)?;
+            self.store.write_meta(FSCK_STATE_FILE, &buf)?;
+        }
+        if opts.remove_corrupt {
+            for (digest, _) in &r.corrupt {
+                if !opts.dry_run {
+                    self.remove_blob(digest)?;
+                }
+                r.actions
+                    .push(FsckAction::RemovedCorruptBlob(digest.clone()));
+            }
+        }
+
+        // In a dry run, corrupt blobs which would have been removed are still present.
+        let removed: BTreeSet<&str> = if opts.dry_run && opts.remove_corrupt {
+            r.corrupt.iter().map(|(d, _)| d.as_str()).collect()
+        } else {
+            BTreeSet::new()
+        };
+        let mut index = self.read_index()?;
+        if let Some(index) = index.as_mut() {
+            let mut keep = Vec::new();
+            for desc in index.manifests() {
+                if self.is_complete(desc, &removed)? {
+                    keep.push(desc.clone());
+                } else {
+                    r.incomplete.push(desc.clone());
+                }
+            }
+            if opts.repair && !r.incomplete.is_empty() {
+                r.actions.extend(
+                    r.incomplete
+                        .iter()
+                        .map(|d| FsckAction::DroppedIndexEntry(Box::new(d.clone()))),
+                );
+                if opts.dry_run {
+                    index.set_manifests(keep);
+                } else {
+                    // Entries may have been added since the index was read.
+                    let _lock = self.lock_index()?;
+                    let mut current = self.read_index()?.unwrap_or_else(|| index.clone());
+                    let mut manifests = current.manifests().clone();
+                    manifests.retain(|d| !r.incomplete.contains(d));
+                    current.set_manifests(manifests);
+                    self.write_index(&current, "fsck-repair", None, None)?;
+                    *index = current;
+                }
+            }
+        }
+
+        if opts.remove_orphans {
+            let orphans = if opts.dry_run {
+                let mut orphans = self.find_orphans(index.as_ref())?;
+                orphans.retain(|d| !removed.contains(d.as_str()));
+                orphans
+            } else {
+                self.remove_orphans(index.as_ref())?
+            };
+            r.actions
+                .extend(orphans.into_iter().map(FsckAction::RemovedOrphanBlob));
+        }
+        trace_event!(
+            verified = r.verified,
+            unchanged = r.unchanged,
+            corrupt = r.corrupt.len(),
+            incomplete = r.incomplete.len(),
+            actions = r.actions.len(),
+            "Checked layout"
+        );
+        Ok(r)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{insert_test_image, write_test_layer};
+    use std::io::Write;
+
+    #[test]
+    fn repair() -> Result<()> {
+        let w = OciDir::new_in_memory()?;
+        let layer_digest = insert_test_image(&w)?.1.blob.digest_id();
+        let good = crate::new_empty_manifest().build().unwrap();
+        let good_config = oci_spec::image::ImageConfigurationBuilder::default()
+            .build()
+            .unwrap();
+        w.insert_manifest_and_config(good, good_config, Some("good"), Default::default())?;
+        let mut orphan = w.create_uncompressed_layer()?;
+        orphan.write_all(b"orphan")?;
+        let orphan = orphan.complete()?.blob.digest_id();
+
+        // Corrupt the layer
+        w.store.delete(&layer_digest)?;
+        let mut staged = w.store.put()?;
+        staged.write_all(b"corrupted")?;
+        staged.commit(&layer_digest)?;
+
+        let report = w.fsck_with(&Default::default())?;
+        assert!(!report.is_clean());
+        assert_eq!(report.verified, 5);
+        assert_eq!(report.corrupt.len(), 1);
+        assert!(report.incomplete.is_empty());
+        assert!(report.actions.is_empty());
+
+        let mut opts = FsckOptions {
+            repair: true,
+            remove_corrupt: true,
+            remove_orphans: true,
+            dry_run: true,
+            ..Default::default()
+        };
+        let dry_run = w.fsck_with(&opts)?;
+        assert!(dry_run.dry_run);
+        assert_eq!(w.fsck_with(&Default::default())?.corrupt.len(), 1);
+        assert!(w.find_manifest_with_tag("latest")?.is_some());
+        let json = serde_json::to_value(&dry_run)?;
+        assert_eq!(json["actions"][0]["action"], "removed-corrupt-blob");
+        assert_eq!(json["actions"][0]["target"], layer_digest.as_str());
+        assert_eq!(json["actions"][1]["action"], "dropped-index-entry");
+
+        opts.dry_run = false;
+        let report = w.fsck_with(&opts)?;
+        assert_eq!(report.actions, dry_run.actions);
+        assert_eq!(report.incomplete.len(), 1);
+        // The layer, then the broken manifest entry, then its orphaned manifest and
+        // config blobs along with the unreferenced layer.
+        assert_eq!(
+            report.actions[0],
+            FsckAction::RemovedCorruptBlob(layer_digest)
+        );
+        assert!(matches!(
+            report.actions[1],
+            FsckAction::DroppedIndexEntry(_)
+        ));
+        assert_eq!(report.actions.len(), 5);
+        assert!(report
+            .actions
+            .contains(&FsckAction::RemovedOrphanBlob(orphan)));
+        assert!(w.find_manifest_with_tag("good")?.is_some());
+        assert!(w.fsck_with(&opts)?.is_clean());
+        assert_eq!(w.fsck()?, 2);
+        Ok(())
+    }
+
+    #[test]
+    fn incremental() -> Result<()> {
+        use cap_std_ext::{cap_std, cap_tempfile};
+        let td = cap_tempfile::tempdir(cap_std::ambient_authority())?;
+        let w = OciDir::ensure(&td)?;
+        let layer_digest = insert_test_image(&w)?.1.blob.digest_id();
+
+        let r = w.fsck_incremental(false)?;
+        assert_eq!((r.verified, r.unchanged), (3, 0));
+        assert!(td.try_exists(FSCK_STATE_FILE)?);
+        let r = w.fsck_incremental(false)?;
+        assert_eq!((r.verified, r.unchanged), (0, 3));
+        assert!(r.is_clean());
+
+        // Modified blobs are verified again
+        let path = crate::store::blob_path(&layer_digest)?;
+        td.remove_file(&path)?;
+        td.write(&path, b"corrupted")?;
+        let r = w.fsck_incremental(false)?;
+        assert_eq!((r.verified, r.unchanged), (0, 2));
+        assert_eq!(r.corrupt.len(), 1);
+        // Corrupt blobs are not recorded as verified
+        assert_eq!(w.fsck_incremental(false)?.corrupt.len(), 1);
+        let r = w.fsck_incremental(true)?;
+        assert_eq!((r.verified, r.unchanged), (2, 0));
+        assert_eq!(r.corrupt.len(), 1);
+
+        // Without the cache, the state is ignored
+        let r = w.fsck_with(&Default::default())?;
+        assert_eq!((r.verified, r.unchanged), (2, 0));
+        // Unreadable state is the same as none
+        td.write(FSCK_STATE_FILE, "garbage")?;
+        assert_eq!(w.fsck_incremental(false)?.verified, 2);
+        Ok(())
+    }
+
+    #[test]
+    fn prune() -> Result<()> {
+        let w = OciDir::new_in_memory()?;
+        let config = oci_spec::image::ImageConfigurationBuilder::default().build()?;
+        let manifest = crate::new_empty_manifest().build()?;
+        w.insert_manifest_and_config(manifest, config, Some("latest"), Default::default())?;
+        let mut orphan = w.create_uncompressed_layer()?;
+        orphan.write_all(b"orphan")?;
+        let orphan = orphan.complete()?.blob.digest_id();
+
+        let r = w.prune(&PruneOptions { dry_run: true })?;
+        let expected = PrunedBlob {
+            digest: orphan.clone(),
+            size: 6,
+        };
+        assert_eq!(r.removed, std::slice::from_ref(&expected));
+        assert_eq!(r.removed_bytes, 6);
+        assert!(w.store.has(&orphan)?);
+        let json = serde_json::to_value(&r)?;
+        assert_eq!(json["dry-run"], true);
+        assert_eq!(json["removed"][0]["digest"], orphan.as_str());
+        assert_eq!(json["removed-bytes"], 6);
+
+        let r = w.prune(&Default::default())?;
+        assert_eq!(r.removed, [expected]);
+        assert!(!w.store.has(&orphan)?);
+        assert!(w.prune(&Default::default())?.removed.is_empty());
+        assert_eq!(w.fsck()?, 2);
+        Ok(())
+    }
+
+    #[test]
+    fn unsupported_digests() -> Result<()> {
+        use cap_std_ext::{cap_std, cap_tempfile};
+        use oci_spec::image::DescriptorBuilder;
+
+        let td = cap_tempfile::tempdir(cap_std::ambient_authority())?;
+        let w = OciDir::ensure(&td)?;
+        let layer = write_test_layer(&w, crate::CompressionFormat::Gzip)?;
+        let mut manifest = crate::new_empty_manifest().build()?;
+        let mut config = oci_spec::image::ImageConfigurationBuilder::default().build()?;
+        w.push_layer(&mut manifest, &mut config, layer, "layer", None);
+        manifest.set_config(w.write_config(config)?);
+
+        // As written by another tool, whose digests are not checked
+        td.create_dir_all("blobs/sha512")?;
+        let buf = serde_json::to_vec(&manifest)?;
+        let digest = format!("sha512:{}", "a".repeat(128));
+        td.write(format!("blobs/sha512/{}", "a".repeat(128)), &buf)?;
+        let orphan = format!("sha512:{}", "b".repeat(128));
+        td.write(format!("blobs/sha512/{}", "b".repeat(128)), b"orphan")?;
+        let desc = DescriptorBuilder::default()
+            .media_type(MediaType::ImageManifest)
+            .digest(digest.clone())
+            .size(buf.len() as i64)
+            .build()?;
+        w.insert_descriptor(desc.clone(), Some("foreign"))?;
+
+        let e = w.read_json_blob::<ImageManifest>(&desc).unwrap_err();
+        assert_eq!(
+            e.downcast_ref::<crate::store::UnsupportedDigest>()
+                .unwrap()
+                .digest,
+            digest
+        );
+        let opts = FsckOptions {
+            repair: true,
+            ..Default::default()
+        };
+        let r = w.fsck_with(&opts)?;
+        assert!(r.is_clean(), "{r:?}");
+        assert_eq!(r.verified, 2);
+        assert_eq!(r.unsupported, [digest.clone(), orphan.clone()]);
+        assert_eq!(w.describe()?.unsupported_blobs, 2);
+
+        // The layers of the foreign manifest are still reachable
+        let pruned = w.prune(&Default::default())?;
+        let removed: Vec<_> = pruned.removed.iter().map(|b| b.digest.as_str()).collect();
+        assert_eq!(removed, [orphan.as_str()]);
+        assert!(w.has_blob(manifest.config())? && w.has_blob(&manifest.layers()[0])?);
+        Ok(())
+    }
+}
@@ -0,0 +1,149 @@
+//! Unix file metadata for tar headers, with approximations on other platforms,
+//! and applying it when extracting.
+
+use std::path::Path;
+
+use anyhow::Result;
+use cap_std::fs::{Dir, FileType, Metadata};
+use cap_std_ext::cap_std;
+
+/// The permission bits of a file.
+#[cfg(unix)]
+pub(crate) fn mode(meta: &Metadata) -> u32 {
+    use cap_std::fs::MetadataExt;
+    meta.mode() & 0o7777
+}
+
+/// The permission bits of a file, derived from its type and read-only flag.
+#[cfg(not(unix))]
+pub(crate) fn mode(meta: &Metadata) -> u32 {
+    if meta.is_dir() {
+        0o755
+    } else if meta.file_type().is_symlink() {
+        0o777
+    } else if meta.permissions().readonly() {
+        0o444
+    } else {
+        0o644
+    }
+}
+
+/// The owning user and group of a file.
+#[cfg(unix)]
+pub(crate) fn owner(meta: &Metadata) -> (u32, u32) {
+    use cap_std::fs::MetadataExt;
+    (meta.uid(), meta.gid())
+}
+
+/// The owning user and group of a file, which are always root.
+#[cfg(not(unix))]
+pub(crate) fn owner(_meta: &Metadata) -> (u32, u32) {
+    (0, 0)
+}
+
+/// The modification time of a file in seconds since the epoch, or 0 if it is unknown
+/// or before the epoch.
+pub(crate) fn mtime(meta: &Metadata) -> u64 {
+    meta.modified()
+        .ok()
+        .and_then(|t| t.into_std().duration_since(std::time::UNIX_EPOCH).ok())
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// The device and inode of a file with more than one link.
+#[cfg(unix)]
+pub(crate) fn hard_link_id(meta: &Metadata) -> Option<(u64, u64)> {
+    use cap_std::fs::MetadataExt;
+    (meta.nlink() > 1).then(|| (meta.dev(), meta.ino()))
+}
+
+/// Hard links are not detected on this platform.
+#[cfg(not(unix))]
+pub(crate) fn hard_link_id(_meta: &Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+/// The tar entry type of a device node or FIFO, along with the device number.
+#[cfg(unix)]
+pub(crate) fn special(ft: &FileType, meta: &Metadata) -> Option<(tar::EntryType, u64)> {
+    use cap_std::fs::{FileTypeExt, MetadataExt};
+    let kind = if ft.is_char_device() {
+        tar::EntryType::Char
+    } else if ft.is_block_device() {
+        tar::EntryType::Block
+    } else if ft.is_fifo() {
+        tar::EntryType::Fifo
+    } else {
+        return None;
+    };
+    Some((kind, meta.rdev()))
+}
+
+/// There are no device nodes or FIFOs on this platform.
+#[cfg(not(unix))]
+pub(crate) fn special(_ft: &FileType, _meta: &Metadata) -> Option<(tar::EntryType, u64)> {
+    None
+}
+
+/// Set the mode, ownership and modification time of a header from file metadata.
+pub(crate) fn set_header(h: &mut tar::Header, meta: &Metadata) {
+    let (uid, gid) = owner(meta);
+    h.set_mode(mode(meta));
+    h.set_uid(uid.into());
+    h.set_gid(gid.into());
+    h.set_mtime(mtime(meta));
+}
+
+/// Set the owner of `path` in `dir`, without following symbolic links.
+#[cfg(target_os = "linux")]
+pub(crate) fn chown(dir: &Dir, path: &Path, uid: u32, gid: u32) -> Result<()> {
+    use rustix::fs::{AtFlags, Gid, Uid};
+    rustix::fs::chownat(
+        dir,
+        path,
+        Some(Uid::from_raw(uid)),
+        Some(Gid::from_raw(gid)),
+        AtFlags::SYMLINK_NOFOLLOW,
+    )?;
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn chown(_dir: &Dir, _path: &Path, _uid: u32, _gid: u32) -> Result<()> {
+    anyhow::bail!("Setting ownership is not supported on this platform")
+}
+
+/// Create a device node or FIFO at `path` in `dir`.
+#[cfg(target_os = "linux")]
+pub(crate) fn mknod(
+    dir: &Dir,
+    path: &Path,
+    kind: tar::EntryType,
+    mode: u32,
+    major: u32,
+    minor: u32,
+) -> Result<()> {
+    use rustix::fs::{FileType, Mode};
+    let file_type = match kind {
+        tar::EntryType::Char => FileType::CharacterDevice,
+        tar::EntryType::Block => FileType::BlockDevice,
+        tar::EntryType::Fifo => FileType::Fifo,
+        _ => anyhow::bail!("Not a device node: {kind:?}"),
+    };
+    let dev = rustix::fs::makedev(major, minor);
+    rustix::fs::mknodat(dir, path, file_type, Mode::from_bits_truncate(mode), dev)?;
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn mknod(
+    _dir: &Dir,
+    _path: &Path,
+    _kind: tar::EntryType,
+    _mode: u32,
+    _major: u32,
+    _minor: u32,
+) -> Result<()> {
+    anyhow::bail!("Creating device nodes is not supported on this platform")
+}
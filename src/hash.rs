@@ -0,0 +1,99 @@
+//! SHA-256 hashing, backed by either OpenSSL or the pure-Rust `sha2` crate.
+//!
+//! OpenSSL is used when the `openssl` feature is enabled; otherwise the
+//! default `rust-crypto` feature provides the implementation.
+
+#[cfg(not(any(feature = "openssl", feature = "rust-crypto")))]
+compile_error!("One of the `openssl` or `rust-crypto` features must be enabled");
+#[cfg(all(target_family = "wasm", feature = "openssl"))]
+compile_error!("The `openssl` feature is not supported on wasm; use `rust-crypto`");
+
+use std::io;
+
+#[cfg(feature = "openssl")]
+type Inner = openssl::hash::Hasher;
+#[cfg(all(feature = "rust-crypto", not(feature = "openssl")))]
+type Inner = sha2::Sha256;
+
+/// An incremental SHA-256 hasher.
+#[derive(Clone)]
+pub struct Sha256(Inner);
+
+impl std::fmt::Debug for Sha256 {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Sha256").finish_non_exhaustive()
+    }
+}
+
+impl Sha256 {
+    /// Create a new hasher.
+    pub fn new() -> io::Result<Self> {
+        #[cfg(feature = "openssl")]
+        let inner = openssl::hash::Hasher::new(openssl::hash::MessageDigest::sha256())?;
+        #[cfg(all(feature = "rust-crypto", not(feature = "openssl")))]
+        let inner = <sha2::Sha256 as sha2::Digest>::new();
+        Ok(Self(inner))
+    }
+
+    /// Feed data into the hasher.
+    pub fn update(&mut self, buf: &[u8]) -> io::Result<()> {
+        #[cfg(feature = "openssl")]
+        self.0.update(buf)?;
+        #[cfg(all(feature = "rust-crypto", not(feature = "openssl")))]
+        sha2::Digest::update(&mut self.0, buf);
+        Ok(())
+    }
+
+    /// Return the digest of all data written so far, and reset the hasher.
+    pub fn finish(&mut self) -> io::Result<[u8; 32]> {
+        #[cfg(feature = "openssl")]
+        let r = self.0.finish()?.as_ref().try_into().unwrap();
+        #[cfg(all(feature = "rust-crypto", not(feature = "openssl")))]
+        let r = sha2::Digest::finalize_reset(&mut self.0).into();
+        Ok(r)
+    }
+
+    /// Return the hex-encoded digest of all data written so far, and reset the hasher.
+    pub fn finish_hex(&mut self) -> io::Result<String> {
+        self.finish().map(hex::encode)
+    }
+}
+
+impl io::Write for Sha256 {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.update(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Compute the hex-encoded SHA-256 of the provided data.
+pub fn sha256_hex(buf: &[u8]) -> io::Result<String> {
+    let mut h = Sha256::new()?;
+    h.update(buf)?;
+    h.finish_hex()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn known_digests() -> io::Result<()> {
+        assert_eq!(
+            sha256_hex(b"")?,
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        let mut h = Sha256::new()?;
+        h.update(b"{")?;
+        h.update(b"}")?;
+        assert_eq!(
+            h.finish_hex()?,
+            "44136fa355b3678a1146ad16f7e8649e94fb4fc21fe77e8310c060f61caaff8a"
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,185 @@
+//! Helpers for editing the history of image configurations.
+
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, SecondsFormat, TimeZone, Utc};
+use oci_spec::image::{History, HistoryBuilder, ImageConfiguration};
+
+/// The environment variable used for reproducible timestamps, see
+/// <https://reproducible-builds.org/specs/source-date-epoch/>.
+pub const SOURCE_DATE_EPOCH: &str = "SOURCE_DATE_EPOCH";
+
+/// Parse the [`SOURCE_DATE_EPOCH`] environment variable, if set.
+pub fn source_date_epoch() -> Result<Option<DateTime<Utc>>> {
+    let Some(v) = std::env::var_os(SOURCE_DATE_EPOCH) else {
+        return Ok(None);
+    };
+    let v = v
+        .to_str()
+        .ok_or_else(|| anyhow!("Invalid {SOURCE_DATE_EPOCH}"))?;
+    let secs: i64 = v
+        .parse()
+        .with_context(|| format!("Parsing {SOURCE_DATE_EPOCH}={v}"))?;
+    Utc.timestamp_opt(secs, 0)
+        .single()
+        .map(Some)
+        .ok_or_else(|| anyhow!("{SOURCE_DATE_EPOCH}={v} is out of range"))
+}
+
+fn format_timestamp(t: DateTime<Utc>) -> String {
+    t.to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+/// Extension methods for editing the `history` of an [`ImageConfiguration`].
+///
+/// Entries are addressed by their index in the history, including entries
+/// with `empty_layer` set.
+pub trait HistoryExt {
+    /// Insert a history entry which does not correspond to a layer at `index`.
+    /// If `created` is `None`, [`source_date_epoch`] or otherwise the current time is used.
+    fn insert_empty_history(
+        &mut self,
+        index: usize,
+        created_by: impl Into<String>,
+        created: Option<DateTime<Utc>>,
+    ) -> Result<&mut Self>;
+    /// Replace the `created_by` field of the entry at `index`.
+    fn set_history_created_by(
+        &mut self,
+        index: usize,
+        created_by: impl Into<String>,
+    ) -> Result<&mut Self>;
+    /// Replace the `comment` field of the entry at `index`.
+    fn set_history_comment(
+        &mut self,
+        index: usize,
+        comment: impl Into<String>,
+    ) -> Result<&mut Self>;
+    /// Set the `created` timestamp of the configuration and of every history entry.
+    fn set_all_created(&mut self, created: DateTime<Utc>) -> &mut Self;
+    /// Check that the history entries which are not `empty_layer` line up with the
+    /// layers in `rootfs.diff_ids`. An empty history is valid.
+    fn validate_history(&self) -> Result<()>;
+}
+
+fn history_entry(config: &mut ImageConfiguration, index: usize) -> Result<&mut History> {
+    let n = config.history().len();
+    config
+        .history_mut()
+        .get_mut(index)
+        .ok_or_else(|| anyhow!("History index {index} out of range for {n} entries"))
+}
+
+impl HistoryExt for ImageConfiguration {
+    fn insert_empty_history(
+        &mut self,
+        index: usize,
+        created_by: impl Into<String>,
+        created: Option<DateTime<Utc>>,
+    ) -> Result<&mut Self> {
+        let n = self.history().len();
+        if index > n {
+            anyhow::bail!("History index {index} out of range for {n} entries");
+        }
+        let created = match created {
+            Some(t) => t,
+            None => source_date_epoch()?.unwrap_or_else(Utc::now),
+        };
+        let h = HistoryBuilder::default()
+            .created(format_timestamp(created))
+            .created_by(created_by.into())
+            .empty_layer(true)
+            .build()?;
+        self.history_mut().insert(index, h);
+        Ok(self)
+    }
+
+    fn set_history_created_by(
+        &mut self,
+        index: usize,
+        created_by: impl Into<String>,
+    ) -> Result<&mut Self> {
+        history_entry(self, index)?.set_created_by(Some(created_by.into()));
+        Ok(self)
+    }
+
+    fn set_history_comment(
+        &mut self,
+        index: usize,
+        comment: impl Into<String>,
+    ) -> Result<&mut Self> {
+        history_entry(self, index)?.set_comment(Some(comment.into()));
+        Ok(self)
+    }
+
+    fn set_all_created(&mut self, created: DateTime<Utc>) -> &mut Self {
+        let created = format_timestamp(created);
+        self.set_created(Some(created.clone()));
+        for h in self.history_mut() {
+            h.set_created(Some(created.clone()));
+        }
+        self
+    }
+
+    fn validate_history(&self) -> Result<()> {
+        if self.history().is_empty() {
+            return Ok(());
+        }
+        let layers = self
+            .history()
+            .iter()
+            .filter(|h| !h.empty_layer().unwrap_or_default())
+            .count();
+        let diff_ids = self.rootfs().diff_ids().len();
+        if layers != diff_ids {
+            anyhow::bail!("History has {layers} layer entries, but there are {diff_ids} layers");
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use oci_spec::image::{ImageConfigurationBuilder, RootFsBuilder};
+
+    #[test]
+    fn history_ext() -> Result<()> {
+        let rootfs = RootFsBuilder::default()
+            .typ("layers")
+            .diff_ids(vec!["sha256:aa".to_owned()])
+            .build()?;
+        let layer = HistoryBuilder::default().created_by("ADD rootfs").build()?;
+        let mut config = ImageConfigurationBuilder::default()
+            .rootfs(rootfs)
+            .history(vec![layer])
+            .build()?;
+        config.validate_history()?;
+        let t = Utc.timestamp_opt(1700000000, 0).unwrap();
+        config
+            .insert_empty_history(0, "ENV A=b", Some(t))?
+            .insert_empty_history(2, "CMD app", None)?
+            .set_history_comment(1, "base")?
+            .set_history_created_by(2, "CMD [\"app\"]")?;
+        assert!(config.insert_empty_history(4, "x", None).is_err());
+        assert!(config.set_history_comment(3, "x").is_err());
+        config.validate_history()?;
+        let h = config.history();
+        assert_eq!(h.len(), 3);
+        assert_eq!(h[0].created().as_deref(), Some("2023-11-14T22:13:20Z"));
+        assert_eq!(h[1].comment().as_deref(), Some("base"));
+        assert_eq!(h[2].created_by().as_deref(), Some("CMD [\"app\"]"));
+
+        config.set_all_created(Utc.timestamp_opt(0, 0).unwrap());
+        assert_eq!(config.created().as_deref(), Some("1970-01-01T00:00:00Z"));
+        assert!(config
+            .history()
+            .iter()
+            .all(|h| h.created().as_deref() == Some("1970-01-01T00:00:00Z")));
+
+        let mut rootfs = config.rootfs().clone();
+        rootfs.diff_ids_mut().push("sha256:bb".into());
+        config.set_rootfs(rootfs);
+        assert!(config.validate_history().is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,171 @@
+//! Building images on top of a base image.
+
+use std::collections::HashMap;
+
+use anyhow::Result;
+use fn_error_context::context;
+use oci_spec::image::{Descriptor, ImageConfiguration, ImageManifest, Platform};
+
+use crate::{new_empty_manifest, Layer, OciDir};
+
+/// An image under construction whose manifest and config are kept in sync,
+/// see [`OciDir::new_image`] and [`OciDir::derive_from`].
+#[derive(Debug)]
+pub struct ImageBuilder<'a> {
+    dir: &'a OciDir,
+    manifest: ImageManifest,
+    config: ImageConfiguration,
+}
+
+impl<'a> ImageBuilder<'a> {
+    /// Add a layer to the top of the image, with a history entry.
+    pub fn push_layer(
+        &mut self,
+        layer: Layer,
+        description: &str,
+        annotations: Option<HashMap<String, String>>,
+    ) -> &mut Self {
+        self.dir.push_layer(
+            &mut self.manifest,
+            &mut self.config,
+            layer,
+            description,
+            annotations,
+        );
+        self
+    }
+
+    /// Add a history entry for a change which does not create a layer.
+    pub fn push_empty_history(&mut self, description: &str) -> &mut Self {
+        self.dir
+            .push_empty_history(&mut self.config, description, None);
+        self
+    }
+
+    /// The manifest, which has a placeholder config until the image is inserted.
+    pub fn manifest(&self) -> &ImageManifest {
+        &self.manifest
+    }
+
+    /// The config.
+    pub fn config(&self) -> &ImageConfiguration {
+        &self.config
+    }
+
+    /// Mutable access to the config, for example to change labels or the entrypoint.
+    /// The rootfs and history are maintained by the builder and should not be changed.
+    pub fn config_mut(&mut self) -> &mut ImageConfiguration {
+        &mut self.config
+    }
+
+    /// Return the manifest and config.
+    pub fn into_parts(self) -> (ImageManifest, ImageConfiguration) {
+        (self.manifest, self.config)
+    }
+
+    /// Write the config and manifest, and add the manifest to the index;
+    /// see [`OciDir::insert_manifest_and_config`].
+    pub fn insert(self, tag: Option<&str>, platform: Platform) -> Result<Descriptor> {
+        self.dir
+            .insert_manifest_and_config(self.manifest, self.config, tag, platform)
+    }
+}
+
+impl OciDir {
+    /// Start building an image from scratch.
+    pub fn new_image(&self) -> Result<ImageBuilder<'_>> {
+        Ok(ImageBuilder {
+            dir: self,
+            manifest: new_empty_manifest().build()?,
+            config: ImageConfiguration::default(),
+        })
+    }
+
+    /// Start building an image on top of a base image, like `FROM` in a
+    /// Containerfile: the layers, diff_ids and history of the base are kept, as
+    /// is the rest of its config apart from the creation time.
+    ///
+    /// The base layers must be present in this layout, and each must have a
+    /// diff_id in the config.
+    #[context("Deriving from base image")]
+    pub fn derive_from(
+        &self,
+        base_manifest: &ImageManifest,
+        base_config: &ImageConfiguration,
+    ) -> Result<ImageBuilder<'_>> {
+        let layers = base_manifest.layers();
+        let n_diffids = base_config.rootfs().diff_ids().len();
+        if n_diffids != layers.len() {
+            anyhow::bail!(
+                "Base config has {n_diffids} diff_ids but manifest has {} layers",
+                layers.len()
+            );
+        }
+        for layer in layers {
+            if !self.has_blob(layer)? {
+                anyhow::bail!("Missing base layer blob {}", layer.digest());
+            }
+        }
+        let mut manifest = new_empty_manifest().build()?;
+        manifest.set_media_type(base_manifest.media_type().clone());
+        manifest.set_layers(layers.clone());
+        let mut config = base_config.clone();
+        config.set_created(None);
+        Ok(ImageBuilder {
+            dir: self,
+            manifest,
+            config,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ImageConfigExt;
+    use std::io::Write;
+
+    fn layer(w: &OciDir, contents: &[u8]) -> Result<Layer> {
+        let mut lw = w.create_gzip_layer(None)?;
+        lw.write_all(contents)?;
+        lw.complete()
+    }
+
+    #[test]
+    fn derive_from() -> Result<()> {
+        let w = OciDir::new_in_memory()?;
+        let mut base = w.new_image()?;
+        base.push_layer(layer(&w, b"base")?, "base", None)
+            .push_empty_history("ENV FOO=bar");
+        base.config_mut().add_label("org.example.base", "yes");
+        let base_desc = base.insert(Some("base"), Platform::default())?;
+        let (base_manifest, _) = w.find_manifest_and_descriptor_with_tag("base")?.unwrap();
+        let base_config: ImageConfiguration = w.read_json_blob(base_manifest.config())?;
+        assert!(base_config.created().is_some());
+
+        let mut app = w.derive_from(&base_manifest, &base_config)?;
+        assert!(app.config().created().is_none());
+        app.push_layer(layer(&w, b"app")?, "app", None);
+        let (manifest, config) = app.into_parts();
+        assert_eq!(manifest.layers().len(), 2);
+        assert_eq!(manifest.layers()[0], base_manifest.layers()[0]);
+        assert_eq!(config.rootfs().diff_ids().len(), 2);
+        assert_eq!(config.history().len(), 3);
+        assert_eq!(config.history()[2].created_by().as_deref(), Some("app"));
+        assert_eq!(
+            config.config().as_ref().unwrap().labels().as_ref().unwrap()["org.example.base"],
+            "yes"
+        );
+        let desc =
+            w.insert_manifest_and_config(manifest, config, Some("app"), Platform::default())?;
+        assert_ne!(desc.digest(), base_desc.digest());
+
+        // The base must be consistent and present
+        let mut inconsistent = base_config.clone();
+        inconsistent.rootfs_mut().diff_ids_mut().clear();
+        assert!(w.derive_from(&base_manifest, &inconsistent).is_err());
+        let other = OciDir::new_in_memory()?;
+        assert!(other.derive_from(&base_manifest, &base_config).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,144 @@
+//! Serialized updates of the image index, and layout-level annotations.
+
+use std::collections::HashMap;
+use std::sync::MutexGuard;
+
+use anyhow::Result;
+use fn_error_context::context;
+use oci_spec::image::{self as oci_image, ImageIndex};
+
+use crate::OciDir;
+
+/// Held while the image index is read, modified and written back.
+///
+/// Within a process, this excludes other updates through the same [`OciDir`]
+/// or its clones. On Linux, layouts on disk are additionally locked with
+/// `flock(2)` on the layout directory, which excludes other processes and
+/// other [`OciDir`] instances for the same directory.
+pub(crate) struct IndexLock<'a> {
+    _guard: MutexGuard<'a, ()>,
+    #[cfg(target_os = "linux")]
+    _fd: Option<std::os::fd::OwnedFd>,
+}
+
+impl OciDir {
+    /// Lock the index for a read-modify-write cycle.
+    pub(crate) fn lock_index(&self) -> Result<IndexLock<'_>> {
+        let guard = self.index_lock.lock().unwrap_or_else(|e| e.into_inner());
+        // A separate open file description is needed, since locks are shared
+        // between duplicated descriptors.
+        #[cfg(target_os = "linux")]
+        let fd = match self.dir() {
+            Some(dir) if !self.is_read_only() => {
+                use rustix::fs::{FlockOperation, Mode, OFlags};
+                let flags = OFlags::RDONLY | OFlags::DIRECTORY | OFlags::CLOEXEC;
+                let fd = rustix::fs::openat(dir, ".", flags, Mode::empty())?;
+                rustix::fs::flock(&fd, FlockOperation::LockExclusive)?;
+                Some(fd)
+            }
+            _ => None,
+        };
+        Ok(IndexLock {
+            _guard: guard,
+            #[cfg(target_os = "linux")]
+            _fd: fd,
+        })
+    }
+
+    /// The annotations of the image index itself, such as
+    /// `org.opencontainers.image.source` for the whole layout.
+    pub fn index_annotations(&self) -> Result<HashMap<String, String>> {
+        Ok(self
+            .read_index()?
+            .and_then(|i| i.annotations().clone())
+            .unwrap_or_default())
+    }
+
+    /// Replace the annotations of the image index; an empty map removes them.
+    pub fn set_index_annotations(&self, annotations: HashMap<String, String>) -> Result<()> {
+        self.update_index_annotations(|a| *a = annotations)
+    }
+
+    /// Modify the annotations of the image index. The index is locked while `f`
+    /// runs, so concurrent updates and manifest insertions are not lost.
+    #[context("Updating index annotations")]
+    pub fn update_index_annotations(
+        &self,
+        f: impl FnOnce(&mut HashMap<String, String>),
+    ) -> Result<()> {
+        let _lock = self.lock_index()?;
+        let mut index = match self.read_index()? {
+            Some(index) => index,
+            None => oci_image::ImageIndexBuilder::default()
+                .schema_version(oci_image::SCHEMA_VERSION)
+                .manifests(Vec::new())
+                .build()?,
+        };
+        let mut annotations = index.annotations().clone().unwrap_or_default();
+        f(&mut annotations);
+        index.set_annotations(Some(annotations).filter(|a| !a.is_empty()));
+        self.write_index(&index, "annotate", None, None)
+    }
+}
+
+/// Carry the layout-level fields of an existing index over to a new one.
+pub(crate) fn preserve_index_metadata(old: Option<&ImageIndex>, new: &mut ImageIndex) {
+    if let Some(old) = old {
+        new.set_annotations(old.annotations().clone());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cap_std_ext::{cap_std, cap_tempfile};
+
+    #[test]
+    fn index_annotations() -> Result<()> {
+        let td = cap_tempfile::tempdir(cap_std::ambient_authority())?;
+        let w = OciDir::ensure(&td)?;
+        assert!(w.index_annotations()?.is_empty());
+        let source = (
+            "org.opencontainers.image.source",
+            "https://example.com/repo",
+        );
+        w.set_index_annotations(HashMap::from([(source.0.into(), source.1.into())]))?;
+
+        // Concurrent insertions via separate instances neither lose the
+        // annotations nor each other's entries.
+        std::thread::scope(|s| {
+            let handles: Vec<_> = (0..4)
+                .map(|i| {
+                    let td = &td;
+                    s.spawn(move || -> Result<()> {
+                        let w = OciDir::open(td)?;
+                        let config = oci_image::ImageConfigurationBuilder::default().build()?;
+                        let manifest = crate::new_empty_manifest().build()?;
+                        let tag = format!("t{i}");
+                        w.insert_manifest_and_config(
+                            manifest,
+                            config,
+                            Some(&tag),
+                            Default::default(),
+                        )?;
+                        w.update_index_annotations(|a| {
+                            a.insert(format!("org.example.{i}"), tag.clone());
+                        })
+                    })
+                })
+                .collect();
+            handles.into_iter().try_for_each(|h| h.join().unwrap())
+        })?;
+        assert_eq!(w.manifests()?.len(), 4);
+        let annotations = w.index_annotations()?;
+        assert_eq!(annotations[source.0], source.1);
+        assert_eq!(annotations.len(), 5);
+
+        let manifest = crate::new_empty_manifest().build()?;
+        w.replace_with_single_manifest(manifest, Default::default())?;
+        assert_eq!(w.index_annotations()?.len(), 5);
+        w.set_index_annotations(HashMap::new())?;
+        assert!(w.read_index()?.unwrap().annotations().is_none());
+        Ok(())
+    }
+}
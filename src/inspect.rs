@@ -0,0 +1,167 @@
+//! A summary of a single image, similar to `skopeo inspect`.
+
+use std::collections::BTreeMap;
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use fn_error_context::context;
+use oci_spec::image::{
+    Arch, Descriptor, ImageConfiguration, ImageManifest, MediaType, Os, Platform, PlatformBuilder,
+};
+use serde::{Serialize, Serializer};
+
+use crate::{effective_created, OciDir, OCI_TAG_ANNOTATION};
+
+/// A summary of an image, see [`OciDir::inspect`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+#[non_exhaustive]
+pub struct ImageSummary {
+    /// The tag of the index entry, if any.
+    pub tag: Option<String>,
+    /// The digest of the manifest.
+    pub digest: String,
+    /// The media type of the manifest.
+    pub media_type: MediaType,
+    /// The platform from the index entry, or otherwise from the config.
+    pub platform: Option<Platform>,
+    /// The effective creation time, see [`effective_created`].
+    #[serde(serialize_with = "serialize_created")]
+    pub created: Option<DateTime<Utc>>,
+    /// The number of layers.
+    pub layers: usize,
+    /// The total compressed size of all layers.
+    pub size: u64,
+    /// Labels from the config.
+    pub labels: BTreeMap<String, String>,
+    /// The entrypoint from the config.
+    pub entrypoint: Option<Vec<String>>,
+}
+
+fn serialize_created<S: Serializer>(t: &Option<DateTime<Utc>>, s: S) -> Result<S::Ok, S::Error> {
+    match t {
+        Some(t) => s.serialize_some(&t.to_rfc3339()),
+        None => s.serialize_none(),
+    }
+}
+
+pub(crate) fn config_platform(config: &ImageConfiguration) -> Option<Platform> {
+    if matches!(config.os(), Os::Other(o) if o.is_empty()) {
+        return None;
+    }
+    if matches!(config.architecture(), Arch::Other(a) if a.is_empty()) {
+        return None;
+    }
+    let mut b = PlatformBuilder::default()
+        .os(config.os().clone())
+        .architecture(config.architecture().clone());
+    if let Some(variant) = config.variant() {
+        b = b.variant(variant.clone());
+    }
+    b.build().ok()
+}
+
+impl OciDir {
+    /// Find the index entry with the provided tag, or failing that, manifest digest.
+    pub(crate) fn resolve(&self, tag_or_digest: &str) -> Result<Descriptor> {
+        let manifests = self.manifests()?;
+        manifests
+            .iter()
+            .find(|m| m.tag.as_deref() == Some(tag_or_digest))
+            .or_else(|| {
+                manifests
+                    .iter()
+                    .find(|m| m.descriptor.digest().as_str() == tag_or_digest)
+            })
+            .map(|m| m.descriptor.clone())
+            .ok_or_else(|| anyhow!("No image found for {tag_or_digest}"))
+    }
+
+    /// Summarize the image with the provided tag or manifest digest.
+    #[context("Inspecting {tag_or_digest}")]
+    pub fn inspect(&self, tag_or_digest: &str) -> Result<ImageSummary> {
+        let desc = self.resolve(tag_or_digest)?;
+        if desc.media_type() != &MediaType::ImageManifest {
+            anyhow::bail!("Unsupported media type {}", desc.media_type());
+        }
+        let manifest: ImageManifest = self.read_json_blob(&desc)?;
+        let config: Option<ImageConfiguration> =
+            if manifest.config().media_type() == &MediaType::ImageConfig {
+                Some(self.read_json_blob(manifest.config())?)
+            } else {
+                None
+            };
+        let inner = config.as_ref().and_then(|c| c.config().as_ref());
+        let size: i64 = manifest.layers().iter().map(|l| l.size()).sum();
+        Ok(ImageSummary {
+            tag: desc
+                .annotations()
+                .as_ref()
+                .and_then(|a| a.get(OCI_TAG_ANNOTATION))
+                .cloned(),
+            digest: desc.digest().to_string(),
+            media_type: desc.media_type().clone(),
+            platform: desc
+                .platform()
+                .clone()
+                .or_else(|| config.as_ref().and_then(config_platform)),
+            created: config.as_ref().and_then(effective_created),
+            layers: manifest.layers().len(),
+            size: size.try_into()?,
+            labels: inner
+                .and_then(|c| c.labels().clone())
+                .map(|l| l.into_iter().collect())
+                .unwrap_or_default(),
+            entrypoint: inner.and_then(|c| c.entrypoint().clone()),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ImageConfigExt;
+    use std::io::Write;
+
+    #[test]
+    fn inspect() -> Result<()> {
+        let w = OciDir::new_in_memory()?;
+        let mut manifest = crate::new_empty_manifest().build()?;
+        let mut config = oci_spec::image::ImageConfigurationBuilder::default()
+            .os(Os::Linux)
+            .architecture(Arch::Amd64)
+            .created("2024-01-02T03:04:05Z")
+            .build()?;
+        config
+            .add_label("org.example.name", "app")
+            .set_entrypoint(["/bin/app", "--serve"]);
+        let mut layer = w.create_gzip_layer(None)?;
+        layer.write_all(b"not actually a tarball")?;
+        let layer = layer.complete()?;
+        let layer_size = layer.blob.size;
+        w.push_layer(&mut manifest, &mut config, layer, "app", None);
+        let desc =
+            w.insert_manifest_and_config(manifest, config, Some("latest"), Platform::default())?;
+
+        let s = w.inspect("latest")?;
+        assert_eq!(s, w.inspect(desc.digest())?);
+        assert_eq!(s.tag.as_deref(), Some("latest"));
+        assert_eq!(s.platform.as_ref().unwrap().os(), &Os::Linux);
+        assert_eq!(s.layers, 1);
+        assert_eq!(s.size, layer_size);
+        assert_eq!(s.labels["org.example.name"], "app");
+        assert_eq!(
+            s.entrypoint,
+            Some(vec!["/bin/app".into(), "--serve".into()])
+        );
+        assert_eq!(s.created.unwrap().to_rfc3339(), "2024-01-02T03:04:05+00:00");
+        let json = serde_json::to_value(&s)?;
+        assert_eq!(json["created"], "2024-01-02T03:04:05+00:00");
+        assert_eq!(
+            json["media-type"],
+            "application/vnd.oci.image.manifest.v1+json"
+        );
+        assert!(w.inspect("missing").is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,344 @@
+//! An optional append-only journal of mutating operations on a layout.
+
+use std::fmt::Debug;
+use std::io::Write;
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use cap_std_ext::cap_std::fs::Dir;
+use fn_error_context::context;
+use serde::{Deserialize, Serialize};
+
+use crate::hash::sha256_hex;
+use crate::store::{BlobStore, StagedBlob};
+use crate::{BlobReader, OciDir, OciDirOptions};
+
+/// The name of the journal file at the root of the layout, see [`crate::OciDirOptions::journal`].
+pub const JOURNAL_FILE: &str = "ocidir-journal.jsonl";
+
+/// A single journal record, stored as one line of JSON.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+#[non_exhaustive]
+pub struct JournalEntry {
+    /// The time of the operation, in RFC 3339 format.
+    pub timestamp: String,
+    /// The kind of operation, such as `insert`, `write-blob` or `remove-blob`.
+    pub operation: String,
+    /// The digest of the affected manifest or blob, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub subject: Option<String>,
+    /// The affected tag, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tag: Option<String>,
+    /// The digest of the manifest the tag referred to before the operation, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub previous: Option<String>,
+    /// The digest of `index.json` after the operation, unless the operation
+    /// only affected a blob.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub index_digest: Option<String>,
+}
+
+/// Receives each journal entry as it is recorded, for example to replicate
+/// changes elsewhere; see [`crate::OciDirOptions::journal_sink`].
+pub trait JournalSink: Send + Sync + Debug {
+    /// Record an entry. An error is returned from the operation, which has
+    /// already taken effect.
+    fn record(&self, entry: &JournalEntry) -> Result<()>;
+}
+
+impl OciDirOptions {
+    /// Returns true if journal entries are recorded at all.
+    pub(crate) fn journal_enabled(&self) -> bool {
+        self.journal || self.journal_sink.is_some()
+    }
+
+    /// Append an entry to the journal file and pass it to the sink, as enabled.
+    fn record_journal(
+        &self,
+        store: &dyn BlobStore,
+        operation: &str,
+        subject: Option<&str>,
+        tag: Option<&str>,
+        previous: Option<&str>,
+        index_digest: Option<String>,
+    ) -> Result<()> {
+        let entry = JournalEntry {
+            timestamp: self
+                .journal_now()
+                .to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+            operation: operation.to_owned(),
+            subject: subject.map(ToOwned::to_owned),
+            tag: tag.map(ToOwned::to_owned),
+            previous: previous.map(ToOwned::to_owned),
+            index_digest,
+        };
+        if self.journal {
+            let mut line = serde_json::to_vec(&entry)?;
+            line.push(b'\n');
+            store.append_meta(JOURNAL_FILE, &line)?;
+        }
+        if let Some(sink) = self.journal_sink.as_ref() {
+            sink.record(&entry)?;
+        }
+        Ok(())
+    }
+}
+
+/// A store which records the blobs written and removed through it in the journal;
+/// [`OciDir::with_store`] wraps the store in this if the journal is enabled.
+#[derive(Debug)]
+pub(crate) struct JournaledStore {
+    inner: Arc<dyn BlobStore>,
+    opts: OciDirOptions,
+}
+
+impl JournaledStore {
+    pub(crate) fn new(inner: Arc<dyn BlobStore>, opts: &OciDirOptions) -> Self {
+        Self {
+            inner,
+            opts: opts.clone(),
+        }
+    }
+
+    fn record(&self, operation: &str, digest: &str) -> Result<()> {
+        self.opts
+            .record_journal(&*self.inner, operation, Some(digest), None, None, None)
+    }
+}
+
+#[derive(Debug)]
+struct JournaledStagedBlob<'a> {
+    store: &'a JournaledStore,
+    inner: Box<dyn StagedBlob + 'a>,
+}
+
+impl<'a> Write for JournaledStagedBlob<'a> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<'a> StagedBlob for JournaledStagedBlob<'a> {
+    fn commit(self: Box<Self>, digest: &str) -> Result<()> {
+        let Self { store, inner } = *self;
+        inner.commit(digest)?;
+        store.record("write-blob", digest)
+    }
+}
+
+impl BlobStore for JournaledStore {
+    fn get(&self, digest: &str) -> Result<Option<BlobReader>> {
+        self.inner.get(digest)
+    }
+
+    fn put(&self) -> Result<Box<dyn StagedBlob + '_>> {
+        Ok(Box::new(JournaledStagedBlob {
+            store: self,
+            inner: self.inner.put()?,
+        }))
+    }
+
+    fn has(&self, digest: &str) -> Result<bool> {
+        self.inner.has(digest)
+    }
+
+    fn list(&self) -> Result<Vec<String>> {
+        self.inner.list()
+    }
+
+    fn delete(&self, digest: &str) -> Result<bool> {
+        let found = self.inner.delete(digest)?;
+        if found {
+            self.record("remove-blob", digest)?;
+        }
+        Ok(found)
+    }
+
+    fn read_meta(&self, name: &str) -> Result<Option<Vec<u8>>> {
+        self.inner.read_meta(name)
+    }
+
+    fn write_meta(&self, name: &str, contents: &[u8]) -> Result<()> {
+        self.inner.write_meta(name, contents)
+    }
+
+    fn append_meta(&self, name: &str, contents: &[u8]) -> Result<()> {
+        self.inner.append_meta(name, contents)
+    }
+
+    fn as_dir(&self) -> Option<&Dir> {
+        self.inner.as_dir()
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.inner.is_read_only()
+    }
+}
+
+impl OciDir {
+    /// Record a change to the index in the journal, if enabled, given the
+    /// serialized contents of the index which was just written.
+    pub(crate) fn journal(
+        &self,
+        operation: &str,
+        subject: Option<&str>,
+        tag: Option<&str>,
+        previous: Option<&str>,
+        index: &[u8],
+    ) -> Result<()> {
+        if !self.opts.journal_enabled() {
+            return Ok(());
+        }
+        let index_digest = format!("sha256:{}", sha256_hex(index)?);
+        self.opts.record_journal(
+            &*self.store,
+            operation,
+            subject,
+            tag,
+            previous,
+            Some(index_digest),
+        )
+    }
+
+    /// Record an operation on a blob which bypassed the store, if the journal is enabled.
+    pub(crate) fn journal_blob(&self, operation: &str, digest: &str) -> Result<()> {
+        if !self.opts.journal_enabled() {
+            return Ok(());
+        }
+        self.opts
+            .record_journal(&*self.store, operation, Some(digest), None, None, None)
+    }
+
+    /// Read all journal entries, oldest first. Returns an empty list if there is no journal.
+    #[context("Reading journal")]
+    pub fn read_journal(&self) -> Result<Vec<JournalEntry>> {
+        let Some(buf) = self.store.read_meta(JOURNAL_FILE)? else {
+            return Ok(Vec::new());
+        };
+        buf.split(|&c| c == b'\n')
+            .filter(|l| !l.is_empty())
+            .enumerate()
+            .map(|(i, l)| serde_json::from_slice(l).with_context(|| format!("Parsing entry {i}")))
+            .collect()
+    }
+
+    /// Remove all but the newest `keep` journal entries, returning the number of
+    /// removed entries.
+    #[context("Compacting journal")]
+    pub fn compact_journal(&self, keep: usize) -> Result<usize> {
+        let entries = self.read_journal()?;
+        let removed = entries.len().saturating_sub(keep);
+        if removed == 0 {
+            return Ok(0);
+        }
+        let mut buf = Vec::new();
+        for entry in &entries[removed..] {
+            serde_json::to_writer(&mut buf, entry)?;
+            buf.push(b'\n');
+        }
+        self.store.write_meta(JOURNAL_FILE, &buf)?;
+        Ok(removed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cap_std_ext::{cap_std, cap_tempfile};
+    use oci_spec::image::{ImageConfigurationBuilder, Os};
+    use std::sync::Mutex;
+
+    #[derive(Debug, Default)]
+    struct VecSink(Mutex<Vec<JournalEntry>>);
+
+    impl JournalSink for VecSink {
+        fn record(&self, entry: &JournalEntry) -> Result<()> {
+            self.0.lock().unwrap().push(entry.clone());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn journal() -> Result<()> {
+        let td = cap_tempfile::tempdir(cap_std::ambient_authority())?;
+        let sink = Arc::new(VecSink::default());
+        let opts = crate::OciDirOptions {
+            journal: true,
+            journal_sink: Some(sink.clone()),
+            ..Default::default()
+        };
+        let w = OciDir::ensure_with(&td, &opts)?;
+        assert!(w.read_journal()?.is_empty());
+        let mut inserted = Vec::new();
+        for (tag, os) in [("a", Os::Linux), ("b", Os::Linux), ("a", Os::Windows)] {
+            let manifest = crate::new_empty_manifest().build().unwrap();
+            let config = ImageConfigurationBuilder::default().os(os).build().unwrap();
+            inserted.push(w.insert_manifest_and_config(
+                manifest,
+                config,
+                Some(tag),
+                Default::default(),
+            )?);
+        }
+        let entries = w.read_journal()?;
+        assert_eq!(&entries, &*sink.0.lock().unwrap());
+        let ops: Vec<_> = entries.iter().map(|e| e.operation.as_str()).collect();
+        // Blobs which are already present are not written again
+        assert_eq!(
+            ops,
+            [
+                "write-blob",
+                "write-blob",
+                "insert",
+                "insert",
+                "write-blob",
+                "write-blob",
+                "insert"
+            ]
+        );
+        assert_eq!(entries[1].subject, Some(inserted[0].digest().to_string()));
+        assert!(entries[0].index_digest.is_none());
+        assert_eq!(entries[3].tag.as_deref(), Some("b"));
+        assert!(entries[3].previous.is_none());
+        // Replacing the tag records what it referred to before
+        assert_eq!(entries[6].tag.as_deref(), Some("a"));
+        assert_eq!(
+            entries[6].previous.as_deref(),
+            Some(inserted[0].digest().as_str())
+        );
+        let index = td.read("index.json")?;
+        assert_eq!(
+            entries[6].index_digest.as_deref().unwrap(),
+            format!("sha256:{}", sha256_hex(&index)?)
+        );
+
+        // Removing an unreferenced blob
+        let orphan = w.create_gzip_layer(None)?.complete()?.blob.digest_id();
+        let pruned = w.prune(&Default::default())?;
+        assert_eq!(pruned.removed.len(), 1);
+        let journal = w.read_journal()?;
+        let [written, removed] = &journal[entries.len()..] else {
+            panic!("unexpected entries {journal:?}");
+        };
+        assert_eq!(written.operation, "write-blob");
+        assert_eq!(removed.operation, "remove-blob");
+        assert_eq!(removed.subject.as_deref(), Some(orphan.as_str()));
+
+        let n = w.read_journal()?.len();
+        assert_eq!(w.compact_journal(1)?, n - 1);
+        assert_eq!(w.read_journal()?.len(), 1);
+
+        // Not enabled by default
+        let w = OciDir::new_in_memory()?;
+        let manifest = crate::new_empty_manifest().build().unwrap();
+        w.replace_with_single_manifest(manifest, Default::default())?;
+        assert!(w.read_journal()?.is_empty());
+        Ok(())
+    }
+}
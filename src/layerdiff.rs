@@ -0,0 +1,256 @@
+//! Generation of layers from the difference between two directory trees.
+
+use std::io::{BufReader, Read};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use cap_std::fs::{Dir, Metadata};
+use cap_std_ext::cap_std;
+use cap_std_ext::dirext::CapStdExtDirExt;
+use fn_error_context::context;
+
+use crate::fsmeta;
+
+/// The prefix of a whiteout entry, which hides the named file in lower layers.
+pub const WHITEOUT_PREFIX: &str = ".wh.";
+/// The name of an opaque whiteout entry, which hides all lower contents of its directory.
+pub const OPAQUE_WHITEOUT: &str = ".wh..wh..opq";
+
+/// Builds a tar layer from two directory trees, containing the files which were
+/// added or changed in the new tree and whiteout entries for the removed ones.
+///
+/// Regular files are considered unchanged if their size, mode, ownership and
+/// modification time match; if only the modification time differs the
+/// contents are compared. Hard links are not preserved.
+#[derive(Debug)]
+pub struct LayerDiffBuilder<'a> {
+    old: &'a Dir,
+    new: &'a Dir,
+}
+
+fn sorted_names(d: &Dir) -> Result<Vec<String>> {
+    let mut r = Vec::new();
+    for ent in d.entries()? {
+        let ent = ent?;
+        let name = ent.file_name();
+        let name = name
+            .to_str()
+            .ok_or_else(|| anyhow::anyhow!("Invalid UTF-8 filename: {name:?}"))?;
+        r.push(name.to_owned());
+    }
+    r.sort();
+    Ok(r)
+}
+
+fn same_contents(a: &Dir, b: &Dir, name: &str) -> Result<bool> {
+    let mut a = BufReader::new(a.open(name)?);
+    let mut b = BufReader::new(b.open(name)?);
+    let mut abuf = [0u8; 8192];
+    let mut bbuf = [0u8; 8192];
+    loop {
+        let n = a.read(&mut abuf)?;
+        if n == 0 {
+            return Ok(b.read(&mut bbuf)? == 0);
+        }
+        // The sizes are known to be equal, so this only fails on concurrent changes.
+        match b.read_exact(&mut bbuf[..n]) {
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(false),
+            r => r?,
+        }
+        if abuf[..n] != bbuf[..n] {
+            return Ok(false);
+        }
+    }
+}
+
+fn header_for(meta: &Metadata, entry_type: tar::EntryType) -> tar::Header {
+    let mut h = tar::Header::new_gnu();
+    h.set_entry_type(entry_type);
+    fsmeta::set_header(&mut h, meta);
+    h.set_size(0);
+    h
+}
+
+impl<'a> LayerDiffBuilder<'a> {
+    /// Create a builder for the changes from `old` to `new`.
+    pub fn new(old: &'a Dir, new: &'a Dir) -> Self {
+        Self { old, new }
+    }
+
+    /// Append the changes to a tar stream, such as one returned by
+    /// [`crate::OciDir::create_layer`].
+    #[context("Building layer diff")]
+    pub fn append_to<W: std::io::Write>(&self, builder: &mut tar::Builder<W>) -> Result<()> {
+        diff_dir(Some(self.old), self.new, Path::new(""), builder)
+    }
+}
+
+/// Append a whiteout entry for `name` in the directory `prefix`.
+fn append_whiteout<W: std::io::Write>(
+    prefix: &Path,
+    name: &str,
+    builder: &mut tar::Builder<W>,
+) -> Result<()> {
+    let mut h = tar::Header::new_gnu();
+    h.set_entry_type(tar::EntryType::Regular);
+    h.set_mode(0o644);
+    h.set_size(0);
+    let path = prefix.join(format!("{WHITEOUT_PREFIX}{name}"));
+    builder.append_data(&mut h, path, std::io::empty())?;
+    Ok(())
+}
+
+/// Append an entry from the new tree, recursing into directories.
+fn append_entry<W: std::io::Write>(
+    new: &Dir,
+    name: &str,
+    meta: &Metadata,
+    path: &Path,
+    builder: &mut tar::Builder<W>,
+) -> Result<()> {
+    let ft = meta.file_type();
+    if ft.is_dir() {
+        let mut h = header_for(meta, tar::EntryType::Directory);
+        builder.append_data(&mut h, path, std::io::empty())?;
+        diff_dir(None, &new.open_dir(name)?, path, builder)
+    } else if ft.is_file() {
+        let mut h = header_for(meta, tar::EntryType::Regular);
+        h.set_size(meta.len());
+        builder.append_data(&mut h, path, new.open(name)?)?;
+        Ok(())
+    } else if ft.is_symlink() {
+        let mut h = header_for(meta, tar::EntryType::Symlink);
+        let target = new.read_link_contents(name)?;
+        builder.append_link(&mut h, path, target)?;
+        Ok(())
+    } else {
+        anyhow::bail!("Unsupported file type for {}", path.display())
+    }
+}
+
+fn metadata_changed(old: &Metadata, new: &Metadata) -> bool {
+    fsmeta::mode(old) != fsmeta::mode(new) || fsmeta::owner(old) != fsmeta::owner(new)
+}
+
+fn diff_dir<W: std::io::Write>(
+    old: Option<&Dir>,
+    new: &Dir,
+    prefix: &Path,
+    builder: &mut tar::Builder<W>,
+) -> Result<()> {
+    let new_names = sorted_names(new)?;
+    if let Some(old) = old {
+        for name in sorted_names(old)? {
+            if new.symlink_metadata_optional(&name)?.is_none() {
+                append_whiteout(prefix, &name, builder)?;
+            }
+        }
+    }
+    for name in new_names {
+        let path: PathBuf = prefix.join(&name);
+        let meta = new
+            .symlink_metadata(&name)
+            .with_context(|| format!("Querying {}", path.display()))?;
+        let old_meta = match old {
+            Some(old) => old.symlink_metadata_optional(&name)?,
+            None => None,
+        };
+        let Some(old_meta) = old_meta else {
+            append_entry(new, &name, &meta, &path, builder)?;
+            continue;
+        };
+        let old = old.unwrap();
+        let (oft, nft) = (old_meta.file_type(), meta.file_type());
+        if oft.is_dir() != nft.is_dir()
+            || oft.is_file() != nft.is_file()
+            || oft.is_symlink() != nft.is_symlink()
+        {
+            // Hide the old entry, including any directory contents.
+            append_whiteout(prefix, &name, builder)?;
+            append_entry(new, &name, &meta, &path, builder)?;
+        } else if nft.is_dir() {
+            let old_sub = old.open_dir(&name)?;
+            if metadata_changed(&old_meta, &meta) {
+                let mut h = header_for(&meta, tar::EntryType::Directory);
+                builder.append_data(&mut h, &path, std::io::empty())?;
+            }
+            diff_dir(Some(&old_sub), &new.open_dir(&name)?, &path, builder)?;
+        } else if nft.is_symlink() {
+            if old.read_link_contents(&name)? != new.read_link_contents(&name)?
+                || metadata_changed(&old_meta, &meta)
+            {
+                append_entry(new, &name, &meta, &path, builder)?;
+            }
+        } else {
+            let changed = metadata_changed(&old_meta, &meta)
+                || old_meta.len() != meta.len()
+                || (old_meta.modified()? != meta.modified()? && !same_contents(old, new, &name)?);
+            if changed {
+                append_entry(new, &name, &meta, &path, builder)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+// The test trees contain symbolic links.
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use cap_std_ext::cap_tempfile;
+
+    #[test]
+    fn layer_diff() -> Result<()> {
+        let td = cap_tempfile::tempdir(cap_std::ambient_authority())?;
+        td.create_dir_all("old/etc/removed-dir")?;
+        td.create_dir_all("old/usr")?;
+        td.write("old/etc/passwd", "root")?;
+        td.write("old/etc/hostname", "old")?;
+        td.write("old/etc/removed", "x")?;
+        td.write("old/usr/replaced", "file")?;
+        td.write("old/etc/removed-dir/f", "x")?;
+        td.create_dir_all("new/etc")?;
+        td.create_dir_all("new/usr/replaced")?;
+        td.create_dir_all("new/opt/app")?;
+        td.write("new/etc/passwd", "root")?;
+        td.write("new/etc/hostname", "new")?;
+        td.write("new/opt/app/bin", "binary")?;
+        td.symlink("../opt/app/bin", "new/usr/app")?;
+        // Keep the unchanged file identical, including its timestamp
+        let old_passwd = td.open("old/etc/passwd")?;
+        td.open_with(
+            "new/etc/passwd",
+            cap_std::fs::OpenOptions::new().write(true),
+        )?
+        .into_std()
+        .set_modified(old_passwd.metadata()?.modified()?.into_std())?;
+        // Different timestamp, same content
+        td.write("new/etc/hostname", "old")?;
+
+        let old = td.open_dir("old")?;
+        let new = td.open_dir("new")?;
+        let mut builder = tar::Builder::new(Vec::new());
+        LayerDiffBuilder::new(&old, &new).append_to(&mut builder)?;
+        let buf = builder.into_inner()?;
+        let mut archive = tar::Archive::new(buf.as_slice());
+        let mut paths = Vec::new();
+        for e in archive.entries()? {
+            let e = e?;
+            paths.push(e.path()?.to_string_lossy().into_owned());
+        }
+        assert_eq!(
+            paths,
+            [
+                "etc/.wh.removed",
+                "etc/.wh.removed-dir",
+                "opt",
+                "opt/app",
+                "opt/app/bin",
+                "usr/app",
+                "usr/.wh.replaced",
+                "usr/replaced",
+            ]
+        );
+        Ok(())
+    }
+}
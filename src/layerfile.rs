@@ -0,0 +1,185 @@
+//! Shipping individual layers as standalone files, outside of a layout.
+
+use std::io::{Read, Write};
+
+use anyhow::{anyhow, Result};
+use fn_error_context::context;
+use oci_spec::image::{Descriptor, MediaType};
+
+use crate::hash::Sha256;
+use crate::{BlobWriter, CompressionFormat, Layer, OciDir};
+
+/// Decompresses written content and hashes the result, to compute a diff_id
+/// while the compressed content is streamed elsewhere.
+enum DiffIdWriter {
+    Uncompressed(Sha256),
+    Gzip(flate2::write::MultiGzDecoder<Sha256>),
+    #[cfg(feature = "zstd")]
+    Zstd(zstd::stream::write::Decoder<'static, Sha256>),
+}
+
+impl DiffIdWriter {
+    fn new(format: CompressionFormat) -> Result<Self> {
+        let hasher = Sha256::new()?;
+        let r = match format {
+            CompressionFormat::None => Self::Uncompressed(hasher),
+            CompressionFormat::Gzip => Self::Gzip(flate2::write::MultiGzDecoder::new(hasher)),
+            #[cfg(feature = "zstd")]
+            CompressionFormat::Zstd => Self::Zstd(zstd::stream::write::Decoder::new(hasher)?),
+            #[cfg(not(feature = "zstd"))]
+            CompressionFormat::Zstd => anyhow::bail!("zstd support is not enabled"),
+        };
+        Ok(r)
+    }
+
+    fn finish_hex(self) -> Result<String> {
+        let mut hasher = match self {
+            Self::Uncompressed(h) => h,
+            Self::Gzip(d) => d.finish()?,
+            #[cfg(feature = "zstd")]
+            Self::Zstd(mut d) => {
+                d.flush()?;
+                d.into_inner()
+            }
+        };
+        Ok(hasher.finish_hex()?)
+    }
+}
+
+impl Write for DiffIdWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Self::Uncompressed(w) => w.write(buf),
+            Self::Gzip(w) => w.write(buf),
+            #[cfg(feature = "zstd")]
+            Self::Zstd(w) => w.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Self::Uncompressed(w) => w.flush(),
+            Self::Gzip(w) => w.flush(),
+            #[cfg(feature = "zstd")]
+            Self::Zstd(w) => w.flush(),
+        }
+    }
+}
+
+impl OciDir {
+    /// Write the blob of a layer to `w` as a standalone file, returning its size;
+    /// see [`Self::import_layer`].
+    ///
+    /// The content is checked against the digest and size of the descriptor while
+    /// it is written, so an error may be returned after `w` received corrupt data.
+    #[context("Exporting layer {}", desc.digest())]
+    pub fn export_layer(&self, desc: &Descriptor, mut w: impl Write) -> Result<u64> {
+        let Some(expected) = desc.digest().strip_prefix("sha256:") else {
+            anyhow::bail!("Unsupported digest algorithm {}", desc.digest());
+        };
+        let (mut r, size) = self.open_blob_sized(desc.digest())?;
+        if i64::try_from(size).ok() != Some(desc.size()) {
+            anyhow::bail!("Blob has size {size}, expected {}", desc.size());
+        }
+        let mut hasher = Sha256::new()?;
+        let mut buf = vec![0u8; crate::layerwriter::DEFAULT_BUFFER_SIZE];
+        loop {
+            let n = r.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            hasher.write_all(&buf[..n])?;
+            w.write_all(&buf[..n])?;
+        }
+        w.flush()?;
+        let found = hasher.finish_hex()?;
+        if found != expected {
+            anyhow::bail!("Corrupted blob: found sha256:{found}");
+        }
+        Ok(size)
+    }
+
+    /// Add a layer from a standalone file, such as one written by
+    /// [`Self::export_layer`], with the provided layer media type.
+    ///
+    /// The diff_id is computed while the layer is written; if `known_diffid` is
+    /// provided and does not match, nothing is added.
+    #[context("Importing layer")]
+    pub fn import_layer(
+        &self,
+        mut r: impl Read,
+        media_type: MediaType,
+        known_diffid: Option<&str>,
+    ) -> Result<Layer> {
+        let format = CompressionFormat::from_media_type(&media_type)
+            .ok_or_else(|| anyhow!("Not a layer media type: {media_type}"))?;
+        let mut bw = BlobWriter::new(&*self.store, &self.progress)?;
+        let mut diffid = DiffIdWriter::new(format)?;
+        let mut buf = vec![0u8; crate::layerwriter::DEFAULT_BUFFER_SIZE];
+        loop {
+            let n = r.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            diffid.write_all(&buf[..n])?;
+            bw.write_all(&buf[..n])?;
+        }
+        let uncompressed_sha256 = diffid.finish_hex()?;
+        if let Some(known) = known_diffid {
+            if known.strip_prefix("sha256:") != Some(uncompressed_sha256.as_str()) {
+                anyhow::bail!("Layer has diff_id sha256:{uncompressed_sha256}, expected {known}");
+            }
+        }
+        Ok(Layer {
+            blob: bw.complete()?,
+            uncompressed_sha256,
+            media_type,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::write_test_layer;
+
+    #[test]
+    fn export_import_layer() -> Result<()> {
+        let src = OciDir::new_in_memory()?;
+        let dest = OciDir::new_in_memory()?;
+        let mut formats = vec![CompressionFormat::None, CompressionFormat::Gzip];
+        if cfg!(feature = "zstd") {
+            formats.push(CompressionFormat::Zstd);
+        }
+        for format in formats {
+            let layer = write_test_layer(&src, format)?;
+            let desc = layer.descriptor().build()?;
+            let mut file = Vec::new();
+            assert_eq!(src.export_layer(&desc, &mut file)?, layer.blob.size);
+
+            let diff_id = layer.diff_id();
+            let imported =
+                dest.import_layer(file.as_slice(), layer.media_type.clone(), Some(&diff_id))?;
+            assert_eq!(imported.descriptor().build()?, desc);
+            assert_eq!(imported.diff_id(), diff_id);
+        }
+
+        let mut lw = src.create_gzip_layer(None)?;
+        lw.write_all(b"another tarball")?;
+        let layer = lw.complete()?;
+        let wrong = format!("sha256:{}", "0".repeat(64));
+        let gz = src.open_blob_sized(&layer.blob.digest_id())?.0;
+        assert!(dest
+            .import_layer(gz, MediaType::ImageLayerGzip, Some(&wrong))
+            .is_err());
+        assert!(!dest.store.has(&layer.blob.digest_id())?);
+        assert!(dest
+            .import_layer(&b"{}"[..], MediaType::ImageConfig, None)
+            .is_err());
+
+        let mut desc = layer.descriptor().build()?;
+        desc.set_size(3);
+        assert!(src.export_layer(&desc, std::io::sink()).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,233 @@
+//! Validation of layer ordering and duplication within an image.
+
+use std::collections::HashMap;
+
+use anyhow::Result;
+use oci_spec::image::{ImageConfiguration, ImageManifest};
+
+/// A problem found by [`check_layers`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum LayerIssue {
+    /// The same layer blob appears more than once in the manifest.
+    DuplicateLayer {
+        /// The layer digest.
+        digest: String,
+        /// Indices of all occurrences in the manifest layers.
+        indices: Vec<usize>,
+    },
+    /// The same diff_id appears more than once in the config.
+    DuplicateDiffId {
+        /// The diff_id.
+        diff_id: String,
+        /// Indices of all occurrences in `rootfs.diff_ids`.
+        indices: Vec<usize>,
+    },
+    /// The number of layers in the manifest and diff_ids in the config differ.
+    CountMismatch {
+        /// Number of manifest layers.
+        layers: usize,
+        /// Number of config diff_ids.
+        diff_ids: usize,
+    },
+}
+
+impl std::fmt::Display for LayerIssue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LayerIssue::DuplicateLayer { digest, indices } => {
+                write!(f, "Layer {digest} is duplicated at indices {indices:?}")
+            }
+            LayerIssue::DuplicateDiffId { diff_id, indices } => {
+                write!(f, "diff_id {diff_id} is duplicated at indices {indices:?}")
+            }
+            LayerIssue::CountMismatch { layers, diff_ids } => {
+                write!(
+                    f,
+                    "Manifest has {layers} layers but config has {diff_ids} diff_ids"
+                )
+            }
+        }
+    }
+}
+
+/// Group the indices of repeated values, in order of first occurrence.
+fn duplicates<'a>(values: impl Iterator<Item = &'a str>) -> Vec<(String, Vec<usize>)> {
+    let mut seen: HashMap<&str, usize> = HashMap::new();
+    let mut groups: Vec<(String, Vec<usize>)> = Vec::new();
+    for (i, v) in values.enumerate() {
+        match seen.get(v) {
+            Some(&g) => groups[g].1.push(i),
+            None => {
+                seen.insert(v, groups.len());
+                groups.push((v.to_owned(), vec![i]));
+            }
+        }
+    }
+    groups.retain(|(_, indices)| indices.len() > 1);
+    groups
+}
+
+/// Check a manifest and its config for duplicated layers and diff_ids, and for a
+/// mismatch in the number of each. An empty result means no issues were found.
+pub fn check_layers(manifest: &ImageManifest, config: &ImageConfiguration) -> Vec<LayerIssue> {
+    let mut r = Vec::new();
+    let layers = manifest.layers();
+    let diff_ids = config.rootfs().diff_ids();
+    if layers.len() != diff_ids.len() {
+        r.push(LayerIssue::CountMismatch {
+            layers: layers.len(),
+            diff_ids: diff_ids.len(),
+        });
+    }
+    r.extend(
+        duplicates(layers.iter().map(|l| l.digest().as_str()))
+            .into_iter()
+            .map(|(digest, indices)| LayerIssue::DuplicateLayer { digest, indices }),
+    );
+    r.extend(
+        duplicates(diff_ids.iter().map(|d| d.as_str()))
+            .into_iter()
+            .map(|(diff_id, indices)| LayerIssue::DuplicateDiffId { diff_id, indices }),
+    );
+    r
+}
+
+/// Remove all but the first occurrence of each repeated layer, keeping the
+/// manifest layers, config diff_ids and config history in sync.
+///
+/// History entries are only adjusted if the non-empty entries line up with the layers.
+/// Returns the number of removed layers.
+pub fn dedup_layers(
+    manifest: &mut ImageManifest,
+    config: &mut ImageConfiguration,
+) -> Result<usize> {
+    let n_layers = manifest.layers().len();
+    let n_diff_ids = config.rootfs().diff_ids().len();
+    if n_layers != n_diff_ids {
+        anyhow::bail!("Manifest has {n_layers} layers but config has {n_diff_ids} diff_ids");
+    }
+    let remove: Vec<usize> = duplicates(manifest.layers().iter().map(|l| l.digest().as_str()))
+        .into_iter()
+        .flat_map(|(_, indices)| indices.into_iter().skip(1))
+        .collect();
+    if remove.is_empty() {
+        return Ok(0);
+    }
+    let keep = |i: &usize| !remove.contains(i);
+
+    let layers = std::mem::take(manifest.layers_mut());
+    *manifest.layers_mut() = layers
+        .into_iter()
+        .enumerate()
+        .filter(|(i, _)| keep(i))
+        .map(|(_, l)| l)
+        .collect();
+
+    let mut rootfs = config.rootfs().clone();
+    let diff_ids = std::mem::take(rootfs.diff_ids_mut());
+    *rootfs.diff_ids_mut() = diff_ids
+        .into_iter()
+        .enumerate()
+        .filter(|(i, _)| keep(i))
+        .map(|(_, d)| d)
+        .collect();
+    config.set_rootfs(rootfs);
+
+    let history = config.history_mut();
+    let is_layer = |h: &oci_spec::image::History| !h.empty_layer().unwrap_or_default();
+    if history.iter().filter(|h| is_layer(h)).count() == n_layers {
+        let mut layer_idx = 0;
+        history.retain(|h| {
+            if !is_layer(h) {
+                return true;
+            }
+            let i = layer_idx;
+            layer_idx += 1;
+            keep(&i)
+        });
+    }
+    Ok(remove.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use oci_spec::image::{
+        DescriptorBuilder, HistoryBuilder, ImageConfigurationBuilder, MediaType,
+    };
+
+    fn build(layers: &[&str], diff_ids: &[&str]) -> (ImageManifest, ImageConfiguration) {
+        let mut manifest = crate::new_empty_manifest().build().unwrap();
+        let mut config = ImageConfigurationBuilder::default().build().unwrap();
+        for l in layers {
+            let desc = DescriptorBuilder::default()
+                .media_type(MediaType::ImageLayerGzip)
+                .digest(format!("sha256:{l}"))
+                .size(1)
+                .build()
+                .unwrap();
+            manifest.layers_mut().push(desc);
+            config
+                .history_mut()
+                .push(HistoryBuilder::default().created_by(*l).build().unwrap());
+        }
+        config.history_mut().push(
+            HistoryBuilder::default()
+                .created_by("metadata")
+                .empty_layer(true)
+                .build()
+                .unwrap(),
+        );
+        let mut rootfs = config.rootfs().clone();
+        *rootfs.diff_ids_mut() = diff_ids.iter().map(|d| format!("sha256:{d}")).collect();
+        config.set_rootfs(rootfs);
+        (manifest, config)
+    }
+
+    #[test]
+    fn check() {
+        let (m, c) = build(&["a", "b"], &["1", "2"]);
+        assert!(check_layers(&m, &c).is_empty());
+        let (m, c) = build(&["a", "b", "a"], &["1", "1"]);
+        let issues = check_layers(&m, &c);
+        assert_eq!(
+            issues,
+            [
+                LayerIssue::CountMismatch {
+                    layers: 3,
+                    diff_ids: 2
+                },
+                LayerIssue::DuplicateLayer {
+                    digest: "sha256:a".into(),
+                    indices: vec![0, 2]
+                },
+                LayerIssue::DuplicateDiffId {
+                    diff_id: "sha256:1".into(),
+                    indices: vec![0, 1]
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn dedup() -> Result<()> {
+        let (mut m, mut c) = build(&["a", "b", "a", "c"], &["1", "2", "1", "3"]);
+        assert_eq!(dedup_layers(&mut m, &mut c)?, 1);
+        let digests: Vec<_> = m.layers().iter().map(|l| l.digest().as_str()).collect();
+        assert_eq!(digests, ["sha256:a", "sha256:b", "sha256:c"]);
+        assert_eq!(c.rootfs().diff_ids(), &["sha256:1", "sha256:2", "sha256:3"]);
+        let history: Vec<_> = c
+            .history()
+            .iter()
+            .map(|h| h.created_by().as_deref().unwrap())
+            .collect();
+        assert_eq!(history, ["a", "b", "c", "metadata"]);
+        assert!(check_layers(&m, &c).is_empty());
+        assert_eq!(dedup_layers(&mut m, &mut c)?, 0);
+
+        let (mut m, mut c) = build(&["a", "b"], &["1"]);
+        assert!(dedup_layers(&mut m, &mut c).is_err());
+        Ok(())
+    }
+}
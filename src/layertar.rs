@@ -0,0 +1,512 @@
+//! Building layer tarballs from directory trees with control over the tar dialect.
+
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use cap_std::fs::{Dir, Metadata};
+use cap_std_ext::cap_std;
+use fn_error_context::context;
+
+use crate::{fsmeta, GzipLayerWriter, Layer, OciDir};
+
+/// The tar dialect used for entry headers.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TarFormat {
+    /// GNU headers, with GNU extension entries for long names.
+    #[default]
+    Gnu,
+    /// POSIX ustar headers, with PAX extended headers for long names and large values.
+    Pax,
+}
+
+/// How character and block device nodes are handled, when building layers and
+/// in [`crate::UnpackOptions::devices`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DevicePolicy {
+    /// Add device nodes to the layer, or create them when extracting.
+    #[default]
+    Include,
+    /// Silently omit device nodes.
+    Skip,
+    /// Fail if a device node is found.
+    Error,
+}
+
+/// Options for layers built by [`OciDir::create_layer_with`] and
+/// [`OciDir::create_layer_from_dir`].
+#[derive(Debug, Clone, Default)]
+pub struct LayerTarOptions {
+    /// The compression level.
+    pub compression: Option<flate2::Compression>,
+    /// The tar dialect.
+    pub format: TarFormat,
+    /// Archive the targets of symbolic links instead of the links themselves.
+    pub follow_symlinks: bool,
+    /// Preserve extended attributes of regular files and directories as
+    /// `SCHILY.xattr.` PAX records. This is only supported on Linux.
+    pub xattrs: bool,
+    /// Store additional links to a regular file as hard link entries, instead of
+    /// copying its contents again.
+    pub hardlinks: bool,
+    /// How device nodes are handled.
+    pub devices: DevicePolicy,
+}
+
+/// The PAX record prefix for extended attributes.
+#[cfg(target_os = "linux")]
+const XATTR_PAX_PREFIX: &str = "SCHILY.xattr.";
+/// The largest value representable in the 8 byte octal uid and gid fields.
+const MAX_OCTAL_ID: u64 = 0o7777777;
+
+#[cfg(target_os = "linux")]
+fn read_xattrs(f: &impl std::os::fd::AsFd) -> Result<Vec<(String, Vec<u8>)>> {
+    let len = rustix::fs::flistxattr(f, &mut [0u8; 0])?;
+    let mut names = vec![0u8; len];
+    let len = rustix::fs::flistxattr(f, &mut names[..])?;
+    let mut r = Vec::new();
+    for name in names[..len].split(|&b| b == 0).filter(|n| !n.is_empty()) {
+        let name = std::ffi::CStr::from_bytes_with_nul(&[name, &[0]].concat())?.to_owned();
+        let len = rustix::fs::fgetxattr(f, name.as_c_str(), &mut [0u8; 0])?;
+        let mut value = vec![0u8; len];
+        let len = rustix::fs::fgetxattr(f, name.as_c_str(), &mut value[..])?;
+        value.truncate(len);
+        let name = name
+            .into_string()
+            .map_err(|e| anyhow::anyhow!("Invalid xattr name {:?}", e.into_cstring()))?;
+        r.push((format!("{XATTR_PAX_PREFIX}{name}"), value));
+    }
+    Ok(r)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn read_xattrs<T>(_f: &T) -> Result<Vec<(String, Vec<u8>)>> {
+    anyhow::bail!("Preserving xattrs is not supported on this platform")
+}
+
+/// Split a Linux `dev_t` into its major and minor numbers.
+fn dev_major_minor(rdev: u64) -> (u32, u32) {
+    let major = ((rdev >> 8) & 0xfff) | ((rdev >> 32) & !0xfff);
+    let minor = (rdev & 0xff) | ((rdev >> 12) & !0xff);
+    (major as u32, minor as u32)
+}
+
+/// Copy as much of `v` as fits into a fixed size header field.
+fn set_truncated(field: &mut [u8], v: &[u8]) {
+    field.fill(0);
+    let n = v.len().min(field.len());
+    field[..n].copy_from_slice(&v[..n]);
+}
+
+/// Called before each entry with its content size; returns true if it replaced
+/// the builder with one for a new layer.
+type BeforeEntry<'h, W> = dyn FnMut(&mut tar::Builder<W>, u64) -> Result<bool> + 'h;
+
+/// State while appending a directory tree.
+struct Appender<'o, 'b, 'h, W: std::io::Write> {
+    opts: &'o LayerTarOptions,
+    builder: &'b mut tar::Builder<W>,
+    /// The first path seen for each (device, inode) of multiply linked files.
+    links: HashMap<(u64, u64), PathBuf>,
+    before_entry: Option<&'h mut BeforeEntry<'h, W>>,
+}
+
+impl<'o, 'b, 'h, W: std::io::Write> Appender<'o, 'b, 'h, W> {
+    fn header_for(&self, meta: &Metadata, entry_type: tar::EntryType) -> tar::Header {
+        let mut h = match self.opts.format {
+            TarFormat::Gnu => tar::Header::new_gnu(),
+            TarFormat::Pax => tar::Header::new_ustar(),
+        };
+        h.set_entry_type(entry_type);
+        fsmeta::set_header(&mut h, meta);
+        h.set_size(0);
+        h
+    }
+
+    /// Append one entry, along with any extended headers it needs.
+    fn append(
+        &mut self,
+        mut h: tar::Header,
+        path: &Path,
+        link: Option<&Path>,
+        mut pax: Vec<(String, Vec<u8>)>,
+        data: impl Read,
+    ) -> Result<()> {
+        match self.opts.format {
+            TarFormat::Gnu => {
+                if !pax.is_empty() {
+                    self.builder.append_pax_extensions(
+                        pax.iter().map(|(k, v)| (k.as_str(), v.as_slice())),
+                    )?;
+                }
+                match link {
+                    Some(link) => self.builder.append_link(&mut h, path, link)?,
+                    None => self.builder.append_data(&mut h, path, data)?,
+                }
+            }
+            TarFormat::Pax => {
+                // The builder would use GNU extensions for long names, so ustar
+                // fields are set directly with PAX records as needed.
+                if h.set_path(path).is_err() {
+                    let path = path.as_os_str().as_encoded_bytes();
+                    pax.push(("path".into(), path.to_vec()));
+                    set_truncated(&mut h.as_old_mut().name, path);
+                }
+                if let Some(link) = link {
+                    if h.set_link_name(link).is_err() {
+                        let link = link.as_os_str().as_encoded_bytes();
+                        pax.push(("linkpath".into(), link.to_vec()));
+                        set_truncated(&mut h.as_old_mut().linkname, link);
+                    }
+                }
+                for (key, id) in [("uid", h.uid()?), ("gid", h.gid()?)] {
+                    if id > MAX_OCTAL_ID {
+                        pax.push((key.into(), id.to_string().into_bytes()));
+                    }
+                }
+                if !pax.is_empty() {
+                    self.builder.append_pax_extensions(
+                        pax.iter().map(|(k, v)| (k.as_str(), v.as_slice())),
+                    )?;
+                }
+                h.set_cksum();
+                self.builder.append(&h, data)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn metadata(&self, dir: &Dir, name: &Path) -> Result<Metadata> {
+        Ok(if self.opts.follow_symlinks {
+            dir.metadata(name)?
+        } else {
+            dir.symlink_metadata(name)?
+        })
+    }
+
+    fn append_dir(&mut self, dir: &Dir, prefix: &Path) -> Result<()> {
+        let mut names = dir
+            .entries()?
+            .map(|e| Ok(e?.file_name()))
+            .collect::<Result<Vec<_>>>()?;
+        names.sort();
+        for name in names {
+            let name = Path::new(&name);
+            let path = prefix.join(name);
+            let meta = self.metadata(dir, name)?;
+            self.append_entry(dir, name, &meta, &path)?;
+        }
+        Ok(())
+    }
+
+    fn append_entry(&mut self, dir: &Dir, name: &Path, meta: &Metadata, path: &Path) -> Result<()> {
+        let ft = meta.file_type();
+        if let Some(f) = self.before_entry.as_mut() {
+            let size = if ft.is_file() { meta.len() } else { 0 };
+            // Hard links must refer to a file in the same layer.
+            if f(self.builder, size)? {
+                self.links.clear();
+            }
+        }
+        if ft.is_dir() {
+            let h = self.header_for(meta, tar::EntryType::Directory);
+            let child = dir.open_dir(name)?;
+            let pax = if self.opts.xattrs {
+                read_xattrs(&child)?
+            } else {
+                Vec::new()
+            };
+            self.append(h, path, None, pax, std::io::empty())?;
+            self.append_dir(&child, path)
+        } else if ft.is_file() {
+            if let Some(key) = fsmeta::hard_link_id(meta).filter(|_| self.opts.hardlinks) {
+                if let Some(target) = self.links.get(&key).cloned() {
+                    let h = self.header_for(meta, tar::EntryType::Link);
+                    return self.append(h, path, Some(&target), Vec::new(), std::io::empty());
+                }
+                self.links.insert(key, path.to_owned());
+            }
+            let mut h = self.header_for(meta, tar::EntryType::Regular);
+            h.set_size(meta.len());
+            let f = dir.open(name)?;
+            let pax = if self.opts.xattrs {
+                read_xattrs(&f)?
+            } else {
+                Vec::new()
+            };
+            self.append(h, path, None, pax, f)
+        } else if ft.is_symlink() {
+            let h = self.header_for(meta, tar::EntryType::Symlink);
+            let target = dir.read_link_contents(name)?;
+            self.append(h, path, Some(&target), Vec::new(), std::io::empty())
+        } else if let Some((kind, rdev)) = fsmeta::special(&ft, meta) {
+            let mut h = self.header_for(meta, kind);
+            if kind != tar::EntryType::Fifo {
+                match self.opts.devices {
+                    DevicePolicy::Include => {}
+                    DevicePolicy::Skip => return Ok(()),
+                    DevicePolicy::Error => anyhow::bail!("Found device node {}", path.display()),
+                }
+                let (major, minor) = dev_major_minor(rdev);
+                h.set_device_major(major)?;
+                h.set_device_minor(minor)?;
+            }
+            self.append(h, path, None, Vec::new(), std::io::empty())
+        } else {
+            anyhow::bail!("Unsupported file type for {}", path.display())
+        }
+    }
+}
+
+impl LayerTarOptions {
+    /// Append the contents of `src`, but not `src` itself, to a tar stream. Entries
+    /// are added in sorted order; sockets are not supported.
+    #[context("Appending directory to layer")]
+    pub fn append_dir<W: std::io::Write>(
+        &self,
+        builder: &mut tar::Builder<W>,
+        src: &Dir,
+    ) -> Result<()> {
+        let mut appender = Appender {
+            opts: self,
+            builder,
+            links: HashMap::new(),
+            before_entry: None,
+        };
+        appender.append_dir(src, Path::new(""))
+    }
+}
+
+/// The size of a tar entry with `size` bytes of content, including its header.
+fn tar_entry_size(size: u64) -> u64 {
+    512 + size.div_ceil(512) * 512
+}
+
+/// An upper bound on the compressed size of `size` bytes of input, allowing
+/// for incompressible content and the end of the stream.
+fn compressed_bound(size: u64) -> u64 {
+    size + size / 512 + 2048
+}
+
+impl OciDir {
+    /// Create a tar output stream, backed by a blob, with the provided options.
+    ///
+    /// The format, xattr, hard link and device options only apply to
+    /// content added by [`LayerTarOptions::append_dir`].
+    pub fn create_layer_with(
+        &self,
+        opts: &LayerTarOptions,
+    ) -> Result<tar::Builder<GzipLayerWriter<'_>>> {
+        let mut builder = self.create_layer(opts.compression)?;
+        builder.follow_symlinks(opts.follow_symlinks);
+        Ok(builder)
+    }
+
+    /// Create a layer containing the contents of `src`.
+    #[context("Creating layer from directory")]
+    pub fn create_layer_from_dir(&self, src: &Dir, opts: &LayerTarOptions) -> Result<Layer> {
+        let mut builder = self.create_layer_with(opts)?;
+        opts.append_dir(&mut builder, src)?;
+        builder.into_inner()?.complete()
+    }
+
+    /// Create layers containing the contents of `src`, in the same order as
+    /// [`Self::create_layer_from_dir`], starting a new layer whenever the next entry
+    /// could make the compressed size exceed `max_layer_size`. The layers are
+    /// returned in order, to be pushed bottom to top.
+    ///
+    /// Files are kept whole, so a file which is larger than the budget by itself
+    /// results in a layer above it. Parent directories are only included in the
+    /// layer where they are first reached, and hard links are only recorded within a
+    /// single layer.
+    #[context("Creating layers from directory")]
+    pub fn create_layers_from_dir_chunked<'a>(
+        &'a self,
+        src: &Dir,
+        opts: &LayerTarOptions,
+        max_layer_size: u64,
+    ) -> Result<Vec<Layer>> {
+        let mut layers = Vec::new();
+        let mut builder = self.create_layer_with(opts)?;
+        // The compressed size when last synced, plus an upper bound for what was
+        // written since.
+        let mut synced = 0;
+        let mut unsynced = 0;
+        let mut empty = true;
+        let mut before_entry = |builder: &mut tar::Builder<GzipLayerWriter<'a>>, size: u64| {
+            let next = compressed_bound(tar_entry_size(size));
+            if !empty && synced + unsynced + next > max_layer_size {
+                synced = builder.get_mut().sync_compressed_size()?;
+                unsynced = 0;
+                if synced + next > max_layer_size {
+                    let full = std::mem::replace(builder, self.create_layer_with(opts)?);
+                    layers.push(full.into_inner()?.complete()?);
+                    synced = 0;
+                    unsynced = next;
+                    return Ok(true);
+                }
+            }
+            empty = false;
+            unsynced += next;
+            Ok(false)
+        };
+        let mut appender = Appender {
+            opts,
+            builder: &mut builder,
+            links: HashMap::new(),
+            before_entry: Some(&mut before_entry),
+        };
+        appender.append_dir(src, Path::new(""))?;
+        let last = builder.into_inner()?.complete()?;
+        layers.push(last);
+        Ok(layers)
+    }
+}
+
+// The test trees contain symbolic and hard links.
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use cap_std_ext::cap_tempfile;
+
+    fn entries(w: &OciDir, layer: &Layer) -> Result<Vec<(String, tar::EntryType, tar::Header)>> {
+        let (_, r) = w.open_blob_decompressed(&layer.descriptor().build()?)?;
+        let mut archive = tar::Archive::new(r);
+        let mut r = Vec::new();
+        for entry in archive.entries()? {
+            let entry = entry?;
+            let path = entry.path()?.to_string_lossy().into_owned();
+            r.push((path, entry.header().entry_type(), entry.header().clone()));
+        }
+        Ok(r)
+    }
+
+    #[test]
+    fn layer_from_dir() -> Result<()> {
+        let td = cap_tempfile::tempdir(cap_std::ambient_authority())?;
+        let long_dir = "d".repeat(120);
+        td.create_dir_all(format!("usr/{long_dir}"))?;
+        td.write("usr/a", "hello")?;
+        td.hard_link("usr/a", &td, "usr/b")?;
+        td.write(format!("usr/{long_dir}/file"), "long")?;
+        td.symlink_contents("a", "usr/link")?;
+        let w = OciDir::new_in_memory()?;
+
+        let layer = w.create_layer_from_dir(&td, &LayerTarOptions::default())?;
+        let e = entries(&w, &layer)?;
+        let names: Vec<_> = e.iter().map(|(p, _, _)| p.as_str()).collect();
+        let long_file = format!("usr/{long_dir}/file");
+        assert_eq!(
+            names,
+            [
+                "usr",
+                "usr/a",
+                "usr/b",
+                &format!("usr/{long_dir}"),
+                &long_file,
+                "usr/link"
+            ]
+        );
+        assert_eq!(e[2].1, tar::EntryType::Regular);
+        assert!(e.iter().all(|(_, _, h)| h.as_gnu().is_some()));
+
+        let opts = LayerTarOptions {
+            format: TarFormat::Pax,
+            hardlinks: true,
+            ..Default::default()
+        };
+        let layer = w.create_layer_from_dir(&td, &opts)?;
+        let e = entries(&w, &layer)?;
+        assert_eq!(e[2].0, "usr/b");
+        assert_eq!(e[2].1, tar::EntryType::Link);
+        assert_eq!(e[4].0, long_file);
+        assert_eq!(e[5].1, tar::EntryType::Symlink);
+        assert!(e.iter().all(|(_, _, h)| h.as_ustar().is_some()));
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn layer_xattrs() -> Result<()> {
+        let td = cap_tempfile::tempdir(cap_std::ambient_authority())?;
+        td.write("f", "x")?;
+        let f = td.open("f")?;
+        // Not all filesystems used for temporary directories support user xattrs.
+        if rustix::fs::fsetxattr(&f, "user.test", b"v", rustix::fs::XattrFlags::empty()).is_err() {
+            return Ok(());
+        }
+        let w = OciDir::new_in_memory()?;
+        let opts = LayerTarOptions {
+            xattrs: true,
+            ..Default::default()
+        };
+        let layer = w.create_layer_from_dir(&td, &opts)?;
+        let (_, r) = w.open_blob_decompressed(&layer.descriptor().build()?)?;
+        let mut archive = tar::Archive::new(r);
+        let mut entry = archive.entries()?.next().unwrap()?;
+        let found = entry
+            .pax_extensions()?
+            .unwrap()
+            .map(|e| {
+                let e = e?;
+                Ok((e.key()?.to_owned(), e.value_bytes().to_vec()))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        assert!(found.contains(&("SCHILY.xattr.user.test".into(), b"v".to_vec())));
+        Ok(())
+    }
+
+    #[test]
+    fn layers_from_dir_chunked() -> Result<()> {
+        // Incompressible content, so that the compressed sizes are predictable
+        let mut state = 0x9e3779b97f4a7c15u64;
+        let mut random = |n: usize| -> Vec<u8> {
+            (0..n)
+                .map(|_| {
+                    state ^= state << 13;
+                    state ^= state >> 7;
+                    state ^= state << 17;
+                    state as u8
+                })
+                .collect()
+        };
+        let td = cap_tempfile::tempdir(cap_std::ambient_authority())?;
+        td.create_dir_all("a/b")?;
+        for name in ["a/1", "a/2", "a/b/3", "a/b/4", "c"] {
+            td.write(name, random(20_000))?;
+        }
+        td.hard_link("a/1", &td, "a/b/5")?;
+        td.write("big", random(100_000))?;
+        let w = OciDir::new_in_memory()?;
+        let opts = LayerTarOptions {
+            hardlinks: true,
+            ..Default::default()
+        };
+        let max = 50_000;
+        let layers = w.create_layers_from_dir_chunked(&td, &opts, max)?;
+        assert!(layers.len() >= 4, "{}", layers.len());
+        let mut all = Vec::new();
+        for layer in &layers {
+            let e = entries(&w, layer)?;
+            // Hard links refer to a file in the same layer
+            for (_, t, h) in &e {
+                if *t == tar::EntryType::Link {
+                    let target = h.link_name()?.unwrap().to_string_lossy().into_owned();
+                    assert!(e.iter().any(|(p, _, _)| *p == target));
+                }
+            }
+            let big = e.iter().any(|(p, _, _)| p == "big");
+            assert!(big || layer.blob.size <= max, "{}", layer.blob.size);
+            all.extend(e.into_iter().map(|(p, _, _)| p));
+        }
+        let single = w.create_layer_from_dir(&td, &opts)?;
+        let expected: Vec<_> = entries(&w, &single)?.into_iter().map(|e| e.0).collect();
+        assert_eq!(all, expected);
+
+        let layers = w.create_layers_from_dir_chunked(&td, &opts, u64::MAX)?;
+        assert_eq!(layers.len(), 1);
+        assert_eq!(layers[0].diff_id(), single.diff_id());
+        Ok(())
+    }
+}
@@ -0,0 +1,213 @@
+//! Tunable layer writers for all supported compression formats.
+
+use std::io::Write;
+use std::num::NonZeroUsize;
+
+use anyhow::Result;
+use fn_error_context::context;
+
+use crate::{CompressionFormat, GzipLayerWriter, Layer, OciDir, UncompressedLayerWriter};
+
+/// The default capacity of the buffer for compressed output.
+pub(crate) const DEFAULT_BUFFER_SIZE: usize = 8192;
+
+/// Options for layer writers, see [`OciDir::create_layer_writer`].
+///
+/// Options which do not apply to the chosen compression format are ignored.
+#[derive(Debug, Clone, Default)]
+pub struct LayerWriterOptions {
+    /// The gzip compression level.
+    pub compression: Option<flate2::Compression>,
+    /// Compress using this many threads. If unset, compression happens on the
+    /// calling thread; [`std::thread::available_parallelism`] is a suitable value
+    /// for large layers. For zstd this sets the number of worker threads.
+    pub threads: Option<NonZeroUsize>,
+    /// The capacity of the buffer holding compressed output before it is written
+    /// to the blob.
+    pub buffer_size: Option<usize>,
+    /// The zstd compression level; `0` or unset selects the zstd default.
+    pub zstd_level: Option<i32>,
+    /// Enable zstd long-distance matching, which helps with large layers containing
+    /// repeated content far apart.
+    pub zstd_long_distance_matching: bool,
+    /// The base 2 logarithm of the zstd window size. Decompressors limit the window
+    /// size they accept, so values above 27 may not be readable everywhere.
+    pub zstd_window_log: Option<u32>,
+}
+
+/// Create an OCI tar+zstd layer.
+#[cfg(feature = "zstd")]
+pub struct ZstdLayerWriter<'a> {
+    enc: zstd::stream::write::Encoder<'static, std::io::BufWriter<crate::BlobWriter<'a>>>,
+    uncompressed_hash: crate::hash::Sha256,
+}
+
+#[cfg(feature = "zstd")]
+impl<'a> std::fmt::Debug for ZstdLayerWriter<'a> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ZstdLayerWriter")
+            .field("bw", self.enc.get_ref().get_ref())
+            .finish()
+    }
+}
+
+#[cfg(feature = "zstd")]
+impl<'a> ZstdLayerWriter<'a> {
+    fn new(bw: crate::BlobWriter<'a>, opts: &LayerWriterOptions) -> Result<Self> {
+        let bw =
+            std::io::BufWriter::with_capacity(opts.buffer_size.unwrap_or(DEFAULT_BUFFER_SIZE), bw);
+        let mut enc = zstd::stream::write::Encoder::new(bw, opts.zstd_level.unwrap_or_default())?;
+        if opts.zstd_long_distance_matching {
+            enc.long_distance_matching(true)?;
+        }
+        if let Some(window_log) = opts.zstd_window_log {
+            enc.window_log(window_log)?;
+        }
+        if let Some(threads) = opts.threads.filter(|t| t.get() > 1) {
+            enc.multithread(threads.get().try_into()?)?;
+        }
+        Ok(Self {
+            enc,
+            uncompressed_hash: crate::hash::Sha256::new()?,
+        })
+    }
+
+    /// Consume this writer, flushing buffered data and put the blob in place.
+    #[context("Completing layer")]
+    pub fn complete(mut self) -> Result<Layer> {
+        let bw = self.enc.finish()?;
+        let blob = bw.into_inner().map_err(|e| e.into_error())?.complete()?;
+        Ok(Layer {
+            blob,
+            uncompressed_sha256: self.uncompressed_hash.finish_hex()?,
+            media_type: oci_spec::image::MediaType::ImageLayerZstd,
+        })
+    }
+}
+
+#[cfg(feature = "zstd")]
+impl<'a> Write for ZstdLayerWriter<'a> {
+    fn write(&mut self, srcbuf: &[u8]) -> std::io::Result<usize> {
+        self.uncompressed_hash.update(srcbuf)?;
+        self.enc.write_all(srcbuf)?;
+        Ok(srcbuf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.enc.flush()
+    }
+}
+
+/// A layer writer for any [`CompressionFormat`], see [`OciDir::create_layer_writer`].
+#[derive(Debug)]
+#[allow(clippy::large_enum_variant)]
+pub enum LayerWriter<'a> {
+    /// An uncompressed layer.
+    Uncompressed(UncompressedLayerWriter<'a>),
+    /// A gzip compressed layer.
+    Gzip(GzipLayerWriter<'a>),
+    /// A zstd compressed layer.
+    #[cfg(feature = "zstd")]
+    Zstd(ZstdLayerWriter<'a>),
+}
+
+impl<'a> LayerWriter<'a> {
+    /// Consume this writer, flushing buffered data and put the blob in place.
+    pub fn complete(self) -> Result<Layer> {
+        match self {
+            Self::Uncompressed(w) => w.complete(),
+            Self::Gzip(w) => w.complete(),
+            #[cfg(feature = "zstd")]
+            Self::Zstd(w) => w.complete(),
+        }
+    }
+}
+
+impl<'a> Write for LayerWriter<'a> {
+    fn write(&mut self, srcbuf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Self::Uncompressed(w) => w.write(srcbuf),
+            Self::Gzip(w) => w.write(srcbuf),
+            #[cfg(feature = "zstd")]
+            Self::Zstd(w) => w.write(srcbuf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Self::Uncompressed(w) => w.flush(),
+            Self::Gzip(w) => w.flush(),
+            #[cfg(feature = "zstd")]
+            Self::Zstd(w) => w.flush(),
+        }
+    }
+}
+
+impl OciDir {
+    /// Create a writer for a new zstd+tar blob with the provided options; the
+    /// contents are not parsed, but are expected to be a tarball.
+    #[cfg(feature = "zstd")]
+    pub fn create_zstd_layer(&self, opts: &LayerWriterOptions) -> Result<ZstdLayerWriter<'_>> {
+        ZstdLayerWriter::new(crate::BlobWriter::new(&*self.store, &self.progress)?, opts)
+    }
+
+    /// Create a writer for a new tar blob compressed with `format`.
+    ///
+    /// Zstd requires the `zstd` feature.
+    #[context("Creating {format:?} layer writer")]
+    pub fn create_layer_writer(
+        &self,
+        format: CompressionFormat,
+        opts: &LayerWriterOptions,
+    ) -> Result<LayerWriter<'_>> {
+        let w = match format {
+            CompressionFormat::None => LayerWriter::Uncompressed(self.create_uncompressed_layer()?),
+            CompressionFormat::Gzip => LayerWriter::Gzip(self.create_gzip_layer_with(opts)?),
+            #[cfg(feature = "zstd")]
+            CompressionFormat::Zstd => LayerWriter::Zstd(self.create_zstd_layer(opts)?),
+            #[cfg(not(feature = "zstd"))]
+            CompressionFormat::Zstd => anyhow::bail!("zstd support is not enabled"),
+        };
+        Ok(w)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn layer_writer() -> Result<()> {
+        let w = OciDir::new_in_memory()?;
+        let data: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+        let opts = LayerWriterOptions {
+            buffer_size: Some(16),
+            zstd_level: Some(19),
+            zstd_long_distance_matching: true,
+            zstd_window_log: Some(20),
+            threads: NonZeroUsize::new(2),
+            ..Default::default()
+        };
+        let mut formats = vec![CompressionFormat::None, CompressionFormat::Gzip];
+        if cfg!(feature = "zstd") {
+            formats.push(CompressionFormat::Zstd);
+        } else {
+            assert!(w
+                .create_layer_writer(CompressionFormat::Zstd, &opts)
+                .is_err());
+        }
+        let mut diff_ids = Vec::new();
+        for format in formats {
+            let mut lw = w.create_layer_writer(format, &opts)?;
+            lw.write_all(&data)?;
+            let layer = lw.complete()?;
+            assert_eq!(layer.media_type, format.layer_media_type());
+            let desc = layer.descriptor().build()?;
+            assert_eq!(w.open_blob_decompressed(&desc)?.0, format);
+            assert_eq!(w.compute_diffid(&desc)?, layer.diff_id());
+            diff_ids.push(layer.diff_id());
+        }
+        assert!(diff_ids.windows(2).all(|w| w[0] == w[1]));
+        Ok(())
+    }
+}
@@ -0,0 +1,83 @@
+//! The `oci-layout` marker file and its version.
+
+use anyhow::{anyhow, Context, Result};
+use oci_spec::image::OciLayout;
+
+use crate::OciDir;
+
+/// The `imageLayoutVersion` written to new layouts.
+pub const OCI_LAYOUT_VERSION: &str = "1.0.0";
+/// The major layout version this crate understands; layouts with a newer
+/// major version are rejected when opened.
+pub const SUPPORTED_LAYOUT_MAJOR: u64 = 1;
+pub(crate) const OCI_LAYOUT_FILE: &str = "oci-layout";
+
+/// Parse a `major.minor.patch` version, ignoring any pre-release or build suffix.
+pub(crate) fn parse_version(v: &str) -> Result<(u64, u64, u64)> {
+    let core = v.split(['-', '+']).next().unwrap_or_default();
+    let mut parts = core.split('.').map(|p| p.parse::<u64>());
+    match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(Ok(major)), Some(Ok(minor)), Some(Ok(patch)), None) => Ok((major, minor, patch)),
+        _ => Err(anyhow!("Invalid image layout version {v:?}")),
+    }
+}
+
+/// Check that a layout version can be operated on by this crate.
+pub(crate) fn check_version(v: &str) -> Result<()> {
+    let (major, _, _) = parse_version(v)?;
+    if major > SUPPORTED_LAYOUT_MAJOR {
+        anyhow::bail!("Unsupported image layout version {v}");
+    }
+    Ok(())
+}
+
+/// Parse and validate the contents of an `oci-layout` file, returning the version.
+pub(crate) fn parse_layout(buf: &[u8]) -> Result<String> {
+    let layout = OciLayout::from_reader(buf).context("Parsing oci-layout")?;
+    let version = layout.image_layout_version();
+    check_version(version)?;
+    Ok(version.to_owned())
+}
+
+impl OciDir {
+    /// The `imageLayoutVersion` from the `oci-layout` file.
+    pub fn layout_version(&self) -> Result<String> {
+        let buf = self
+            .store
+            .read_meta(OCI_LAYOUT_FILE)?
+            .ok_or_else(|| anyhow!("Missing {OCI_LAYOUT_FILE}"))?;
+        parse_layout(&buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cap_std_ext::{cap_std, cap_tempfile};
+
+    #[test]
+    fn layout_version() -> Result<()> {
+        assert_eq!(parse_version("1.0.0")?, (1, 0, 0));
+        assert_eq!(parse_version("1.2.3-rc.1")?, (1, 2, 3));
+        assert!(parse_version("1.0").is_err());
+        assert!(parse_version("1.0.0.0").is_err());
+
+        let td = cap_tempfile::tempdir(cap_std::ambient_authority())?;
+        assert!(OciDir::open(&td).is_err());
+        let w = OciDir::ensure(&td)?;
+        assert_eq!(w.layout_version()?, OCI_LAYOUT_VERSION);
+        let w = OciDir::ensure_with_version(&td, "1.1.0", &Default::default())?;
+        assert_eq!(w.layout_version()?, "1.1.0");
+        // Never downgraded
+        OciDir::ensure_with_version(&td, "1.0.0", &Default::default())?;
+        assert_eq!(OciDir::open(&td)?.layout_version()?, "1.1.0");
+        assert!(OciDir::ensure_with_version(&td, "2.0.0", &Default::default()).is_err());
+
+        td.write(OCI_LAYOUT_FILE, r#"{"imageLayoutVersion":"2.0.0"}"#)?;
+        let err = OciDir::open(&td).unwrap_err();
+        assert!(format!("{err:#}").contains("Unsupported"), "{err:#}");
+        td.write(OCI_LAYOUT_FILE, "not json")?;
+        assert!(OciDir::open(&td).is_err());
+        Ok(())
+    }
+}
@@ -30,40 +30,175 @@
 //! such as synthesizing tar layers; [`OciDir::push_layer`] for example can
 //! be used for this.
 //!
+//! ## WebAssembly
+//!
+//! The crate builds for `wasm32-wasip1` with the default `rust-crypto` hashing
+//! backend; the OpenSSL based features and `mmap` are not supported there. A
+//! layout can be read through a preopened directory, or through any
+//! [`store::BlobStore`] with [`OciDir::with_store`], for example one backed by
+//! memory provided by the host.
+//!
 //! [cap-std]: https://docs.rs/cap-std/
 //! [OCI images]: https://github.com/opencontainers/image-spec
 //!
 
 use anyhow::{anyhow, Context, Result};
-use camino::Utf8Path;
-use cap_std::fs::{Dir, DirBuilderExt};
-use cap_std_ext::cap_tempfile;
+use base64::prelude::*;
+use camino::{Utf8Path, Utf8PathBuf};
+use cap_std::fs::Dir;
 use cap_std_ext::dirext::CapStdExtDirExt;
 use flate2::write::GzEncoder;
 use fn_error_context::context;
 use oci_image::MediaType;
 use oci_spec::image::{self as oci_image, Descriptor, ImageIndex};
-use openssl::hash::{Hasher, MessageDigest};
-use serde::Serialize;
 use std::collections::HashMap;
 use std::fmt::Debug;
 use std::fs::File;
 use std::io::{prelude::*, BufReader};
-use std::path::{Path, PathBuf};
+use std::path::Path;
+use std::sync::Arc;
 
 // Re-export our dependencies that are used as part of the public API.
 pub use cap_std_ext::cap_std;
+pub use chrono;
 pub use oci_spec;
 
+/// Emit a `tracing` event at debug level, if the `tracing` feature is enabled.
+macro_rules! trace_event {
+    ($($arg:tt)*) => {
+        #[cfg(feature = "tracing")]
+        tracing::debug!($($arg)*);
+    };
+}
+
+mod attest;
+pub use attest::{
+    SbomFormat, DSSE_ENVELOPE_MEDIA_TYPE, IN_TOTO_ARTIFACT_TYPE, PREDICATE_TYPE_ANNOTATION,
+};
+pub mod batch;
+mod cache;
+pub use cache::{LayerCache, MemoryLayerCache};
+mod canonical;
+pub use canonical::to_canonical_json;
+#[cfg(feature = "cdc")]
+pub mod cdc;
+mod checksums;
+pub use checksums::CHECKSUMS_FILE;
+pub mod chunked;
+mod clock;
+pub use clock::{Clock, FixedClock, SystemClock};
+mod clone;
+pub use clone::CloneMode;
+mod compression;
+mod config;
+pub use compression::{CompressionFormat, LayerFormatPolicy, MediaTypeMismatch};
+pub use config::ImageConfigExt;
+mod created;
+pub use created::{effective_created, TaggedImage};
+mod describe;
+mod diff;
+mod diffid;
+mod edit;
+pub use diffid::DIFFID_CACHE_FILE;
+#[cfg(feature = "encrypt")]
+pub mod encrypt;
+mod entries;
+pub use diff::{diff_layouts, ChangedTag, LayoutDiff, ManifestDiff};
+pub use entries::{LayerEntries, LayerEntry};
+mod export;
+mod extract;
+pub use extract::{IdMapping, LinkPolicy, Ownership, SetuidPolicy, UnpackOptions};
+mod filter;
+pub use filter::{FilterAction, FilterEntry};
+mod fsck;
+mod fsmeta;
+mod history;
+mod image;
+pub use image::ImageBuilder;
+mod index;
+pub use fsck::{
+    FsckAction, FsckCache, FsckOptions, FsckReport, PruneOptions, PruneReport, PrunedBlob,
+    FSCK_STATE_FILE,
+};
+pub use history::{source_date_epoch, HistoryExt, SOURCE_DATE_EPOCH};
+mod inspect;
+pub use inspect::ImageSummary;
+mod journal;
+pub use journal::{JournalEntry, JournalSink, JOURNAL_FILE};
+mod layerdiff;
+mod layerfile;
+mod layerwriter;
+mod layout;
+mod limits;
+pub use layerdiff::{LayerDiffBuilder, OPAQUE_WHITEOUT, WHITEOUT_PREFIX};
+#[cfg(feature = "zstd")]
+pub use layerwriter::ZstdLayerWriter;
+pub use layerwriter::{LayerWriter, LayerWriterOptions};
+pub use layout::{OCI_LAYOUT_VERSION, SUPPORTED_LAYOUT_MAJOR};
+pub use limits::{BlobTooLarge, JsonSizeLimits};
+mod layertar;
+pub use layertar::{DevicePolicy, LayerTarOptions, TarFormat};
+pub mod layers;
+pub mod model;
+mod mtime;
+mod pargz;
+pub mod progress;
+mod range;
+use progress::{BlobProgress, Progress, ProgressOp, ProgressReader};
+#[cfg(feature = "mmap")]
+pub use range::MappedBlob;
+mod recover;
+mod reference;
+pub use reference::{Reference, ReferenceMatch};
+mod referrers;
+pub use referrers::{empty_descriptor, EMPTY_BLOB_DIGEST};
+mod remove;
+pub use remove::{RemoveOptions, RemoveReport};
+mod retention;
+mod reuse;
+pub use retention::{RetentionPolicy, RetentionReport, RetentionRule};
+#[cfg(feature = "rust-crypto")]
+mod resumable;
+#[cfg(feature = "rust-crypto")]
+pub use resumable::ResumableBlobWriter;
+#[cfg(feature = "registry")]
+pub mod registry;
+mod session;
+mod shard;
+pub use session::{BuildSession, BuildSessionOptions, LayerSlot, SessionLayerWriter};
+#[cfg(feature = "sign")]
+pub mod sign;
+mod squash;
+pub mod stargz;
+pub use describe::{LayoutDescription, LayoutExtension};
+pub mod hash;
+use hash::Sha256;
+pub mod store;
+mod sync;
+pub use sync::{SyncConflict, SyncOptions, SyncReport};
+mod throttle;
+mod validate;
+pub use validate::{ValidationLevel, Violation, ViolationKind};
+mod verify;
+use store::{BlobStore, MemoryStore, StagedBlob};
+pub use verify::VerifyPolicy;
+mod walk;
+pub use walk::{WalkControl, WalkNode};
+
+/// Path inside an OCI directory to the per-algorithm blob directories
+const BLOBS: &str = "blobs";
 /// Path inside an OCI directory to the blobs
 const BLOBDIR: &str = "blobs/sha256";
 /// Length of a hex-formatted sha256
 const BLOB_SHA256_LEN: usize = 64;
 
+/// Contents of the `oci-layout` file for newly created layouts.
+const OCI_LAYOUT_DEFAULT: &str = r#"{"imageLayoutVersion":"1.0.0"}"#;
+
 const OCI_TAG_ANNOTATION: &str = "org.opencontainers.image.ref.name";
 
 /// Completed blob metadata
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Blob {
     /// SHA-256 digest
     pub sha256: String,
@@ -86,28 +221,109 @@ impl Blob {
 }
 
 /// Completed layer metadata
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Layer {
     /// The underlying blob (usually compressed)
     pub blob: Blob,
     /// The uncompressed digest, which will be used for "diffid"s
     pub uncompressed_sha256: String,
+    /// The media type of the layer blob, set by the writer which produced it
+    /// according to its compression; see [`Self::with_media_type`].
+    pub media_type: MediaType,
 }
 
 impl Layer {
     /// Return the descriptor for this layer
     pub fn descriptor(&self) -> oci_image::DescriptorBuilder {
-        self.blob.descriptor()
+        self.blob.descriptor().media_type(self.media_type.clone())
+    }
+
+    /// The digest of the uncompressed layer, as used in the config `rootfs.diff_ids`
+    pub fn diff_id(&self) -> String {
+        format!("sha256:{}", self.uncompressed_sha256)
+    }
+
+    /// Override the media type, for example to push a layer as
+    /// [`MediaType::ImageLayerNonDistributableGzip`] or with a vendor-specific type.
+    /// The blob is not changed, so the media type must still describe its content.
+    pub fn with_media_type(mut self, media_type: MediaType) -> Self {
+        self.media_type = media_type;
+        self
+    }
+}
+
+/// Compute the [chain IDs] for a stack of layers, given their diff_ids from
+/// the root upwards.
+///
+/// [chain IDs]: https://github.com/opencontainers/image-spec/blob/main/config.md#layer-chainid
+pub fn chain_ids(diff_ids: &[String]) -> Vec<String> {
+    let mut r: Vec<String> = Vec::with_capacity(diff_ids.len());
+    for diff_id in diff_ids {
+        let chain_id = match r.last() {
+            None => diff_id.clone(),
+            Some(parent) => {
+                let v = hash::sha256_hex(format!("{parent} {diff_id}").as_bytes())
+                    .expect("sha256 hashing");
+                format!("sha256:{v}")
+            }
+        };
+        r.push(chain_id);
+    }
+    r
+}
+
+/// A seekable reader, as returned by a custom [`store::BlobStore`] in [`BlobReader::Other`].
+pub trait ReadSeek: Read + Seek + Send + Debug {}
+
+impl<T: Read + Seek + Send + Debug> ReadSeek for T {}
+
+/// A reader for blob content, which may be stored on disk or embedded in a descriptor.
+#[derive(Debug)]
+pub enum BlobReader {
+    /// A blob file in the layout.
+    File(File),
+    /// Content held in memory, either embedded in a descriptor or from an in-memory layout.
+    Memory(std::io::Cursor<Arc<[u8]>>),
+    /// Content streamed by a custom store, such as one backed by object storage.
+    Other(Box<dyn ReadSeek>),
+}
+
+impl Read for BlobReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            BlobReader::File(f) => f.read(buf),
+            BlobReader::Memory(c) => c.read(buf),
+            BlobReader::Other(r) => r.read(buf),
+        }
+    }
+}
+
+impl Seek for BlobReader {
+    fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+        match self {
+            BlobReader::File(f) => f.seek(pos),
+            BlobReader::Memory(c) => c.seek(pos),
+            BlobReader::Other(r) => r.seek(pos),
+        }
     }
 }
 
 /// Create an OCI blob.
 pub struct BlobWriter<'a> {
     /// Compute checksum
-    pub hash: Hasher,
+    pub hash: Sha256,
     /// Target file
-    pub target: Option<cap_tempfile::TempFile<'a>>,
+    target: Option<Box<dyn StagedBlob + 'a>>,
+    store: &'a dyn BlobStore,
     size: u64,
+    /// The expected digest and size, if known.
+    expected: Option<(String, u64)>,
+    /// Skip hashing, and trust that the content matches the expected digest.
+    trusted: bool,
+    progress: BlobProgress,
+    /// Covers the lifetime of the writer; the digest and size are recorded on completion.
+    #[cfg(feature = "tracing")]
+    span: tracing::Span,
 }
 
 impl<'a> Debug for BlobWriter<'a> {
@@ -122,8 +338,20 @@ impl<'a> Debug for BlobWriter<'a> {
 /// Create an OCI tar+gzip layer.
 pub struct GzipLayerWriter<'a> {
     bw: BlobWriter<'a>,
-    uncompressed_hash: Hasher,
-    compressor: GzEncoder<Vec<u8>>,
+    uncompressed_hash: Sha256,
+    compressor: GzipCompressor,
+}
+
+#[derive(Debug)]
+enum GzipCompressor {
+    Serial(GzEncoder<Vec<u8>>),
+    Parallel(pargz::ParallelGzip),
+}
+
+/// Create an uncompressed OCI tar layer.
+#[derive(Debug)]
+pub struct UncompressedLayerWriter<'a> {
+    bw: BlobWriter<'a>,
 }
 
 impl<'a> Debug for GzipLayerWriter<'a> {
@@ -135,11 +363,25 @@ impl<'a> Debug for GzipLayerWriter<'a> {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 /// An opened OCI directory.
 pub struct OciDir {
-    /// The underlying directory.
-    pub dir: std::sync::Arc<Dir>,
+    /// The underlying storage.
+    store: Arc<dyn BlobStore>,
+    opts: OciDirOptions,
+    progress: Progress,
+    /// Serializes index updates, see [`index::IndexLock`].
+    index_lock: Arc<std::sync::Mutex<()>>,
+}
+
+/// Options for writing JSON blobs, see [`write_json_blob_with`].
+#[derive(Debug, Clone, Default)]
+pub struct JsonBlobOptions {
+    /// Blobs with a serialized size at or below this threshold also have their
+    /// contents embedded in the descriptor `data` field.
+    pub inline_threshold: Option<u64>,
+    /// Serialize as canonical JSON, see [`to_canonical_json`].
+    pub canonical: bool,
 }
 
 /// Write a serializable data (JSON) as an OCI blob
@@ -149,11 +391,75 @@ pub fn write_json_blob<S: serde::Serialize>(
     v: &S,
     media_type: oci_image::MediaType,
 ) -> Result<oci_image::DescriptorBuilder> {
-    let mut w = BlobWriter::new(ocidir)?;
-    let mut ser = serde_json::Serializer::new(&mut w);
-    v.serialize(&mut ser).context("Failed to serialize")?;
+    write_json_blob_with(ocidir, v, media_type, &JsonBlobOptions::default())
+}
+
+/// Write a serializable data (JSON) as an OCI blob, with the provided options.
+#[context("Writing json blob")]
+pub fn write_json_blob_with<S: serde::Serialize>(
+    ocidir: &Dir,
+    v: &S,
+    media_type: oci_image::MediaType,
+    opts: &JsonBlobOptions,
+) -> Result<oci_image::DescriptorBuilder> {
+    write_json_blob_to_store(ocidir, &Progress::default(), v, media_type, opts)
+}
+
+/// Serialize a value as JSON, optionally in canonical form.
+fn serialize_json<S: serde::Serialize>(v: &S, canonical: bool) -> Result<Vec<u8>> {
+    if canonical {
+        to_canonical_json(v)
+    } else {
+        serde_json::to_vec(v).context("Failed to serialize")
+    }
+}
+
+fn write_json_blob_to_store<S: serde::Serialize>(
+    store: &dyn BlobStore,
+    progress: &Progress,
+    v: &S,
+    media_type: oci_image::MediaType,
+    opts: &JsonBlobOptions,
+) -> Result<oci_image::DescriptorBuilder> {
+    let buf = serialize_json(v, opts.canonical)?;
+    let mut w = BlobWriter::new(store, progress)?;
+    w.write_all(&buf)?;
     let blob = w.complete()?;
-    Ok(blob.descriptor().media_type(media_type))
+    let mut builder = blob.descriptor().media_type(media_type);
+    if opts
+        .inline_threshold
+        .is_some_and(|threshold| blob.size <= threshold)
+    {
+        builder = builder.data(BASE64_STANDARD.encode(&buf));
+    }
+    Ok(builder)
+}
+
+/// A recursive directory builder; on Unix, new directories are created with mode `0755`.
+fn dir_builder() -> cap_std::fs::DirBuilder {
+    let mut db = cap_std::fs::DirBuilder::new();
+    db.recursive(true);
+    #[cfg(unix)]
+    {
+        use cap_std::fs::DirBuilderExt;
+        db.mode(0o755);
+    }
+    db
+}
+
+/// Atomically write the file `name` in `dir`. On Windows, where
+/// [`CapStdExtDirExt::atomic_write`] is unavailable, a temporary file is renamed
+/// into place.
+fn atomic_write(dir: &Dir, name: impl AsRef<Path>, contents: impl AsRef<[u8]>) -> Result<()> {
+    #[cfg(not(windows))]
+    dir.atomic_write(name, contents)?;
+    #[cfg(windows)]
+    {
+        let mut f = cap_std_ext::cap_tempfile::TempFile::new(dir)?;
+        f.write_all(contents.as_ref())?;
+        f.replace(name.as_ref())?;
+    }
+    Ok(())
 }
 
 // Parse a filename from a string; this will ignore any directory components, and error out on `/` and `..` for example.
@@ -184,57 +490,383 @@ pub fn new_empty_manifest() -> oci_image::ImageManifestBuilder {
         .layers(Vec::new())
 }
 
+/// A manifest referenced from the index, see [`OciDir::manifests`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ManifestEntry {
+    /// The index descriptor.
+    pub descriptor: Descriptor,
+    /// The tag, from the `org.opencontainers.image.ref.name` annotation.
+    pub tag: Option<String>,
+    /// The platform of the descriptor.
+    pub platform: Option<oci_image::Platform>,
+}
+
+/// The config of a manifest, see [`OciDir::insert_manifest_and_config`].
+#[derive(Debug, Clone)]
+pub enum ManifestConfig {
+    /// An image configuration, which is written with [`OciDir::write_config`].
+    Image(oci_image::ImageConfiguration),
+    /// A config blob which is already present, with any media type; for example
+    /// one written with [`OciDir::write_blob_with_media_type`].
+    Descriptor(Descriptor),
+}
+
+impl From<oci_image::ImageConfiguration> for ManifestConfig {
+    fn from(config: oci_image::ImageConfiguration) -> Self {
+        Self::Image(config)
+    }
+}
+
+impl From<Descriptor> for ManifestConfig {
+    fn from(desc: Descriptor) -> Self {
+        Self::Descriptor(desc)
+    }
+}
+
+/// Options for [`OciDir::insert_manifest_with`].
+#[derive(Debug, Clone, Default)]
+pub struct InsertOptions {
+    /// The tag of the index entry; any existing entry with the same tag is replaced.
+    pub tag: Option<String>,
+    /// The platform of the index entry, which is not meaningful for artifacts.
+    pub platform: Option<oci_image::Platform>,
+    /// Annotations for the index entry, merged with the tag annotation (which
+    /// takes precedence).
+    pub annotations: HashMap<String, String>,
+    /// Annotations added to the manifest itself before it is written, replacing
+    /// any existing values for the same keys.
+    pub manifest_annotations: HashMap<String, String>,
+    /// Check with [`OciDir::verify_references`] that the config and layers are
+    /// present with the right sizes before anything is written.
+    pub verify_references: bool,
+}
+
+/// Options controlling how an OCI directory is opened.
+#[derive(Debug, Clone, Default)]
+pub struct OciDirOptions {
+    /// If set, run [`OciDir::recover`] with this age threshold after opening.
+    pub recover_older_than: Option<std::time::Duration>,
+    /// Verify manifests with [`OciDir::verify_manifest`] before inserting them into the index,
+    /// and config history with [`HistoryExt::validate_history`] before writing configs.
+    pub strict_manifests: bool,
+    /// Whether to verify blob digests in [`OciDir::read_blob`].
+    pub verify: VerifyPolicy,
+    /// Append a record of each change to the index, and of each blob written or
+    /// removed, to [`JOURNAL_FILE`]; see [`OciDir::read_journal`].
+    pub journal: bool,
+    /// Also pass each journal entry to this sink, whether or not [`Self::journal`]
+    /// is set.
+    pub journal_sink: Option<Arc<dyn JournalSink>>,
+    /// Skip checking the size and digest of blobs in [`OciDir::read_json_blob`]
+    /// against their descriptor.
+    pub trust_json_blobs: bool,
+    /// Create the temporary files for new blobs in this directory relative to the
+    /// layout, instead of the layout root; see [`store::StagingDir`]. For example
+    /// `blobs/tmp` when `blobs` is a separate mount. This is only used when opening
+    /// a directory, not with [`OciDir::with_store`].
+    pub staging_dir: Option<Utf8PathBuf>,
+    /// Reuse previously built layers in [`OciDir::cached_layer`] and
+    /// [`OciDir::create_layer_from_dir_cached`].
+    pub layer_cache: Option<Arc<dyn LayerCache>>,
+    /// Write manifests, configs and the index as canonical JSON, so that their
+    /// digests only depend on their contents; see [`to_canonical_json`].
+    pub canonical_json: bool,
+    /// The source of timestamps for history entries, config `created` fields and
+    /// the journal. By default [`SOURCE_DATE_EPOCH`] is used if set, and otherwise
+    /// the current time.
+    pub clock: Option<Arc<dyn Clock>>,
+    /// How [`OciDir::open_layer`] handles layers whose media type does not match
+    /// their compression format.
+    pub layer_format: LayerFormatPolicy,
+    /// Store new blobs in two-character prefix subdirectories; see [`store::ShardedDir`].
+    /// This is only used when opening a directory, not with [`OciDir::with_store`].
+    pub shard_blobs: bool,
+    /// The maximum sizes of JSON blobs parsed by [`OciDir::read_json_blob`].
+    pub json_limits: JsonSizeLimits,
+    /// Set the modification time of each blob and metadata file written through
+    /// this layout to this value, as [`OciDir::normalize_timestamps`] does.
+    pub normalize_mtime: Option<chrono::DateTime<chrono::Utc>>,
+}
+
 impl OciDir {
     /// Open the OCI directory at the target path; if it does not already
     /// have the standard OCI metadata, it is created.
-    #[context("Opening OCI dir")]
     pub fn ensure(dir: &Dir) -> Result<Self> {
-        let mut db = cap_std::fs::DirBuilder::new();
-        db.recursive(true).mode(0o755);
+        Self::ensure_with(dir, &OciDirOptions::default())
+    }
+
+    /// Like [`Self::ensure`], but with the provided options.
+    pub fn ensure_with(dir: &Dir, opts: &OciDirOptions) -> Result<Self> {
+        Self::ensure_with_version(dir, OCI_LAYOUT_VERSION, opts)
+    }
+
+    /// Like [`Self::ensure_with`], but new layouts are marked with the provided
+    /// `imageLayoutVersion`. An existing layout with an older version of the same
+    /// major version is upgraded.
+    #[context("Opening OCI dir with layout version {version}")]
+    pub fn ensure_with_version(dir: &Dir, version: &str, opts: &OciDirOptions) -> Result<Self> {
+        layout::check_version(version)?;
+        let db = dir_builder();
         dir.ensure_dir_with(BLOBDIR, &db)?;
-        if !dir.try_exists("oci-layout")? {
-            dir.atomic_write("oci-layout", r#"{"imageLayoutVersion":"1.0.0"}"#)?;
+        let upgrade = match dir.read_optional(layout::OCI_LAYOUT_FILE)? {
+            Some(buf) => {
+                let existing = layout::parse_layout(&buf)?;
+                layout::parse_version(&existing)? < layout::parse_version(version)?
+            }
+            None => true,
+        };
+        if upgrade {
+            let layout = oci_image::OciLayoutBuilder::default()
+                .image_layout_version(version)
+                .build()?;
+            atomic_write(dir, layout::OCI_LAYOUT_FILE, serde_json::to_vec(&layout)?)?;
+            if let Some(time) = opts.normalize_mtime {
+                mtime::set_mtime(dir, layout::OCI_LAYOUT_FILE, time.into())?;
+            }
         }
-        Self::open(dir)
+        Self::open_with(dir, opts)
     }
 
-    /// Clone an OCI directory, using reflinks for blobs.
-    pub fn clone_to(&self, destdir: &Dir, p: impl AsRef<Path>) -> Result<Self> {
-        let p = p.as_ref();
-        destdir.create_dir(p)?;
-        let cloned = Self::ensure(&destdir.open_dir(p)?)?;
-        for blob in self.dir.read_dir(BLOBDIR)? {
-            let blob = blob?;
-            let path = Path::new(BLOBDIR).join(blob.file_name());
-            let mut src = self.dir.open(&path).map(BufReader::new)?;
-            self.dir
-                .atomic_replace_with(&path, |w| std::io::copy(&mut src, w))?;
-        }
-        Ok(cloned)
+    /// Clone an OCI directory into the new subdirectory `p` of `destdir`,
+    /// using [`CloneMode::Auto`].
+    pub fn clone_to(&self, destdir: &Dir, p: impl AsRef<Utf8Path>) -> Result<Self> {
+        self.clone_to_with(destdir, p, CloneMode::Auto)
     }
 
-    /// Open an existing OCI directory.
+    /// Open an existing OCI directory. Its `oci-layout` file must have a
+    /// supported version, see [`Self::layout_version`].
     pub fn open(dir: &Dir) -> Result<Self> {
-        let dir = std::sync::Arc::new(dir.try_clone()?);
-        Ok(Self { dir })
+        Self::open_with(dir, &OciDirOptions::default())
+    }
+
+    /// Open an existing OCI directory with the provided options.
+    pub fn open_with(dir: &Dir, opts: &OciDirOptions) -> Result<Self> {
+        let store: Arc<dyn BlobStore> = match opts.staging_dir.as_deref() {
+            Some(staging) => Arc::new(
+                store::StagingDir::new(dir.try_clone()?, staging)?.sharded(opts.shard_blobs),
+            ),
+            None if opts.shard_blobs => Arc::new(store::ShardedDir::new(dir.try_clone()?)),
+            None => Arc::new(dir.try_clone()?),
+        };
+        Self::with_store(store, opts)
+    }
+
+    /// Open an existing OCI directory without ever modifying it, for example
+    /// on read-only media. All operations which would write fail with an error.
+    pub fn open_readonly(dir: &Dir) -> Result<Self> {
+        Self::open_readonly_with(dir, &OciDirOptions::default())
+    }
+
+    /// Like [`Self::open_readonly`], but with the provided options.
+    #[context("Opening OCI dir read-only")]
+    pub fn open_readonly_with(dir: &Dir, opts: &OciDirOptions) -> Result<Self> {
+        Self::with_store(Arc::new(store::ReadOnlyDir::new(dir.try_clone()?)), opts)
+    }
+
+    /// Returns true if this layout refuses modifications; see [`Self::open_readonly`].
+    pub fn is_read_only(&self) -> bool {
+        self.store.is_read_only()
+    }
+
+    /// Open a layout backed by a custom [`BlobStore`]. Nothing is written;
+    /// to create a new layout, the store must already hold an `oci-layout` file.
+    #[context("Opening OCI dir")]
+    pub fn with_store(store: Arc<dyn BlobStore>, opts: &OciDirOptions) -> Result<Self> {
+        let layout = store
+            .read_meta("oci-layout")?
+            .ok_or_else(|| anyhow!("Missing oci-layout"))?;
+        layout::parse_layout(&layout)?;
+        let store: Arc<dyn BlobStore> = match opts.normalize_mtime {
+            Some(time) => Arc::new(mtime::MtimeStore::new(store, time)),
+            None => store,
+        };
+        let store: Arc<dyn BlobStore> = if opts.journal_enabled() {
+            Arc::new(journal::JournaledStore::new(store, opts))
+        } else {
+            store
+        };
+        let r = Self {
+            store,
+            opts: opts.clone(),
+            progress: Default::default(),
+            index_lock: Default::default(),
+        };
+        if let Some(min_age) = opts.recover_older_than {
+            r.recover(min_age)?;
+        }
+        Ok(r)
+    }
+
+    /// Create a new empty OCI layout which is held entirely in memory.
+    ///
+    /// This is useful for tests and short-lived pipelines; the result
+    /// can be written to disk with [`Self::persist_to`].
+    pub fn new_in_memory() -> Result<Self> {
+        let store = MemoryStore::default();
+        store.write_meta("oci-layout", OCI_LAYOUT_DEFAULT.as_bytes())?;
+        Ok(Self {
+            store: Arc::new(store),
+            opts: Default::default(),
+            progress: Default::default(),
+            index_lock: Default::default(),
+        })
+    }
+
+    /// Write all blobs and the index of this layout into `dir`, which is
+    /// initialized as an OCI directory if necessary.
+    #[context("Persisting OCI dir")]
+    pub fn persist_to(&self, dir: &Dir) -> Result<Self> {
+        let dest = Self::ensure(dir)?;
+        self.copy_contents_to(&dest, CloneMode::Copy)?;
+        Ok(dest)
+    }
+
+    /// Return a handle to this layout which reports progress of all blob operations,
+    /// including those of writers created from it, to the provided reporter.
+    pub fn with_progress(&self, reporter: Arc<dyn progress::ProgressReporter>) -> Self {
+        Self {
+            progress: self.progress.with_reporter(reporter),
+            ..self.clone()
+        }
+    }
+
+    /// The underlying directory, unless this layout is stored in memory or in a
+    /// custom store. This replaces the public `dir` field of earlier versions.
+    pub fn dir(&self) -> Option<&Dir> {
+        self.store.as_dir()
+    }
+
+    /// The underlying directory for operations which modify it directly.
+    fn writable_dir(&self) -> Result<Option<&Dir>> {
+        if self.is_read_only() {
+            anyhow::bail!("Layout is read-only");
+        }
+        Ok(self.dir())
+    }
+
+    /// The storage backend of this layout.
+    pub fn store(&self) -> &Arc<dyn BlobStore> {
+        &self.store
+    }
+
+    /// Open a blob by digest, also returning its size.
+    fn open_blob_sized(&self, digest: &str) -> Result<(BlobReader, u64)> {
+        let mut f = self
+            .store
+            .get(digest)?
+            .ok_or_else(|| anyhow!("Blob {digest} disappeared"))?;
+        let size = f.seek(std::io::SeekFrom::End(0))?;
+        f.rewind()?;
+        Ok((f, size))
+    }
+
+    /// Copy all blobs and the index into another layout, sharing blobs
+    /// where possible according to `mode`.
+    fn copy_contents_to(&self, dest: &OciDir, mode: CloneMode) -> Result<()> {
+        let dirs = self.store.as_dir().zip(dest.store.as_dir());
+        for digest in self.store.list()? {
+            let (src, size) = self.open_blob_sized(&digest)?;
+            let progress = self
+                .progress
+                .begin(ProgressOp::Copy, Some(&digest), Some(size));
+            if let Some((srcdir, destdir)) = dirs {
+                let sharded = dest.opts.shard_blobs;
+                // Hard links share their timestamps with the source.
+                let mode = match mode {
+                    CloneMode::Hardlink if dest.opts.normalize_mtime.is_some() => CloneMode::Auto,
+                    mode => mode,
+                };
+                if clone::share_blob(srcdir, destdir, &digest, sharded, mode)?
+                    || clone::copy_blob_fast(srcdir, destdir, &digest, sharded, false)?
+                {
+                    progress.bytes(size);
+                    progress.end(&digest);
+                    dest.blob_written_directly(&digest)?;
+                    continue;
+                }
+            }
+            let mut src = ProgressReader {
+                inner: src,
+                progress: &progress,
+            };
+            let mut w = dest.store.put()?;
+            std::io::copy(&mut src, &mut w)?;
+            w.commit(&digest)?;
+            progress.end(&digest);
+        }
+        if let Some(index) = self.store.read_meta("index.json")? {
+            dest.store.write_meta("index.json", &index)?;
+        }
+        Ok(())
+    }
+
+    /// Create a writer for a blob whose digest and size are already known, such as
+    /// when mirroring content. Completing the writer fails without adding the blob
+    /// if the written content does not match, and writing past the size fails early.
+    pub fn create_blob_with_expected(&self, digest: &str, size: u64) -> Result<BlobWriter<'_>> {
+        BlobWriter::new_with_expected(&*self.store, &self.progress, digest, size)
+    }
+
+    /// Like [`Self::create_blob_with_expected`], but the content is not hashed: the
+    /// caller asserts that it matches the sha256 `digest`, for example because it
+    /// comes from another content-addressed store which already verified it.
+    ///
+    /// Only the size is checked. Content which does not match is stored under the
+    /// wrong digest, corrupting the layout until [`Self::fsck`] removes it.
+    pub fn create_blob_unsafe_assume_digest(
+        &self,
+        digest: &str,
+        size: u64,
+    ) -> Result<BlobWriter<'_>> {
+        BlobWriter::new_trusted(&*self.store, &self.progress, digest, size)
+    }
+
+    /// Write the contents of `r` as a blob, unless a blob with the same digest and
+    /// size already exists. The returned boolean is true if the blob was added.
+    /// An existing blob whose content does not match its digest is replaced.
+    ///
+    /// This is safe to call concurrently for the same content.
+    #[context("Writing blob")]
+    pub fn write_blob_dedup(&self, mut r: impl Read) -> Result<(Blob, bool)> {
+        let mut w = BlobWriter::new(&*self.store, &self.progress)?;
+        std::io::copy(&mut r, &mut w)?;
+        w.finish()
     }
 
     /// Create a writer for a new gzip+tar blob; the contents
     /// are not parsed, but are expected to be a tarball.
-    pub fn create_gzip_layer(&self, c: Option<flate2::Compression>) -> Result<GzipLayerWriter> {
-        GzipLayerWriter::new(&self.dir, c)
+    pub fn create_gzip_layer(&self, c: Option<flate2::Compression>) -> Result<GzipLayerWriter<'_>> {
+        let opts = LayerWriterOptions {
+            compression: c,
+            ..Default::default()
+        };
+        self.create_gzip_layer_with(&opts)
+    }
+
+    /// Create a writer for a new gzip+tar blob with the provided options.
+    pub fn create_gzip_layer_with(&self, opts: &LayerWriterOptions) -> Result<GzipLayerWriter<'_>> {
+        GzipLayerWriter::new(&*self.store, &self.progress, opts)
+    }
+
+    /// Create a writer for a new uncompressed tar blob; the contents
+    /// are not parsed, but are expected to be a tarball.
+    pub fn create_uncompressed_layer(&self) -> Result<UncompressedLayerWriter<'_>> {
+        UncompressedLayerWriter::new(&*self.store, &self.progress)
     }
 
     /// Create a tar output stream, backed by a blob
     pub fn create_layer(
         &self,
         c: Option<flate2::Compression>,
-    ) -> Result<tar::Builder<GzipLayerWriter>> {
+    ) -> Result<tar::Builder<GzipLayerWriter<'_>>> {
         Ok(tar::Builder::new(self.create_gzip_layer(c)?))
     }
 
     /// Add a layer to the top of the image stack.  The firsh pushed layer becomes the root.
-
+    ///
+    /// The manifest entry has the media type of the layer, such as
+    /// [`MediaType::ImageLayer`] for [`Self::create_uncompressed_layer`]; use
+    /// [`Layer::with_media_type`] to override it.
     pub fn push_layer(
         &self,
         manifest: &mut oci_image::ImageManifest,
@@ -257,7 +889,25 @@ impl OciDir {
         annotations: Option<impl Into<HashMap<String, String>>>,
         description: &str,
     ) {
-        let mut builder = layer.descriptor().media_type(MediaType::ImageLayerGzip);
+        let annotations = annotations.map(Into::into);
+        let created = self.now();
+        self.push_layer_with_time(manifest, config, layer, description, annotations, created);
+    }
+
+    /// Add a layer to the top of the image stack, with `created` as the timestamp of
+    /// its history entry. The config `created` field is also set if it is unset.
+    ///
+    /// [`Self::push_layer`] uses the configured [`OciDirOptions::clock`] instead.
+    pub fn push_layer_with_time(
+        &self,
+        manifest: &mut oci_image::ImageManifest,
+        config: &mut oci_image::ImageConfiguration,
+        layer: Layer,
+        description: &str,
+        annotations: Option<HashMap<String, String>>,
+        created: chrono::DateTime<chrono::Utc>,
+    ) {
+        let mut builder = layer.descriptor();
         if let Some(annotations) = annotations {
             builder = builder.annotations(annotations);
         }
@@ -268,120 +918,487 @@ impl OciDir {
             .diff_ids_mut()
             .push(format!("sha256:{}", layer.uncompressed_sha256));
         config.set_rootfs(rootfs);
-        let now = chrono::offset::Utc::now();
+        let created = created.to_rfc3339_opts(chrono::SecondsFormat::Secs, true);
         let h = oci_image::HistoryBuilder::default()
-            .created(now.to_rfc3339_opts(chrono::SecondsFormat::Secs, true))
+            .created(created.clone())
             .created_by(description.to_string())
             .build()
             .unwrap();
         config.history_mut().push(h);
+        if config.created().is_none() {
+            config.set_created(Some(created));
+        }
     }
 
-    fn parse_descriptor_to_path(desc: &oci_spec::image::Descriptor) -> Result<PathBuf> {
-        let (alg, hash) = desc
-            .digest()
-            .split_once(':')
-            .ok_or_else(|| anyhow!("Invalid digest {}", desc.digest()))?;
-        let alg = parse_one_filename(alg)?;
-        if alg != "sha256" {
-            anyhow::bail!("Unsupported digest algorithm {}", desc.digest());
+    /// Append a layer to the manifest only, with the provided media type; the config
+    /// is not modified. This is useful for artifacts, whose layers have no rootfs.
+    pub fn push_manifest_layer(
+        &self,
+        manifest: &mut oci_image::ImageManifest,
+        layer: &Layer,
+        media_type: MediaType,
+        annotations: Option<HashMap<String, String>>,
+    ) {
+        let mut builder = layer.descriptor().media_type(media_type);
+        if let Some(annotations) = annotations {
+            builder = builder.annotations(annotations);
+        }
+        manifest.layers_mut().push(builder.build().unwrap());
+    }
+
+    /// Add a history entry which does not correspond to a layer (`empty_layer: true`),
+    /// e.g. for changes to the config such as setting environment variables.
+    ///
+    /// If `created` is `None`, the time from [`OciDirOptions::clock`] is used.
+    pub fn push_empty_history(
+        &self,
+        config: &mut oci_image::ImageConfiguration,
+        description: &str,
+        created: Option<chrono::DateTime<chrono::Utc>>,
+    ) {
+        let created = created.unwrap_or_else(|| self.now());
+        let h = oci_image::HistoryBuilder::default()
+            .created(created.to_rfc3339_opts(chrono::SecondsFormat::Secs, true))
+            .created_by(description.to_string())
+            .empty_layer(true)
+            .build()
+            .unwrap();
+        config.history_mut().push(h);
+    }
+
+    /// Open a blob; if the descriptor has embedded `data`, it is validated and served
+    /// from memory instead.
+    ///
+    /// Blobs from storage are verified according to [`OciDirOptions::verify`]. Blobs
+    /// whose digest algorithm is not supported are rejected with a
+    /// [`store::UnsupportedDigest`] error.
+    pub fn read_blob(&self, desc: &oci_spec::image::Descriptor) -> Result<BlobReader> {
+        if let Some(data) = Self::read_embedded_data(desc)? {
+            return Ok(BlobReader::Memory(std::io::Cursor::new(data.into())));
+        }
+        store::split_digest(desc.digest())?;
+        if !store::is_supported_digest(desc.digest()) {
+            return Err(store::UnsupportedDigest {
+                digest: desc.digest().to_string(),
+            }
+            .into());
+        }
+        let r = self
+            .store
+            .get(desc.digest())?
+            .ok_or_else(|| anyhow!("Missing blob {}", desc.digest()))?;
+        if self.opts.verify.should_verify() {
+            self.verify_blob(desc.digest())?;
+        }
+        Ok(r)
+    }
+
+    /// Decode and verify the `data` field of a descriptor, if present.
+    fn read_embedded_data(desc: &oci_spec::image::Descriptor) -> Result<Option<Vec<u8>>> {
+        let Some(data) = desc.data().as_deref() else {
+            return Ok(None);
+        };
+        let data = BASE64_STANDARD
+            .decode(data)
+            .with_context(|| format!("Decoding embedded data for {}", desc.digest()))?;
+        if u64::try_from(desc.size()).ok() != Some(data.len() as u64) {
+            anyhow::bail!(
+                "Embedded data for {} has size {}, expected {}",
+                desc.digest(),
+                data.len(),
+                desc.size()
+            );
         }
-        let hash = parse_one_filename(hash)?;
-        Ok(Path::new(BLOBDIR).join(hash))
+        if !store::is_supported_digest(desc.digest()) {
+            return Err(store::UnsupportedDigest {
+                digest: desc.digest().to_string(),
+            }
+            .into());
+        }
+        let found = format!("sha256:{}", hash::sha256_hex(&data)?);
+        if found != *desc.digest() {
+            anyhow::bail!(
+                "Embedded data digest mismatch: expected {} but found {found}",
+                desc.digest()
+            );
+        }
+        Ok(Some(data))
     }
 
-    /// Open a blob
-    pub fn read_blob(&self, desc: &oci_spec::image::Descriptor) -> Result<File> {
-        let path = Self::parse_descriptor_to_path(desc)?;
-        self.dir
-            .open(path)
-            .map_err(Into::into)
-            .map(|f| f.into_std())
+    /// Compute the chain IDs for the layers of a manifest, using the diff_ids from its config.
+    pub fn chain_ids_for_manifest(
+        &self,
+        manifest: &oci_image::ImageManifest,
+    ) -> Result<Vec<String>> {
+        let config: oci_image::ImageConfiguration = self.read_json_blob(manifest.config())?;
+        Ok(chain_ids(config.rootfs().diff_ids()))
     }
 
     /// Read a JSON blob.
+    ///
+    /// Unless [`OciDirOptions::trust_json_blobs`] is set, at most the size from the
+    /// descriptor is read, and the size and digest are checked before parsing.
+    /// Blobs above the [`OciDirOptions::json_limits`] for their media type are
+    /// rejected with a [`BlobTooLarge`] error.
     pub fn read_json_blob<T: serde::de::DeserializeOwned + Send + 'static>(
         &self,
         desc: &oci_spec::image::Descriptor,
     ) -> Result<T> {
-        let blob = BufReader::new(self.read_blob(desc)?);
-        serde_json::from_reader(blob).with_context(|| format!("Parsing object {}", desc.digest()))
+        let blob = self.read_blob(desc)?;
+        self.parse_json_blob(desc, blob, self.opts.trust_json_blobs)
+    }
+
+    /// Like [`Self::read_json_blob`], but blobs whose digest algorithm is not
+    /// supported are parsed without verifying them, so that traversal of layouts
+    /// written by other tools can follow their references.
+    pub(crate) fn read_json_blob_lenient<T: serde::de::DeserializeOwned + Send + 'static>(
+        &self,
+        desc: &oci_spec::image::Descriptor,
+    ) -> Result<T> {
+        if store::is_supported_digest(desc.digest()) {
+            return self.read_json_blob(desc);
+        }
+        let blob = self
+            .store
+            .get(desc.digest())?
+            .ok_or_else(|| anyhow!("Missing blob {}", desc.digest()))?;
+        self.parse_json_blob(desc, blob, true)
+    }
+
+    /// Read the contents of a JSON blob, as bounded and checked by
+    /// [`Self::read_json_blob`].
+    #[cfg(any(feature = "registry", feature = "sign"))]
+    pub(crate) fn read_json_bytes(&self, desc: &oci_spec::image::Descriptor) -> Result<Vec<u8>> {
+        let blob = self.read_blob(desc)?;
+        self.read_json_contents(desc, blob, self.opts.trust_json_blobs)
+    }
+
+    /// Parse a JSON blob, checking its size and digest against the descriptor
+    /// unless it is `trusted`.
+    fn parse_json_blob<T: serde::de::DeserializeOwned + Send + 'static>(
+        &self,
+        desc: &oci_spec::image::Descriptor,
+        blob: BlobReader,
+        trusted: bool,
+    ) -> Result<T> {
+        let buf = self.read_json_contents(desc, blob, trusted)?;
+        serde_json::from_slice(&buf).with_context(|| format!("Parsing object {}", desc.digest()))
+    }
+
+    /// Read a blob up to the [`JsonSizeLimits`] for its media type, checking its
+    /// size and digest against the descriptor unless it is `trusted`.
+    fn read_json_contents(
+        &self,
+        desc: &oci_spec::image::Descriptor,
+        blob: BlobReader,
+        trusted: bool,
+    ) -> Result<Vec<u8>> {
+        let limit = self.opts.json_limits.for_media_type(desc.media_type());
+        let too_large = |size| BlobTooLarge {
+            digest: desc.digest().to_string(),
+            media_type: desc.media_type().clone(),
+            size,
+            limit,
+        };
+        if trusted {
+            let mut buf = Vec::new();
+            blob.take(limit.saturating_add(1)).read_to_end(&mut buf)?;
+            if buf.len() as u64 > limit {
+                return Err(too_large(buf.len() as u64).into());
+            }
+            return Ok(buf);
+        }
+        let size = u64::try_from(desc.size())
+            .map_err(|_| anyhow!("Invalid size {} for {}", desc.size(), desc.digest()))?;
+        if size > limit {
+            return Err(too_large(size).into());
+        }
+        let mut buf = Vec::new();
+        blob.take(size.saturating_add(1)).read_to_end(&mut buf)?;
+        if buf.len() as u64 != size {
+            anyhow::bail!(
+                "Blob {} does not match its descriptor size {size}",
+                desc.digest()
+            );
+        }
+        let found = format!("sha256:{}", hash::sha256_hex(&buf)?);
+        if found != *desc.digest() {
+            anyhow::bail!(
+                "Blob digest mismatch: expected {} but found {found}",
+                desc.digest()
+            );
+        }
+        Ok(buf)
     }
 
     /// Write a configuration blob.
+    ///
+    /// With [`OciDirOptions::strict_manifests`], the history is first checked with
+    /// [`HistoryExt::validate_history`].
     pub fn write_config(
         &self,
         config: oci_image::ImageConfiguration,
     ) -> Result<oci_image::Descriptor> {
-        Ok(write_json_blob(&self.dir, &config, MediaType::ImageConfig)?
-            .build()
-            .unwrap())
+        if self.opts.strict_manifests {
+            config.validate_history()?;
+        }
+        Ok(write_json_blob_to_store(
+            &*self.store,
+            &self.progress,
+            &config,
+            MediaType::ImageConfig,
+            &self.json_options(),
+        )?
+        .build()
+        .unwrap())
+    }
+
+    /// Write a blob from memory with an arbitrary media type, such as the config of
+    /// an artifact like `application/vnd.cncf.helm.config.v1+json`.
+    pub fn write_blob_with_media_type(
+        &self,
+        buf: &[u8],
+        media_type: MediaType,
+    ) -> Result<Descriptor> {
+        Ok(self.write_blob_with_type(buf, media_type)?.build()?)
     }
 
     /// Read the image index.
     pub fn read_index(&self) -> Result<Option<ImageIndex>> {
-        let r = if let Some(index) = self.dir.open_optional("index.json")?.map(BufReader::new) {
-            Some(oci_image::ImageIndex::from_reader(index)?)
+        let r = if let Some(index) = self.store.read_meta("index.json")? {
+            Some(oci_image::ImageIndex::from_reader(index.as_slice())?)
         } else {
             None
         };
         Ok(r)
     }
 
-    /// Write a manifest as a blob, and replace the index with a reference to it.
-    pub fn insert_manifest(
+    /// Read the image index, returning an error if it does not exist.
+    fn read_index_required(&self) -> Result<ImageIndex> {
+        self.read_index()?
+            .ok_or_else(|| anyhow!("Failed to open index.json: not found"))
+    }
+
+    /// Options for JSON blobs written by this layout.
+    fn json_options(&self) -> JsonBlobOptions {
+        JsonBlobOptions {
+            canonical: self.opts.canonical_json,
+            ..Default::default()
+        }
+    }
+
+    /// Atomically replace the image index, recording the operation in the journal.
+    fn write_index(
         &self,
-        manifest: oci_image::ImageManifest,
+        index: &ImageIndex,
+        operation: &str,
+        subject: Option<&str>,
         tag: Option<&str>,
-        platform: oci_image::Platform,
-    ) -> Result<Descriptor> {
-        let mut manifest = write_json_blob(&self.dir, &manifest, MediaType::ImageManifest)?
-            .platform(platform)
-            .build()
-            .unwrap();
-        if let Some(tag) = tag {
-            let annotations: HashMap<_, _> = [(OCI_TAG_ANNOTATION.to_string(), tag.to_string())]
-                .into_iter()
-                .collect();
-            manifest.set_annotations(Some(annotations));
-        }
+    ) -> Result<()> {
+        self.write_index_retagged(index, operation, subject, tag, None)
+    }
 
-        let index = self.read_index()?;
-        let index = if let Some(mut index) = index {
-            let mut manifests = index.manifests().clone();
-            if let Some(tag) = tag {
-                manifests.retain(|d| !Self::descriptor_is_tagged(d, tag));
+    /// Like [`Self::write_index`], also recording the digest `tag` referred to before.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            level = "debug",
+            skip_all,
+            fields(operation, subject, tag, previous, manifests = index.manifests().len())
+        )
+    )]
+    fn write_index_retagged(
+        &self,
+        index: &ImageIndex,
+        operation: &str,
+        subject: Option<&str>,
+        tag: Option<&str>,
+        previous: Option<&str>,
+    ) -> Result<()> {
+        let buf = serialize_json(index, self.opts.canonical_json)?;
+        self.store.write_meta("index.json", &buf)?;
+        trace_event!(size = buf.len(), "Wrote index");
+        self.journal(operation, subject, tag, previous, &buf)
+    }
+
+    /// Check that a manifest is consistent with the contents of this layout: its config and
+    /// layer blobs must be present, and the config must have one diff_id per layer.
+    #[context("Verifying manifest")]
+    pub fn verify_manifest(&self, manifest: &oci_image::ImageManifest) -> Result<()> {
+        let config = manifest.config();
+        if !self.has_blob(config)? {
+            anyhow::bail!("Missing config blob {}", config.digest());
+        }
+        for layer in manifest.layers() {
+            if !self.has_blob(layer)? {
+                anyhow::bail!("Missing layer blob {}", layer.digest());
             }
-            manifests.push(manifest.clone());
-            index.set_manifests(manifests);
-            index
+        }
+        if config.media_type() == &MediaType::ImageConfig {
+            let config: oci_image::ImageConfiguration = self.read_json_blob(config)?;
+            let n_diffids = config.rootfs().diff_ids().len();
+            let n_layers = manifest.layers().len();
+            if n_diffids != n_layers {
+                anyhow::bail!("Config has {n_diffids} diff_ids but manifest has {n_layers} layers");
+            }
+        }
+        Ok(())
+    }
+
+    /// Check that the config and layer blobs of a manifest are present, and that
+    /// their sizes match the descriptors. Unlike [`Self::verify_manifest`], their
+    /// contents are not read.
+    #[context("Verifying manifest references")]
+    pub fn verify_references(&self, manifest: &oci_image::ImageManifest) -> Result<()> {
+        let descs = std::iter::once(("config", manifest.config()))
+            .chain(manifest.layers().iter().map(|l| ("layer", l)));
+        for (kind, desc) in descs {
+            if desc.data().is_some() {
+                continue;
+            }
+            if !self.store.has(desc.digest())? {
+                anyhow::bail!("Missing {kind} blob {}", desc.digest());
+            }
+            let (_, size) = self.open_blob_sized(desc.digest())?;
+            if i64::try_from(size).ok() != Some(desc.size()) {
+                anyhow::bail!(
+                    "The {kind} blob {} has size {size}, expected {}",
+                    desc.digest(),
+                    desc.size()
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns true if the blob referenced by this descriptor is present.
+    pub fn has_blob(&self, desc: &Descriptor) -> Result<bool> {
+        if desc.data().is_some() {
+            return Ok(true);
+        }
+        self.store.has(desc.digest())
+    }
+
+    /// The path of the blob referenced by this descriptor relative to the layout
+    /// root, such as `blobs/sha256/<hex>`, for callers which need to access it
+    /// directly; see [`Self::dir`]. Returns an error if the blob is not present,
+    /// or if the layout is not stored in a directory.
+    #[context("Finding path of blob {}", desc.digest())]
+    pub fn blob_path(&self, desc: &Descriptor) -> Result<Utf8PathBuf> {
+        let dir = self
+            .dir()
+            .ok_or_else(|| anyhow!("Layout is not stored in a directory"))?;
+        store::find_blob_path(dir, desc.digest())?
+            .ok_or_else(|| anyhow!("Missing blob {}", desc.digest()))
+    }
+
+    /// Write a manifest as a blob, and replace the index with a reference to it.
+    pub fn insert_manifest(
+        &self,
+        manifest: oci_image::ImageManifest,
+        tag: Option<&str>,
+        platform: oci_image::Platform,
+    ) -> Result<Descriptor> {
+        let opts = InsertOptions {
+            tag: tag.map(ToOwned::to_owned),
+            platform: Some(platform),
+            ..Default::default()
+        };
+        self.insert_manifest_with(manifest, &opts)
+    }
+
+    /// Write a manifest as a blob, and add a reference to it to the index, with
+    /// the tag, platform and annotations from `opts`.
+    ///
+    /// This is otherwise equivalent to [`Self::insert_manifest`].
+    pub fn insert_manifest_with(
+        &self,
+        mut manifest: oci_image::ImageManifest,
+        opts: &InsertOptions,
+    ) -> Result<Descriptor> {
+        if !opts.manifest_annotations.is_empty() {
+            let mut annotations = manifest.annotations().clone().unwrap_or_default();
+            annotations.extend(opts.manifest_annotations.clone());
+            manifest.set_annotations(Some(annotations));
+        }
+        if opts.verify_references {
+            self.verify_references(&manifest)?;
+        }
+        if self.opts.strict_manifests {
+            self.verify_manifest(&manifest)?;
+        }
+        let mut desc = write_json_blob_to_store(
+            &*self.store,
+            &self.progress,
+            &manifest,
+            MediaType::ImageManifest,
+            &self.json_options(),
+        )?
+        .build()
+        .unwrap();
+        desc.set_platform(opts.platform.clone());
+        let tag = opts.tag.as_deref();
+        let mut annotations = opts.annotations.clone();
+        if let Some(tag) = tag {
+            annotations.insert(OCI_TAG_ANNOTATION.to_string(), tag.to_string());
+        }
+        if !annotations.is_empty() {
+            desc.set_annotations(Some(annotations));
+        }
+
+        self.insert_descriptor(desc.clone(), tag)?;
+        Ok(desc)
+    }
+
+    /// Add a descriptor to the index, replacing any existing entry with the same tag.
+    /// The tag annotation must already be set on the descriptor.
+    fn insert_descriptor(&self, desc: Descriptor, tag: Option<&str>) -> Result<()> {
+        let digest = desc.digest().to_string();
+        let _lock = self.lock_index()?;
+        let index = self.read_index()?;
+        let mut previous = None;
+        let index = if let Some(mut index) = index {
+            let mut manifests = index.manifests().clone();
+            if let Some(tag) = tag {
+                previous = manifests
+                    .iter()
+                    .find(|d| Self::descriptor_is_tagged(d, tag))
+                    .map(|d| d.digest().to_string());
+                manifests.retain(|d| !Self::descriptor_is_tagged(d, tag));
+            }
+            manifests.push(desc);
+            index.set_manifests(manifests);
+            index
         } else {
             oci_image::ImageIndexBuilder::default()
                 .schema_version(oci_image::SCHEMA_VERSION)
-                .manifests(vec![manifest.clone()])
+                .manifests(vec![desc])
                 .build()
                 .unwrap()
         };
-
-        self.dir
-            .atomic_replace_with("index.json", |mut w| -> Result<()> {
-                let mut ser =
-                    serde_json::Serializer::new(&mut w);
-                index.serialize(&mut ser).context("Failed to serialize")?;
-                Ok(())
-            })?;
-        Ok(manifest)
+        self.write_index_retagged(&index, "insert", Some(&digest), tag, previous.as_deref())
     }
 
     /// Convenience helper to write the provided config, update the manifest to use it, then call [`insert_manifest`].
+    ///
+    /// The config may also be the descriptor of a config blob which was already
+    /// written, which must be present.
     pub fn insert_manifest_and_config(
         &self,
         mut manifest: oci_image::ImageManifest,
-        config: oci_image::ImageConfiguration,
+        config: impl Into<ManifestConfig>,
         tag: Option<&str>,
         platform: oci_image::Platform,
     ) -> Result<Descriptor> {
-        let config = self.write_config(config)?;
+        let config = match config.into() {
+            ManifestConfig::Image(config) => self.write_config(config)?,
+            ManifestConfig::Descriptor(desc) => {
+                if !self.has_blob(&desc)? {
+                    anyhow::bail!("Missing config blob {}", desc.digest());
+                }
+                desc
+            }
+        };
         manifest.set_config(config);
         self.insert_manifest(manifest, tag, platform)
     }
@@ -392,26 +1409,29 @@ impl OciDir {
         manifest: oci_image::ImageManifest,
         platform: oci_image::Platform,
     ) -> Result<()> {
-        let manifest = write_json_blob(&self.dir, &manifest, MediaType::ImageManifest)?
-            .platform(platform)
-            .build()
-            .unwrap();
+        if self.opts.strict_manifests {
+            self.verify_manifest(&manifest)?;
+        }
+        let manifest = write_json_blob_to_store(
+            &*self.store,
+            &self.progress,
+            &manifest,
+            MediaType::ImageManifest,
+            &self.json_options(),
+        )?
+        .platform(platform)
+        .build()
+        .unwrap();
 
-        let index_data = oci_image::ImageIndexBuilder::default()
+        let digest = manifest.digest().to_string();
+        let _lock = self.lock_index()?;
+        let mut index_data = oci_image::ImageIndexBuilder::default()
             .schema_version(oci_image::SCHEMA_VERSION)
             .manifests(vec![manifest])
             .build()
             .unwrap();
-        self.dir
-            .atomic_replace_with("index.json", |mut w| -> Result<()> {
-                let mut ser =
-                    serde_json::Serializer::new(&mut w);
-                index_data
-                    .serialize(&mut ser)
-                    .context("Failed to serialize")?;
-                Ok(())
-            })?;
-        Ok(())
+        index::preserve_index_metadata(self.read_index()?.as_ref(), &mut index_data);
+        self.write_index(&index_data, "replace", Some(&digest), None)
     }
 
     /// If this OCI directory has a single manifest, return it.  Otherwise, an error is returned.
@@ -429,26 +1449,113 @@ impl OciDir {
 
     /// Find the manifest with the provided tag
     pub fn find_manifest_with_tag(&self, tag: &str) -> Result<Option<oci_image::ImageManifest>> {
-        let f = self
-            .dir
-            .open("index.json")
-            .context("Failed to open index.json")?;
-        let idx: oci_image::ImageIndex = serde_json::from_reader(BufReader::new(f))?;
+        Ok(self
+            .find_manifest_and_descriptor_with_tag(tag)?
+            .map(|(manifest, _)| manifest))
+    }
+
+    /// Find the manifest with the provided tag, along with its index descriptor.
+    pub fn find_manifest_and_descriptor_with_tag(
+        &self,
+        tag: &str,
+    ) -> Result<Option<(oci_image::ImageManifest, Descriptor)>> {
+        let idx = self.read_index_required()?;
         for img in idx.manifests() {
             if Self::descriptor_is_tagged(img, tag) {
-                return self.read_json_blob(img).map(Some);
+                return Ok(Some((self.read_json_blob(img)?, img.clone())));
             }
         }
         Ok(None)
     }
 
+    /// Find the image manifests whose index entry matches the predicate, returning
+    /// each descriptor along with the manifest. Other entries such as nested
+    /// indexes are skipped, and there are no matches if there is no index.
+    pub fn find_manifests(
+        &self,
+        mut f: impl FnMut(&Descriptor) -> bool,
+    ) -> Result<Vec<(Descriptor, oci_image::ImageManifest)>> {
+        let Some(idx) = self.read_index()? else {
+            return Ok(Vec::new());
+        };
+        idx.manifests()
+            .iter()
+            .filter(|d| d.media_type() == &MediaType::ImageManifest && f(d))
+            .map(|d| Ok((d.clone(), self.read_json_blob(d)?)))
+            .collect()
+    }
+
+    /// Find the image manifests with the annotation `key` set to `value`, either in
+    /// their index entry or in the manifest itself.
+    pub fn find_manifests_by_annotation(
+        &self,
+        key: &str,
+        value: &str,
+    ) -> Result<Vec<(Descriptor, oci_image::ImageManifest)>> {
+        let matches = |a: &Option<HashMap<String, String>>| {
+            a.as_ref()
+                .and_then(|a| a.get(key))
+                .is_some_and(|v| v == value)
+        };
+        let r = self.find_manifests(|_| true)?;
+        Ok(r.into_iter()
+            .filter(|(desc, manifest)| {
+                matches(desc.annotations()) || matches(manifest.annotations())
+            })
+            .collect())
+    }
+
+    /// List the manifests referenced from the index, along with their tags and platforms.
+    /// Returns an empty list if there is no index.
+    pub fn manifests(&self) -> Result<Vec<ManifestEntry>> {
+        let Some(idx) = self.read_index()? else {
+            return Ok(Vec::new());
+        };
+        let r = idx
+            .manifests()
+            .iter()
+            .map(|desc| ManifestEntry {
+                tag: desc
+                    .annotations()
+                    .as_ref()
+                    .and_then(|a| a.get(OCI_TAG_ANNOTATION))
+                    .cloned(),
+                platform: desc.platform().clone(),
+                descriptor: desc.clone(),
+            })
+            .collect();
+        Ok(r)
+    }
+
+    /// Read the manifest with the provided digest, if it is referenced from the index.
+    pub fn read_manifest_by_digest(
+        &self,
+        digest: &str,
+    ) -> Result<Option<oci_image::ImageManifest>> {
+        Ok(self
+            .read_manifest_and_descriptor_by_digest(digest)?
+            .map(|(manifest, _)| manifest))
+    }
+
+    /// Read the manifest with the provided digest along with its index descriptor,
+    /// if it is referenced from the index.
+    pub fn read_manifest_and_descriptor_by_digest(
+        &self,
+        digest: &str,
+    ) -> Result<Option<(oci_image::ImageManifest, Descriptor)>> {
+        let Some(idx) = self.read_index()? else {
+            return Ok(None);
+        };
+        idx.manifests()
+            .iter()
+            .find(|d| d.digest() == digest)
+            .map(|d| Ok((self.read_json_blob(d)?, d.clone())))
+            .transpose()
+    }
+
     /// If this OCI directory has a single manifest, return it.  Otherwise, an error is returned.
     pub fn read_manifest_and_descriptor(&self) -> Result<(oci_image::ImageManifest, Descriptor)> {
-        let f = self
-            .dir
-            .open("index.json")
-            .context("Failed to open index.json")?;
-        let idx: oci_image::ImageIndex = serde_json::from_reader(BufReader::new(f))?;
+        let idx = self.read_index_required()?;
         let desc = match idx.manifests().as_slice() {
             [] => anyhow::bail!("No manifests found"),
             [desc] => desc.clone(),
@@ -458,70 +1565,202 @@ impl OciDir {
     }
 
     /// Verify consistency; primarily this checks the sha256 digest in `blobs/sha256`.
-    /// Returns the number of verified objects.
+    /// Returns the number of verified objects; blobs with other digest algorithms
+    /// are skipped, see [`FsckReport::unsupported`].
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all))]
     pub fn fsck(&self) -> Result<u32> {
         let mut r = 0;
-        for ent in self.dir.read_dir(BLOBDIR)? {
-            let ent = ent?;
-            let name = ent.file_name();
-            // For now ignore non-blobs
-            if name.len() != BLOB_SHA256_LEN {
-                continue;
+        for digest in self.store.list()? {
+            if self.verify_blob(&digest)? {
+                r += 1;
             }
-            let ty = ent.file_type()?;
-            if !ty.is_file() {
-                continue;
-            }
-            let Some(expected_digest) = name.to_str() else {
-                anyhow::bail!("Invalid blob name: {name:?}");
-            };
-            let mut f = ent.open().map(BufReader::new)?;
-            let mut digest = Hasher::new(MessageDigest::sha256())?;
-            std::io::copy(&mut f, &mut digest)?;
-            let found_digest = hex::encode(digest.finish()?);
-            if expected_digest != found_digest {
-                anyhow::bail!("Expected blob digest {expected_digest} but found {found_digest}");
-            }
-            r += 1;
         }
+        trace_event!(verified = r, "Verified blobs");
         Ok(r)
     }
+
+    /// Verify the digest of a single blob, returning false if its digest
+    /// algorithm is not supported.
+    fn verify_blob(&self, digest: &str) -> Result<bool> {
+        // For now ignore non-blobs
+        let Some(expected_digest) = digest
+            .strip_prefix("sha256:")
+            .filter(|d| d.len() == BLOB_SHA256_LEN)
+        else {
+            return Ok(false);
+        };
+        let (f, size) = self.open_blob_sized(digest)?;
+        let progress = self
+            .progress
+            .begin(ProgressOp::Verify, Some(digest), Some(size));
+        let mut f = BufReader::new(ProgressReader {
+            inner: f,
+            progress: &progress,
+        });
+        let mut hasher = Sha256::new()?;
+        std::io::copy(&mut f, &mut hasher)?;
+        let found_digest = hasher.finish_hex()?;
+        if expected_digest != found_digest {
+            anyhow::bail!("Expected blob digest {expected_digest} but found {found_digest}");
+        }
+        progress.end(digest);
+        Ok(true)
+    }
+}
+
+#[cfg(feature = "tracing")]
+fn blob_write_span() -> tracing::Span {
+    tracing::debug_span!(
+        "blob_write",
+        digest = tracing::field::Empty,
+        size = tracing::field::Empty
+    )
 }
 
 impl<'a> BlobWriter<'a> {
     #[context("Creating blob writer")]
-    fn new(ocidir: &'a Dir) -> Result<Self> {
+    fn new(store: &'a dyn BlobStore, progress: &Progress) -> Result<Self> {
+        Ok(Self {
+            hash: Sha256::new()?,
+            target: Some(store.put()?),
+            store,
+            size: 0,
+            expected: None,
+            trusted: false,
+            progress: progress.begin(ProgressOp::Write, None, None),
+            #[cfg(feature = "tracing")]
+            span: blob_write_span(),
+        })
+    }
+
+    /// Create a writer for a blob whose sha256 digest and size are already known.
+    #[context("Creating blob writer for {digest}")]
+    fn new_with_expected(
+        store: &'a dyn BlobStore,
+        progress: &Progress,
+        digest: &str,
+        size: u64,
+    ) -> Result<Self> {
+        let (alg, _) = store::split_digest(digest)?;
+        if alg != "sha256" {
+            anyhow::bail!("Unsupported digest algorithm {alg}");
+        }
         Ok(Self {
-            hash: Hasher::new(MessageDigest::sha256())?,
-            // FIXME add ability to choose filename after completion
-            target: Some(cap_tempfile::TempFile::new(ocidir)?),
+            hash: Sha256::new()?,
+            target: Some(store.put()?),
+            store,
             size: 0,
+            expected: Some((digest.to_owned(), size)),
+            trusted: false,
+            progress: progress.begin(ProgressOp::Write, Some(digest), Some(size)),
+            #[cfg(feature = "tracing")]
+            span: blob_write_span(),
         })
     }
 
+    /// Like [`Self::new_with_expected`], but the content is not hashed.
+    #[context("Creating trusted blob writer for {digest}")]
+    fn new_trusted(
+        store: &'a dyn BlobStore,
+        progress: &Progress,
+        digest: &str,
+        size: u64,
+    ) -> Result<Self> {
+        let valid = digest
+            .strip_prefix("sha256:")
+            .is_some_and(|d| d.len() == BLOB_SHA256_LEN && hex::decode(d).is_ok());
+        if !valid {
+            anyhow::bail!("Invalid sha256 digest {digest}");
+        }
+        let mut r = Self::new_with_expected(store, progress, digest, size)?;
+        r.trusted = true;
+        Ok(r)
+    }
+
     #[context("Completing blob")]
     /// Finish writing this blob object.
-    pub fn complete(mut self) -> Result<Blob> {
-        let sha256 = hex::encode(self.hash.finish()?);
-        let destname = &format!("{}/{}", BLOBDIR, sha256);
+    ///
+    /// If the digest and size were provided up front, they are checked and
+    /// on mismatch the written content is discarded.
+    pub fn complete(self) -> Result<Blob> {
+        self.finish().map(|(blob, _)| blob)
+    }
+
+    /// Finish writing, also returning false if a blob with the same digest and
+    /// size was already present, in which case the written content is discarded.
+    /// The existing blob is hashed first, and replaced if it does not match.
+    fn finish(mut self) -> Result<(Blob, bool)> {
+        let sha256 = match &self.expected {
+            Some((expected_digest, _)) if self.trusted => {
+                expected_digest.strip_prefix("sha256:").unwrap().to_owned()
+            }
+            _ => self.hash.finish_hex()?,
+        };
+        let digest = format!("sha256:{sha256}");
+        if let Some((expected_digest, expected_size)) = &self.expected {
+            if self.size != *expected_size {
+                anyhow::bail!("Expected blob size {expected_size} but found {}", self.size);
+            }
+            if &digest != expected_digest {
+                anyhow::bail!("Expected blob digest {expected_digest} but found {digest}");
+            }
+        }
         let target = self.target.take().unwrap();
-        target.replace(destname)?;
-        Ok(Blob {
-            sha256,
-            size: self.size,
-        })
+        let existing = match self.store.get(&digest)? {
+            Some(r) => blob_matches(r, self.size, &sha256)?,
+            None => false,
+        };
+        if existing {
+            drop(target);
+        } else {
+            target.commit(&digest)?;
+        }
+        self.progress.end(&digest);
+        #[cfg(feature = "tracing")]
+        {
+            self.span.record("digest", digest.as_str());
+            self.span.record("size", self.size);
+            self.span
+                .in_scope(|| tracing::debug!(existing, "Wrote blob"));
+        }
+        Ok((
+            Blob {
+                sha256,
+                size: self.size,
+            },
+            !existing,
+        ))
+    }
+}
+
+/// Returns true if the blob read from `r` has the given size and sha256 digest.
+fn blob_matches(mut r: BlobReader, size: u64, sha256: &str) -> Result<bool> {
+    if r.seek(std::io::SeekFrom::End(0))? != size {
+        return Ok(false);
     }
+    r.seek(std::io::SeekFrom::Start(0))?;
+    let mut hash = Sha256::new()?;
+    std::io::copy(&mut r, &mut hash)?;
+    Ok(hash.finish_hex()? == sha256)
 }
 
 impl<'a> std::io::Write for BlobWriter<'a> {
     fn write(&mut self, srcbuf: &[u8]) -> std::io::Result<usize> {
-        self.hash.update(srcbuf)?;
-        self.target
-            .as_mut()
-            .unwrap()
-            .as_file_mut()
-            .write_all(srcbuf)?;
+        if let Some((_, expected_size)) = &self.expected {
+            if self.size + srcbuf.len() as u64 > *expected_size {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("Blob exceeds expected size {expected_size}"),
+                ));
+            }
+        }
+        if !self.trusted {
+            self.hash.update(srcbuf)?;
+        }
+        self.target.as_mut().unwrap().write_all(srcbuf)?;
         self.size += srcbuf.len() as u64;
+        self.progress.bytes(srcbuf.len() as u64);
+        self.progress.throttle(srcbuf.len() as u64);
         Ok(srcbuf.len())
     }
 
@@ -532,37 +1771,117 @@ impl<'a> std::io::Write for BlobWriter<'a> {
 
 impl<'a> GzipLayerWriter<'a> {
     /// Create a writer for a gzip compressed layer blob.
-    fn new(ocidir: &'a Dir, c: Option<flate2::Compression>) -> Result<Self> {
-        let bw = BlobWriter::new(ocidir)?;
+    fn new(
+        store: &'a dyn BlobStore,
+        progress: &Progress,
+        opts: &LayerWriterOptions,
+    ) -> Result<Self> {
+        let bw = BlobWriter::new(store, progress)?;
+        let level = opts.compression.unwrap_or_default();
+        let compressor = match opts.threads {
+            Some(threads) if threads.get() > 1 => {
+                GzipCompressor::Parallel(pargz::ParallelGzip::new(level, threads.get()))
+            }
+            _ => {
+                let capacity = opts.buffer_size.unwrap_or(layerwriter::DEFAULT_BUFFER_SIZE);
+                GzipCompressor::Serial(GzEncoder::new(Vec::with_capacity(capacity), level))
+            }
+        };
         Ok(Self {
             bw,
-            uncompressed_hash: Hasher::new(MessageDigest::sha256())?,
-            compressor: GzEncoder::new(Vec::with_capacity(8192), c.unwrap_or_default()),
+            uncompressed_hash: Sha256::new()?,
+            compressor,
         })
     }
 
+    /// Flush data buffered by a serial compressor and return the size of the
+    /// compressed output so far. This ends the current deflate block, so calling
+    /// it often makes compression slightly worse.
+    pub(crate) fn sync_compressed_size(&mut self) -> std::io::Result<u64> {
+        if let GzipCompressor::Serial(c) = &mut self.compressor {
+            c.get_mut().clear();
+            c.flush()?;
+            self.bw.write_all(c.get_ref())?;
+            c.get_mut().clear();
+        }
+        Ok(self.bw.size)
+    }
+
     #[context("Completing layer")]
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(name = "compress_layer", level = "debug", skip_all)
+    )]
     /// Consume this writer, flushing buffered data and put the blob in place.
     pub fn complete(mut self) -> Result<Layer> {
-        self.compressor.get_mut().clear();
-        let buf = self.compressor.finish()?;
-        self.bw.write_all(&buf)?;
+        match self.compressor {
+            GzipCompressor::Serial(mut c) => {
+                c.get_mut().clear();
+                let buf = c.finish()?;
+                self.bw.write_all(&buf)?;
+            }
+            GzipCompressor::Parallel(c) => c.finish(&mut self.bw)?,
+        }
         let blob = self.bw.complete()?;
-        let uncompressed_sha256 = hex::encode(self.uncompressed_hash.finish()?);
+        let uncompressed_sha256 = self.uncompressed_hash.finish_hex()?;
+        trace_event!(
+            digest = %blob.digest_id(),
+            size = blob.size,
+            diff_id = %format!("sha256:{uncompressed_sha256}"),
+            "Compressed layer"
+        );
         Ok(Layer {
             blob,
             uncompressed_sha256,
+            media_type: MediaType::ImageLayerGzip,
         })
     }
 }
 
 impl<'a> std::io::Write for GzipLayerWriter<'a> {
     fn write(&mut self, srcbuf: &[u8]) -> std::io::Result<usize> {
-        self.compressor.get_mut().clear();
-        self.compressor.write_all(srcbuf).unwrap();
         self.uncompressed_hash.update(srcbuf)?;
-        let compressed_buf = self.compressor.get_mut().as_slice();
-        self.bw.write_all(compressed_buf)?;
+        match &mut self.compressor {
+            GzipCompressor::Serial(c) => {
+                c.get_mut().clear();
+                c.write_all(srcbuf).unwrap();
+                let compressed_buf = c.get_mut().as_slice();
+                self.bw.write_all(compressed_buf)?;
+            }
+            GzipCompressor::Parallel(c) => c.write(srcbuf, &mut self.bw)?,
+        }
+        Ok(srcbuf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.bw.flush()
+    }
+}
+
+impl<'a> UncompressedLayerWriter<'a> {
+    /// Create a writer for an uncompressed layer blob.
+    fn new(store: &'a dyn BlobStore, progress: &Progress) -> Result<Self> {
+        Ok(Self {
+            bw: BlobWriter::new(store, progress)?,
+        })
+    }
+
+    #[context("Completing layer")]
+    /// Consume this writer and put the blob in place.
+    pub fn complete(self) -> Result<Layer> {
+        let blob = self.bw.complete()?;
+        let uncompressed_sha256 = blob.sha256.clone();
+        Ok(Layer {
+            blob,
+            uncompressed_sha256,
+            media_type: MediaType::ImageLayer,
+        })
+    }
+}
+
+impl<'a> std::io::Write for UncompressedLayerWriter<'a> {
+    fn write(&mut self, srcbuf: &[u8]) -> std::io::Result<usize> {
+        self.bw.write_all(srcbuf)?;
         Ok(srcbuf.len())
     }
 
@@ -574,9 +1893,29 @@ impl<'a> std::io::Write for GzipLayerWriter<'a> {
 #[cfg(test)]
 mod tests {
     use cap_std::fs::OpenOptions;
+    use cap_std_ext::cap_tempfile;
 
     use super::*;
 
+    /// Write a layer in `format` whose contents are a placeholder tarball.
+    pub(crate) fn write_test_layer(w: &OciDir, format: CompressionFormat) -> Result<Layer> {
+        let mut layerw = w.create_layer_writer(format, &Default::default())?;
+        layerw.write_all(b"pretend this is a tarball")?;
+        layerw.complete()
+    }
+
+    /// Insert an image tagged `latest` with a single gzip [`write_test_layer`],
+    /// returning its manifest descriptor and the layer.
+    pub(crate) fn insert_test_image(w: &OciDir) -> Result<(Descriptor, Layer)> {
+        let layer = write_test_layer(w, CompressionFormat::Gzip)?;
+        let mut manifest = new_empty_manifest().build()?;
+        let mut config = oci_image::ImageConfigurationBuilder::default().build()?;
+        w.push_layer(&mut manifest, &mut config, layer.clone(), "root", None);
+        let desc =
+            w.insert_manifest_and_config(manifest, config, Some("latest"), Default::default())?;
+        Ok((desc, layer))
+    }
+
     const MANIFEST_DERIVE: &str = r#"{
         "schemaVersion": 2,
         "config": {
@@ -613,13 +1952,34 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_chain_ids() -> Result<()> {
+        let a = format!("sha256:{}", "a".repeat(64));
+        let b = format!("sha256:{}", "b".repeat(64));
+        let c = format!("sha256:{}", "c".repeat(64));
+        assert!(chain_ids(&[]).is_empty());
+        assert_eq!(chain_ids(std::slice::from_ref(&a)), [a.as_str()]);
+        assert_eq!(
+            chain_ids(&[a.clone(), b, c]),
+            [
+                a,
+                "sha256:ccd722928bd92476ba1745586fed6e45a102504185ad88cd89e01ff116fd146c".into(),
+                "sha256:c1377126441fb2f5ec2c21ae2a60255331d639e830f0ee1b40a36e52d4c40588".into(),
+            ]
+        );
+
+        let w = OciDir::new_in_memory()?;
+        let (desc, layer) = insert_test_image(&w)?;
+        let manifest: oci_image::ImageManifest = w.read_json_blob(&desc)?;
+        assert_eq!(w.chain_ids_for_manifest(&manifest)?, [layer.diff_id()]);
+        Ok(())
+    }
+
     #[test]
     fn test_build() -> Result<()> {
         let td = cap_tempfile::tempdir(cap_std::ambient_authority())?;
         let w = OciDir::ensure(&td)?;
-        let mut layerw = w.create_gzip_layer(None)?;
-        layerw.write_all(b"pretend this is a tarball")?;
-        let root_layer = layerw.complete()?;
+        let root_layer = write_test_layer(&w, CompressionFormat::Gzip)?;
         assert_eq!(
             root_layer.uncompressed_sha256,
             "349438e5faf763e8875b43de4d7101540ef4d865190336c2cc549a11f33f8d7c"
@@ -627,7 +1987,7 @@ mod tests {
         assert_eq!(w.fsck().unwrap(), 1);
         // Also verify that corrupting the object is found
         {
-            let mut f = w.dir.open_with(
+            let mut f = td.open_with(
                 format!("blobs/sha256/{}", root_layer.blob.sha256),
                 OpenOptions::new().write(true),
             )?;
@@ -682,4 +2042,594 @@ mod tests {
         assert_eq!(w.fsck().unwrap(), 6);
         Ok(())
     }
+
+    #[test]
+    fn test_uncompressed_layer() -> Result<()> {
+        let w = OciDir::new_in_memory()?;
+        let mut layerw = w.create_uncompressed_layer()?;
+        layerw.write_all(b"pretend this is an uncompressed tarball")?;
+        let layer = layerw.complete()?;
+        assert_eq!(layer.blob.sha256, layer.uncompressed_sha256);
+        let mut manifest = new_empty_manifest().build().unwrap();
+        let mut config = oci_image::ImageConfigurationBuilder::default()
+            .build()
+            .unwrap();
+        w.push_layer(&mut manifest, &mut config, layer, "root", None);
+        assert_eq!(manifest.layers()[0].media_type(), &MediaType::ImageLayer);
+        assert_eq!(
+            config.rootfs().diff_ids()[0]
+                .strip_prefix("sha256:")
+                .unwrap(),
+            manifest.layers()[0]
+                .digest()
+                .strip_prefix("sha256:")
+                .unwrap()
+        );
+        assert_eq!(w.fsck().unwrap(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_embedded_data() -> Result<()> {
+        let td = cap_tempfile::tempdir(cap_std::ambient_authority())?;
+        let w = OciDir::ensure(&td)?;
+        let opts = JsonBlobOptions {
+            inline_threshold: Some(64),
+            ..Default::default()
+        };
+        let small =
+            write_json_blob_with(&td, &vec!["a", "b"], MediaType::EmptyJSON, &opts)?.build()?;
+        assert!(small.data().is_some());
+        // Content is served from the descriptor even if the blob goes away.
+        td.remove_file(store::blob_path(small.digest())?)?;
+        let v: Vec<String> = w.read_json_blob(&small)?;
+        assert_eq!(v, ["a", "b"]);
+        let mut corrupted = small.clone();
+        corrupted.set_data(Some(BASE64_STANDARD.encode(b"[]")));
+        assert!(w.read_json_blob::<Vec<String>>(&corrupted).is_err());
+        let mut sha512 = small.clone();
+        sha512.set_digest(format!("sha512:{}", "a".repeat(128)));
+        let e = w.read_blob(&sha512).unwrap_err();
+        assert!(e.downcast_ref::<store::UnsupportedDigest>().is_some());
+        let large =
+            write_json_blob_with(&td, &vec!["a"; 64], MediaType::EmptyJSON, &opts)?.build()?;
+        assert!(large.data().is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_strict_manifests() -> Result<()> {
+        let td = cap_tempfile::tempdir(cap_std::ambient_authority())?;
+        let opts = OciDirOptions {
+            strict_manifests: true,
+            ..Default::default()
+        };
+        let w = OciDir::ensure_with(&td, &opts)?;
+        let root_layer = write_test_layer(&w, CompressionFormat::Gzip)?;
+        let mut manifest = new_empty_manifest().build().unwrap();
+        let mut config = oci_image::ImageConfigurationBuilder::default()
+            .build()
+            .unwrap();
+        w.push_layer(&mut manifest, &mut config, root_layer, "root", None);
+        // The placeholder config is not present
+        assert!(w
+            .insert_manifest(manifest.clone(), None, Default::default())
+            .is_err());
+        assert!(w.read_index()?.is_none());
+
+        // A config without diff_ids does not match the layers
+        let empty_config = oci_image::ImageConfigurationBuilder::default()
+            .build()
+            .unwrap();
+        assert!(w
+            .insert_manifest_and_config(manifest.clone(), empty_config, None, Default::default())
+            .is_err());
+
+        // History which does not line up with the layers
+        let mut bad_history = config.clone();
+        bad_history.history_mut().push(
+            oci_image::HistoryBuilder::default()
+                .created_by("RUN true")
+                .build()?,
+        );
+        assert!(w.write_config(bad_history).is_err());
+
+        w.insert_manifest_and_config(manifest, config, None, Default::default())?;
+        assert_eq!(w.read_index()?.unwrap().manifests().len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_references() -> Result<()> {
+        let w = OciDir::new_in_memory()?;
+        let layer = write_test_layer(&w, CompressionFormat::Gzip)?;
+        let mut manifest = new_empty_manifest().build().unwrap();
+        let mut config = oci_image::ImageConfigurationBuilder::default()
+            .build()
+            .unwrap();
+        w.push_layer(&mut manifest, &mut config, layer, "root", None);
+        manifest.set_config(w.write_config(config)?);
+        let opts = InsertOptions {
+            verify_references: true,
+            ..Default::default()
+        };
+
+        let mut wrong_size = manifest.clone();
+        wrong_size.layers_mut()[0].set_size(1);
+        let err = w.insert_manifest_with(wrong_size, &opts).unwrap_err();
+        assert!(format!("{err:#}").contains("has size"));
+        let mut missing = manifest.clone();
+        missing.layers_mut()[0].set_digest(
+            "sha256:0000000000000000000000000000000000000000000000000000000000000000".into(),
+        );
+        assert!(w.insert_manifest_with(missing.clone(), &opts).is_err());
+        assert!(w.read_index()?.is_none());
+        // Not checked by default
+        w.insert_manifest_with(missing, &Default::default())?;
+
+        w.insert_manifest_with(manifest, &opts)?;
+        assert_eq!(w.read_index()?.unwrap().manifests().len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_insert_manifest_with() -> Result<()> {
+        let w = OciDir::new_in_memory()?;
+        let manifest = new_empty_manifest().build().unwrap();
+        let opts = InsertOptions {
+            tag: Some("v1".into()),
+            annotations: [
+                ("foo".to_string(), "bar".to_string()),
+                (OCI_TAG_ANNOTATION.to_string(), "ignored".to_string()),
+            ]
+            .into(),
+            manifest_annotations: [("created-by".to_string(), "test".to_string())].into(),
+            ..Default::default()
+        };
+        let desc = w.insert_manifest_with(manifest.clone(), &opts)?;
+        assert!(desc.platform().is_none());
+        let annotations = desc.annotations().as_ref().unwrap();
+        assert_eq!(annotations.get("foo").unwrap(), "bar");
+        assert_eq!(annotations.get(OCI_TAG_ANNOTATION).unwrap(), "v1");
+        assert!(!annotations.contains_key("created-by"));
+        let written = w.find_manifest_with_tag("v1")?.unwrap();
+        let manifest_annotations = written.annotations().as_ref().unwrap();
+        assert_eq!(manifest_annotations.get("created-by").unwrap(), "test");
+        assert_eq!(written.layers(), manifest.layers());
+
+        let desc = w.insert_manifest_with(manifest.clone(), &Default::default())?;
+        assert!(desc.annotations().is_none());
+        assert_eq!(w.read_index()?.unwrap().manifests().len(), 2);
+
+        let entries = w.manifests()?;
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].tag.as_deref(), Some("v1"));
+        assert!(entries[1].tag.is_none());
+        assert!(entries.iter().all(|e| e.platform.is_none()));
+        assert_eq!(w.read_manifest_by_digest(desc.digest())?.unwrap(), manifest);
+        assert!(w.read_manifest_by_digest("sha256:noent")?.is_none());
+        let (m, d) = w
+            .read_manifest_and_descriptor_by_digest(desc.digest())?
+            .unwrap();
+        assert_eq!((m, &d), (manifest, &desc));
+        let (m, d) = w.find_manifest_and_descriptor_with_tag("v1")?.unwrap();
+        assert_eq!(&m, &written);
+        assert_eq!(d, entries[0].descriptor);
+        assert!(w.find_manifest_and_descriptor_with_tag("v2")?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_progress() -> Result<()> {
+        #[derive(Default)]
+        struct Counter {
+            events: std::sync::Mutex<Vec<(u64, ProgressOp, u64, bool)>>,
+        }
+        impl progress::ProgressReporter for Counter {
+            fn blob_begin(&self, id: u64, op: ProgressOp, _: Option<&str>, _: Option<u64>) {
+                self.events.lock().unwrap().push((id, op, 0, false));
+            }
+            fn blob_bytes(&self, id: u64, bytes: u64) {
+                let mut events = self.events.lock().unwrap();
+                let ev = events.iter_mut().find(|e| e.0 == id).unwrap();
+                ev.2 += bytes;
+            }
+            fn blob_end(&self, id: u64, _: &str) {
+                let mut events = self.events.lock().unwrap();
+                events.iter_mut().find(|e| e.0 == id).unwrap().3 = true;
+            }
+        }
+
+        let counter = Arc::new(Counter::default());
+        let w = OciDir::new_in_memory()?.with_progress(counter.clone());
+        write_test_layer(&w, CompressionFormat::None)?;
+        assert_eq!(w.fsck()?, 1);
+        let td = cap_tempfile::tempdir(cap_std::ambient_authority())?;
+        w.persist_to(&td)?;
+        let events = counter.events.lock().unwrap();
+        let ops: Vec<_> = events.iter().map(|e| e.1).collect();
+        assert_eq!(
+            ops,
+            [ProgressOp::Write, ProgressOp::Verify, ProgressOp::Copy]
+        );
+        assert!(events.iter().all(|e| e.2 == 25 && e.3));
+        Ok(())
+    }
+
+    #[test]
+    fn test_in_memory() -> Result<()> {
+        let w = OciDir::new_in_memory()?;
+        assert!(w.dir().is_none());
+        let root_layer = write_test_layer(&w, CompressionFormat::Gzip)?;
+        let mut manifest = new_empty_manifest().build().unwrap();
+        let mut config = oci_image::ImageConfigurationBuilder::default()
+            .build()
+            .unwrap();
+        w.push_layer(&mut manifest, &mut config, root_layer, "root", None);
+        let ts = chrono::DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        w.push_empty_history(&mut config, "ENV FOO=bar", Some(ts));
+        let h = config.history().last().unwrap();
+        assert_eq!(h.empty_layer(), Some(true));
+        assert_eq!(h.created().as_deref(), Some("2023-11-14T22:13:20Z"));
+        assert_eq!(config.history().len(), 2);
+        assert_eq!(config.rootfs().diff_ids().len(), 1);
+        w.insert_manifest_and_config(
+            manifest.clone(),
+            config,
+            Some("latest"),
+            oci_image::Platform::default(),
+        )?;
+        assert_eq!(w.fsck()?, 3);
+        assert_eq!(w.describe()?.images, 1);
+
+        let td = cap_tempfile::tempdir(cap_std::ambient_authority())?;
+        let persisted = w.persist_to(&td)?;
+        assert_eq!(persisted.fsck()?, 3);
+        let found = persisted.find_manifest_with_tag("latest")?.unwrap();
+        assert_eq!(found.layers(), manifest.layers());
+        Ok(())
+    }
+
+    #[test]
+    fn test_parallel_gzip() -> Result<()> {
+        let w = OciDir::new_in_memory()?;
+        let data: Vec<u8> = (0..1_000_000u32).map(|i| (i % 97) as u8).collect();
+        let serial = {
+            let mut layerw = w.create_gzip_layer(None)?;
+            layerw.write_all(&data)?;
+            layerw.complete()?
+        };
+        let opts = LayerWriterOptions {
+            threads: Some(std::num::NonZeroUsize::new(4).unwrap()),
+            ..Default::default()
+        };
+        let mut layerw = w.create_gzip_layer_with(&opts)?;
+        layerw.write_all(&data)?;
+        let parallel = layerw.complete()?;
+        assert_eq!(parallel.diff_id(), serial.diff_id());
+        assert_eq!(parallel.media_type, MediaType::ImageLayerGzip);
+        let desc = parallel.descriptor().build()?;
+        assert_eq!(w.compute_diffid(&desc)?, serial.diff_id());
+        Ok(())
+    }
+
+    #[test]
+    fn test_push_layer_media_type() -> Result<()> {
+        let w = OciDir::new_in_memory()?;
+        let mut manifest = new_empty_manifest().build()?;
+        let mut config = oci_image::ImageConfigurationBuilder::default().build()?;
+        let mut check = |layer: Layer, expected: MediaType| {
+            w.push_layer(&mut manifest, &mut config, layer, "layer", None);
+            assert_eq!(manifest.layers().last().unwrap().media_type(), &expected);
+        };
+        check(
+            w.create_gzip_layer(None)?.complete()?,
+            MediaType::ImageLayerGzip,
+        );
+        check(
+            w.create_uncompressed_layer()?.complete()?,
+            MediaType::ImageLayer,
+        );
+        #[cfg(feature = "zstd")]
+        check(
+            w.create_zstd_layer(&Default::default())?.complete()?,
+            MediaType::ImageLayerZstd,
+        );
+        let layer = w.create_gzip_layer(None)?.complete()?;
+        check(
+            layer.with_media_type(MediaType::ImageLayerNonDistributableGzip),
+            MediaType::ImageLayerNonDistributableGzip,
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_policy() -> Result<()> {
+        let td = cap_tempfile::tempdir(cap_std::ambient_authority())?;
+        let opts = OciDirOptions {
+            verify: VerifyPolicy::Always,
+            ..Default::default()
+        };
+        let w = OciDir::ensure_with(&td, &opts)?;
+        let layer = write_test_layer(&w, CompressionFormat::None)?;
+        let desc = layer.descriptor().build()?;
+        w.read_blob(&desc)?;
+        td.write(format!("blobs/sha256/{}", layer.blob.sha256), "corrupted")?;
+        assert!(w.read_blob(&desc).is_err());
+        // The default is to not verify reads
+        assert!(OciDir::open(&td)?.read_blob(&desc).is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn test_push_manifest_layer() -> Result<()> {
+        let w = OciDir::new_in_memory()?;
+        let mut layerw = w.create_uncompressed_layer()?;
+        layerw.write_all(b"some artifact")?;
+        let layer = layerw.complete()?;
+        let mut manifest = new_empty_manifest().build().unwrap();
+        let media_type = MediaType::Other("application/vnd.example.data".into());
+        let annotations = HashMap::from([("key".to_string(), "value".to_string())]);
+        w.push_manifest_layer(&mut manifest, &layer, media_type.clone(), Some(annotations));
+        let desc = &manifest.layers()[0];
+        assert_eq!(desc.media_type(), &media_type);
+        assert_eq!(desc.digest(), &layer.blob.digest_id());
+        assert_eq!(desc.annotations().as_ref().unwrap()["key"], "value");
+        Ok(())
+    }
+
+    #[test]
+    fn test_blob_with_expected() -> Result<()> {
+        let td = cap_tempfile::tempdir(cap_std::ambient_authority())?;
+        let w = OciDir::ensure(&td)?;
+        let contents = b"some content";
+        let digest = format!("sha256:{}", hash::sha256_hex(contents)?);
+        let size = contents.len() as u64;
+
+        let mut bw = w.create_blob_with_expected(&digest, size)?;
+        bw.write_all(b"some contenT")?;
+        assert!(bw.complete().is_err());
+        let mut bw = w.create_blob_with_expected(&digest, size)?;
+        assert!(bw.write_all(b"some content, and more").is_err());
+        let mut bw = w.create_blob_with_expected(&digest, size)?;
+        bw.write_all(b"some")?;
+        assert!(bw.complete().is_err());
+        // Nothing landed, and the temporary files were cleaned up
+        assert_eq!(td.entries()?.count(), 2);
+        assert!(w.store.list()?.is_empty());
+
+        let mut bw = w.create_blob_with_expected(&digest, size)?;
+        bw.write_all(contents)?;
+        assert_eq!(bw.complete()?.digest_id(), digest);
+        assert!(w.store.has(&digest)?);
+        assert!(w.create_blob_with_expected("sha512:abcd", 1).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_blob_assume_digest() -> Result<()> {
+        let w = OciDir::new_in_memory()?;
+        let contents = b"some content";
+        let digest = format!("sha256:{}", hash::sha256_hex(contents)?);
+        let size = contents.len() as u64;
+
+        let mut bw = w.create_blob_unsafe_assume_digest(&digest, size)?;
+        bw.write_all(b"some")?;
+        assert!(bw.complete().is_err());
+        let mut bw = w.create_blob_unsafe_assume_digest(&digest, size)?;
+        bw.write_all(contents)?;
+        assert_eq!(bw.complete()?.digest_id(), digest);
+        assert_eq!(w.fsck()?, 1);
+
+        // The content really is trusted
+        let other = format!("sha256:{}", hash::sha256_hex(b"other")?);
+        let mut bw = w.create_blob_unsafe_assume_digest(&other, size)?;
+        bw.write_all(b"some contenT")?;
+        bw.complete()?;
+        assert!(w.fsck().is_err());
+        assert!(w
+            .create_blob_unsafe_assume_digest("sha256:abcd", 1)
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_open_readonly() -> Result<()> {
+        let td = cap_tempfile::tempdir(cap_std::ambient_authority())?;
+        assert!(OciDir::open_readonly(&td).is_err());
+        let w = OciDir::ensure(&td)?;
+        let config = oci_image::ImageConfigurationBuilder::default().build()?;
+        let manifest = new_empty_manifest().build()?;
+        w.insert_manifest_and_config(manifest, config, Some("latest"), Default::default())?;
+        let list = |d: &Dir| -> Result<Vec<_>> {
+            let mut r = Vec::new();
+            for e in d.entries()? {
+                r.push(e?.file_name());
+            }
+            r.sort();
+            Ok(r)
+        };
+        let before = list(&td)?;
+
+        let r = OciDir::open_readonly(&td)?;
+        assert!(r.is_read_only());
+        assert!(!w.is_read_only());
+        assert!(r.find_manifest_with_tag("latest")?.is_some());
+        assert!(r.fsck()? > 0);
+        let config = oci_image::ImageConfigurationBuilder::default().build()?;
+        let err = r.write_config(config).unwrap_err();
+        assert!(format!("{err:#}").contains("read-only"), "{err:#}");
+        assert!(r.create_gzip_layer(None).is_err());
+        assert!(r.recover(std::time::Duration::ZERO).is_err());
+        assert_eq!(list(&td)?, before);
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_blob_dedup() -> Result<()> {
+        let td = cap_tempfile::tempdir(cap_std::ambient_authority())?;
+        let w = OciDir::ensure(&td)?;
+        let content = vec![42u8; 128 * 1024];
+        let added = std::thread::scope(|s| {
+            let handles: Vec<_> = (0..4)
+                .map(|_| s.spawn(|| w.write_blob_dedup(content.as_slice())))
+                .collect();
+            handles
+                .into_iter()
+                .map(|h| h.join().unwrap())
+                .collect::<Result<Vec<_>>>()
+        })?;
+        let digest = added[0].0.digest_id();
+        assert!(added.iter().all(|(b, _)| b.digest_id() == digest));
+        assert!(added.iter().any(|(_, new)| *new));
+        let (_, new) = w.write_blob_dedup(content.as_slice())?;
+        assert!(!new);
+        assert_eq!(w.store.list()?, vec![digest]);
+        assert!(w.fsck()? > 0);
+
+        // A truncated blob is replaced
+        let path = format!("blobs/sha256/{}", added[0].0.sha256);
+        td.write(&path, b"truncated")?;
+        let (_, new) = w.write_blob_dedup(content.as_slice())?;
+        assert!(new);
+        assert_eq!(td.read(&path)?, content);
+
+        // So is one of the right size with the wrong content
+        td.write(&path, vec![0u8; content.len()])?;
+        let (_, new) = w.write_blob_dedup(content.as_slice())?;
+        assert!(new);
+        assert_eq!(td.read(&path)?, content);
+        Ok(())
+    }
+
+    #[test]
+    fn test_custom_config() -> Result<()> {
+        let w = OciDir::new_in_memory()?;
+        let config_type = MediaType::Other("application/vnd.cncf.helm.config.v1+json".into());
+        let config = w.write_blob_with_media_type(br#"{"name":"chart"}"#, config_type.clone())?;
+        assert_eq!(config.media_type(), &config_type);
+        let chart = w.write_blob_with_media_type(
+            b"chart",
+            MediaType::Other("application/vnd.cncf.helm.chart.content.v1.tar+gzip".into()),
+        )?;
+        let mut manifest = new_empty_manifest().build()?;
+        manifest.set_layers(vec![chart]);
+        let desc = w.insert_manifest_and_config(
+            manifest.clone(),
+            config.clone(),
+            Some("chart"),
+            Default::default(),
+        )?;
+        let found: oci_image::ImageManifest = w.read_json_blob(&desc)?;
+        assert_eq!(found.config(), &config);
+        assert_eq!(w.validate(ValidationLevel::Strict)?, []);
+
+        let mut missing = config;
+        missing.set_digest(format!("sha256:{}", "0".repeat(64)));
+        assert!(w
+            .insert_manifest_and_config(manifest, missing, None, Default::default())
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_find_manifests() -> Result<()> {
+        let w = OciDir::new_in_memory()?;
+        assert!(w.find_manifests(|_| true)?.is_empty());
+        let config = oci_image::ImageConfigurationBuilder::default().build()?;
+        let mut manifest = new_empty_manifest().build()?;
+        manifest.set_annotations(Some(HashMap::from([(
+            "ostree.commit".to_owned(),
+            "abc".to_owned(),
+        )])));
+        let a = w.insert_manifest_and_config(manifest, config, Some("a"), Default::default())?;
+        let config = oci_image::ImageConfigurationBuilder::default()
+            .architecture(oci_image::Arch::ARM64)
+            .build()?;
+        let manifest = new_empty_manifest().build()?;
+        let b = w.insert_manifest_and_config(manifest, config, Some("b"), Default::default())?;
+
+        let found = w.find_manifests_by_annotation("ostree.commit", "abc")?;
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].0, a);
+        assert!(found[0].1.annotations().is_some());
+        assert!(w
+            .find_manifests_by_annotation("ostree.commit", "def")?
+            .is_empty());
+        // Annotations of the index entry also match
+        let found = w.find_manifests_by_annotation(OCI_TAG_ANNOTATION, "b")?;
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].0, b);
+
+        let found = w.find_manifests(|d| d.digest() != a.digest())?;
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].0, b);
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_json_blob_checked() -> Result<()> {
+        let td = cap_tempfile::tempdir(cap_std::ambient_authority())?;
+        let w = OciDir::ensure(&td)?;
+        let config = oci_image::ImageConfigurationBuilder::default().build()?;
+        let desc = w.write_config(config)?;
+        let _: oci_image::ImageConfiguration = w.read_json_blob(&desc)?;
+        let path = format!("blobs/sha256/{}", desc.digest().split_once(':').unwrap().1);
+        let swapped =
+            br#"{"architecture":"arm64","os":"linux","rootfs":{"type":"layers","diff_ids":[]}}"#;
+        td.write(&path, swapped)?;
+        let err = w
+            .read_json_blob::<oci_image::ImageConfiguration>(&desc)
+            .unwrap_err();
+        assert!(format!("{err:#}").contains("size"), "{err:#}");
+        // Same size, different content
+        let mut same_size = vec![b' '; usize::try_from(desc.size())?];
+        same_size[..2].copy_from_slice(b"{}");
+        td.write(&path, &same_size)?;
+        let err = w.read_json_blob::<serde_json::Value>(&desc).unwrap_err();
+        assert!(format!("{err:#}").contains("digest mismatch"), "{err:#}");
+
+        let opts = OciDirOptions {
+            trust_json_blobs: true,
+            ..Default::default()
+        };
+        let trusting = OciDir::open_with(&td, &opts)?;
+        assert!(trusting
+            .read_json_blob::<serde_json::Value>(&desc)?
+            .is_object());
+        Ok(())
+    }
+
+    #[test]
+    fn test_blob_path() -> Result<()> {
+        let td = cap_tempfile::tempdir(cap_std::ambient_authority())?;
+        let w = OciDir::ensure(&td)?;
+        let config = oci_image::ImageConfigurationBuilder::default().build()?;
+        let desc = w.write_config(config.clone())?;
+        let path = w.blob_path(&desc)?;
+        assert_eq!(path, store::blob_path(desc.digest())?);
+        assert!(td.try_exists(&path)?);
+
+        let opts = OciDirOptions {
+            shard_blobs: true,
+            ..Default::default()
+        };
+        let sharded = OciDir::open_with(&td, &opts)?;
+        let mut lw = sharded.create_gzip_layer(None)?;
+        lw.write_all(b"sharded")?;
+        let layer = lw.complete()?.descriptor().build()?;
+        assert_eq!(
+            sharded.blob_path(&layer)?,
+            store::sharded_blob_path(layer.digest())?
+        );
+        assert_eq!(w.blob_path(&desc)?, path);
+
+        let mut missing = desc.clone();
+        missing.set_digest(format!("sha256:{}", "0".repeat(64)));
+        assert!(w.blob_path(&missing).is_err());
+        let mem = OciDir::new_in_memory()?;
+        let desc = mem.write_config(config)?;
+        assert!(mem.blob_path(&desc).is_err());
+        Ok(())
+    }
 }
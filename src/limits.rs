@@ -0,0 +1,132 @@
+//! Size limits for parsing JSON blobs.
+
+use oci_spec::image::MediaType;
+
+const MIB: u64 = 1024 * 1024;
+
+/// The maximum sizes of JSON blobs parsed by [`crate::OciDir::read_json_blob`],
+/// by media type; see [`crate::OciDirOptions::json_limits`]. Use `u64::MAX` to
+/// disable a limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct JsonSizeLimits {
+    /// The limit for image manifests, 4 MiB by default.
+    pub manifest: u64,
+    /// The limit for image indexes, 4 MiB by default.
+    pub index: u64,
+    /// The limit for image configs, 16 MiB by default.
+    pub config: u64,
+    /// The limit for all other media types, 16 MiB by default.
+    pub other: u64,
+}
+
+impl Default for JsonSizeLimits {
+    fn default() -> Self {
+        Self {
+            manifest: 4 * MIB,
+            index: 4 * MIB,
+            config: 16 * MIB,
+            other: 16 * MIB,
+        }
+    }
+}
+
+impl JsonSizeLimits {
+    /// The limit for a blob with this media type.
+    pub fn for_media_type(&self, media_type: &MediaType) -> u64 {
+        match media_type {
+            MediaType::ImageManifest => self.manifest,
+            MediaType::ImageIndex => self.index,
+            MediaType::ImageConfig => self.config,
+            _ => self.other,
+        }
+    }
+}
+
+/// The error returned by [`crate::OciDir::read_json_blob`] for a blob larger
+/// than its [`JsonSizeLimits`]; it can be retrieved with
+/// [`anyhow::Error::downcast_ref`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlobTooLarge {
+    /// The digest of the blob.
+    pub digest: String,
+    /// The media type from the descriptor.
+    pub media_type: MediaType,
+    /// The size from the descriptor, or the number of bytes read before the
+    /// limit was exceeded if the descriptor is not trusted.
+    pub size: u64,
+    /// The limit for the media type.
+    pub limit: u64,
+}
+
+impl std::fmt::Display for BlobTooLarge {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Blob {} of type {} has size {}, above the limit of {} bytes",
+            self.digest, self.media_type, self.size, self.limit
+        )
+    }
+}
+
+impl std::error::Error for BlobTooLarge {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{OciDir, OciDirOptions};
+    use anyhow::Result;
+    use cap_std_ext::{cap_std, cap_tempfile};
+    use oci_spec::image::{ImageConfiguration, ImageConfigurationBuilder, ImageManifest};
+
+    #[test]
+    fn json_limits() -> Result<()> {
+        let td = cap_tempfile::tempdir(cap_std::ambient_authority())?;
+        let w = OciDir::ensure(&td)?;
+        let config = ImageConfigurationBuilder::default().build()?;
+        let desc = w.insert_manifest_and_config(
+            crate::new_empty_manifest().build()?,
+            config,
+            Some("app"),
+            Default::default(),
+        )?;
+        let manifest: ImageManifest = w.read_json_blob(&desc)?;
+
+        let limits = JsonSizeLimits {
+            config: 1,
+            ..Default::default()
+        };
+        for trust_json_blobs in [false, true] {
+            let opts = OciDirOptions {
+                json_limits: limits,
+                trust_json_blobs,
+                ..Default::default()
+            };
+            let w = OciDir::open_with(&td, &opts)?;
+            let _: ImageManifest = w.read_json_blob(&desc)?;
+            let e = w
+                .read_json_blob::<ImageConfiguration>(manifest.config())
+                .unwrap_err();
+            let e = e.downcast_ref::<BlobTooLarge>().unwrap();
+            assert_eq!(e.limit, 1);
+            assert_eq!(e.media_type, MediaType::ImageConfig);
+            let expected = if trust_json_blobs {
+                2
+            } else {
+                manifest.config().size() as u64
+            };
+            assert_eq!(e.size, expected);
+        }
+
+        // Validation reports oversized blobs instead of reading them
+        let opts = OciDirOptions {
+            json_limits: limits,
+            ..Default::default()
+        };
+        let w = OciDir::open_with(&td, &opts)?;
+        let violations = w.validate(crate::ValidationLevel::Basic)?;
+        assert_eq!(violations.len(), 1);
+        assert_eq!(violations[0].kind, crate::ViolationKind::TooLarge);
+        assert_eq!(violations[0].location, "index.json/manifests/0/config");
+        Ok(())
+    }
+}
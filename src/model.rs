@@ -0,0 +1,214 @@
+//! An owned, serializable model of the full state of a layout.
+//!
+//! A [`LayoutSnapshot`] is read with a single call to [`OciDir::snapshot`] and
+//! holds everything needed to render a layout, such as in a web UI, without
+//! further reads.
+
+use anyhow::Result;
+use fn_error_context::context;
+use oci_spec::image::{
+    Descriptor, ImageConfiguration, ImageIndex, ImageManifest, MediaType, Platform,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::inspect::config_platform;
+use crate::{effective_created, OciDir, OCI_TAG_ANNOTATION};
+
+/// The state of a layout, see [`OciDir::snapshot`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+#[non_exhaustive]
+pub struct LayoutSnapshot {
+    /// The version from the `oci-layout` file.
+    pub layout_version: String,
+    /// The entries of the index, in order.
+    pub images: Vec<ImageRecord>,
+    /// The number of blobs in the layout, whether referenced or not.
+    pub blobs: usize,
+    /// The total size of all blobs.
+    pub blob_bytes: u64,
+}
+
+/// A manifest or image index, see [`LayoutSnapshot::images`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+#[non_exhaustive]
+pub struct ImageRecord {
+    /// The tag of the index entry, if any; always unset for the manifests of an image index.
+    pub tag: Option<String>,
+    /// The digest of the manifest or index.
+    pub digest: String,
+    /// The media type from the descriptor.
+    pub media_type: String,
+    /// The platform from the descriptor, or otherwise from the config.
+    pub platform: Option<Platform>,
+    /// The effective creation time in RFC 3339 format, see [`effective_created`].
+    pub created: Option<String>,
+    /// The digest of the config, for image manifests.
+    pub config_digest: Option<String>,
+    /// The layers, for image manifests.
+    pub layers: Vec<LayerRecord>,
+    /// The manifests referenced by an image index.
+    pub manifests: Vec<ImageRecord>,
+    /// The total size of this manifest and everything it references, counting
+    /// blobs once per reference.
+    pub size: u64,
+}
+
+/// A layer of an image, see [`ImageRecord::layers`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+#[non_exhaustive]
+pub struct LayerRecord {
+    /// The digest of the layer blob.
+    pub digest: String,
+    /// The media type of the layer.
+    pub media_type: String,
+    /// The size of the layer blob.
+    pub size: u64,
+    /// The digest of the uncompressed layer from the config, if present.
+    pub diff_id: Option<String>,
+}
+
+fn tag_of(desc: &Descriptor) -> Option<String> {
+    desc.annotations()
+        .as_ref()
+        .and_then(|a| a.get(OCI_TAG_ANNOTATION))
+        .cloned()
+}
+
+impl OciDir {
+    fn image_record(&self, desc: &Descriptor, tag: Option<String>) -> Result<ImageRecord> {
+        let mut r = ImageRecord {
+            tag,
+            digest: desc.digest().to_string(),
+            media_type: desc.media_type().to_string(),
+            platform: desc.platform().clone(),
+            created: None,
+            config_digest: None,
+            layers: Vec::new(),
+            manifests: Vec::new(),
+            size: desc.size().try_into()?,
+        };
+        match desc.media_type() {
+            MediaType::ImageManifest => {
+                let manifest: ImageManifest = self.read_json_blob(desc)?;
+                let config_desc = manifest.config();
+                let config: Option<ImageConfiguration> =
+                    if config_desc.media_type() == &MediaType::ImageConfig {
+                        Some(self.read_json_blob(config_desc)?)
+                    } else {
+                        None
+                    };
+                let diff_ids = config.as_ref().map(|c| c.rootfs().diff_ids().as_slice());
+                r.config_digest = Some(config_desc.digest().to_string());
+                r.size += u64::try_from(config_desc.size())?;
+                for (i, layer) in manifest.layers().iter().enumerate() {
+                    let size = u64::try_from(layer.size())?;
+                    r.size += size;
+                    r.layers.push(LayerRecord {
+                        digest: layer.digest().to_string(),
+                        media_type: layer.media_type().to_string(),
+                        size,
+                        diff_id: diff_ids.and_then(|d| d.get(i)).cloned(),
+                    });
+                }
+                if let Some(config) = config.as_ref() {
+                    r.created = effective_created(config).map(|t| t.to_rfc3339());
+                    if r.platform.is_none() {
+                        r.platform = config_platform(config);
+                    }
+                }
+            }
+            MediaType::ImageIndex => {
+                let index: ImageIndex = self.read_json_blob(desc)?;
+                for child in index.manifests() {
+                    let child = self.image_record(child, None)?;
+                    r.size += child.size;
+                    r.manifests.push(child);
+                }
+            }
+            _ => {}
+        }
+        Ok(r)
+    }
+
+    /// Read the tags, manifests, platforms and sizes of everything in this layout.
+    ///
+    /// Manifests and configs are parsed, but layers are not read.
+    #[context("Taking layout snapshot")]
+    pub fn snapshot(&self) -> Result<LayoutSnapshot> {
+        let mut images = Vec::new();
+        if let Some(index) = self.read_index()? {
+            for desc in index.manifests() {
+                images.push(self.image_record(desc, tag_of(desc))?);
+            }
+        }
+        let mut blobs = 0;
+        let mut blob_bytes = 0;
+        for digest in self.store.list()? {
+            blobs += 1;
+            blob_bytes += self.open_blob_sized(&digest)?.1;
+        }
+        Ok(LayoutSnapshot {
+            layout_version: self.layout_version()?,
+            images,
+            blobs,
+            blob_bytes,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use oci_spec::image::{Arch, ImageConfigurationBuilder, Os};
+    use std::io::Write;
+
+    #[test]
+    fn snapshot() -> Result<()> {
+        let w = OciDir::new_in_memory()?;
+        let empty = w.snapshot()?;
+        assert!(empty.images.is_empty());
+        assert_eq!(empty.blobs, 0);
+
+        let mut manifest = crate::new_empty_manifest().build()?;
+        let mut config = ImageConfigurationBuilder::default()
+            .os(Os::Linux)
+            .architecture(Arch::Amd64)
+            .created("2024-01-02T03:04:05Z")
+            .build()?;
+        let mut layer = w.create_gzip_layer(None)?;
+        layer.write_all(b"not actually a tarball")?;
+        let layer = layer.complete()?;
+        let diff_id = layer.diff_id();
+        let layer_size = layer.blob.size;
+        w.push_layer(&mut manifest, &mut config, layer, "app", None);
+        let desc =
+            w.insert_manifest_and_config(manifest, config, Some("latest"), Platform::default())?;
+
+        let s = w.snapshot()?;
+        assert_eq!(s.layout_version, "1.0.0");
+        assert_eq!(s.blobs, 3);
+        let [image] = s.images.as_slice() else {
+            panic!("Expected one image: {:?}", s.images);
+        };
+        assert_eq!(image.tag.as_deref(), Some("latest"));
+        assert_eq!(image.digest, desc.digest().as_str());
+        assert_eq!(
+            image.platform.as_ref().unwrap().architecture(),
+            &Arch::Amd64
+        );
+        assert_eq!(image.created.as_deref(), Some("2024-01-02T03:04:05+00:00"));
+        assert_eq!(image.layers.len(), 1);
+        assert_eq!(image.layers[0].size, layer_size);
+        assert_eq!(image.layers[0].diff_id.as_deref(), Some(diff_id.as_str()));
+        assert_eq!(s.blob_bytes, image.size);
+
+        let json = serde_json::to_string(&s)?;
+        assert!(json.contains("\"config-digest\""));
+        let parsed: LayoutSnapshot = serde_json::from_str(&json)?;
+        assert_eq!(parsed, s);
+        Ok(())
+    }
+}
@@ -0,0 +1,259 @@
+//! Fixed modification times for blobs and metadata files, so that layouts
+//! stored in caches or tar snapshots do not change when rebuilt.
+
+use std::io::Write;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use anyhow::Result;
+use cap_std_ext::cap_std::fs::{Dir, OpenOptions};
+use chrono::{DateTime, Utc};
+use fn_error_context::context;
+
+use crate::store::{find_blob_path, BlobStore, StagedBlob};
+use crate::{BlobReader, OciDir};
+
+/// Set the modification time of a file. It is opened for writing, since that
+/// is required to change its attributes on Windows.
+pub(crate) fn set_mtime(dir: &Dir, path: impl AsRef<Path>, time: SystemTime) -> Result<()> {
+    dir.open_with(path, OpenOptions::new().write(true))?
+        .into_std()
+        .set_modified(time)?;
+    Ok(())
+}
+
+/// A store which sets the modification time of each blob and metadata file
+/// written through it; [`OciDir::with_store`] wraps the store in this if
+/// [`crate::OciDirOptions::normalize_mtime`] is set.
+#[derive(Debug)]
+pub(crate) struct MtimeStore {
+    inner: Arc<dyn BlobStore>,
+    time: SystemTime,
+}
+
+impl MtimeStore {
+    pub(crate) fn new(inner: Arc<dyn BlobStore>, time: DateTime<Utc>) -> Self {
+        Self {
+            inner,
+            time: time.into(),
+        }
+    }
+
+    fn set_blob_mtime(&self, digest: &str) -> Result<()> {
+        let Some(dir) = self.inner.as_dir() else {
+            return Ok(());
+        };
+        if let Some(path) = find_blob_path(dir, digest)? {
+            set_mtime(dir, path, self.time)?;
+        }
+        Ok(())
+    }
+
+    fn set_meta_mtime(&self, name: &str) -> Result<()> {
+        match self.inner.as_dir() {
+            Some(dir) => set_mtime(dir, name, self.time),
+            None => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct MtimeStagedBlob<'a> {
+    store: &'a MtimeStore,
+    inner: Box<dyn StagedBlob + 'a>,
+}
+
+impl<'a> Write for MtimeStagedBlob<'a> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<'a> StagedBlob for MtimeStagedBlob<'a> {
+    fn commit(self: Box<Self>, digest: &str) -> Result<()> {
+        let Self { store, inner } = *self;
+        inner.commit(digest)?;
+        store.set_blob_mtime(digest)
+    }
+}
+
+impl BlobStore for MtimeStore {
+    fn get(&self, digest: &str) -> Result<Option<BlobReader>> {
+        self.inner.get(digest)
+    }
+
+    fn put(&self) -> Result<Box<dyn StagedBlob + '_>> {
+        Ok(Box::new(MtimeStagedBlob {
+            store: self,
+            inner: self.inner.put()?,
+        }))
+    }
+
+    fn has(&self, digest: &str) -> Result<bool> {
+        self.inner.has(digest)
+    }
+
+    fn list(&self) -> Result<Vec<String>> {
+        self.inner.list()
+    }
+
+    fn delete(&self, digest: &str) -> Result<bool> {
+        self.inner.delete(digest)
+    }
+
+    fn read_meta(&self, name: &str) -> Result<Option<Vec<u8>>> {
+        self.inner.read_meta(name)
+    }
+
+    fn write_meta(&self, name: &str, contents: &[u8]) -> Result<()> {
+        self.inner.write_meta(name, contents)?;
+        self.set_meta_mtime(name)
+    }
+
+    fn append_meta(&self, name: &str, contents: &[u8]) -> Result<()> {
+        self.inner.append_meta(name, contents)?;
+        self.set_meta_mtime(name)
+    }
+
+    fn as_dir(&self) -> Option<&Dir> {
+        self.inner.as_dir()
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.inner.is_read_only()
+    }
+}
+
+impl OciDir {
+    /// Apply what the store does for new blobs to one written directly into the
+    /// layout directory, such as by a fast copy: the modification time from
+    /// [`crate::OciDirOptions::normalize_mtime`] and the journal entry.
+    pub(crate) fn blob_written_directly(&self, digest: &str) -> Result<()> {
+        if let (Some(time), Some(dir)) = (self.opts.normalize_mtime, self.dir()) {
+            if let Some(path) = find_blob_path(dir, digest)? {
+                set_mtime(dir, path, time.into())?;
+            }
+        }
+        self.journal_blob("write-blob", digest)
+    }
+
+    /// Set the modification time of every blob and of the files at the root of
+    /// the layout, such as `index.json`, to `epoch`. Returns the number of files
+    /// changed; nothing is done for layouts stored in memory.
+    ///
+    /// Directory timestamps are not changed. To keep new files at this time, see
+    /// [`crate::OciDirOptions::normalize_mtime`].
+    #[context("Normalizing timestamps")]
+    pub fn normalize_timestamps(&self, epoch: DateTime<Utc>) -> Result<usize> {
+        let Some(dir) = self.writable_dir()? else {
+            return Ok(0);
+        };
+        let time = SystemTime::from(epoch);
+        let mut n = 0;
+        for digest in self.store.list()? {
+            if let Some(path) = find_blob_path(dir, &digest)? {
+                set_mtime(dir, path, time)?;
+                n += 1;
+            }
+        }
+        for ent in dir.entries()? {
+            let ent = ent?;
+            if ent.file_type()?.is_file() {
+                set_mtime(dir, ent.file_name(), time)?;
+                n += 1;
+            }
+        }
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::OciDirOptions;
+    use cap_std_ext::{cap_std, cap_tempfile};
+    use chrono::TimeZone;
+    use oci_spec::image::ImageConfigurationBuilder;
+
+    fn mtimes(td: &Dir, w: &OciDir) -> Result<Vec<SystemTime>> {
+        let mut paths: Vec<_> = ["oci-layout", "index.json"].map(Into::into).into();
+        for digest in w.store.list()? {
+            paths.push(find_blob_path(td, &digest)?.unwrap());
+        }
+        paths
+            .iter()
+            .map(|p| Ok(td.metadata(p)?.modified()?.into_std()))
+            .collect()
+    }
+
+    #[test]
+    fn normalize_timestamps() -> Result<()> {
+        let epoch = Utc.timestamp_opt(1_700_000_000, 0).unwrap();
+        let expected = SystemTime::from(epoch);
+        let insert = |w: &OciDir, os: oci_spec::image::Os| -> Result<()> {
+            let config = ImageConfigurationBuilder::default().os(os).build()?;
+            let manifest = crate::new_empty_manifest().build()?;
+            w.insert_manifest_and_config(manifest, config, Some("latest"), Default::default())?;
+            Ok(())
+        };
+
+        let td = cap_tempfile::tempdir(cap_std::ambient_authority())?;
+        let w = OciDir::ensure(&td)?;
+        insert(&w, oci_spec::image::Os::Linux)?;
+        assert!(mtimes(&td, &w)?.iter().all(|t| *t != expected));
+        assert_eq!(w.normalize_timestamps(epoch)?, 4);
+        assert!(mtimes(&td, &w)?.iter().all(|t| *t == expected));
+
+        // New files get the time when the option is set
+        let td = cap_tempfile::tempdir(cap_std::ambient_authority())?;
+        let opts = OciDirOptions {
+            normalize_mtime: Some(epoch),
+            journal: true,
+            ..Default::default()
+        };
+        let w = OciDir::ensure_with(&td, &opts)?;
+        insert(&w, oci_spec::image::Os::Windows)?;
+        let times = mtimes(&td, &w)?;
+        assert_eq!(times.len(), 4);
+        assert!(times.iter().all(|t| *t == expected));
+        let journal = td.metadata(crate::JOURNAL_FILE)?.modified()?.into_std();
+        assert_eq!(journal, expected);
+
+        assert_eq!(OciDir::new_in_memory()?.normalize_timestamps(epoch)?, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn direct_writes() -> Result<()> {
+        let epoch = Utc.timestamp_opt(1_700_000_000, 0).unwrap();
+        let src_td = cap_tempfile::tempdir(cap_std::ambient_authority())?;
+        let src = OciDir::ensure(&src_td)?;
+        let config = ImageConfigurationBuilder::default().build()?;
+        let manifest = crate::new_empty_manifest().build()?;
+        src.insert_manifest_and_config(manifest, config, Some("latest"), Default::default())?;
+
+        let td = cap_tempfile::tempdir(cap_std::ambient_authority())?;
+        let opts = OciDirOptions {
+            normalize_mtime: Some(epoch),
+            ..Default::default()
+        };
+        let w = OciDir::ensure_with(&td, &opts)?;
+        let r = w.sync_from(&src, &Default::default())?;
+        assert_eq!(r.copied.len(), 2);
+        #[cfg(feature = "rust-crypto")]
+        {
+            let mut bw = w.create_resumable_blob()?;
+            bw.write_all(b"resumed")?;
+            bw.complete()?;
+        }
+        assert!(mtimes(&td, &w)?
+            .iter()
+            .all(|t| *t == SystemTime::from(epoch)));
+        Ok(())
+    }
+}
@@ -0,0 +1,135 @@
+//! Multithreaded gzip compression.
+//!
+//! Like `pigz`, the input is split into chunks which are compressed independently
+//! as raw deflate streams terminated by a sync flush, and concatenated into a
+//! single gzip member. The result is a standard gzip stream that any decoder
+//! can read, at the cost of a slightly worse compression ratio.
+
+use std::io::{self, Write};
+
+use flate2::write::DeflateEncoder;
+use flate2::{Compression, Crc};
+
+/// The amount of uncompressed input per chunk.
+const CHUNK_SIZE: usize = 128 * 1024;
+
+/// A gzip header without a file name or timestamp.
+const GZIP_HEADER: [u8; 10] = [0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 0xff];
+
+/// Compress a chunk; all but the last chunk end with a sync flush rather
+/// than a final block.
+fn deflate_chunk(data: &[u8], level: Compression, last: bool) -> io::Result<Vec<u8>> {
+    let mut e = DeflateEncoder::new(Vec::with_capacity(data.len() / 2), level);
+    e.write_all(data)?;
+    if last {
+        e.finish()
+    } else {
+        e.flush()?;
+        Ok(std::mem::take(e.get_mut()))
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct ParallelGzip {
+    level: Compression,
+    threads: usize,
+    /// Full chunks waiting to be compressed.
+    pending: Vec<Vec<u8>>,
+    current: Vec<u8>,
+    crc: Crc,
+    header_written: bool,
+}
+
+impl ParallelGzip {
+    pub(crate) fn new(level: Compression, threads: usize) -> Self {
+        Self {
+            level,
+            threads,
+            pending: Vec::with_capacity(threads),
+            current: Vec::with_capacity(CHUNK_SIZE),
+            crc: Crc::new(),
+            header_written: false,
+        }
+    }
+
+    /// Buffer input, writing compressed output to `out` once enough chunks
+    /// are available to keep all threads busy.
+    pub(crate) fn write(&mut self, mut buf: &[u8], out: &mut impl Write) -> io::Result<()> {
+        self.crc.update(buf);
+        while !buf.is_empty() {
+            let n = (CHUNK_SIZE - self.current.len()).min(buf.len());
+            self.current.extend_from_slice(&buf[..n]);
+            buf = &buf[n..];
+            if self.current.len() == CHUNK_SIZE {
+                let chunk = std::mem::replace(&mut self.current, Vec::with_capacity(CHUNK_SIZE));
+                self.pending.push(chunk);
+                if self.pending.len() == self.threads {
+                    self.compress_pending(out, false)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn compress_pending(&mut self, out: &mut impl Write, last: bool) -> io::Result<()> {
+        let mut chunks = std::mem::take(&mut self.pending);
+        if last {
+            chunks.push(std::mem::take(&mut self.current));
+        }
+        let n = chunks.len();
+        let level = self.level;
+        let compressed = std::thread::scope(|s| {
+            let handles: Vec<_> = chunks
+                .iter()
+                .enumerate()
+                .map(|(i, c)| s.spawn(move || deflate_chunk(c, level, last && i + 1 == n)))
+                .collect();
+            handles
+                .into_iter()
+                .map(|h| h.join().expect("compression thread panicked"))
+                .collect::<io::Result<Vec<_>>>()
+        })?;
+        if !self.header_written {
+            out.write_all(&GZIP_HEADER)?;
+            self.header_written = true;
+        }
+        for c in compressed {
+            out.write_all(&c)?;
+        }
+        Ok(())
+    }
+
+    /// Compress all remaining input and write the gzip trailer.
+    pub(crate) fn finish(mut self, out: &mut impl Write) -> io::Result<()> {
+        self.compress_pending(out, true)?;
+        out.write_all(&self.crc.sum().to_le_bytes())?;
+        out.write_all(&self.crc.amount().to_le_bytes())?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn roundtrip() -> io::Result<()> {
+        let data: Vec<u8> = (0..(CHUNK_SIZE * 5 + 17))
+            .map(|i| (i % 251) as u8 ^ (i / 4096) as u8)
+            .collect();
+        for len in [0, 100, CHUNK_SIZE, data.len()] {
+            let mut out = Vec::new();
+            let mut gz = ParallelGzip::new(Compression::default(), 3);
+            for piece in data[..len].chunks(10_000) {
+                gz.write(piece, &mut out)?;
+            }
+            gz.finish(&mut out)?;
+            // A single gzip member, readable by a non-multistream decoder
+            let mut decompressed = Vec::new();
+            flate2::read::GzDecoder::new(out.as_slice()).read_to_end(&mut decompressed)?;
+            assert_eq!(decompressed, &data[..len]);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,155 @@
+//! Progress reporting for long-running operations.
+//!
+//! Install a [`ProgressReporter`] on an [`crate::OciDir`] via
+//! [`crate::OciDir::with_progress`]; all operations performed through it and the
+//! writers it creates will then emit per-blob events.
+
+use std::fmt::Debug;
+use std::io::Read;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use crate::throttle::RateLimiter;
+
+/// The kind of operation being performed on a blob.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ProgressOp {
+    /// A new blob is being written.
+    Write,
+    /// A blob is being copied from another layout.
+    Copy,
+    /// A blob's digest is being verified.
+    Verify,
+    /// A blob is being removed.
+    Remove,
+}
+
+/// Receives progress events; all methods have empty default implementations.
+///
+/// Each blob operation is identified by an `id` which is unique within the process,
+/// so that events from concurrent operations can be told apart.
+pub trait ProgressReporter: Send + Sync {
+    /// Processing of a blob has started. The digest is `None` for new blobs whose
+    /// digest is not yet known; the size is `None` if it is not known in advance.
+    fn blob_begin(&self, id: u64, op: ProgressOp, digest: Option<&str>, size: Option<u64>) {
+        let _ = (id, op, digest, size);
+    }
+
+    /// The given number of additional bytes have been processed.
+    fn blob_bytes(&self, id: u64, bytes: u64) {
+        let _ = (id, bytes);
+    }
+
+    /// Processing of the blob with the given digest has completed.
+    fn blob_end(&self, id: u64, digest: &str) {
+        let _ = (id, digest);
+    }
+}
+
+/// An optional reporter and rate limit, as stored on an `OciDir`.
+#[derive(Clone, Default)]
+pub(crate) struct Progress {
+    reporter: Option<Arc<dyn ProgressReporter>>,
+    limit: Option<Arc<RateLimiter>>,
+}
+
+impl Debug for Progress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Progress")
+            .field("reporter", &self.reporter.is_some())
+            .field("limit", &self.limit)
+            .finish()
+    }
+}
+
+impl Progress {
+    pub(crate) fn with_reporter(&self, reporter: Arc<dyn ProgressReporter>) -> Self {
+        Self {
+            reporter: Some(reporter),
+            limit: self.limit.clone(),
+        }
+    }
+
+    pub(crate) fn with_limit(&self, limit: RateLimiter) -> Self {
+        Self {
+            reporter: self.reporter.clone(),
+            limit: Some(Arc::new(limit)),
+        }
+    }
+
+    /// Begin an operation on a blob.
+    pub(crate) fn begin(
+        &self,
+        op: ProgressOp,
+        digest: Option<&str>,
+        size: Option<u64>,
+    ) -> BlobProgress {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        let limit = self.limit.clone();
+        let Some(reporter) = self.reporter.as_ref() else {
+            return BlobProgress {
+                reporter: None,
+                limit,
+            };
+        };
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        reporter.blob_begin(id, op, digest, size);
+        BlobProgress {
+            reporter: Some((id, Arc::clone(reporter))),
+            limit,
+        }
+    }
+}
+
+/// Progress of a single blob operation.
+#[derive(Clone, Default)]
+pub(crate) struct BlobProgress {
+    reporter: Option<(u64, Arc<dyn ProgressReporter>)>,
+    limit: Option<Arc<RateLimiter>>,
+}
+
+impl Debug for BlobProgress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("BlobProgress")
+            .field(&self.reporter.as_ref().map(|v| v.0))
+            .finish()
+    }
+}
+
+impl BlobProgress {
+    pub(crate) fn bytes(&self, n: u64) {
+        if let Some((id, reporter)) = self.reporter.as_ref() {
+            reporter.blob_bytes(*id, n);
+        }
+    }
+
+    /// Block as necessary to stay within the rate limit, if any, after
+    /// transferring `n` bytes.
+    pub(crate) fn throttle(&self, n: u64) {
+        if let Some(limit) = self.limit.as_ref() {
+            limit.consume(n);
+        }
+    }
+
+    pub(crate) fn end(&self, digest: &str) {
+        if let Some((id, reporter)) = self.reporter.as_ref() {
+            reporter.blob_end(*id, digest);
+        }
+    }
+}
+
+/// A reader which reports the bytes read through it, subject to the rate limit.
+pub(crate) struct ProgressReader<'a, R> {
+    pub(crate) inner: R,
+    pub(crate) progress: &'a BlobProgress,
+}
+
+impl<'a, R: Read> Read for ProgressReader<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.progress.bytes(n as u64);
+        self.progress.throttle(n as u64);
+        Ok(n)
+    }
+}
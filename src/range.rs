@@ -0,0 +1,130 @@
+//! Reading byte ranges of blobs, such as for lazy pulling.
+
+use std::io::{Read, Seek};
+#[cfg(feature = "mmap")]
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use fn_error_context::context;
+use oci_spec::image::Descriptor;
+
+use crate::{BlobReader, OciDir};
+
+/// The contents of a blob mapped into memory, see [`OciDir::map_blob`].
+#[cfg(feature = "mmap")]
+#[derive(Debug)]
+pub struct MappedBlob(Mapped);
+
+#[cfg(feature = "mmap")]
+#[derive(Debug)]
+enum Mapped {
+    File(memmap2::Mmap),
+    Memory(Arc<[u8]>),
+}
+
+#[cfg(feature = "mmap")]
+impl std::ops::Deref for MappedBlob {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match &self.0 {
+            Mapped::File(m) => m,
+            Mapped::Memory(m) => m,
+        }
+    }
+}
+
+#[cfg(feature = "mmap")]
+impl AsRef<[u8]> for MappedBlob {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+fn descriptor_size(desc: &Descriptor) -> Result<u64> {
+    u64::try_from(desc.size()).map_err(|_| anyhow!("Invalid size {}", desc.size()))
+}
+
+impl OciDir {
+    /// Open `len` bytes of a blob starting at `offset`, which must lie within the
+    /// size of the descriptor.
+    ///
+    /// Like [`Self::read_blob`], this verifies the whole blob first if
+    /// [`crate::OciDirOptions::verify`] requires it.
+    #[context("Reading range of {}", desc.digest())]
+    pub fn read_blob_range(
+        &self,
+        desc: &Descriptor,
+        offset: u64,
+        len: u64,
+    ) -> Result<std::io::Take<BlobReader>> {
+        let size = descriptor_size(desc)?;
+        if !matches!(offset.checked_add(len), Some(end) if end <= size) {
+            anyhow::bail!("Range of {len} bytes at {offset} exceeds blob size {size}");
+        }
+        let mut r = self.read_blob(desc)?;
+        r.seek(std::io::SeekFrom::Start(offset))?;
+        Ok(r.take(len))
+    }
+
+    /// Map the contents of a blob into memory, which is efficient for many small
+    /// random reads from a large blob. Blobs of in-memory layouts are shared
+    /// without copying, and blobs streamed by a custom store are read into memory.
+    ///
+    /// Blobs are never modified in place by this crate; the mapping must not be
+    /// used if other processes may truncate or rewrite blob files.
+    #[cfg(feature = "mmap")]
+    #[context("Mapping {}", desc.digest())]
+    pub fn map_blob(&self, desc: &Descriptor) -> Result<MappedBlob> {
+        let size = descriptor_size(desc)?;
+        let mapped = match self.read_blob(desc)? {
+            BlobReader::File(f) if size > 0 => {
+                // SAFETY: Blob files are content addressed and only ever replaced
+                // atomically, never modified in place.
+                Mapped::File(unsafe { memmap2::Mmap::map(&f)? })
+            }
+            BlobReader::File(_) => Mapped::Memory(Arc::new([])),
+            BlobReader::Memory(c) => Mapped::Memory(c.into_inner()),
+            BlobReader::Other(mut r) => {
+                let mut buf = Vec::new();
+                r.read_to_end(&mut buf)?;
+                Mapped::Memory(buf.into())
+            }
+        };
+        let r = MappedBlob(mapped);
+        if r.len() as u64 != size {
+            anyhow::bail!("Expected blob size {size} but found {}", r.len());
+        }
+        Ok(r)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cap_std_ext::{cap_std, cap_tempfile};
+
+    #[test]
+    fn range() -> Result<()> {
+        let td = cap_tempfile::tempdir(cap_std::ambient_authority())?;
+        let content: Vec<u8> = (0..=255).collect();
+        for w in [OciDir::ensure(&td)?, OciDir::new_in_memory()?] {
+            let (blob, _) = w.write_blob_dedup(content.as_slice())?;
+            let desc = blob
+                .descriptor()
+                .media_type(oci_spec::image::MediaType::ImageLayer)
+                .build()?;
+            let mut buf = Vec::new();
+            w.read_blob_range(&desc, 10, 5)?.read_to_end(&mut buf)?;
+            assert_eq!(buf, [10, 11, 12, 13, 14]);
+            buf.clear();
+            w.read_blob_range(&desc, 250, 6)?.read_to_end(&mut buf)?;
+            assert_eq!(buf, &content[250..]);
+            assert!(w.read_blob_range(&desc, 250, 7).is_err());
+            assert!(w.read_blob_range(&desc, u64::MAX, 2).is_err());
+            #[cfg(feature = "mmap")]
+            assert_eq!(&*w.map_blob(&desc)?, content.as_slice());
+        }
+        Ok(())
+    }
+}
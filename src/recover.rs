@@ -0,0 +1,122 @@
+//! Cleanup of state left behind by interrupted writers.
+
+use std::time::{Duration, SystemTime};
+
+use anyhow::Result;
+use camino::Utf8Path;
+use cap_std_ext::cap_std::fs::Dir;
+use cap_std_ext::dirext::CapStdExtDirExt;
+use fn_error_context::context;
+
+use crate::store::STAGED_TEMP_PREFIX;
+use crate::OciDir;
+
+/// Returns true if this looks like the name of a temporary file created
+/// by `cap_tempfile` when `O_TMPFILE` is unavailable.
+fn is_tempfile_name(name: &str) -> bool {
+    let b = name.as_bytes();
+    if b.len() == 36 {
+        return b.iter().enumerate().all(|(i, &c)| match i {
+            8 | 13 | 18 | 23 => c == b'-',
+            _ => c.is_ascii_hexdigit(),
+        });
+    }
+    name.strip_prefix("cap-primitives.")
+        .is_some_and(|n| !n.is_empty() && n.bytes().all(|c| c.is_ascii_digit()))
+}
+
+impl OciDir {
+    /// Remove temporary files left behind by crashed or killed writers which are
+    /// older than `min_age`, returning the names of the removed files.
+    ///
+    /// A threshold should be chosen that exceeds the duration of any blob write which
+    /// may be concurrently in progress. This is a no-op for in-memory layouts.
+    #[context("Recovering OCI dir")]
+    pub fn recover(&self, min_age: Duration) -> Result<Vec<String>> {
+        let mut removed = Vec::new();
+        let Some(dir) = self.writable_dir()? else {
+            return Ok(removed);
+        };
+        let now = SystemTime::now();
+        remove_stale(dir, None, is_tempfile_name, now, min_age, &mut removed)?;
+        if let Some(staging) = self.opts.staging_dir.as_deref() {
+            if let Some(sdir) = dir.open_dir_optional(staging)? {
+                let is_staged = |n: &str| n.starts_with(STAGED_TEMP_PREFIX);
+                remove_stale(&sdir, Some(staging), is_staged, now, min_age, &mut removed)?;
+            }
+        }
+        removed.sort();
+        Ok(removed)
+    }
+}
+
+/// Remove the files in `dir` matching `filter` which are older than `min_age`,
+/// recording their paths relative to the layout.
+fn remove_stale(
+    dir: &Dir,
+    prefix: Option<&Utf8Path>,
+    filter: impl Fn(&str) -> bool,
+    now: SystemTime,
+    min_age: Duration,
+    removed: &mut Vec<String>,
+) -> Result<()> {
+    for ent in dir.entries()? {
+        let ent = ent?;
+        let Some(name) = ent.file_name().to_str().map(ToOwned::to_owned) else {
+            continue;
+        };
+        if !filter(&name) {
+            continue;
+        }
+        let meta = ent.metadata()?;
+        if !meta.is_file() {
+            continue;
+        }
+        let mtime = meta.modified()?.into_std();
+        // Files with an mtime in the future are left alone.
+        let age = now.duration_since(mtime).unwrap_or_default();
+        if age < min_age {
+            continue;
+        }
+        dir.remove_file(&name)?;
+        match prefix {
+            Some(p) => removed.push(p.join(&name).into_string()),
+            None => removed.push(name),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cap_std;
+    use cap_std_ext::cap_tempfile;
+
+    #[test]
+    fn names() {
+        assert!(is_tempfile_name("67e55044-10b1-426f-9247-bb680e5fe0c8"));
+        assert!(is_tempfile_name("cap-primitives.42"));
+        assert!(!is_tempfile_name("index.json"));
+        assert!(!is_tempfile_name("67e55044-10b1-426f-9247-bb680e5fe0c8x"));
+        assert!(!is_tempfile_name("cap-primitives."));
+    }
+
+    #[test]
+    fn recover() -> Result<()> {
+        let td = cap_tempfile::tempdir(cap_std::ambient_authority())?;
+        let stale = "67e55044-10b1-426f-9247-bb680e5fe0c8";
+        td.write(stale, "partial blob")?;
+        let opts = crate::OciDirOptions {
+            recover_older_than: Some(Duration::from_secs(3600)),
+            ..Default::default()
+        };
+        let d = OciDir::ensure_with(&td, &opts)?;
+        assert!(td.try_exists(stale)?);
+        assert_eq!(d.recover(Duration::ZERO)?, [stale]);
+        assert!(!td.try_exists(stale)?);
+        assert!(td.try_exists("oci-layout")?);
+        assert!(OciDir::new_in_memory()?.recover(Duration::ZERO)?.is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,250 @@
+//! Image references such as `quay.io/example/app:latest`, and finding images
+//! by reference when the `org.opencontainers.image.ref.name` annotation holds
+//! full references rather than bare tags.
+
+use std::fmt::Display;
+use std::str::FromStr;
+
+use anyhow::Result;
+use fn_error_context::context;
+use oci_spec::image::{Descriptor, ImageManifest, MediaType};
+
+use crate::{OciDir, OCI_TAG_ANNOTATION};
+
+pub(crate) const DOCKER_HUB: &str = "docker.io";
+/// The tag implied by a reference with neither a tag nor a digest.
+const DEFAULT_TAG: &str = "latest";
+
+/// A reference to an image in a registry, such as `quay.io/example/app:latest`.
+///
+/// As with other container tools, a reference without a registry refers to
+/// Docker Hub, and single component repositories there are in `library/`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reference {
+    /// The registry host, with an optional port.
+    pub registry: String,
+    /// The repository name within the registry.
+    pub repository: String,
+    /// The tag, if any.
+    pub tag: Option<String>,
+    /// The manifest digest, if any.
+    pub digest: Option<String>,
+}
+
+impl FromStr for Reference {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (name, digest) = match s.split_once('@') {
+            Some((name, digest)) => {
+                crate::store::split_digest(digest)?;
+                (name, Some(digest.to_owned()))
+            }
+            None => (s, None),
+        };
+        let (name, tag) = match name.rsplit_once(':') {
+            Some((n, t)) if !t.contains('/') => (n, Some(t.to_owned())),
+            _ => (name, None),
+        };
+        let (registry, repository) = match name.split_once('/') {
+            Some((r, repo)) if r.contains(['.', ':']) || r == "localhost" => {
+                (r.to_owned(), repo.to_owned())
+            }
+            Some(_) => (DOCKER_HUB.to_owned(), name.to_owned()),
+            None => (DOCKER_HUB.to_owned(), format!("library/{name}")),
+        };
+        if repository.is_empty()
+            || !repository
+                .chars()
+                .all(|c| matches!(c, 'a'..='z' | '0'..='9' | '.' | '_' | '-' | '/'))
+        {
+            anyhow::bail!("Invalid repository name in reference {s}");
+        }
+        Ok(Self {
+            registry,
+            repository,
+            tag,
+            digest,
+        })
+    }
+}
+
+impl Display for Reference {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.registry, self.repository)?;
+        if let Some(tag) = &self.tag {
+            write!(f, ":{tag}")?;
+        }
+        if let Some(digest) = &self.digest {
+            write!(f, "@{digest}")?;
+        }
+        Ok(())
+    }
+}
+
+impl Reference {
+    /// The tag this reference refers to: its tag, or otherwise `latest` unless
+    /// it has a digest.
+    fn effective_tag(&self) -> Option<&str> {
+        match (&self.tag, &self.digest) {
+            (Some(tag), _) => Some(tag),
+            (None, Some(_)) => None,
+            (None, None) => Some(DEFAULT_TAG),
+        }
+    }
+}
+
+/// How [`OciDir::find_manifest_with_reference`] compares a reference with the
+/// `org.opencontainers.image.ref.name` annotation of index entries.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ReferenceMatch {
+    /// The annotation must be a full reference to the same repository, after
+    /// normalization; for example `busybox` matches `docker.io/library/busybox:latest`.
+    #[default]
+    Exact,
+    /// Only the tag is compared, so that entries annotated with bare tags or with
+    /// references to other repositories match as well.
+    Tag,
+}
+
+/// A bare tag, as opposed to a full reference; tags cannot contain `/` or `:`.
+fn is_bare_tag(s: &str) -> bool {
+    !s.contains(['/', ':', '@'])
+}
+
+/// Returns true if the `ref.name` annotation `name` of the index entry `desc`
+/// matches `reference`.
+fn reference_matches(
+    reference: &Reference,
+    name: &str,
+    desc: &Descriptor,
+    mode: ReferenceMatch,
+) -> bool {
+    if reference
+        .digest
+        .as_deref()
+        .is_some_and(|d| d != desc.digest().as_str())
+    {
+        return false;
+    }
+    let (tag, repository) = if is_bare_tag(name) {
+        (Some(name.to_owned()), None)
+    } else {
+        match name.parse::<Reference>() {
+            Ok(r) => (r.effective_tag().map(ToOwned::to_owned), Some(r)),
+            Err(_) => return false,
+        }
+    };
+    if let Some(expected) = reference.effective_tag() {
+        if tag.as_deref() != Some(expected) {
+            return false;
+        }
+    }
+    match mode {
+        ReferenceMatch::Tag => true,
+        ReferenceMatch::Exact => repository.is_some_and(|r| {
+            r.registry == reference.registry && r.repository == reference.repository
+        }),
+    }
+}
+
+impl OciDir {
+    /// Find the image manifest whose index entry matches the provided reference,
+    /// along with its index descriptor.
+    ///
+    /// A reference with neither a tag nor a digest refers to `latest`; one with a
+    /// digest only matches entries with that digest. If several entries match, the
+    /// first is returned.
+    #[context("Finding manifest for {reference}")]
+    pub fn find_manifest_with_reference(
+        &self,
+        reference: &Reference,
+        mode: ReferenceMatch,
+    ) -> Result<Option<(ImageManifest, Descriptor)>> {
+        let Some(idx) = self.read_index()? else {
+            return Ok(None);
+        };
+        for desc in idx.manifests() {
+            if desc.media_type() != &MediaType::ImageManifest {
+                continue;
+            }
+            let Some(name) = desc
+                .annotations()
+                .as_ref()
+                .and_then(|a| a.get(OCI_TAG_ANNOTATION))
+            else {
+                continue;
+            };
+            if reference_matches(reference, name, desc, mode) {
+                return Ok(Some((self.read_json_blob(desc)?, desc.clone())));
+            }
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() -> Result<()> {
+        let r: Reference = "quay.io/example/app:v1@sha256:0123".parse()?;
+        assert_eq!(r.registry, "quay.io");
+        assert_eq!(r.repository, "example/app");
+        assert_eq!(r.tag.as_deref(), Some("v1"));
+        assert_eq!(r.digest.as_deref(), Some("sha256:0123"));
+        assert_eq!(r.to_string(), "quay.io/example/app:v1@sha256:0123");
+        let r: Reference = "busybox".parse()?;
+        assert_eq!(r.to_string(), "docker.io/library/busybox");
+        assert_eq!(r.effective_tag(), Some("latest"));
+        let r: Reference = "example/app@sha256:0123".parse()?;
+        assert_eq!(r.to_string(), "docker.io/example/app@sha256:0123");
+        assert_eq!(r.effective_tag(), None);
+        assert!("quay.io/Upper".parse::<Reference>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn find_with_reference() -> Result<()> {
+        let w = OciDir::new_in_memory()?;
+        let insert = |tag: &str, contents: &[u8]| -> Result<Descriptor> {
+            let mut manifest = crate::new_empty_manifest().build()?;
+            manifest.set_annotations(Some(
+                [("contents".to_owned(), String::from_utf8(contents.to_vec())?)].into(),
+            ));
+            w.insert_manifest(manifest, Some(tag), Default::default())
+        };
+        let full = insert("quay.io/example/app:v1", b"full")?;
+        let hub = insert("docker.io/library/busybox:latest", b"hub")?;
+        let bare = insert("v2", b"bare")?;
+
+        let find = |s: &str, mode| -> Result<Option<Descriptor>> {
+            Ok(w.find_manifest_with_reference(&s.parse()?, mode)?
+                .map(|(_, d)| d))
+        };
+        assert_eq!(
+            find("quay.io/example/app:v1", ReferenceMatch::Exact)?,
+            Some(full.clone())
+        );
+        assert_eq!(find("example.com/other:v1", ReferenceMatch::Exact)?, None);
+        assert_eq!(
+            find("example.com/other:v1", ReferenceMatch::Tag)?,
+            Some(full.clone())
+        );
+        // Normalization of Docker Hub references and the implied tag
+        assert_eq!(find("busybox", ReferenceMatch::Exact)?, Some(hub.clone()));
+        // Bare tags only match by tag
+        assert_eq!(find("quay.io/example/app:v2", ReferenceMatch::Exact)?, None);
+        assert_eq!(
+            find("quay.io/example/app:v2", ReferenceMatch::Tag)?,
+            Some(bare)
+        );
+        let by_digest = format!("quay.io/example/app@{}", full.digest());
+        assert_eq!(find(&by_digest, ReferenceMatch::Exact)?, Some(full));
+        let wrong_digest = format!("busybox:latest@{}", hub.digest().as_str().replace('a', "b"));
+        assert_eq!(find(&wrong_digest, ReferenceMatch::Tag)?, None);
+        Ok(())
+    }
+}
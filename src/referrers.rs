@@ -0,0 +1,208 @@
+//! Artifacts which refer to another manifest via the `subject` field, as
+//! introduced in OCI 1.1.
+
+use std::collections::HashMap;
+
+use anyhow::Result;
+use fn_error_context::context;
+use oci_spec::image::{self as oci_image, Descriptor, ImageManifest, MediaType};
+
+use crate::{write_json_blob_to_store, BlobWriter, OciDir};
+
+/// Contents of the empty config blob used by artifacts.
+const EMPTY_JSON: &[u8] = b"{}";
+
+/// The digest of the OCI empty blob `{}`, see [`empty_descriptor`].
+pub const EMPTY_BLOB_DIGEST: &str =
+    "sha256:44136fa355b3678a1146ad16f7e8649e94fb4fc21fe77e8310c060f61caaff8a";
+
+/// The descriptor of the OCI empty blob, the two bytes `{}` with media type
+/// `application/vnd.oci.empty.v1+json`. This is the config of artifacts which have
+/// none, and may also be used as a layer that has no content.
+///
+/// The blob itself is added to a layout by [`OciDir::ensure_empty_blob`].
+pub fn empty_descriptor() -> Descriptor {
+    oci_image::DescriptorBuilder::default()
+        .media_type(MediaType::EmptyJSON)
+        .digest(EMPTY_BLOB_DIGEST)
+        .size(EMPTY_JSON.len() as i64)
+        .build()
+        .unwrap()
+}
+
+/// The tag used by cosign for artifacts of the provided kind, such as `sig`,
+/// which refer to this digest.
+pub(crate) fn cosign_tag(digest: &str, suffix: &str) -> Result<String> {
+    let (alg, encoded) = crate::store::split_digest(digest)?;
+    Ok(format!("{alg}-{encoded}.{suffix}"))
+}
+
+impl OciDir {
+    /// Write a blob from memory, returning a descriptor with the provided media type.
+    pub(crate) fn write_blob_with_type(
+        &self,
+        buf: &[u8],
+        media_type: MediaType,
+    ) -> Result<oci_image::DescriptorBuilder> {
+        let mut w = BlobWriter::new(&*self.store, &self.progress)?;
+        std::io::Write::write_all(&mut w, buf)?;
+        Ok(w.complete()?.descriptor().media_type(media_type))
+    }
+
+    /// Add the OCI empty blob to this layout unless it is already present, and
+    /// return its descriptor; see [`empty_descriptor`].
+    #[context("Writing empty blob")]
+    pub fn ensure_empty_blob(&self) -> Result<Descriptor> {
+        if !self.store.has(EMPTY_BLOB_DIGEST)? {
+            self.write_blob_with_type(EMPTY_JSON, MediaType::EmptyJSON)?;
+        }
+        Ok(empty_descriptor())
+    }
+
+    /// Write an artifact manifest with the provided layers which refers to `subject`,
+    /// and add it to the index (untagged) with its artifact type. The layer blobs
+    /// must already be present; the config is the empty JSON blob.
+    ///
+    /// The annotations are set on both the manifest and its index entry.
+    #[context("Attaching referrer to {}", subject.digest())]
+    pub fn attach_referrer(
+        &self,
+        subject: &Descriptor,
+        artifact_type: MediaType,
+        layers: Vec<Descriptor>,
+        annotations: Option<HashMap<String, String>>,
+    ) -> Result<Descriptor> {
+        let config = self.ensure_empty_blob()?;
+        let mut subject_desc = oci_image::DescriptorBuilder::default()
+            .media_type(subject.media_type().clone())
+            .digest(subject.digest().clone())
+            .size(subject.size())
+            .build()?;
+        subject_desc.set_artifact_type(subject.artifact_type().clone());
+        let mut manifest = oci_image::ImageManifestBuilder::default()
+            .schema_version(oci_image::SCHEMA_VERSION)
+            .media_type(MediaType::ImageManifest)
+            .artifact_type(artifact_type.clone())
+            .config(config)
+            .layers(layers)
+            .subject(subject_desc)
+            .build()?;
+        manifest.set_annotations(annotations.clone());
+        let mut desc = write_json_blob_to_store(
+            &*self.store,
+            &self.progress,
+            &manifest,
+            MediaType::ImageManifest,
+            &self.json_options(),
+        )?
+        .build()?;
+        desc.set_artifact_type(Some(artifact_type));
+        desc.set_annotations(annotations);
+        self.insert_descriptor(desc.clone(), None)?;
+        Ok(desc)
+    }
+
+    /// Append a layer to the manifest with the provided cosign tag, creating it if needed.
+    /// The config lists the layer digests as diff_ids, as cosign does.
+    pub(crate) fn append_to_cosign_tag(&self, tag: &str, layer: Descriptor) -> Result<Descriptor> {
+        let mut layers = match self.find_manifest_with_tag(tag)? {
+            Some(existing) => existing.layers().clone(),
+            None => Vec::new(),
+        };
+        layers.push(layer);
+        let diff_ids: Vec<_> = layers.iter().map(|l| l.digest().clone()).collect();
+        let rootfs = oci_image::RootFsBuilder::default()
+            .typ("layers")
+            .diff_ids(diff_ids)
+            .build()?;
+        let config = oci_image::ImageConfigurationBuilder::default()
+            .rootfs(rootfs)
+            .build()?;
+        let mut manifest = crate::new_empty_manifest().build()?;
+        manifest.set_media_type(Some(MediaType::ImageManifest));
+        manifest.set_config(self.write_config(config)?);
+        manifest.set_layers(layers);
+        let opts = crate::InsertOptions {
+            tag: Some(tag.to_owned()),
+            ..Default::default()
+        };
+        self.insert_manifest_with(manifest, &opts)
+    }
+
+    /// Find the manifests in the index which refer to `subject`, optionally only
+    /// those with the provided artifact type.
+    #[context("Finding referrers of {}", subject.digest())]
+    pub fn referrers(
+        &self,
+        subject: &Descriptor,
+        artifact_type: Option<&MediaType>,
+    ) -> Result<Vec<Descriptor>> {
+        let Some(index) = self.read_index()? else {
+            return Ok(Vec::new());
+        };
+        let mut r = Vec::new();
+        for desc in index.manifests() {
+            if desc.media_type() != &MediaType::ImageManifest {
+                continue;
+            }
+            let manifest: ImageManifest = self.read_json_blob(desc)?;
+            if manifest.subject().as_ref().map(|s| s.digest()) != Some(subject.digest()) {
+                continue;
+            }
+            if artifact_type.is_some_and(|t| manifest.artifact_type().as_ref() != Some(t)) {
+                continue;
+            }
+            r.push(desc.clone());
+        }
+        Ok(r)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn referrers() -> Result<()> {
+        let w = OciDir::new_in_memory()?;
+        let manifest = crate::new_empty_manifest().build().unwrap();
+        let subject = w.insert_manifest(manifest, Some("app"), Default::default())?;
+        let layer = w
+            .write_blob_with_type(b"notes", MediaType::Other("text/plain".into()))?
+            .build()?;
+        let t = MediaType::Other("application/vnd.example.notes".into());
+        let desc = w.attach_referrer(&subject, t.clone(), vec![layer], None)?;
+        assert_eq!(desc.artifact_type().as_ref(), Some(&t));
+        assert_eq!(w.referrers(&subject, None)?, std::slice::from_ref(&desc));
+        assert_eq!(
+            w.referrers(&subject, Some(&t))?,
+            std::slice::from_ref(&desc)
+        );
+        assert!(w
+            .referrers(&subject, Some(&MediaType::ImageLayer))?
+            .is_empty());
+        assert!(w.referrers(&desc, None)?.is_empty());
+        assert!(w
+            .describe()?
+            .extensions
+            .contains(&crate::LayoutExtension::Referrers));
+        let manifest: ImageManifest = w.read_json_blob(&desc)?;
+        assert_eq!(manifest.config(), &empty_descriptor());
+        Ok(())
+    }
+
+    #[test]
+    fn empty_blob() -> Result<()> {
+        let w = OciDir::new_in_memory()?;
+        let desc = w.ensure_empty_blob()?;
+        assert_eq!(desc, w.ensure_empty_blob()?);
+        assert_eq!(w.fsck()?, 1);
+        assert_eq!(
+            crate::hash::sha256_hex(EMPTY_JSON)?,
+            EMPTY_BLOB_DIGEST.strip_prefix("sha256:").unwrap()
+        );
+        let v: serde_json::Value = w.read_json_blob(&desc)?;
+        assert_eq!(v, serde_json::json!({}));
+        Ok(())
+    }
+}
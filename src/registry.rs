@@ -0,0 +1,1080 @@
+//! Copying images to and from registries using the
+//! [OCI distribution API](https://github.com/opencontainers/distribution-spec).
+//!
+//! This requires the `registry` feature. The built-in [`HttpTransport`] speaks
+//! HTTP/1.1, using OpenSSL for `https` independently of the hashing backend;
+//! a custom [`Transport`] can be used to plug in another HTTP client.
+
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::sync::Mutex;
+
+use anyhow::{anyhow, Context, Result};
+use base64::prelude::*;
+use fn_error_context::context;
+use oci_spec::image::{Descriptor, DescriptorBuilder, ImageIndex, ImageManifest, MediaType};
+
+use crate::reference::DOCKER_HUB;
+pub use crate::Reference;
+use crate::{JsonSizeLimits, OciDir, OCI_TAG_ANNOTATION};
+
+/// The media type of a Docker schema 2 manifest.
+const DOCKER_MANIFEST: &str = "application/vnd.docker.distribution.manifest.v2+json";
+/// The media type of a Docker manifest list.
+const DOCKER_MANIFEST_LIST: &str = "application/vnd.docker.distribution.manifest.list.v2+json";
+const MAX_REDIRECTS: usize = 5;
+/// The maximum size of a token response.
+const MAX_TOKEN_RESPONSE: u64 = 1024 * 1024;
+const DOCKER_HUB_API: &str = "registry-1.docker.io";
+
+impl Reference {
+    /// The host to use for API requests.
+    fn api_host(&self) -> &str {
+        if self.registry == DOCKER_HUB {
+            DOCKER_HUB_API
+        } else {
+            &self.registry
+        }
+    }
+
+    /// Returns true if the registry is on the local machine.
+    fn is_local(&self) -> bool {
+        let host = self.registry.split(':').next().unwrap_or_default();
+        matches!(host, "localhost" | "127.0.0.1")
+    }
+}
+
+/// Credentials for a registry.
+#[derive(Clone, Default)]
+#[non_exhaustive]
+pub enum Auth {
+    /// No credentials; anonymous tokens are still requested if needed.
+    #[default]
+    Anonymous,
+    /// A username and password, used directly or to request tokens.
+    Basic {
+        /// The username.
+        username: String,
+        /// The password.
+        password: String,
+    },
+    /// A bearer token, such as an identity token from a credential helper.
+    Bearer(String),
+}
+
+impl Debug for Auth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Anonymous => write!(f, "Anonymous"),
+            Self::Basic { username, .. } => write!(f, "Basic({username})"),
+            Self::Bearer(_) => write!(f, "Bearer"),
+        }
+    }
+}
+
+impl Auth {
+    fn basic_header(&self) -> Option<String> {
+        match self {
+            Self::Basic { username, password } => Some(format!(
+                "Basic {}",
+                BASE64_STANDARD.encode(format!("{username}:{password}"))
+            )),
+            _ => None,
+        }
+    }
+}
+
+/// The body of a [`Request`].
+#[non_exhaustive]
+pub enum Body<'a> {
+    /// No body.
+    Empty,
+    /// A body held in memory.
+    Bytes(&'a [u8]),
+    /// A streamed body of the provided length.
+    Reader(Box<dyn Read + 'a>, u64),
+}
+
+impl<'a> Body<'a> {
+    /// The length of the body.
+    pub fn len(&self) -> u64 {
+        match self {
+            Self::Empty => 0,
+            Self::Bytes(b) => b.len() as u64,
+            Self::Reader(_, n) => *n,
+        }
+    }
+
+    /// Returns true if the body is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Copy the body into a writer.
+    pub fn write_to(self, w: &mut dyn Write) -> std::io::Result<u64> {
+        match self {
+            Self::Empty => Ok(0),
+            Self::Bytes(b) => w.write_all(b).map(|_| b.len() as u64),
+            Self::Reader(mut r, _) => std::io::copy(&mut r, w),
+        }
+    }
+}
+
+/// An HTTP request sent through a [`Transport`].
+#[non_exhaustive]
+pub struct Request<'a> {
+    /// The method, such as `GET`.
+    pub method: &'a str,
+    /// The absolute URL.
+    pub url: String,
+    /// Additional headers.
+    pub headers: Vec<(String, String)>,
+    /// The request body.
+    pub body: Body<'a>,
+}
+
+/// An HTTP response returned by a [`Transport`].
+pub struct Response {
+    /// The status code.
+    pub status: u16,
+    /// The response headers.
+    pub headers: Vec<(String, String)>,
+    /// The response body.
+    pub body: Box<dyn Read + Send>,
+}
+
+impl Debug for Response {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Response")
+            .field("status", &self.status)
+            .field("headers", &self.headers)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Response {
+    /// Create a response with an in-memory body.
+    pub fn new(status: u16, headers: Vec<(String, String)>, body: Vec<u8>) -> Self {
+        Self {
+            status,
+            headers,
+            body: Box::new(std::io::Cursor::new(body)),
+        }
+    }
+
+    /// Look up a header, ignoring case.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Read the whole body, failing if it is larger than `limit` bytes.
+    fn into_bytes(self, limit: u64) -> Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        self.body
+            .take(limit.saturating_add(1))
+            .read_to_end(&mut buf)?;
+        if buf.len() as u64 > limit {
+            anyhow::bail!("Response body exceeds the limit of {limit} bytes");
+        }
+        Ok(buf)
+    }
+
+    /// Convert an unexpected status into an error, including any error message from the registry.
+    fn error(mut self, what: &str) -> anyhow::Error {
+        let mut buf = Vec::new();
+        let _ = (&mut self.body).take(4096).read_to_end(&mut buf);
+        let msg = String::from_utf8_lossy(&buf);
+        anyhow!("{what}: HTTP {}: {}", self.status, msg.trim())
+    }
+}
+
+/// Sends HTTP requests for a [`Client`].
+pub trait Transport: Debug + Send + Sync {
+    /// Send a request and return the response, without following redirects.
+    fn send(&self, req: Request<'_>) -> Result<Response>;
+}
+
+/// A minimal HTTP/1.1 client, using a new connection per request.
+#[derive(Debug, Default)]
+pub struct HttpTransport {}
+
+trait Stream: Read + Write + Send {}
+impl<T: Read + Write + Send> Stream for T {}
+
+/// Split a URL into whether it uses TLS, the host, the port and the path.
+fn parse_url(url: &str) -> Result<(bool, &str, u16, &str)> {
+    let (tls, rest) = if let Some(rest) = url.strip_prefix("https://") {
+        (true, rest)
+    } else if let Some(rest) = url.strip_prefix("http://") {
+        (false, rest)
+    } else {
+        anyhow::bail!("Unsupported URL {url}");
+    };
+    let (authority, path) = match rest.find('/') {
+        Some(i) => rest.split_at(i),
+        None => (rest, "/"),
+    };
+    let (host, port) = match authority.rsplit_once(':') {
+        Some((h, p)) => (
+            h,
+            p.parse()
+                .with_context(|| format!("Invalid port in {url}"))?,
+        ),
+        None => (authority, if tls { 443 } else { 80 }),
+    };
+    Ok((tls, host, port, path))
+}
+
+fn host_of(url: &str) -> Option<&str> {
+    let rest = url.split_once("://")?.1;
+    Some(rest.split('/').next().unwrap_or(rest))
+}
+
+fn connect_tls(host: &str, tcp: std::net::TcpStream) -> Result<Box<dyn Stream>> {
+    let connector =
+        openssl::ssl::SslConnector::builder(openssl::ssl::SslMethod::tls_client())?.build();
+    Ok(Box::new(connector.connect(host, tcp)?))
+}
+
+/// Reads a body with chunked transfer encoding.
+struct ChunkedReader<R> {
+    inner: R,
+    remaining: u64,
+    done: bool,
+}
+
+impl<R: BufRead> ChunkedReader<R> {
+    fn read_line(&mut self) -> std::io::Result<String> {
+        let mut line = String::new();
+        self.inner.read_line(&mut line)?;
+        Ok(line)
+    }
+}
+
+impl<R: BufRead> Read for ChunkedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.done || buf.is_empty() {
+            return Ok(0);
+        }
+        if self.remaining == 0 {
+            let line = self.read_line()?;
+            let size = line.trim().split(';').next().unwrap_or_default();
+            self.remaining = u64::from_str_radix(size, 16).map_err(|_| {
+                std::io::Error::new(std::io::ErrorKind::InvalidData, "Invalid chunk size")
+            })?;
+            if self.remaining == 0 {
+                // Skip trailers
+                while !self.read_line()?.trim().is_empty() {}
+                self.done = true;
+                return Ok(0);
+            }
+        }
+        let n = buf
+            .len()
+            .min(self.remaining.try_into().unwrap_or(usize::MAX));
+        let n = self.inner.read(&mut buf[..n])?;
+        if n == 0 {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        self.remaining -= n as u64;
+        if self.remaining == 0 {
+            self.read_line()?;
+        }
+        Ok(n)
+    }
+}
+
+impl Transport for HttpTransport {
+    fn send(&self, req: Request<'_>) -> Result<Response> {
+        let (tls, host, port, path) = parse_url(&req.url)?;
+        let tcp = std::net::TcpStream::connect((host, port))
+            .with_context(|| format!("Connecting to {host}:{port}"))?;
+        let mut stream: Box<dyn Stream> = if tls {
+            connect_tls(host, tcp)?
+        } else {
+            Box::new(tcp)
+        };
+        let mut head = format!(
+            "{} {path} HTTP/1.1\r\nHost: {host}\r\nConnection: close\r\nUser-Agent: ocidir/{}\r\n",
+            req.method,
+            env!("CARGO_PKG_VERSION")
+        );
+        for (k, v) in &req.headers {
+            head.push_str(&format!("{k}: {v}\r\n"));
+        }
+        if !req.body.is_empty() || matches!(req.method, "POST" | "PUT" | "PATCH") {
+            head.push_str(&format!("Content-Length: {}\r\n", req.body.len()));
+        }
+        head.push_str("\r\n");
+        {
+            let mut w = std::io::BufWriter::new(&mut stream);
+            w.write_all(head.as_bytes())?;
+            req.body.write_to(&mut w)?;
+            w.flush()?;
+        }
+
+        let mut r = BufReader::new(stream);
+        let mut line = String::new();
+        r.read_line(&mut line)?;
+        let status = line
+            .split_whitespace()
+            .nth(1)
+            .and_then(|s| s.parse().ok())
+            .ok_or_else(|| anyhow!("Invalid HTTP status line: {}", line.trim()))?;
+        let mut headers = Vec::new();
+        loop {
+            line.clear();
+            if r.read_line(&mut line)? == 0 {
+                anyhow::bail!("Unexpected EOF in HTTP headers");
+            }
+            let l = line.trim_end();
+            if l.is_empty() {
+                break;
+            }
+            if let Some((k, v)) = l.split_once(':') {
+                headers.push((k.trim().to_owned(), v.trim().to_owned()));
+            }
+        }
+        let mut resp = Response::new(status, headers, Vec::new());
+        if req.method == "HEAD" || status == 204 || status == 304 {
+            return Ok(resp);
+        }
+        let chunked = resp
+            .header("transfer-encoding")
+            .is_some_and(|v| v.eq_ignore_ascii_case("chunked"));
+        let len = resp.header("content-length").and_then(|v| v.parse().ok());
+        resp.body = if chunked {
+            Box::new(ChunkedReader {
+                inner: r,
+                remaining: 0,
+                done: false,
+            })
+        } else if let Some(len) = len {
+            Box::new(r.take(len))
+        } else {
+            Box::new(r)
+        };
+        Ok(resp)
+    }
+}
+
+/// Options for a [`Client`].
+#[derive(Debug, Clone, Default)]
+pub struct RegistryOptions {
+    /// Credentials for the registry.
+    pub auth: Auth,
+    /// Use plain HTTP instead of https for API requests.
+    pub plain_http: bool,
+}
+
+/// A client for the OCI distribution API; see [`OciDir::pull_with`] and [`OciDir::push_with`].
+#[derive(Debug)]
+pub struct Client {
+    transport: Box<dyn Transport>,
+    opts: RegistryOptions,
+    token: Mutex<Option<String>>,
+}
+
+/// Percent-encode a URL query value.
+fn urlencode(s: &str) -> String {
+    let mut r = String::new();
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                r.push(b as char)
+            }
+            _ => r.push_str(&format!("%{b:02X}")),
+        }
+    }
+    r
+}
+
+/// Parse a `WWW-Authenticate` header into its scheme and parameters.
+fn parse_challenge(s: &str) -> (String, HashMap<String, String>) {
+    let (scheme, rest) = s.trim().split_once(' ').unwrap_or((s.trim(), ""));
+    let mut params = HashMap::new();
+    let mut rest = rest.trim();
+    while !rest.is_empty() {
+        let Some((k, v)) = rest.split_once('=') else {
+            break;
+        };
+        let k = k.trim().trim_start_matches(',').trim().to_ascii_lowercase();
+        let (v, next) = if let Some(v) = v.strip_prefix('"') {
+            v.split_once('"').unwrap_or((v, ""))
+        } else {
+            v.split_once(',').unwrap_or((v, ""))
+        };
+        params.insert(k, v.to_owned());
+        rest = next.trim_start_matches(',').trim();
+    }
+    (scheme.to_ascii_lowercase(), params)
+}
+
+fn media_type_is_index(mt: &MediaType) -> bool {
+    match mt {
+        MediaType::ImageIndex => true,
+        MediaType::Other(o) => o == DOCKER_MANIFEST_LIST,
+        _ => false,
+    }
+}
+
+fn media_type_is_manifest(mt: &MediaType) -> bool {
+    match mt {
+        MediaType::ImageManifest => true,
+        MediaType::Other(o) => o == DOCKER_MANIFEST,
+        _ => media_type_is_index(mt),
+    }
+}
+
+impl Client {
+    /// Create a client using [`HttpTransport`].
+    pub fn new(opts: RegistryOptions) -> Self {
+        Self::with_transport(opts, Box::<HttpTransport>::default())
+    }
+
+    /// Create a client using a custom transport.
+    pub fn with_transport(opts: RegistryOptions, transport: Box<dyn Transport>) -> Self {
+        Self {
+            transport,
+            opts,
+            token: Default::default(),
+        }
+    }
+
+    fn base_url(&self, r: &Reference) -> String {
+        let scheme = if self.opts.plain_http {
+            "http"
+        } else {
+            "https"
+        };
+        format!("{scheme}://{}", r.api_host())
+    }
+
+    fn url(&self, r: &Reference, path: &str) -> String {
+        format!("{}/v2/{}/{path}", self.base_url(r), r.repository)
+    }
+
+    fn authorization(&self) -> Option<String> {
+        if let Some(token) = self.token.lock().unwrap().as_ref() {
+            return Some(format!("Bearer {token}"));
+        }
+        match &self.opts.auth {
+            Auth::Bearer(token) => Some(format!("Bearer {token}")),
+            auth => auth.basic_header(),
+        }
+    }
+
+    /// Handle an authentication challenge, returning false if it cannot be satisfied.
+    fn authenticate(&self, challenge: &str) -> Result<bool> {
+        let (scheme, params) = parse_challenge(challenge);
+        match scheme.as_str() {
+            "basic" => Ok(matches!(self.opts.auth, Auth::Basic { .. })),
+            "bearer" => {
+                let realm = params
+                    .get("realm")
+                    .ok_or_else(|| anyhow!("Missing realm in authentication challenge"))?;
+                let mut url = realm.clone();
+                let query: Vec<String> = ["service", "scope"]
+                    .iter()
+                    .filter_map(|k| params.get(*k).map(|v| format!("{k}={}", urlencode(v))))
+                    .collect();
+                if !query.is_empty() {
+                    url.push(if url.contains('?') { '&' } else { '?' });
+                    url.push_str(&query.join("&"));
+                }
+                let headers = self
+                    .opts
+                    .auth
+                    .basic_header()
+                    .map(|h| ("Authorization".to_owned(), h))
+                    .into_iter()
+                    .collect();
+                let resp = self.transport.send(Request {
+                    method: "GET",
+                    url,
+                    headers,
+                    body: Body::Empty,
+                })?;
+                if resp.status != 200 {
+                    return Err(resp.error("Requesting token"));
+                }
+                let v: serde_json::Value =
+                    serde_json::from_slice(&resp.into_bytes(MAX_TOKEN_RESPONSE)?)?;
+                let token = v
+                    .get("token")
+                    .or_else(|| v.get("access_token"))
+                    .and_then(|t| t.as_str())
+                    .ok_or_else(|| anyhow!("Missing token in token response"))?;
+                *self.token.lock().unwrap() = Some(token.to_owned());
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    /// Send a request, handling authentication challenges and redirects.
+    /// Streamed bodies are not resent, so they should only be used once
+    /// authentication has been established by a previous request.
+    fn send(
+        &self,
+        r: &Reference,
+        method: &str,
+        url: String,
+        headers: &[(&str, &str)],
+        body: Body<'_>,
+    ) -> Result<Response> {
+        let replay = match &body {
+            Body::Empty => Some(&[][..]),
+            Body::Bytes(b) => Some(*b),
+            Body::Reader(..) => None,
+        };
+        let mut body = Some(body);
+        let mut url = url;
+        let mut authenticated = false;
+        for _ in 0..MAX_REDIRECTS {
+            let same_host = host_of(&url) == Some(r.api_host());
+            let mut hdrs: Vec<_> = headers
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect();
+            if same_host {
+                if let Some(a) = self.authorization() {
+                    hdrs.push(("Authorization".to_owned(), a));
+                }
+            }
+            let b = match body.take() {
+                Some(b) => b,
+                None => Body::Bytes(
+                    replay.ok_or_else(|| anyhow!("Cannot resend streamed request to {url}"))?,
+                ),
+            };
+            let resp = self.transport.send(Request {
+                method,
+                url: url.clone(),
+                headers: hdrs,
+                body: b,
+            })?;
+            match resp.status {
+                401 if same_host && !authenticated => {
+                    let Some(challenge) = resp.header("www-authenticate") else {
+                        return Ok(resp);
+                    };
+                    if !self.authenticate(challenge)? {
+                        return Ok(resp);
+                    }
+                    authenticated = true;
+                }
+                301 | 302 | 303 | 307 | 308 => {
+                    let location = resp
+                        .header("location")
+                        .ok_or_else(|| anyhow!("Redirect without location from {url}"))?;
+                    url = self.resolve(r, location);
+                }
+                _ => return Ok(resp),
+            }
+        }
+        anyhow::bail!("Too many redirects or authentication attempts for {url}")
+    }
+
+    /// Resolve a possibly relative location against the registry.
+    fn resolve(&self, r: &Reference, location: &str) -> String {
+        if location.contains("://") {
+            location.to_owned()
+        } else {
+            format!("{}{location}", self.base_url(r))
+        }
+    }
+
+    /// Fetch a manifest or index, bounded by the `limits` for its media type.
+    fn fetch_manifest(
+        &self,
+        r: &Reference,
+        mref: &str,
+        limits: &JsonSizeLimits,
+    ) -> Result<(MediaType, Vec<u8>)> {
+        let accept = [
+            MediaType::ImageManifest.to_string(),
+            MediaType::ImageIndex.to_string(),
+            DOCKER_MANIFEST.to_owned(),
+            DOCKER_MANIFEST_LIST.to_owned(),
+        ]
+        .join(", ");
+        let url = self.url(r, &format!("manifests/{mref}"));
+        let resp = self.send(r, "GET", url, &[("Accept", &accept)], Body::Empty)?;
+        if resp.status != 200 {
+            return Err(resp.error(&format!("Fetching manifest {mref}")));
+        }
+        let content_type = resp
+            .header("content-type")
+            .map(|v| v.split(';').next().unwrap_or(v).trim().to_owned());
+        let limit = match content_type.as_deref().map(MediaType::from) {
+            Some(t) if media_type_is_index(&t) => limits.index,
+            Some(t) if media_type_is_manifest(&t) => limits.manifest,
+            _ => limits.manifest.max(limits.index),
+        };
+        let buf = resp
+            .into_bytes(limit)
+            .with_context(|| format!("Fetching manifest {mref}"))?;
+        let media_type = match content_type {
+            Some(t) if t != "application/json" => t,
+            _ => serde_json::from_slice::<serde_json::Value>(&buf)?
+                .get("mediaType")
+                .and_then(|v| v.as_str())
+                .map(ToOwned::to_owned)
+                .ok_or_else(|| anyhow!("Unknown media type for manifest {mref}"))?,
+        };
+        Ok((MediaType::from(media_type.as_str()), buf))
+    }
+
+    fn has_blob(&self, r: &Reference, digest: &str) -> Result<bool> {
+        let url = self.url(r, &format!("blobs/{digest}"));
+        let resp = self.send(r, "HEAD", url, &[], Body::Empty)?;
+        match resp.status {
+            200 => Ok(true),
+            404 => Ok(false),
+            _ => Err(resp.error(&format!("Checking blob {digest}"))),
+        }
+    }
+
+    fn fetch_blob(&self, r: &Reference, digest: &str) -> Result<Response> {
+        let url = self.url(r, &format!("blobs/{digest}"));
+        let resp = self.send(r, "GET", url, &[], Body::Empty)?;
+        if resp.status != 200 {
+            return Err(resp.error(&format!("Fetching blob {digest}")));
+        }
+        Ok(resp)
+    }
+
+    fn upload_blob(&self, r: &Reference, digest: &str, body: Body<'_>) -> Result<()> {
+        let url = self.url(r, "blobs/uploads/");
+        let resp = self.send(r, "POST", url, &[], Body::Empty)?;
+        if resp.status != 202 {
+            return Err(resp.error(&format!("Starting upload of {digest}")));
+        }
+        let location = resp
+            .header("location")
+            .ok_or_else(|| anyhow!("Missing upload location for {digest}"))?;
+        let mut url = self.resolve(r, location);
+        url.push(if url.contains('?') { '&' } else { '?' });
+        url.push_str(&format!("digest={}", urlencode(digest)));
+        let headers = [("Content-Type", "application/octet-stream")];
+        let resp = self.send(r, "PUT", url, &headers, body)?;
+        if resp.status != 201 {
+            return Err(resp.error(&format!("Uploading blob {digest}")));
+        }
+        Ok(())
+    }
+
+    fn put_manifest(
+        &self,
+        r: &Reference,
+        mref: &str,
+        media_type: &MediaType,
+        buf: &[u8],
+    ) -> Result<()> {
+        let url = self.url(r, &format!("manifests/{mref}"));
+        let media_type = media_type.to_string();
+        let headers = [("Content-Type", media_type.as_str())];
+        let resp = self.send(r, "PUT", url, &headers, Body::Bytes(buf))?;
+        if resp.status != 201 {
+            return Err(resp.error(&format!("Pushing manifest {mref}")));
+        }
+        Ok(())
+    }
+}
+
+impl OciDir {
+    fn client_for(reference: &Reference, auth: &Auth) -> Client {
+        Client::new(RegistryOptions {
+            auth: auth.clone(),
+            plain_http: reference.is_local(),
+        })
+    }
+
+    /// Pull an image or index from a registry into this layout, and add it to the
+    /// index tagged with the tag of the reference (`latest` if the reference has
+    /// neither tag nor digest). For an index, all referenced manifests are pulled.
+    /// Manifests and indexes above the [`crate::OciDirOptions::json_limits`] are
+    /// rejected.
+    ///
+    /// Blobs which are already present are not fetched again. Plain HTTP is used for
+    /// registries on `localhost`.
+    pub fn pull(&self, reference: &str, auth: &Auth) -> Result<Descriptor> {
+        let reference: Reference = reference.parse()?;
+        self.pull_with(&Self::client_for(&reference, auth), &reference)
+    }
+
+    /// Like [`Self::pull`], using the provided client.
+    #[context("Pulling {reference}")]
+    pub fn pull_with(&self, client: &Client, reference: &Reference) -> Result<Descriptor> {
+        let (mref, tag) = match (&reference.digest, &reference.tag) {
+            (Some(digest), tag) => (digest.as_str(), tag.as_deref()),
+            (None, Some(tag)) => (tag.as_str(), Some(tag.as_str())),
+            (None, None) => ("latest", Some("latest")),
+        };
+        let desc = self.pull_manifest(client, reference, mref)?;
+        let mut entry = desc.clone();
+        if let Some(tag) = tag {
+            entry.set_annotations(Some(HashMap::from([(
+                OCI_TAG_ANNOTATION.to_owned(),
+                tag.to_owned(),
+            )])));
+        }
+        self.insert_descriptor(entry, tag)?;
+        Ok(desc)
+    }
+
+    /// Write a blob fetched from a registry, verifying its digest.
+    fn write_fetched_blob(&self, digest: &str, size: u64, mut r: impl Read) -> Result<()> {
+        let mut w = self.create_blob_with_expected(digest, size)?;
+        std::io::copy(&mut r, &mut w)?;
+        w.complete()?;
+        Ok(())
+    }
+
+    fn pull_manifest(&self, client: &Client, r: &Reference, mref: &str) -> Result<Descriptor> {
+        let (media_type, buf) = client.fetch_manifest(r, mref, &self.opts.json_limits)?;
+        let digest = format!("sha256:{}", crate::hash::sha256_hex(&buf)?);
+        if mref.contains(':') && mref != digest {
+            anyhow::bail!("Expected manifest digest {mref} but found {digest}");
+        }
+        if !self.store.has(&digest)? {
+            self.write_fetched_blob(&digest, buf.len() as u64, buf.as_slice())?;
+        }
+        if media_type_is_index(&media_type) {
+            let index: ImageIndex = serde_json::from_slice(&buf)?;
+            for d in index.manifests() {
+                if media_type_is_manifest(d.media_type()) {
+                    self.pull_manifest(client, r, d.digest())?;
+                } else {
+                    self.pull_blob(client, r, d)?;
+                }
+            }
+        } else if media_type_is_manifest(&media_type) {
+            let manifest: ImageManifest = serde_json::from_slice(&buf)?;
+            for d in std::iter::once(manifest.config()).chain(manifest.layers()) {
+                self.pull_blob(client, r, d)?;
+            }
+        } else {
+            anyhow::bail!("Unsupported manifest media type {media_type}");
+        }
+        Ok(DescriptorBuilder::default()
+            .media_type(media_type)
+            .digest(digest)
+            .size(buf.len() as i64)
+            .build()?)
+    }
+
+    fn pull_blob(&self, client: &Client, r: &Reference, desc: &Descriptor) -> Result<()> {
+        if self.has_blob(desc)? {
+            return Ok(());
+        }
+        let resp = client.fetch_blob(r, desc.digest())?;
+        self.write_fetched_blob(desc.digest(), desc.size().try_into()?, resp.body)
+    }
+
+    /// Push the manifest or index tagged `tag` in this layout to a registry, along
+    /// with all blobs it references which are not already present there. The
+    /// remote tag is taken from the reference, defaulting to `tag`.
+    ///
+    /// Plain HTTP is used for registries on `localhost`.
+    pub fn push(&self, reference: &str, tag: &str, auth: &Auth) -> Result<Descriptor> {
+        let reference: Reference = reference.parse()?;
+        self.push_with(&Self::client_for(&reference, auth), &reference, tag)
+    }
+
+    /// Like [`Self::push`], using the provided client.
+    #[context("Pushing {tag} to {reference}")]
+    pub fn push_with(
+        &self,
+        client: &Client,
+        reference: &Reference,
+        tag: &str,
+    ) -> Result<Descriptor> {
+        let desc = self
+            .read_index_required()?
+            .manifests()
+            .iter()
+            .find(|d| Self::descriptor_is_tagged(d, tag))
+            .cloned()
+            .ok_or_else(|| anyhow!("No manifest tagged {tag}"))?;
+        let mref = match (&reference.tag, &reference.digest) {
+            (Some(t), _) => t.as_str(),
+            (None, Some(d)) => {
+                if d != desc.digest() {
+                    anyhow::bail!("Reference digest {d} does not match {}", desc.digest());
+                }
+                d.as_str()
+            }
+            (None, None) => tag,
+        };
+        self.push_manifest(client, reference, &desc, mref)?;
+        Ok(desc)
+    }
+
+    fn push_manifest(
+        &self,
+        client: &Client,
+        r: &Reference,
+        desc: &Descriptor,
+        mref: &str,
+    ) -> Result<()> {
+        let buf = self.read_json_bytes(desc)?;
+        if media_type_is_index(desc.media_type()) {
+            let index: ImageIndex = serde_json::from_slice(&buf)?;
+            for d in index.manifests() {
+                if media_type_is_manifest(d.media_type()) {
+                    self.push_manifest(client, r, d, d.digest())?;
+                } else {
+                    self.push_blob(client, r, d)?;
+                }
+            }
+        } else {
+            let manifest: ImageManifest = serde_json::from_slice(&buf)?;
+            for d in std::iter::once(manifest.config()).chain(manifest.layers()) {
+                self.push_blob(client, r, d)?;
+            }
+        }
+        client.put_manifest(r, mref, desc.media_type(), &buf)
+    }
+
+    fn push_blob(&self, client: &Client, r: &Reference, desc: &Descriptor) -> Result<()> {
+        if client.has_blob(r, desc.digest())? {
+            return Ok(());
+        }
+        let size = desc.size().try_into()?;
+        let blob = self.read_blob(desc)?;
+        client.upload_blob(r, desc.digest(), Body::Reader(Box::new(blob), size))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::insert_test_image;
+    use std::io::Write;
+
+    /// An in-memory registry which requires a bearer token.
+    #[derive(Debug, Default)]
+    struct FakeRegistry {
+        blobs: Mutex<HashMap<String, Vec<u8>>>,
+        manifests: Mutex<HashMap<String, (String, Vec<u8>)>>,
+        uploads: Mutex<u32>,
+    }
+
+    const TOKEN: &str = "secret";
+
+    fn resp(status: u16, headers: &[(&str, &str)], body: Vec<u8>) -> Result<Response> {
+        let headers = headers
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        Ok(Response::new(status, headers, body))
+    }
+
+    impl Transport for FakeRegistry {
+        fn send(&self, req: Request<'_>) -> Result<Response> {
+            let path = req.url.strip_prefix("http://registry.test").unwrap();
+            if let Some(query) = path.strip_prefix("/token?") {
+                assert!(query.contains("scope=repository%3Aapp%3Apull"));
+                return resp(200, &[], format!(r#"{{"token":"{TOKEN}"}}"#).into_bytes());
+            }
+            let authorized = req
+                .headers
+                .iter()
+                .any(|(k, v)| k == "Authorization" && v == &format!("Bearer {TOKEN}"));
+            if !authorized {
+                let challenge = r#"Bearer realm="http://registry.test/token",service="registry.test",scope="repository:app:pull,push""#;
+                return resp(401, &[("WWW-Authenticate", challenge)], Vec::new());
+            }
+            let path = path.strip_prefix("/v2/app/").unwrap();
+            let mut body = Vec::new();
+            if let Body::Reader(mut r, _) = req.body {
+                r.read_to_end(&mut body)?;
+            } else if let Body::Bytes(b) = req.body {
+                body = b.to_vec();
+            }
+            match (req.method, path) {
+                ("POST", "blobs/uploads/") => resp(
+                    202,
+                    &[("Location", "/v2/app/blobs/uploads/1?state=x")],
+                    vec![],
+                ),
+                ("PUT", p) if p.starts_with("blobs/uploads/1?state=x&digest=") => {
+                    let digest = p.rsplit_once('=').unwrap().1.replace("%3A", ":");
+                    *self.uploads.lock().unwrap() += 1;
+                    self.blobs.lock().unwrap().insert(digest, body);
+                    resp(201, &[], vec![])
+                }
+                (m, p) if p.starts_with("blobs/") => {
+                    let blobs = self.blobs.lock().unwrap();
+                    match blobs.get(p.strip_prefix("blobs/").unwrap()) {
+                        Some(b) if m == "GET" => resp(200, &[], b.clone()),
+                        Some(_) => resp(200, &[], vec![]),
+                        None => resp(404, &[], vec![]),
+                    }
+                }
+                ("PUT", p) => {
+                    let mref = p.strip_prefix("manifests/").unwrap();
+                    let content_type = req
+                        .headers
+                        .iter()
+                        .find(|(k, _)| k == "Content-Type")
+                        .unwrap()
+                        .1
+                        .clone();
+                    let digest = format!("sha256:{}", crate::hash::sha256_hex(&body)?);
+                    let mut manifests = self.manifests.lock().unwrap();
+                    manifests.insert(mref.to_owned(), (content_type.clone(), body.clone()));
+                    manifests.insert(digest, (content_type, body));
+                    resp(201, &[], vec![])
+                }
+                ("GET", p) => {
+                    let manifests = self.manifests.lock().unwrap();
+                    match manifests.get(p.strip_prefix("manifests/").unwrap()) {
+                        Some((t, b)) => resp(200, &[("Content-Type", t)], b.clone()),
+                        None => resp(404, &[], br#"{"errors":[]}"#.to_vec()),
+                    }
+                }
+                _ => unreachable!(),
+            }
+        }
+    }
+
+    #[derive(Debug)]
+    struct Shared(std::sync::Arc<FakeRegistry>);
+
+    impl Transport for Shared {
+        fn send(&self, req: Request<'_>) -> Result<Response> {
+            self.0.send(req)
+        }
+    }
+
+    #[test]
+    fn reference_hosts() -> Result<()> {
+        let r: Reference = "localhost:5000/app".parse()?;
+        assert_eq!(r.registry, "localhost:5000");
+        assert!(r.is_local());
+        assert_eq!(r.tag, None);
+        let r: Reference = "busybox".parse()?;
+        assert_eq!(r.api_host(), DOCKER_HUB_API);
+        assert!(!r.is_local());
+        Ok(())
+    }
+
+    #[test]
+    fn push_pull() -> Result<()> {
+        let src = OciDir::new_in_memory()?;
+        let (desc, _) = insert_test_image(&src)?;
+
+        let registry = std::sync::Arc::new(FakeRegistry::default());
+        let opts = RegistryOptions {
+            plain_http: true,
+            ..Default::default()
+        };
+        let client = Client::with_transport(opts.clone(), Box::new(Shared(registry.clone())));
+        let reference: Reference = "registry.test/app".parse()?;
+        let pushed = src.push_with(&client, &reference, "latest")?;
+        assert_eq!(pushed.digest(), desc.digest());
+        assert_eq!(*registry.uploads.lock().unwrap(), 2);
+        // Blobs are not uploaded again
+        src.push_with(&client, &"registry.test/app:v2".parse()?, "latest")?;
+        assert_eq!(*registry.uploads.lock().unwrap(), 2);
+        assert!(src.push_with(&client, &reference, "missing").is_err());
+
+        let dest = OciDir::new_in_memory()?;
+        let client = Client::with_transport(opts, Box::new(Shared(registry.clone())));
+        let pulled = dest.pull_with(&client, &"registry.test/app:v2".parse()?)?;
+        assert_eq!(pulled.digest(), desc.digest());
+        assert!(dest.find_manifest_with_tag("v2")?.is_some());
+        assert_eq!(dest.fsck()?, 3);
+        let by_digest = format!("registry.test/app@{}", desc.digest());
+        dest.pull_with(&client, &by_digest.parse()?)?;
+        assert_eq!(dest.manifests()?.len(), 2);
+        assert!(dest
+            .pull_with(&client, &"registry.test/app:missing".parse()?)
+            .is_err());
+
+        // Corrupt content is rejected
+        let manifest: ImageManifest = src.read_json_blob(&desc)?;
+        let layer_digest = manifest.layers()[0].digest().to_string();
+        registry
+            .blobs
+            .lock()
+            .unwrap()
+            .insert(layer_digest, b"corrupt".to_vec());
+        let dest = OciDir::new_in_memory()?;
+        assert!(dest
+            .pull_with(&client, &"registry.test/app:v2".parse()?)
+            .is_err());
+        assert!(dest.manifests()?.is_empty());
+
+        // Manifests above the JSON size limits are not read
+        let mut dest = OciDir::new_in_memory()?;
+        dest.opts.json_limits.manifest = 16;
+        let e = dest
+            .pull_with(&client, &"registry.test/app:v2".parse()?)
+            .unwrap_err();
+        assert!(format!("{e:#}").contains("exceeds the limit of 16 bytes"));
+        Ok(())
+    }
+
+    #[test]
+    fn http_transport() -> Result<()> {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+        let port = listener.local_addr()?.port();
+        let server = std::thread::spawn(move || -> Result<String> {
+            let (mut conn, _) = listener.accept()?;
+            let mut r = BufReader::new(conn.try_clone()?);
+            let mut request = String::new();
+            loop {
+                let mut line = String::new();
+                r.read_line(&mut line)?;
+                if line == "\r\n" {
+                    break;
+                }
+                request.push_str(&line);
+            }
+            let mut body = [0u8; 5];
+            r.read_exact(&mut body)?;
+            conn.write_all(
+                b"HTTP/1.1 201 Created\r\nTransfer-Encoding: chunked\r\nX-Test: yes\r\n\r\n\
+                  5\r\nhello\r\n6;ext\r\n world\r\n0\r\n\r\n",
+            )?;
+            Ok(request + std::str::from_utf8(&body)?)
+        });
+        let resp = HttpTransport::default().send(Request {
+            method: "PUT",
+            url: format!("http://127.0.0.1:{port}/v2/x"),
+            headers: vec![("X-Req".into(), "1".into())],
+            body: Body::Bytes(b"input"),
+        })?;
+        assert_eq!(resp.status, 201);
+        assert_eq!(resp.header("x-test"), Some("yes"));
+        assert_eq!(resp.into_bytes(11)?, b"hello world");
+        let request = server.join().unwrap()?;
+        assert!(request.starts_with("PUT /v2/x HTTP/1.1\r\n"));
+        assert!(request.contains("X-Req: 1\r\n"));
+        assert!(request.contains("Content-Length: 5\r\n"));
+        assert!(request.ends_with("input"));
+        Ok(())
+    }
+
+    #[test]
+    fn challenge() {
+        let (scheme, params) = parse_challenge(
+            r#"Bearer realm="https://auth.example.com/token",service="example.com",scope="repository:a/b:pull""#,
+        );
+        assert_eq!(scheme, "bearer");
+        assert_eq!(params["realm"], "https://auth.example.com/token");
+        assert_eq!(params["scope"], "repository:a/b:pull");
+        assert_eq!(
+            urlencode("repository:a/b:pull,push"),
+            "repository%3Aa%2Fb%3Apull%2Cpush"
+        );
+    }
+}
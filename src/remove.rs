@@ -0,0 +1,176 @@
+//! Removing images from the index, along with the artifacts which refer to them.
+
+use std::collections::BTreeSet;
+
+use anyhow::Result;
+use fn_error_context::context;
+use oci_spec::image::{Descriptor, ImageManifest, MediaType};
+
+use crate::referrers::cosign_tag;
+use crate::{OciDir, OCI_TAG_ANNOTATION};
+
+/// Options for [`OciDir::remove_image`].
+#[derive(Debug, Clone, Default)]
+pub struct RemoveOptions {
+    /// Also remove artifacts whose `subject` is a removed manifest, or which use the
+    /// cosign tag convention for it (such as `sha256-<digest>.sig`), recursively.
+    pub cascade_referrers: bool,
+    /// Delete blobs which are no longer reachable from the index afterwards.
+    pub prune: bool,
+}
+
+/// The result of [`OciDir::remove_image`].
+#[derive(Debug, Clone, Default)]
+#[non_exhaustive]
+pub struct RemoveReport {
+    /// The index entries which were removed.
+    pub removed: Vec<Descriptor>,
+    /// The digests of blobs deleted by [`RemoveOptions::prune`].
+    pub pruned: Vec<String>,
+}
+
+impl OciDir {
+    /// Returns true if the index entry refers to one of the provided digests.
+    pub(crate) fn refers_to_any(
+        &self,
+        desc: &Descriptor,
+        digests: &BTreeSet<String>,
+    ) -> Result<bool> {
+        let tag = desc
+            .annotations()
+            .as_ref()
+            .and_then(|a| a.get(OCI_TAG_ANNOTATION));
+        if let Some(tag) = tag {
+            for digest in digests {
+                if tag.starts_with(&cosign_tag(digest, "")?) {
+                    return Ok(true);
+                }
+            }
+        }
+        if desc.media_type() != &MediaType::ImageManifest || !self.has_blob(desc)? {
+            return Ok(false);
+        }
+        let manifest: ImageManifest = self.read_json_blob(desc)?;
+        Ok(manifest
+            .subject()
+            .as_ref()
+            .is_some_and(|s| digests.contains(s.digest().as_str())))
+    }
+
+    /// Remove all index entries for the manifest with the provided digest; see
+    /// [`RemoveOptions`]. It is an error if there is no such entry.
+    #[context("Removing {digest}")]
+    pub fn remove_image(&self, digest: &str, opts: &RemoveOptions) -> Result<RemoveReport> {
+        let mut r = RemoveReport::default();
+        let _lock = self.lock_index()?;
+        let mut index = self.read_index_required()?;
+        if !index.manifests().iter().any(|d| d.digest() == digest) {
+            anyhow::bail!("No index entry for {digest}");
+        }
+        let mut targets = BTreeSet::from([digest.to_owned()]);
+        // Each pass finds referrers of the entries found in the previous one.
+        if opts.cascade_referrers {
+            loop {
+                let mut found = Vec::new();
+                for desc in index.manifests() {
+                    if !targets.contains(desc.digest().as_str())
+                        && self.refers_to_any(desc, &targets)?
+                    {
+                        found.push(desc.digest().to_string());
+                    }
+                }
+                if found.is_empty() {
+                    break;
+                }
+                targets.extend(found);
+            }
+        }
+        let (removed, keep) = index
+            .manifests()
+            .iter()
+            .cloned()
+            .partition(|d| targets.contains(d.digest().as_str()));
+        r.removed = removed;
+        index.set_manifests(keep);
+        self.write_index(&index, "remove", Some(digest), None)?;
+        drop(_lock);
+
+        if opts.prune {
+            r.pruned = self.remove_orphans(Some(&index))?;
+        }
+        Ok(r)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn remove_image() -> Result<()> {
+        let w = OciDir::new_in_memory()?;
+        let config = oci_spec::image::ImageConfigurationBuilder::default().build()?;
+        let app = w.insert_manifest_and_config(
+            crate::new_empty_manifest().build()?,
+            config,
+            Some("app"),
+            Default::default(),
+        )?;
+        let other_config = oci_spec::image::ImageConfigurationBuilder::default()
+            .architecture(oci_spec::image::Arch::ARM64)
+            .build()?;
+        let other = w.insert_manifest_and_config(
+            crate::new_empty_manifest().build()?,
+            other_config,
+            Some("other"),
+            Default::default(),
+        )?;
+        let notes = MediaType::Other("application/vnd.example.notes".into());
+        let layer = w
+            .write_blob_with_type(b"sbom", MediaType::Other("text/plain".into()))?
+            .build()?;
+        let sbom = w.attach_referrer(&app, notes.clone(), vec![layer], None)?;
+        // A referrer of the referrer
+        let layer = w
+            .write_blob_with_type(b"sig", MediaType::Other("text/plain".into()))?
+            .build()?;
+        let annotations = HashMap::from([("k".to_owned(), "v".to_owned())]);
+        let sig = w.attach_referrer(&sbom, notes.clone(), vec![layer], Some(annotations))?;
+        let layer = w
+            .write_blob_with_type(b"other-notes", MediaType::Other("text/plain".into()))?
+            .build()?;
+        w.attach_referrer(&other, notes, vec![layer], None)?;
+        let cosign = cosign_tag(app.digest(), "sig")?;
+        let layer = w
+            .write_blob_with_type(b"cosign", MediaType::Other("text/plain".into()))?
+            .build()?;
+        w.append_to_cosign_tag(&cosign, layer)?;
+        assert_eq!(w.manifests()?.len(), 6);
+
+        assert!(w.remove_image("sha256:0000", &Default::default()).is_err());
+        let opts = RemoveOptions {
+            cascade_referrers: true,
+            prune: true,
+        };
+        let r = w.remove_image(app.digest(), &opts)?;
+        let removed: BTreeSet<_> = r.removed.iter().map(|d| d.digest().to_string()).collect();
+        assert_eq!(removed.len(), 4);
+        assert!(removed.contains(sbom.digest().as_str()));
+        assert!(removed.contains(sig.digest().as_str()));
+        let remaining = w.manifests()?;
+        assert_eq!(remaining.len(), 2);
+        assert!(w.find_manifest_with_tag(&cosign)?.is_none());
+        assert!(!r.pruned.is_empty());
+        assert!(!w.has_blob(&app)?);
+        assert!(w.find_manifest_with_tag("other")?.is_some());
+        w.fsck()?;
+
+        // Without cascading, referrers are kept
+        let r = w.remove_image(other.digest(), &Default::default())?;
+        assert_eq!(r.removed.len(), 1);
+        assert!(r.pruned.is_empty());
+        assert_eq!(w.manifests()?.len(), 1);
+        Ok(())
+    }
+}
@@ -0,0 +1,394 @@
+//! Blob writes which can be resumed after the process exits, similar to
+//! chunked uploads to a registry.
+//!
+//! Partial content lives in `blobs/staging/<id>`, next to a `<id>.state` file
+//! holding the offset and the SHA-256 state at the last checkpoint. These are
+//! not blobs, and are ignored by listing, garbage collection and fsck.
+
+use std::io::{Seek, Write};
+
+use anyhow::{anyhow, Context, Result};
+use cap_std::fs::{Dir, File, OpenOptions};
+use cap_std_ext::cap_std;
+use cap_std_ext::dirext::CapStdExtDirExt;
+use fn_error_context::context;
+use serde::{Deserialize, Serialize};
+use sha2::digest::generic_array::GenericArray;
+
+use crate::progress::{BlobProgress, ProgressOp};
+use crate::store::{ensure_blob_parent, new_blob_path, STAGING_DIR};
+use crate::{Blob, OciDir, BLOBS};
+
+const STATE_SUFFIX: &str = ".state";
+/// Checkpoint automatically after this many bytes.
+const CHECKPOINT_INTERVAL: u64 = 16 * 1024 * 1024;
+
+const SHA256_INIT: [u32; 8] = [
+    0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+];
+
+/// A SHA-256 hasher whose intermediate state can be saved, which the
+/// hashing backends do not otherwise allow.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct HashState {
+    /// Total number of bytes hashed.
+    offset: u64,
+    state: [u32; 8],
+    /// Data not yet forming a full block.
+    #[serde(with = "pending_hex")]
+    pending: Vec<u8>,
+}
+
+mod pending_hex {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub(super) fn serialize<S: Serializer>(v: &[u8], s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(&hex::encode(v))
+    }
+
+    pub(super) fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<u8>, D::Error> {
+        let v = String::deserialize(d)?;
+        let v = hex::decode(v).map_err(serde::de::Error::custom)?;
+        if v.len() >= 64 {
+            return Err(serde::de::Error::custom("Pending data exceeds a block"));
+        }
+        Ok(v)
+    }
+}
+
+impl HashState {
+    fn new() -> Self {
+        Self {
+            offset: 0,
+            state: SHA256_INIT,
+            pending: Vec::new(),
+        }
+    }
+
+    fn compress(&mut self, blocks: &[u8]) {
+        let blocks: Vec<_> = blocks
+            .chunks_exact(64)
+            .map(GenericArray::clone_from_slice)
+            .collect();
+        sha2::compress256(&mut self.state, &blocks);
+    }
+
+    fn update(&mut self, mut buf: &[u8]) {
+        self.offset += buf.len() as u64;
+        if !self.pending.is_empty() {
+            let n = (64 - self.pending.len()).min(buf.len());
+            self.pending.extend_from_slice(&buf[..n]);
+            buf = &buf[n..];
+            if self.pending.len() < 64 {
+                return;
+            }
+            let block = std::mem::take(&mut self.pending);
+            self.compress(&block);
+        }
+        let full = buf.len() - buf.len() % 64;
+        self.compress(&buf[..full]);
+        self.pending.extend_from_slice(&buf[full..]);
+    }
+
+    fn finish(mut self) -> [u8; 32] {
+        let mut tail = std::mem::take(&mut self.pending);
+        tail.push(0x80);
+        while tail.len() % 64 != 56 {
+            tail.push(0);
+        }
+        tail.extend_from_slice(&(self.offset * 8).to_be_bytes());
+        self.compress(&tail);
+        let mut r = [0u8; 32];
+        for (dest, word) in r.chunks_exact_mut(4).zip(self.state) {
+            dest.copy_from_slice(&word.to_be_bytes());
+        }
+        r
+    }
+}
+
+fn validate_id(id: &str) -> Result<()> {
+    if id.is_empty() || !id.bytes().all(|c| c.is_ascii_hexdigit()) {
+        anyhow::bail!("Invalid staged blob id {id:?}");
+    }
+    Ok(())
+}
+
+/// Generate a random identifier for a staged blob.
+fn new_id() -> String {
+    use std::hash::{BuildHasher, Hasher};
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default();
+    (0..2)
+        .map(|_| {
+            let mut h = std::collections::hash_map::RandomState::new().build_hasher();
+            h.write_u128(now.as_nanos());
+            h.write_u32(std::process::id());
+            format!("{:016x}", h.finish())
+        })
+        .collect()
+}
+
+/// A blob writer which persists its progress so that writing can continue
+/// later via [`OciDir::resume_blob`].
+///
+/// Data is checkpointed every 16 MiB, by [`Self::checkpoint`], and when the
+/// writer is dropped without calling [`Self::complete`] or [`Self::abort`].
+/// After a crash, any data written since the last checkpoint is discarded
+/// on resume.
+#[derive(Debug)]
+pub struct ResumableBlobWriter {
+    root: Dir,
+    staging: Dir,
+    id: String,
+    file: Option<File>,
+    hash: HashState,
+    unsaved: u64,
+    progress: BlobProgress,
+    /// Store the completed blob in the sharded form, see [`crate::OciDirOptions::shard_blobs`].
+    sharded: bool,
+    /// The layout the blob is written to.
+    layout: OciDir,
+}
+
+impl ResumableBlobWriter {
+    /// The identifier to pass to [`OciDir::resume_blob`].
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// The number of bytes written so far, at which a resumed upload should continue.
+    pub fn offset(&self) -> u64 {
+        self.hash.offset
+    }
+
+    /// Flush the written data to disk and record the current offset, so that
+    /// resuming continues from here.
+    #[context("Checkpointing staged blob {}", self.id)]
+    pub fn checkpoint(&mut self) -> Result<()> {
+        let Some(file) = self.file.as_ref() else {
+            return Ok(());
+        };
+        file.sync_data()?;
+        let state = serde_json::to_vec(&self.hash)?;
+        crate::atomic_write(&self.staging, format!("{}{STATE_SUFFIX}", self.id), state)?;
+        self.unsaved = 0;
+        Ok(())
+    }
+
+    /// Finish writing, moving the content into place as a blob.
+    #[context("Completing staged blob {}", self.id)]
+    pub fn complete(mut self) -> Result<Blob> {
+        let file = self.file.take().unwrap();
+        file.sync_data()?;
+        drop(file);
+        let size = self.hash.offset;
+        let sha256 = hex::encode(std::mem::replace(&mut self.hash, HashState::new()).finish());
+        let path = new_blob_path(&format!("sha256:{sha256}"), self.sharded)?;
+        ensure_blob_parent(&self.root, &path)?;
+        self.staging.rename(&self.id, &self.root, &path)?;
+        self.staging
+            .remove_file_optional(format!("{}{STATE_SUFFIX}", self.id))?;
+        let blob = Blob { sha256, size };
+        self.progress.end(&blob.digest_id());
+        self.layout.blob_written_directly(&blob.digest_id())?;
+        Ok(blob)
+    }
+
+    /// Discard the partially written content.
+    #[context("Aborting staged blob {}", self.id)]
+    pub fn abort(mut self) -> Result<()> {
+        self.file.take();
+        remove_staged(&self.staging, &self.id)
+    }
+}
+
+impl Drop for ResumableBlobWriter {
+    fn drop(&mut self) {
+        if self.unsaved > 0 {
+            let _ = self.checkpoint();
+        }
+    }
+}
+
+impl Write for ResumableBlobWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let file = self.file.as_mut().unwrap();
+        file.write_all(buf)?;
+        self.hash.update(buf);
+        self.unsaved += buf.len() as u64;
+        self.progress.bytes(buf.len() as u64);
+        self.progress.throttle(buf.len() as u64);
+        if self.unsaved >= CHECKPOINT_INTERVAL {
+            self.checkpoint().map_err(std::io::Error::other)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+fn remove_staged(staging: &Dir, id: &str) -> Result<()> {
+    staging.remove_file_optional(id)?;
+    staging.remove_file_optional(format!("{id}{STATE_SUFFIX}"))?;
+    Ok(())
+}
+
+impl OciDir {
+    fn staging_dir(&self) -> Result<(&Dir, Dir)> {
+        let dir = self
+            .writable_dir()?
+            .ok_or_else(|| anyhow!("Resumable blobs require an on-disk layout"))?;
+        let db = crate::dir_builder();
+        let path = std::path::Path::new(BLOBS).join(STAGING_DIR);
+        dir.ensure_dir_with(&path, &db)?;
+        Ok((dir, dir.open_dir(&path)?))
+    }
+
+    /// Start writing a blob which can be continued later with [`Self::resume_blob`]
+    /// using its [`ResumableBlobWriter::id`].
+    #[context("Creating resumable blob")]
+    pub fn create_resumable_blob(&self) -> Result<ResumableBlobWriter> {
+        let (root, staging) = self.staging_dir()?;
+        let id = new_id();
+        let file = staging.open_with(&id, OpenOptions::new().write(true).create_new(true))?;
+        let mut w = ResumableBlobWriter {
+            root: root.try_clone()?,
+            staging,
+            id,
+            file: Some(file),
+            hash: HashState::new(),
+            unsaved: 0,
+            progress: self.progress.begin(ProgressOp::Write, None, None),
+            sharded: self.opts.shard_blobs,
+            layout: self.clone(),
+        };
+        w.checkpoint()?;
+        Ok(w)
+    }
+
+    /// Continue writing a blob started by [`Self::create_resumable_blob`], from
+    /// its last checkpoint.
+    #[context("Resuming blob {id}")]
+    pub fn resume_blob(&self, id: &str) -> Result<ResumableBlobWriter> {
+        validate_id(id)?;
+        let (root, staging) = self.staging_dir()?;
+        let state = staging
+            .read(format!("{id}{STATE_SUFFIX}"))
+            .with_context(|| format!("Reading state of staged blob {id}"))?;
+        let hash: HashState = serde_json::from_slice(&state)?;
+        let mut file = staging.open_with(id, OpenOptions::new().write(true))?;
+        let len = file.metadata()?.len();
+        if len < hash.offset {
+            anyhow::bail!("Staged blob is truncated: {len} < {}", hash.offset);
+        }
+        // Discard anything written after the checkpoint.
+        file.set_len(hash.offset)?;
+        file.seek(std::io::SeekFrom::End(0))?;
+        Ok(ResumableBlobWriter {
+            root: root.try_clone()?,
+            staging,
+            id: id.to_owned(),
+            file: Some(file),
+            hash,
+            unsaved: 0,
+            progress: self.progress.begin(ProgressOp::Write, None, None),
+            sharded: self.opts.shard_blobs,
+            layout: self.clone(),
+        })
+    }
+
+    /// List the identifiers of blobs which have been staged but not completed.
+    pub fn staged_blobs(&self) -> Result<Vec<String>> {
+        let mut r = Vec::new();
+        let Some(staging) = self
+            .dir()
+            .map(|d| d.open_dir_optional(std::path::Path::new(BLOBS).join(STAGING_DIR)))
+            .transpose()?
+            .flatten()
+        else {
+            return Ok(r);
+        };
+        for ent in staging.entries()? {
+            let name = ent?.file_name();
+            if let Some(id) = name.to_str().and_then(|n| n.strip_suffix(STATE_SUFFIX)) {
+                r.push(id.to_owned());
+            }
+        }
+        r.sort();
+        Ok(r)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hash::sha256_hex;
+
+    #[test]
+    fn hash_state() -> Result<()> {
+        let data: Vec<u8> = (0..1000u32).map(|i| (i * 7) as u8).collect();
+        for split in [0, 1, 55, 56, 64, 65, 128, 999, 1000] {
+            let mut h = HashState::new();
+            h.update(&data[..split]);
+            let saved: HashState = serde_json::from_slice(&serde_json::to_vec(&h)?)?;
+            let mut h = saved;
+            h.update(&data[split..]);
+            assert_eq!(hex::encode(h.finish()), sha256_hex(&data)?);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn resume() -> Result<()> {
+        for shard_blobs in [false, true] {
+            let td = cap_std_ext::cap_tempfile::tempdir(cap_std::ambient_authority())?;
+            let opts = crate::OciDirOptions {
+                shard_blobs,
+                ..Default::default()
+            };
+            let w = OciDir::ensure_with(&td, &opts)?;
+            resume_in(&td, &w)?;
+        }
+        assert!(OciDir::new_in_memory()?.create_resumable_blob().is_err());
+        Ok(())
+    }
+
+    fn resume_in(td: &Dir, w: &OciDir) -> Result<()> {
+        let data: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+
+        let mut bw = w.create_resumable_blob()?;
+        let id = bw.id().to_owned();
+        bw.write_all(&data[..40_000])?;
+        bw.checkpoint()?;
+        bw.write_all(&data[40_000..50_000])?;
+        // Simulate a crash after the checkpoint
+        std::mem::forget(bw);
+        assert_eq!(w.staged_blobs()?, vec![id.clone()]);
+        assert!(!w.store.list()?.iter().any(|d| d.contains(STAGING_DIR)));
+
+        let mut bw = w.resume_blob(&id)?;
+        assert_eq!(bw.offset(), 40_000);
+        bw.write_all(&data[40_000..70_000])?;
+        drop(bw);
+        let mut bw = w.resume_blob(&id)?;
+        assert_eq!(bw.offset(), 70_000);
+        bw.write_all(&data[70_000..])?;
+        let blob = bw.complete()?;
+        assert_eq!(blob.sha256, sha256_hex(&data)?);
+        assert_eq!(blob.size, data.len() as u64);
+        assert!(w.store.has(&blob.digest_id())?);
+        let path = new_blob_path(&blob.digest_id(), w.opts.shard_blobs)?;
+        assert!(td.try_exists(&path)?);
+        assert!(w.staged_blobs()?.is_empty());
+        assert!(w.resume_blob(&id).is_err());
+        assert!(w.resume_blob("../index.json").is_err());
+
+        let bw = w.create_resumable_blob()?;
+        bw.abort()?;
+        assert!(w.staged_blobs()?.is_empty());
+        Ok(())
+    }
+}
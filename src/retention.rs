@@ -0,0 +1,319 @@
+//! Bounding the size of a layout by removing old images.
+
+use std::collections::BTreeSet;
+use std::time::Duration;
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use fn_error_context::context;
+use oci_spec::image::{Descriptor, ImageManifest, MediaType};
+use serde::Serialize;
+
+use crate::{OciDir, PruneOptions, PruneReport, OCI_TAG_ANNOTATION};
+
+/// A rule of a [`RetentionPolicy`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum RetentionRule {
+    /// Keep the `count` newest tagged images whose tag matches `pattern`, where
+    /// `*` matches any sequence of characters. Images without a creation time
+    /// are treated as the oldest; see [`crate::effective_created`].
+    KeepLast {
+        /// The tag pattern, such as `build-*`.
+        pattern: String,
+        /// The number of images to keep.
+        count: usize,
+    },
+    /// Keep images created less than this long ago, according to the configured
+    /// [`crate::Clock`].
+    KeepNewerThan(Duration),
+    /// Keep the index entries for these manifest or index digests.
+    KeepDigests(BTreeSet<String>),
+}
+
+/// Which index entries to keep, see [`OciDir::apply_retention`].
+///
+/// An index entry is kept if any rule keeps it, or if it is an artifact which
+/// refers to a kept entry, such as a signature; all other entries are removed.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RetentionPolicy {
+    /// The rules, in any order.
+    pub rules: Vec<RetentionRule>,
+}
+
+/// The result of [`OciDir::apply_retention`].
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "kebab-case")]
+#[non_exhaustive]
+pub struct RetentionReport {
+    /// The index entries which were removed.
+    pub removed: Vec<Descriptor>,
+    /// The blobs which were no longer reachable afterwards.
+    pub pruned: PruneReport,
+}
+
+/// Match `s` against a pattern where `*` matches any sequence of characters.
+fn glob_match(pattern: &str, s: &str) -> bool {
+    let mut parts = pattern.split('*');
+    // There is always a first part, which must be a prefix.
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = s.strip_prefix(first) else {
+        return false;
+    };
+    let mut parts: Vec<&str> = parts.collect();
+    let Some(last) = parts.pop() else {
+        // No `*` at all
+        return rest.is_empty();
+    };
+    for part in parts {
+        match rest.find(part) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+fn tag_of(desc: &Descriptor) -> Option<&str> {
+    desc.annotations()
+        .as_ref()
+        .and_then(|a| a.get(OCI_TAG_ANNOTATION))
+        .map(|s| s.as_str())
+}
+
+impl OciDir {
+    fn entry_created(&self, desc: &Descriptor) -> Result<Option<DateTime<Utc>>> {
+        if desc.media_type() != &MediaType::ImageManifest || !self.has_blob(desc)? {
+            return Ok(None);
+        }
+        let manifest: ImageManifest = self.read_json_blob(desc)?;
+        self.image_created(&manifest)
+    }
+
+    /// Remove all index entries which are not kept by `policy`, and then delete
+    /// the blobs which are no longer reachable; see [`OciDir::prune`].
+    ///
+    /// With [`PruneOptions::dry_run`], nothing is changed and the report
+    /// describes what would be removed.
+    #[context("Applying retention policy")]
+    pub fn apply_retention(
+        &self,
+        policy: &RetentionPolicy,
+        opts: &PruneOptions,
+    ) -> Result<RetentionReport> {
+        let _lock = self.lock_index()?;
+        let Some(mut index) = self.read_index()? else {
+            return Ok(RetentionReport {
+                pruned: self.prune_unreachable(None, opts)?,
+                ..Default::default()
+            });
+        };
+        let mut created = Vec::new();
+        for desc in index.manifests() {
+            created.push(self.entry_created(desc)?);
+        }
+        let now = self.now();
+        let mut keep = vec![false; created.len()];
+        for rule in &policy.rules {
+            match rule {
+                RetentionRule::KeepLast { pattern, count } => {
+                    let mut matching: Vec<_> = index
+                        .manifests()
+                        .iter()
+                        .enumerate()
+                        .filter_map(|(i, d)| tag_of(d).map(|t| (i, t)))
+                        .filter(|(_, t)| glob_match(pattern, t))
+                        .collect();
+                    // Newest first, then by tag
+                    matching
+                        .sort_by(|a, b| created[b.0].cmp(&created[a.0]).then_with(|| a.1.cmp(b.1)));
+                    for (i, _) in matching.into_iter().take(*count) {
+                        keep[i] = true;
+                    }
+                }
+                RetentionRule::KeepNewerThan(age) => {
+                    // An age beyond the representable range keeps everything.
+                    let cutoff = chrono::Duration::from_std(*age)
+                        .ok()
+                        .and_then(|age| now.checked_sub_signed(age));
+                    for (i, c) in created.iter().enumerate() {
+                        let newer = match cutoff {
+                            Some(cutoff) => c.is_some_and(|c| c > cutoff),
+                            None => true,
+                        };
+                        if newer {
+                            keep[i] = true;
+                        }
+                    }
+                }
+                RetentionRule::KeepDigests(digests) => {
+                    for (i, d) in index.manifests().iter().enumerate() {
+                        if digests.contains(d.digest().as_str()) {
+                            keep[i] = true;
+                        }
+                    }
+                }
+            }
+        }
+        // Each pass keeps the referrers of the entries kept in the previous one.
+        loop {
+            let kept: BTreeSet<String> = index
+                .manifests()
+                .iter()
+                .zip(&keep)
+                .filter(|(_, k)| **k)
+                .map(|(d, _)| d.digest().to_string())
+                .collect();
+            let mut found = false;
+            for (i, desc) in index.manifests().iter().enumerate() {
+                if !keep[i] && self.refers_to_any(desc, &kept)? {
+                    keep[i] = true;
+                    found = true;
+                }
+            }
+            if !found {
+                break;
+            }
+        }
+
+        let mut r = RetentionReport::default();
+        let mut kept = Vec::new();
+        for (desc, k) in index.manifests().iter().zip(keep) {
+            if k {
+                kept.push(desc.clone());
+            } else {
+                r.removed.push(desc.clone());
+            }
+        }
+        index.set_manifests(kept);
+        if !opts.dry_run && !r.removed.is_empty() {
+            self.write_index(&index, "retention", None, None)?;
+        }
+        r.pruned = self.prune_unreachable(Some(&index), opts)?;
+        Ok(r)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{FixedClock, OciDirOptions};
+    use cap_std_ext::{cap_std, cap_tempfile};
+    use oci_spec::image::ImageConfigurationBuilder;
+    use std::io::Write;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_glob_match() {
+        for (pattern, s, expected) in [
+            ("build-*", "build-1", true),
+            ("build-*", "build-", true),
+            ("build-*", "release", false),
+            ("*", "", true),
+            ("latest", "latest", true),
+            ("latest", "latest2", false),
+            ("a*b*c", "aXbYc", true),
+            ("a*b*c", "acb", false),
+            ("*-rc", "1.0-rc", true),
+            ("ab*ba", "aba", false),
+        ] {
+            assert_eq!(glob_match(pattern, s), expected, "{pattern} {s}");
+        }
+    }
+
+    #[test]
+    fn apply_retention() -> Result<()> {
+        let td = cap_tempfile::tempdir(cap_std::ambient_authority())?;
+        let now = DateTime::parse_from_rfc3339("2024-01-10T00:00:00Z")?.with_timezone(&Utc);
+        let opts = OciDirOptions {
+            clock: Some(Arc::new(FixedClock(now))),
+            ..Default::default()
+        };
+        let w = OciDir::ensure_with(&td, &opts)?;
+        let mut digests = Vec::new();
+        for (tag, day) in [
+            ("build-1", 1),
+            ("build-2", 2),
+            ("build-3", 3),
+            ("release", 4),
+            ("pinned", 5),
+        ] {
+            let mut manifest = crate::new_empty_manifest().build()?;
+            let mut config = ImageConfigurationBuilder::default()
+                .created(format!("2024-01-0{day}T00:00:00Z"))
+                .build()?;
+            let mut layer = w.create_gzip_layer(None)?;
+            layer.write_all(tag.as_bytes())?;
+            w.push_layer(&mut manifest, &mut config, layer.complete()?, tag, None);
+            let desc =
+                w.insert_manifest_and_config(manifest, config, Some(tag), Default::default())?;
+            digests.push(desc.digest().to_string());
+        }
+        let notes = MediaType::Other("application/vnd.example.notes".into());
+        let sig = w.attach_referrer(
+            &w.find_manifest_and_descriptor_with_tag("build-3")?
+                .unwrap()
+                .1,
+            notes,
+            vec![],
+            None,
+        )?;
+
+        let policy = RetentionPolicy {
+            rules: vec![
+                RetentionRule::KeepLast {
+                    pattern: "build-*".into(),
+                    count: 1,
+                },
+                RetentionRule::KeepNewerThan(Duration::from_secs(7 * 24 * 60 * 60)),
+                RetentionRule::KeepDigests(BTreeSet::from([digests[4].clone()])),
+            ],
+        };
+        let dry = w.apply_retention(&policy, &PruneOptions { dry_run: true })?;
+        let removed: Vec<_> = dry.removed.iter().map(|d| tag_of(d).unwrap()).collect();
+        assert_eq!(removed, ["build-1", "build-2"]);
+        // Two layers, configs and manifests
+        assert_eq!(dry.pruned.removed.len(), 6);
+        assert!(dry.pruned.dry_run);
+        assert_eq!(w.read_index()?.unwrap().manifests().len(), 6);
+
+        let r = w.apply_retention(&policy, &Default::default())?;
+        assert_eq!(r.removed, dry.removed);
+        assert_eq!(r.pruned.removed, dry.pruned.removed);
+        let index = w.read_index()?.unwrap();
+        let remaining: BTreeSet<_> = index
+            .manifests()
+            .iter()
+            .map(|d| d.digest().as_str())
+            .collect();
+        assert_eq!(
+            remaining,
+            BTreeSet::from([
+                digests[2].as_str(),
+                digests[3].as_str(),
+                digests[4].as_str(),
+                sig.digest().as_str()
+            ])
+        );
+        assert!(w.fsck_with(&Default::default())?.is_clean());
+
+        // Ages beyond the range of timestamps keep everything
+        for age in [
+            Duration::MAX,
+            Duration::from_secs(u64::from(u32::MAX) * 1000),
+        ] {
+            let policy = RetentionPolicy {
+                rules: vec![RetentionRule::KeepNewerThan(age)],
+            };
+            let r = w.apply_retention(&policy, &PruneOptions { dry_run: true })?;
+            assert!(r.removed.is_empty());
+        }
+
+        // Nothing is kept without rules
+        let r = w.apply_retention(&RetentionPolicy::default(), &Default::default())?;
+        assert_eq!(r.removed.len(), 4);
+        assert!(w.read_index()?.unwrap().manifests().is_empty());
+        assert!(w.store.list()?.is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,117 @@
+//! Finding layers which are already present in the layout.
+
+use anyhow::Result;
+use fn_error_context::context;
+use oci_spec::image::{Descriptor, ImageConfiguration, ImageIndex, ImageManifest, MediaType};
+
+use crate::{Blob, Layer, OciDir};
+
+impl OciDir {
+    fn find_layer_in(
+        &self,
+        desc: &Descriptor,
+        diff_id: &str,
+    ) -> Result<Option<(Layer, Descriptor)>> {
+        if !self.has_blob(desc)? {
+            return Ok(None);
+        }
+        match desc.media_type() {
+            MediaType::ImageManifest => {
+                let manifest: ImageManifest = self.read_json_blob(desc)?;
+                let config_desc = manifest.config();
+                if config_desc.media_type() != &MediaType::ImageConfig
+                    || !self.has_blob(config_desc)?
+                {
+                    return Ok(None);
+                }
+                let config: ImageConfiguration = self.read_json_blob(config_desc)?;
+                let found = manifest
+                    .layers()
+                    .iter()
+                    .zip(config.rootfs().diff_ids())
+                    .find(|(_, d)| d.as_str() == diff_id);
+                let Some((layer, _)) = found else {
+                    return Ok(None);
+                };
+                let Some(sha256) = layer.digest().strip_prefix("sha256:") else {
+                    return Ok(None);
+                };
+                if !self.has_blob(layer)? {
+                    return Ok(None);
+                }
+                let uncompressed_sha256 = diff_id.strip_prefix("sha256:").unwrap().to_owned();
+                let r = Layer {
+                    blob: Blob {
+                        sha256: sha256.to_owned(),
+                        size: layer.size().try_into()?,
+                    },
+                    uncompressed_sha256,
+                    media_type: layer.media_type().clone(),
+                };
+                Ok(Some((r, layer.clone())))
+            }
+            MediaType::ImageIndex => {
+                let index: ImageIndex = self.read_json_blob(desc)?;
+                for child in index.manifests() {
+                    if let Some(r) = self.find_layer_in(child, diff_id)? {
+                        return Ok(Some(r));
+                    }
+                }
+                Ok(None)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Find a layer blob in any image of this layout whose uncompressed digest is
+    /// `diff_id`, such as `sha256:<hex>`, returning it along with its descriptor in
+    /// the first manifest found. The layer can be pushed to another image with
+    /// [`OciDir::push_layer`] instead of writing a duplicate blob.
+    ///
+    /// All manifests and configs are read; layers are not.
+    #[context("Finding layer with diff_id {diff_id}")]
+    pub fn find_layer_by_diffid(&self, diff_id: &str) -> Result<Option<(Layer, Descriptor)>> {
+        if !diff_id.starts_with("sha256:") {
+            return Ok(None);
+        }
+        let Some(index) = self.read_index()? else {
+            return Ok(None);
+        };
+        for desc in index.manifests() {
+            if let Some(r) = self.find_layer_in(desc, diff_id)? {
+                return Ok(Some(r));
+            }
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use oci_spec::image::ImageConfigurationBuilder;
+    use std::io::Write;
+
+    #[test]
+    fn find_layer_by_diffid() -> Result<()> {
+        let w = OciDir::new_in_memory()?;
+        let mut lw = w.create_gzip_layer(None)?;
+        lw.write_all(b"shared")?;
+        let layer = lw.complete()?;
+        let diff_id = layer.diff_id();
+        assert!(w.find_layer_by_diffid(&diff_id)?.is_none());
+
+        let mut manifest = crate::new_empty_manifest().build()?;
+        let mut config = ImageConfigurationBuilder::default().build()?;
+        w.push_layer(&mut manifest, &mut config, layer.clone(), "shared", None);
+        w.insert_manifest_and_config(manifest, config, Some("base"), Default::default())?;
+
+        let (found, desc) = w.find_layer_by_diffid(&diff_id)?.unwrap();
+        assert_eq!(desc, layer.descriptor().build()?);
+        assert_eq!(found.descriptor().build()?, desc);
+        assert_eq!(found.diff_id(), diff_id);
+        assert!(w.find_layer_by_diffid("sha256:0000")?.is_none());
+        assert!(w.find_layer_by_diffid("shared")?.is_none());
+        Ok(())
+    }
+}
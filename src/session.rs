@@ -0,0 +1,303 @@
+//! Building the layers of an image concurrently.
+
+use std::io::Write;
+use std::sync::Mutex;
+
+use anyhow::{anyhow, Result};
+use fn_error_context::context;
+use oci_spec::image::{Descriptor, ImageConfiguration, ImageManifest};
+
+use crate::{CompressionFormat, Layer, LayerWriter, LayerWriterOptions, OciDir};
+
+/// A position in the layer stack of a [`BuildSession`], see [`BuildSession::declare`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct LayerSlot(usize);
+
+#[derive(Debug)]
+struct SlotState {
+    description: String,
+    layer: Option<Layer>,
+}
+
+/// Options for [`OciDir::build_session_with`].
+#[derive(Debug, Clone, Default)]
+pub struct BuildSessionOptions {
+    /// When a layer is completed, use an existing blob with the same diff_id from
+    /// another image in the layout or an earlier slot, if any, instead of the new
+    /// blob; see [`OciDir::find_layer_by_diffid`]. The new blob is left for
+    /// [`OciDir::prune`].
+    pub dedupe: bool,
+}
+
+/// Builds the layers of an image from multiple threads, see [`OciDir::build_session`].
+///
+/// Layers are declared up front in the order they are stacked, and may then be
+/// written concurrently and completed in any order. Each writer has its own
+/// temporary file, and blobs are only put in place once complete, so concurrent
+/// writers never observe each other's partial content.
+#[derive(Debug)]
+pub struct BuildSession<'a> {
+    dir: &'a OciDir,
+    opts: BuildSessionOptions,
+    slots: Mutex<Vec<SlotState>>,
+}
+
+/// A writer for one layer of a [`BuildSession`].
+#[derive(Debug)]
+pub struct SessionLayerWriter<'a> {
+    session: &'a BuildSession<'a>,
+    slot: LayerSlot,
+    inner: LayerWriter<'a>,
+}
+
+impl<'a> SessionLayerWriter<'a> {
+    /// Complete the layer and record it in its slot of the session, returning the
+    /// descriptor of the recorded layer; see [`BuildSessionOptions::dedupe`].
+    pub fn complete(self) -> Result<Descriptor> {
+        let layer = self.inner.complete()?;
+        let layer = self.session.fill_slot(self.slot, layer)?;
+        Ok(layer.descriptor().build()?)
+    }
+}
+
+impl<'a> Write for SessionLayerWriter<'a> {
+    fn write(&mut self, srcbuf: &[u8]) -> std::io::Result<usize> {
+        self.inner.write(srcbuf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<'a> BuildSession<'a> {
+    /// Declare the next layer of the stack, with the description used for its
+    /// history entry.
+    pub fn declare(&self, description: &str) -> LayerSlot {
+        let mut slots = self.slots.lock().unwrap();
+        slots.push(SlotState {
+            description: description.to_owned(),
+            layer: None,
+        });
+        LayerSlot(slots.len() - 1)
+    }
+
+    /// Create a writer for the layer in `slot`; this may be called from any thread.
+    pub fn create_layer(
+        &'a self,
+        slot: LayerSlot,
+        format: CompressionFormat,
+        opts: &LayerWriterOptions,
+    ) -> Result<SessionLayerWriter<'a>> {
+        self.check_slot(slot)?;
+        Ok(SessionLayerWriter {
+            session: self,
+            slot,
+            inner: self.dir.create_layer_writer(format, opts)?,
+        })
+    }
+
+    /// Record a layer built by other means, such as [`OciDir::create_layer_from_dir`],
+    /// in `slot`. Each slot may only be filled once.
+    pub fn set_layer(&self, slot: LayerSlot, layer: Layer) -> Result<()> {
+        self.fill_slot(slot, layer).map(drop)
+    }
+
+    /// Return a layer already in the session or the layout with the same diff_id.
+    fn find_existing(&self, layer: &Layer) -> Result<Option<Layer>> {
+        {
+            let slots = self.slots.lock().unwrap();
+            let found = slots
+                .iter()
+                .filter_map(|s| s.layer.as_ref())
+                .find(|l| l.uncompressed_sha256 == layer.uncompressed_sha256);
+            if let Some(found) = found {
+                return Ok(Some(found.clone()));
+            }
+        }
+        Ok(self
+            .dir
+            .find_layer_by_diffid(&layer.diff_id())?
+            .map(|(l, _)| l))
+    }
+
+    fn fill_slot(&self, slot: LayerSlot, mut layer: Layer) -> Result<Layer> {
+        if self.opts.dedupe {
+            if let Some(existing) = self.find_existing(&layer)? {
+                layer = existing;
+            }
+        }
+        let mut slots = self.slots.lock().unwrap();
+        let state = slots
+            .get_mut(slot.0)
+            .ok_or_else(|| anyhow!("Unknown layer slot {}", slot.0))?;
+        if state.layer.is_some() {
+            anyhow::bail!("Layer {:?} was already completed", state.description);
+        }
+        state.layer = Some(layer.clone());
+        Ok(layer)
+    }
+
+    fn check_slot(&self, slot: LayerSlot) -> Result<()> {
+        let slots = self.slots.lock().unwrap();
+        match slots.get(slot.0) {
+            None => Err(anyhow!("Unknown layer slot {}", slot.0)),
+            Some(state) if state.layer.is_some() => Err(anyhow!(
+                "Layer {:?} was already completed",
+                state.description
+            )),
+            Some(_) => Ok(()),
+        }
+    }
+
+    /// Append all layers to the manifest and config in the order they were
+    /// declared. Fails without modifying either if a layer is not complete.
+    #[context("Finishing build session")]
+    pub fn finish(
+        self,
+        manifest: &mut ImageManifest,
+        config: &mut ImageConfiguration,
+    ) -> Result<()> {
+        let slots = self.slots.into_inner().unwrap();
+        if let Some(missing) = slots.iter().find(|s| s.layer.is_none()) {
+            anyhow::bail!("Layer {:?} was not completed", missing.description);
+        }
+        for state in slots {
+            let layer = state.layer.unwrap();
+            self.dir
+                .push_layer(manifest, config, layer, &state.description, None);
+        }
+        Ok(())
+    }
+}
+
+impl OciDir {
+    /// Start building layers concurrently; see [`BuildSession`].
+    pub fn build_session(&self) -> BuildSession<'_> {
+        self.build_session_with(&Default::default())
+    }
+
+    /// Start building layers concurrently, with the provided options.
+    pub fn build_session_with(&self, opts: &BuildSessionOptions) -> BuildSession<'_> {
+        BuildSession {
+            dir: self,
+            opts: opts.clone(),
+            slots: Default::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn build_session() -> Result<()> {
+        let w = OciDir::new_in_memory()?;
+        let session = w.build_session();
+        let slots: Vec<_> = (0..4)
+            .map(|i| session.declare(&format!("layer {i}")))
+            .collect();
+        std::thread::scope(|s| -> Result<()> {
+            let handles: Vec<_> = slots
+                .iter()
+                .rev()
+                .map(|&slot| {
+                    let session = &session;
+                    s.spawn(move || -> Result<Descriptor> {
+                        let mut lw = session.create_layer(
+                            slot,
+                            CompressionFormat::Gzip,
+                            &Default::default(),
+                        )?;
+                        write!(lw, "contents of {slot:?}")?;
+                        lw.complete()
+                    })
+                })
+                .collect();
+            for h in handles {
+                h.join().unwrap()?;
+            }
+            Ok(())
+        })?;
+        let mut lw = w.create_gzip_layer(None)?;
+        lw.write_all(b"again")?;
+        assert!(session.set_layer(slots[0], lw.complete()?).is_err());
+        assert!(session
+            .create_layer(LayerSlot(7), CompressionFormat::None, &Default::default())
+            .is_err());
+
+        let mut manifest = crate::new_empty_manifest().build()?;
+        let mut config = oci_spec::image::ImageConfigurationBuilder::default().build()?;
+        session.finish(&mut manifest, &mut config)?;
+        assert_eq!(manifest.layers().len(), 4);
+        for (i, layer) in manifest.layers().iter().enumerate() {
+            assert_eq!(
+                w.compute_diffid(layer)?,
+                config.rootfs().diff_ids()[i].as_str()
+            );
+            let (_, mut r) = w.open_layer(layer)?;
+            let mut buf = String::new();
+            std::io::Read::read_to_string(&mut r, &mut buf)?;
+            assert_eq!(buf, format!("contents of {:?}", slots[i]));
+            assert_eq!(
+                config.history()[i].created_by().as_deref(),
+                Some(format!("layer {i}").as_str())
+            );
+        }
+
+        let session = w.build_session();
+        session.declare("never written");
+        let mut manifest = crate::new_empty_manifest().build()?;
+        assert!(session.finish(&mut manifest, &mut config).is_err());
+        assert!(manifest.layers().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn build_session_dedupe() -> Result<()> {
+        let w = OciDir::new_in_memory()?;
+        let mut lw = w.create_gzip_layer(None)?;
+        lw.write_all(b"base")?;
+        let base = lw.complete()?;
+        let mut manifest = crate::new_empty_manifest().build()?;
+        let mut config = oci_spec::image::ImageConfigurationBuilder::default().build()?;
+        w.push_layer(&mut manifest, &mut config, base.clone(), "base", None);
+        w.insert_manifest_and_config(manifest, config, Some("base"), Default::default())?;
+
+        let opts = BuildSessionOptions { dedupe: true };
+        let session = w.build_session_with(&opts);
+        let slots: Vec<_> = (0..3).map(|i| session.declare(&i.to_string())).collect();
+        let none = CompressionFormat::None;
+        for (slot, contents) in slots.iter().zip(["base", "new", "new"]) {
+            let mut lw = session.create_layer(*slot, none, &Default::default())?;
+            lw.write_all(contents.as_bytes())?;
+            let desc = lw.complete()?;
+            if contents == "base" {
+                assert_eq!(desc, base.descriptor().build()?);
+            }
+        }
+        let mut manifest = crate::new_empty_manifest().build()?;
+        let mut config = oci_spec::image::ImageConfigurationBuilder::default().build()?;
+        session.finish(&mut manifest, &mut config)?;
+        let layers = manifest.layers();
+        assert_eq!(
+            layers[0].media_type(),
+            &oci_spec::image::MediaType::ImageLayerGzip
+        );
+        assert_eq!(layers[1], layers[2]);
+        assert_eq!(
+            layers[1].media_type(),
+            &oci_spec::image::MediaType::ImageLayer
+        );
+        assert_eq!(config.rootfs().diff_ids()[0], base.diff_id());
+
+        // Without the option, the new blob is used
+        let session = w.build_session();
+        let slot = session.declare("base");
+        let mut lw = session.create_layer(slot, none, &Default::default())?;
+        lw.write_all(b"base")?;
+        assert_ne!(lw.complete()?, base.descriptor().build()?);
+        Ok(())
+    }
+}
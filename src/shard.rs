@@ -0,0 +1,129 @@
+//! Converting layouts between flat and sharded blob storage.
+
+use anyhow::{anyhow, Result};
+use cap_std_ext::dirext::CapStdExtDirExt;
+use fn_error_context::context;
+
+use crate::store::{blob_path, ensure_blob_parent, sharded_blob_path, STAGING_DIR};
+use crate::{OciDir, BLOBS};
+
+impl OciDir {
+    fn move_blobs(&self, sharded: bool) -> Result<u64> {
+        let dir = self
+            .writable_dir()?
+            .ok_or_else(|| anyhow!("Sharding requires an on-disk layout"))?;
+        let mut moved = 0;
+        for digest in self.store.list()? {
+            let (from, to) = if sharded {
+                (blob_path(&digest)?, sharded_blob_path(&digest)?)
+            } else {
+                (sharded_blob_path(&digest)?, blob_path(&digest)?)
+            };
+            if from == to || !dir.try_exists(&from)? {
+                continue;
+            }
+            if dir.try_exists(&to)? {
+                // Stored in both forms; the copies are identical.
+                dir.remove_file(&from)?;
+                continue;
+            }
+            ensure_blob_parent(dir, &to)?;
+            dir.rename(&from, dir, &to)?;
+            moved += 1;
+        }
+        if !sharded {
+            let Some(blobs) = dir.open_dir_optional(BLOBS)? else {
+                return Ok(moved);
+            };
+            for algdir in blobs.entries()? {
+                let algdir = algdir?;
+                if !algdir.file_type()?.is_dir() || algdir.file_name() == STAGING_DIR {
+                    continue;
+                }
+                let algdir = algdir.open_dir()?;
+                for ent in algdir.entries()? {
+                    let ent = ent?;
+                    let name = ent.file_name();
+                    if ent.file_type()?.is_dir()
+                        && name.len() == 2
+                        && ent.open_dir()?.entries()?.next().is_none()
+                    {
+                        algdir.remove_dir(name)?;
+                    }
+                }
+            }
+        }
+        Ok(moved)
+    }
+
+    /// Move all blobs into two-character prefix subdirectories, returning the number
+    /// of blobs moved; see [`crate::store::ShardedDir`]. Open the layout with
+    /// [`crate::OciDirOptions::shard_blobs`] to store new blobs the same way.
+    ///
+    /// Readers of this crate find blobs in either form while this runs.
+    #[context("Migrating to sharded blobs")]
+    pub fn migrate_to_sharded(&self) -> Result<u64> {
+        self.move_blobs(true)
+    }
+
+    /// Move all blobs out of shard subdirectories into the standard flat form,
+    /// for example before handing the layout to other tools; returns the number of
+    /// blobs moved.
+    #[context("Migrating to flat blobs")]
+    pub fn migrate_to_flat(&self) -> Result<u64> {
+        self.move_blobs(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cap_std_ext::{cap_std, cap_tempfile};
+
+    use crate::tests::insert_test_image;
+    use crate::{LayoutExtension, OciDirOptions};
+
+    #[test]
+    fn sharding() -> Result<()> {
+        let td = cap_tempfile::tempdir(cap_std::ambient_authority())?;
+        let opts = OciDirOptions {
+            shard_blobs: true,
+            ..Default::default()
+        };
+        let w = OciDir::ensure_with(&td, &opts)?;
+        let digest = insert_test_image(&w)?.1.blob.digest_id();
+
+        assert!(td.try_exists(sharded_blob_path(&digest)?)?);
+        assert!(!td.try_exists(blob_path(&digest)?)?);
+        assert!(w
+            .describe()?
+            .extensions
+            .contains(&LayoutExtension::ShardedBlobs));
+        assert_eq!(w.fsck()?, 3);
+
+        // A flat layout reads sharded blobs, and the two forms can be mixed
+        let flat = OciDir::open(&td)?;
+        assert!(flat.find_manifest_with_tag("latest")?.is_some());
+        let other = flat.write_blob_dedup(&b"flat"[..])?.0;
+        assert!(td.try_exists(blob_path(&other.digest_id())?)?);
+        assert_eq!(flat.store().list()?.len(), 4);
+
+        assert_eq!(flat.migrate_to_flat()?, 3);
+        assert!(td.try_exists(blob_path(&digest)?)?);
+        assert!(!flat
+            .describe()?
+            .extensions
+            .contains(&LayoutExtension::ShardedBlobs));
+        assert_eq!(flat.fsck()?, 4);
+
+        assert_eq!(flat.migrate_to_sharded()?, 4);
+        assert_eq!(flat.migrate_to_sharded()?, 0);
+        assert!(td.try_exists(sharded_blob_path(&other.digest_id())?)?);
+        assert_eq!(flat.fsck()?, 4);
+        assert!(flat.store().delete(&other.digest_id())?);
+        assert_eq!(flat.store().list()?.len(), 3);
+
+        assert!(OciDir::new_in_memory()?.migrate_to_sharded().is_err());
+        Ok(())
+    }
+}
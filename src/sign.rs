@@ -0,0 +1,249 @@
+//! Offline signing of manifests with cosign-compatible signature artifacts.
+//!
+//! This requires the `sign` feature. A [simple signing] payload is signed with
+//! SHA-256 using OpenSSL; keys are ECDSA or RSA keys in unencrypted PEM format,
+//! as encrypted cosign key files are not supported.
+//!
+//! [simple signing]: https://github.com/containers/image/blob/main/docs/containers-signature.5.md
+
+use std::collections::HashMap;
+
+use anyhow::{anyhow, Context, Result};
+use base64::prelude::*;
+use fn_error_context::context;
+use oci_spec::image::{Descriptor, ImageManifest, MediaType};
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use serde::{Deserialize, Serialize};
+
+use crate::referrers::cosign_tag;
+use crate::OciDir;
+
+/// The media type of a simple signing payload layer.
+pub const SIMPLE_SIGNING_MEDIA_TYPE: &str = "application/vnd.dev.cosign.simplesigning.v1+json";
+/// The artifact type of signatures stored as referrers.
+pub const SIGNATURE_ARTIFACT_TYPE: &str = "application/vnd.dev.cosign.artifact.sig.v1+json";
+/// The layer annotation holding the base64 encoded signature.
+pub const SIGNATURE_ANNOTATION: &str = "dev.cosignproject.cosign/signature";
+const SIGNATURE_TYPE: &str = "cosign container image signature";
+
+/// Where signatures are stored in the layout.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum SignatureScheme {
+    /// A manifest tagged `sha256-<digest>.sig`, with one layer per signature.
+    #[default]
+    Tag,
+    /// A separate artifact per signature referring to the signed manifest.
+    Referrers,
+}
+
+/// Options for [`OciDir::sign_manifest`].
+#[derive(Debug, Clone, Default)]
+pub struct SignOptions {
+    /// Where to store the signature.
+    pub scheme: SignatureScheme,
+    /// The image reference recorded in the payload, such as `quay.io/example/app`.
+    pub identity: Option<String>,
+    /// Additional claims recorded in the `optional` section of the payload.
+    pub annotations: Option<HashMap<String, String>>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct Identity {
+    docker_reference: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct Image {
+    docker_manifest_digest: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Critical {
+    identity: Identity,
+    image: Image,
+    #[serde(rename = "type")]
+    kind: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Payload {
+    critical: Critical,
+    optional: Option<HashMap<String, String>>,
+}
+
+fn simple_signing_type() -> MediaType {
+    MediaType::Other(SIMPLE_SIGNING_MEDIA_TYPE.to_owned())
+}
+
+impl OciDir {
+    /// Sign the manifest with the provided PEM private key, storing the signature
+    /// in the layout according to [`SignOptions::scheme`]. Returns the descriptor of
+    /// the signature manifest.
+    #[context("Signing {}", manifest.digest())]
+    pub fn sign_manifest(
+        &self,
+        manifest: &Descriptor,
+        private_key_pem: &[u8],
+        opts: &SignOptions,
+    ) -> Result<Descriptor> {
+        let key = PKey::private_key_from_pem(private_key_pem).context("Parsing private key")?;
+        let payload = Payload {
+            critical: Critical {
+                identity: Identity {
+                    docker_reference: opts.identity.clone().unwrap_or_default(),
+                },
+                image: Image {
+                    docker_manifest_digest: manifest.digest().clone(),
+                },
+                kind: SIGNATURE_TYPE.to_owned(),
+            },
+            optional: opts.annotations.clone(),
+        };
+        let payload = serde_json::to_vec(&payload)?;
+        let mut signer = openssl::sign::Signer::new(MessageDigest::sha256(), &key)?;
+        let signature = BASE64_STANDARD.encode(signer.sign_oneshot_to_vec(&payload)?);
+        let mut layer = self
+            .write_blob_with_type(&payload, simple_signing_type())?
+            .build()?;
+        layer.set_annotations(Some(HashMap::from([(
+            SIGNATURE_ANNOTATION.to_owned(),
+            signature,
+        )])));
+
+        match opts.scheme {
+            SignatureScheme::Referrers => self.attach_referrer(
+                manifest,
+                MediaType::Other(SIGNATURE_ARTIFACT_TYPE.to_owned()),
+                vec![layer],
+                None,
+            ),
+            SignatureScheme::Tag => {
+                let tag = cosign_tag(manifest.digest(), "sig")?;
+                self.append_to_cosign_tag(&tag, layer)
+            }
+        }
+    }
+
+    /// Verify the signatures of a manifest stored with either [`SignatureScheme`]
+    /// against the provided PEM public key, returning the number of valid signatures.
+    ///
+    /// Signatures made with other keys are ignored, but an error is returned
+    /// if there is no valid signature at all.
+    #[context("Verifying signatures of {}", manifest.digest())]
+    pub fn verify_signatures(&self, manifest: &Descriptor, public_key_pem: &[u8]) -> Result<u32> {
+        let key = PKey::public_key_from_pem(public_key_pem).context("Parsing public key")?;
+        let mut signatures = Vec::new();
+        if let Some(m) = self.find_manifest_with_tag(&cosign_tag(manifest.digest(), "sig")?)? {
+            signatures.push(m);
+        }
+        let artifact_type = MediaType::Other(SIGNATURE_ARTIFACT_TYPE.to_owned());
+        for desc in self.referrers(manifest, Some(&artifact_type))? {
+            signatures.push(self.read_json_blob::<ImageManifest>(&desc)?);
+        }
+
+        let mut valid = 0;
+        for layer in signatures.iter().flat_map(|m| m.layers()) {
+            if layer.media_type() != &simple_signing_type() {
+                continue;
+            }
+            let Some(signature) = layer
+                .annotations()
+                .as_ref()
+                .and_then(|a| a.get(SIGNATURE_ANNOTATION))
+            else {
+                continue;
+            };
+            let signature = BASE64_STANDARD.decode(signature)?;
+            let payload = self.read_json_bytes(layer)?;
+            let mut verifier = openssl::sign::Verifier::new(MessageDigest::sha256(), &key)?;
+            // Invalid signatures from other keys may return either false or an error.
+            if !verifier
+                .verify_oneshot(&signature, &payload)
+                .unwrap_or_default()
+            {
+                continue;
+            }
+            let payload: Payload =
+                serde_json::from_slice(&payload).context("Parsing signature payload")?;
+            if payload.critical.image.docker_manifest_digest != manifest.digest().as_str() {
+                anyhow::bail!(
+                    "Signature is for {} instead",
+                    payload.critical.image.docker_manifest_digest
+                );
+            }
+            valid += 1;
+        }
+        if valid == 0 {
+            return Err(anyhow!("No valid signatures found"));
+        }
+        Ok(valid)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::nid::Nid;
+
+    fn keypair() -> Result<(Vec<u8>, Vec<u8>)> {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?;
+        let key = PKey::from_ec_key(EcKey::generate(&group)?)?;
+        Ok((key.private_key_to_pem_pkcs8()?, key.public_key_to_pem()?))
+    }
+
+    #[test]
+    fn sign_verify() -> Result<()> {
+        let w = OciDir::new_in_memory()?;
+        let manifest = crate::new_empty_manifest().build().unwrap();
+        let desc = w.insert_manifest(manifest, Some("app"), Default::default())?;
+        let other = crate::new_empty_manifest()
+            .annotations(HashMap::from([("a".to_owned(), "b".to_owned())]))
+            .build()
+            .unwrap();
+        let other = w.insert_manifest(other, Some("other"), Default::default())?;
+        let (private, public) = keypair()?;
+        let (_, other_public) = keypair()?;
+
+        assert!(w.verify_signatures(&desc, &public).is_err());
+        let opts = SignOptions {
+            identity: Some("quay.io/example/app".into()),
+            ..Default::default()
+        };
+        w.sign_manifest(&desc, &private, &opts)?;
+        assert_eq!(w.verify_signatures(&desc, &public)?, 1);
+        // A second signature is added to the same manifest
+        let sig = w.sign_manifest(&desc, &private, &opts)?;
+        let sig_manifest: ImageManifest = w.read_json_blob(&sig)?;
+        assert_eq!(sig_manifest.layers().len(), 2);
+        let tag = cosign_tag(desc.digest(), "sig")?;
+        assert!(tag.starts_with("sha256-") && tag.ends_with(".sig"));
+        assert!(w.find_manifest_with_tag(&tag)?.is_some());
+        let opts = SignOptions {
+            scheme: SignatureScheme::Referrers,
+            ..Default::default()
+        };
+        w.sign_manifest(&desc, &private, &opts)?;
+        assert_eq!(w.verify_signatures(&desc, &public)?, 3);
+
+        assert!(w.verify_signatures(&desc, &other_public).is_err());
+        assert!(w.verify_signatures(&other, &public).is_err());
+
+        // Signature payloads are bounded like other JSON blobs
+        let opts = crate::OciDirOptions {
+            json_limits: crate::JsonSizeLimits {
+                other: 1,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let w = OciDir::with_store(w.store.clone(), &opts)?;
+        let e = w.verify_signatures(&desc, &public).unwrap_err();
+        assert!(e.downcast_ref::<crate::BlobTooLarge>().is_some());
+        Ok(())
+    }
+}
@@ -0,0 +1,333 @@
+//! Merging runs of layers into a single layer.
+
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+use chrono::SecondsFormat;
+use fn_error_context::context;
+use oci_spec::image::{self as oci_image, Descriptor, ImageConfiguration, ImageManifest};
+
+use crate::extract::{normalize, whiteout_target};
+use crate::{Layer, OciDir, OPAQUE_WHITEOUT, WHITEOUT_PREFIX};
+
+/// An entry of the squashed layer.
+enum SquashEntry {
+    /// A file, directory, or link with its contents.
+    Entry {
+        header: Box<tar::Header>,
+        data: Vec<u8>,
+    },
+    /// A whiteout or opaque whiteout, which must be kept to hide content
+    /// from the layers below the squashed range.
+    Whiteout,
+}
+
+/// Remove all entries below `dir`.
+fn remove_children(entries: &mut BTreeMap<PathBuf, SquashEntry>, dir: &Path) {
+    entries.retain(|p, _| p == dir || !p.starts_with(dir));
+}
+
+impl OciDir {
+    /// Apply `layers` on top of each other into a new gzip compressed layer, holding
+    /// their contents in memory. Whiteouts are only emitted if `keep_whiteouts` is set.
+    fn merge_layers(&self, layers: &[Descriptor], keep_whiteouts: bool) -> Result<Layer> {
+        let mut entries = BTreeMap::new();
+        for desc in layers {
+            let (_, r) = self.open_layer(desc)?;
+            let mut archive = tar::Archive::new(r);
+            for entry in archive.entries()? {
+                let mut entry = entry?;
+                let path = normalize(&entry.path()?)?;
+                let Some(name) = path.file_name().and_then(|n| n.to_str()) else {
+                    continue;
+                };
+                let parent = path.parent().unwrap_or(Path::new("")).to_owned();
+                if name == OPAQUE_WHITEOUT {
+                    remove_children(&mut entries, &parent);
+                    if keep_whiteouts {
+                        entries.insert(path, SquashEntry::Whiteout);
+                    }
+                    continue;
+                }
+                if let Some(target) = name.strip_prefix(WHITEOUT_PREFIX) {
+                    let target = whiteout_target(&parent, target)?;
+                    remove_children(&mut entries, &target);
+                    entries.remove(&target);
+                    if keep_whiteouts {
+                        entries.insert(path, SquashEntry::Whiteout);
+                    }
+                    continue;
+                }
+                let header = Box::new(entry.header().clone());
+                if !header.entry_type().is_dir() {
+                    remove_children(&mut entries, &path);
+                }
+                let mut data = Vec::new();
+                entry.read_to_end(&mut data)?;
+                entries.insert(path, SquashEntry::Entry { header, data });
+            }
+        }
+
+        let mut builder = self.create_layer(None)?;
+        for (path, entry) in entries {
+            match entry {
+                SquashEntry::Whiteout => {
+                    let mut h = tar::Header::new_gnu();
+                    h.set_mode(0o644);
+                    h.set_size(0);
+                    builder.append_data(&mut h, &path, std::io::empty())?;
+                }
+                SquashEntry::Entry { mut header, data } => {
+                    let et = header.entry_type();
+                    if et.is_symlink() || et.is_hard_link() {
+                        let target = header
+                            .link_name()?
+                            .ok_or_else(|| anyhow!("Missing link target for {}", path.display()))?
+                            .into_owned();
+                        builder.append_link(&mut header, &path, target)?;
+                    } else {
+                        builder.append_data(&mut header, &path, data.as_slice())?;
+                    }
+                }
+            }
+        }
+        builder.into_inner()?.complete()
+    }
+
+    /// Merge the layers in `range` into a single new gzip compressed layer, applying
+    /// whiteouts between them, and rewrite the manifest layers, config diff_ids and
+    /// history accordingly. Returns the new layer.
+    ///
+    /// Whiteouts which may refer to content below the range are preserved. The
+    /// contents of all layers in the range are held in memory. History entries
+    /// are only rewritten if the non-empty entries line up with the layers.
+    #[context("Squashing layers")]
+    pub fn squash_layers(
+        &self,
+        manifest: &mut ImageManifest,
+        config: &mut ImageConfiguration,
+        range: Range<usize>,
+    ) -> Result<Layer> {
+        let n_layers = manifest.layers().len();
+        if range.is_empty() || range.end > n_layers {
+            anyhow::bail!("Invalid layer range {range:?} for {n_layers} layers");
+        }
+        let n_diff_ids = config.rootfs().diff_ids().len();
+        if n_layers != n_diff_ids {
+            anyhow::bail!("Manifest has {n_layers} layers but config has {n_diff_ids} diff_ids");
+        }
+
+        let layer = self.merge_layers(&manifest.layers()[range.clone()], true)?;
+
+        let is_layer = |h: &oci_image::History| !h.empty_layer().unwrap_or_default();
+        let history = config.history_mut();
+        let layer_history: Vec<usize> = history
+            .iter()
+            .enumerate()
+            .filter(|(_, h)| is_layer(h))
+            .map(|(i, _)| i)
+            .collect();
+        if layer_history.len() == n_layers {
+            let squashed = &layer_history[range.clone()];
+            let created = history[*squashed.last().unwrap()].created().clone();
+            let mut h = oci_image::HistoryBuilder::default()
+                .created_by(format!("squashed layers {}..{}", range.start, range.end))
+                .build()
+                .unwrap();
+            h.set_created(created);
+            let first = squashed[0];
+            for &i in squashed.iter().rev() {
+                history.remove(i);
+            }
+            history.insert(first, h);
+        }
+
+        let mut rootfs = config.rootfs().clone();
+        rootfs
+            .diff_ids_mut()
+            .splice(range.clone(), [layer.diff_id()]);
+        config.set_rootfs(rootfs);
+        manifest
+            .layers_mut()
+            .splice(range, [layer.descriptor().build()?]);
+        Ok(layer)
+    }
+
+    /// Merge all layers of an image into a single new gzip compressed layer, returning
+    /// a new manifest and config which can be added with
+    /// [`OciDir::insert_manifest_and_config`].
+    ///
+    /// Whiteouts are applied and dropped, as there is nothing below the result for
+    /// them to hide. The history is replaced by one entry, with the `created` time of
+    /// the config or otherwise the time from [`crate::OciDirOptions::clock`]. The
+    /// contents of all layers are held in memory.
+    #[context("Flattening image")]
+    pub fn flatten(
+        &self,
+        manifest: &ImageManifest,
+        config: &ImageConfiguration,
+    ) -> Result<(ImageManifest, ImageConfiguration)> {
+        let n_layers = manifest.layers().len();
+        let n_diff_ids = config.rootfs().diff_ids().len();
+        if n_layers != n_diff_ids {
+            anyhow::bail!("Manifest has {n_layers} layers but config has {n_diff_ids} diff_ids");
+        }
+        let layer = self.merge_layers(manifest.layers(), false)?;
+
+        let mut config = config.clone();
+        let created = config
+            .created()
+            .clone()
+            .unwrap_or_else(|| self.now().to_rfc3339_opts(SecondsFormat::Secs, true));
+        let h = oci_image::HistoryBuilder::default()
+            .created(created)
+            .created_by(format!("flattened {n_layers} layers"))
+            .build()?;
+        config.set_history(vec![h]);
+        let mut rootfs = config.rootfs().clone();
+        rootfs.set_diff_ids(vec![layer.diff_id()]);
+        config.set_rootfs(rootfs);
+        let mut manifest = manifest.clone();
+        manifest.set_layers(vec![layer.descriptor().build()?]);
+        Ok((manifest, config))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use oci_spec::image::ImageConfigurationBuilder;
+
+    fn push(w: &OciDir, m: &mut ImageManifest, c: &mut ImageConfiguration, files: &[(&str, &str)]) {
+        let mut builder = w.create_layer(None).unwrap();
+        for (path, contents) in files {
+            let mut h = tar::Header::new_gnu();
+            h.set_mode(0o644);
+            h.set_size(contents.len() as u64);
+            builder
+                .append_data(&mut h, path, contents.as_bytes())
+                .unwrap();
+        }
+        let layer = builder.into_inner().unwrap().complete().unwrap();
+        w.push_layer(m, c, layer, "test", None);
+    }
+
+    #[test]
+    fn squash() -> Result<()> {
+        let w = OciDir::new_in_memory()?;
+        let mut m = crate::new_empty_manifest().build().unwrap();
+        let mut c = ImageConfigurationBuilder::default().build().unwrap();
+        push(&w, &mut m, &mut c, &[("lower", "x")]);
+        push(&w, &mut m, &mut c, &[("a", "a"), ("b", "1"), ("d/x", "x")]);
+        w.push_empty_history(&mut c, "metadata", None);
+        push(
+            &w,
+            &mut m,
+            &mut c,
+            &[
+                (".wh.a", ""),
+                (".wh.lower", ""),
+                ("d/.wh..wh..opq", ""),
+                ("d/y", "y"),
+            ],
+        );
+        push(&w, &mut m, &mut c, &[("b", "2")]);
+        push(&w, &mut m, &mut c, &[("top", "t")]);
+
+        let layer = w.squash_layers(&mut m, &mut c, 1..4)?;
+        assert_eq!(m.layers().len(), 3);
+        assert_eq!(m.layers()[1].digest(), &layer.blob.digest_id());
+        assert_eq!(c.rootfs().diff_ids()[1], layer.diff_id());
+        assert!(crate::layers::check_layers(&m, &c).is_empty());
+        let history: Vec<_> = c
+            .history()
+            .iter()
+            .map(|h| h.created_by().as_deref().unwrap())
+            .collect();
+        assert_eq!(
+            history,
+            ["test", "squashed layers 1..4", "metadata", "test"]
+        );
+
+        let (_, r) = w.open_blob_decompressed(&m.layers()[1])?;
+        let mut contents = Vec::new();
+        for e in tar::Archive::new(r).entries()? {
+            let mut e = e?;
+            let path = e.path()?.to_string_lossy().into_owned();
+            let mut buf = String::new();
+            e.read_to_string(&mut buf)?;
+            contents.push((path, buf));
+        }
+        let contents: Vec<_> = contents
+            .iter()
+            .map(|(p, c)| (p.as_str(), c.as_str()))
+            .collect();
+        assert_eq!(
+            contents,
+            [
+                (".wh.a", ""),
+                (".wh.lower", ""),
+                ("b", "2"),
+                ("d/.wh..wh..opq", ""),
+                ("d/y", "y")
+            ]
+        );
+        assert!(w.squash_layers(&mut m, &mut c, 2..4).is_err());
+
+        // Whiteouts must name an entry of their own directory
+        push(&w, &mut m, &mut c, &[("d/.wh...", "")]);
+        assert!(w.squash_layers(&mut m, &mut c, 1..4).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn flatten() -> Result<()> {
+        let w = OciDir::new_in_memory()?;
+        let mut m = crate::new_empty_manifest().build().unwrap();
+        let mut c = ImageConfigurationBuilder::default().build().unwrap();
+        push(&w, &mut m, &mut c, &[("a", "a"), ("b", "1"), ("d/x", "x")]);
+        w.push_empty_history(&mut c, "metadata", None);
+        push(
+            &w,
+            &mut m,
+            &mut c,
+            &[(".wh.a", ""), ("d/.wh..wh..opq", ""), ("d/y", "y")],
+        );
+        push(&w, &mut m, &mut c, &[("b", "2")]);
+
+        let (flat, flat_config) = w.flatten(&m, &c)?;
+        assert_eq!(flat.layers().len(), 1);
+        assert_eq!(flat_config.rootfs().diff_ids().len(), 1);
+        assert!(crate::layers::check_layers(&flat, &flat_config).is_empty());
+        let h = flat_config.history();
+        assert_eq!(h.len(), 1);
+        assert_eq!(h[0].created_by().as_deref(), Some("flattened 3 layers"));
+        assert_eq!(h[0].created(), c.created());
+        // The input is unchanged
+        assert_eq!(m.layers().len(), 3);
+
+        let (_, r) = w.open_blob_decompressed(&flat.layers()[0])?;
+        let mut contents = Vec::new();
+        for e in tar::Archive::new(r).entries()? {
+            let mut e = e?;
+            let path = e.path()?.to_string_lossy().into_owned();
+            let mut buf = String::new();
+            e.read_to_string(&mut buf)?;
+            contents.push((path, buf));
+        }
+        assert_eq!(
+            contents,
+            [("b".into(), "2".into()), ("d/y".into(), "y".into())]
+        );
+        w.insert_manifest_and_config(flat, flat_config, Some("flat"), Default::default())?;
+        w.fsck()?;
+
+        let mut c = c.clone();
+        c.rootfs_mut().diff_ids_mut().pop();
+        assert!(w.flatten(&m, &c).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,512 @@
+//! Layers in the [eStargz] format, which can be lazily pulled by the stargz snapshotter.
+//!
+//! An eStargz layer is an ordinary gzip compressed tarball, made of a separate gzip
+//! member per file chunk so that chunks can be fetched with range requests. It ends
+//! with a table of contents (TOC) entry named [`TOC_TAR_NAME`], followed by a footer
+//! recording the offset of the TOC.
+//!
+//! [eStargz]: https://github.com/containerd/stargz-snapshotter/blob/main/docs/estargz.md
+
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::num::NonZeroU64;
+
+use anyhow::Result;
+use chrono::{SecondsFormat, TimeZone, Utc};
+use flate2::write::GzEncoder;
+use fn_error_context::context;
+use oci_spec::image::{DescriptorBuilder, MediaType};
+use serde::Serialize;
+
+use crate::hash::Sha256;
+use crate::{hash, BlobWriter, Layer, OciDir};
+
+/// Descriptor annotation holding the digest of the uncompressed TOC.
+pub const TOC_DIGEST_ANNOTATION: &str = "containerd.io/snapshot/stargz/toc.digest";
+/// Descriptor annotation holding the size of the uncompressed layer.
+pub const UNCOMPRESSED_SIZE_ANNOTATION: &str = "io.containers.estargz.uncompressed-size";
+/// The name of the tar entry holding the TOC.
+pub const TOC_TAR_NAME: &str = "stargz.index.json";
+/// The size of the footer at the end of every eStargz layer.
+pub const FOOTER_SIZE: usize = 51;
+const DEFAULT_CHUNK_SIZE: u64 = 4 * 1024 * 1024;
+const BLOCK_SIZE: u64 = 512;
+
+/// Options for [`OciDir::create_stargz_layer`].
+#[derive(Debug, Clone, Default)]
+pub struct StargzLayerOptions {
+    /// The compression level.
+    pub compression: Option<flate2::Compression>,
+    /// Files larger than this are split into separately compressed chunks;
+    /// the default is 4 MiB.
+    pub chunk_size: Option<NonZeroU64>,
+}
+
+/// A completed eStargz layer, see [`StargzLayerWriter::complete`].
+#[derive(Debug)]
+pub struct StargzLayer {
+    /// The layer blob.
+    pub layer: Layer,
+    /// The digest of the uncompressed TOC JSON.
+    pub toc_digest: String,
+    /// The size of the uncompressed layer.
+    pub uncompressed_size: u64,
+}
+
+impl StargzLayer {
+    /// The annotations which must be set on the layer descriptor for lazy pulling,
+    /// e.g. when passing the layer to [`OciDir::push_layer`].
+    pub fn annotations(&self) -> HashMap<String, String> {
+        HashMap::from([
+            (TOC_DIGEST_ANNOTATION.to_owned(), self.toc_digest.clone()),
+            (
+                UNCOMPRESSED_SIZE_ANNOTATION.to_owned(),
+                self.uncompressed_size.to_string(),
+            ),
+        ])
+    }
+
+    /// Return the descriptor for this layer, including the annotations.
+    pub fn descriptor(&self) -> DescriptorBuilder {
+        self.layer.descriptor().annotations(self.annotations())
+    }
+}
+
+fn is_zero(v: &u64) -> bool {
+    *v == 0
+}
+
+#[derive(Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct TocEntry {
+    name: String,
+    #[serde(rename = "type")]
+    kind: &'static str,
+    #[serde(skip_serializing_if = "is_zero")]
+    size: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    modtime: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    link_name: Option<String>,
+    #[serde(skip_serializing_if = "is_zero")]
+    mode: u64,
+    #[serde(skip_serializing_if = "is_zero")]
+    uid: u64,
+    #[serde(skip_serializing_if = "is_zero")]
+    gid: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    user_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    group_name: Option<String>,
+    #[serde(skip_serializing_if = "is_zero")]
+    offset: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dev_major: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dev_minor: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    digest: Option<String>,
+    #[serde(skip_serializing_if = "is_zero")]
+    chunk_offset: u64,
+    #[serde(skip_serializing_if = "is_zero")]
+    chunk_size: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    chunk_digest: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct Toc {
+    version: u32,
+    entries: Vec<TocEntry>,
+}
+
+/// Normalize a tar entry name as used in the TOC, without leading `./` or `/`
+/// and without a trailing `/`.
+fn toc_name(name: &[u8]) -> String {
+    let name = String::from_utf8_lossy(name);
+    let name = name.trim_start_matches("./").trim_start_matches('/');
+    name.trim_end_matches('/').to_owned()
+}
+
+/// Strip the trailing NUL from GNU long name entries.
+fn trim_nul(mut v: Vec<u8>) -> Vec<u8> {
+    while v.last() == Some(&0) {
+        v.pop();
+    }
+    v
+}
+
+/// The footer pointing to the TOC: an empty gzip member whose header has an
+/// extra field with the offset, exactly [`FOOTER_SIZE`] bytes long.
+fn footer(toc_offset: u64) -> Vec<u8> {
+    let subfield = format!("{toc_offset:016x}STARGZ");
+    let mut r = Vec::with_capacity(FOOTER_SIZE);
+    // Magic, deflate, FEXTRA, no mtime, no extra flags, unknown OS
+    r.extend_from_slice(&[0x1f, 0x8b, 0x08, 0x04, 0, 0, 0, 0, 0, 0xff]);
+    r.extend_from_slice(&((subfield.len() + 4) as u16).to_le_bytes());
+    r.extend_from_slice(b"SG");
+    r.extend_from_slice(&(subfield.len() as u16).to_le_bytes());
+    r.extend_from_slice(subfield.as_bytes());
+    // An empty final stored block, then the CRC-32 and size of no data
+    r.extend_from_slice(&[0x01, 0x00, 0x00, 0xff, 0xff]);
+    r.extend_from_slice(&[0; 8]);
+    debug_assert_eq!(r.len(), FOOTER_SIZE);
+    r
+}
+
+/// Create an eStargz layer from one or more tar streams.
+pub struct StargzLayerWriter<'a> {
+    bw: BlobWriter<'a>,
+    uncompressed_hash: Sha256,
+    uncompressed_size: u64,
+    compression: flate2::Compression,
+    chunk_size: u64,
+    member: Option<GzEncoder<Vec<u8>>>,
+    entries: Vec<TocEntry>,
+}
+
+impl<'a> std::fmt::Debug for StargzLayerWriter<'a> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("StargzLayerWriter")
+            .field("bw", &self.bw)
+            .field("entries", &self.entries.len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl<'a> StargzLayerWriter<'a> {
+    /// Write uncompressed data to the current gzip member, starting one if needed.
+    fn write_uncompressed(&mut self, buf: &[u8]) -> Result<()> {
+        let compression = self.compression;
+        self.member
+            .get_or_insert_with(|| GzEncoder::new(Vec::new(), compression))
+            .write_all(buf)?;
+        self.uncompressed_hash.update(buf)?;
+        self.uncompressed_size += buf.len() as u64;
+        Ok(())
+    }
+
+    fn write_padding(&mut self, len: u64) -> Result<()> {
+        let pad = (BLOCK_SIZE - len % BLOCK_SIZE) % BLOCK_SIZE;
+        self.write_uncompressed(&vec![0; pad as usize])
+    }
+
+    /// Finish the current gzip member and write it to the blob.
+    fn close_member(&mut self) -> Result<()> {
+        if let Some(member) = self.member.take() {
+            let buf = member.finish()?;
+            self.bw.write_all(&buf)?;
+        }
+        Ok(())
+    }
+
+    /// Append all entries of an uncompressed tar stream, without its end-of-archive marker.
+    ///
+    /// GNU long names and PAX extended headers are preserved; sparse files
+    /// are not supported.
+    #[context("Appending tar stream to eStargz layer")]
+    pub fn append_tar(&mut self, r: impl Read) -> Result<()> {
+        let mut archive = tar::Archive::new(r);
+        let mut long_name = None;
+        let mut long_link = None;
+        for entry in archive.entries()?.raw(true) {
+            let mut entry = entry?;
+            let header = entry.header().clone();
+            self.write_uncompressed(header.as_bytes())?;
+            let size = header.entry_size()?;
+            let kind = match header.entry_type() {
+                tar::EntryType::GNULongName
+                | tar::EntryType::GNULongLink
+                | tar::EntryType::XHeader
+                | tar::EntryType::XGlobalHeader => {
+                    let mut buf = Vec::new();
+                    entry.read_to_end(&mut buf)?;
+                    self.write_uncompressed(&buf)?;
+                    self.write_padding(size)?;
+                    match header.entry_type() {
+                        tar::EntryType::GNULongName => long_name = Some(trim_nul(buf)),
+                        tar::EntryType::GNULongLink => long_link = Some(trim_nul(buf)),
+                        tar::EntryType::XHeader => {
+                            for ext in tar::PaxExtensions::new(&buf) {
+                                let ext = ext?;
+                                match ext.key_bytes() {
+                                    b"path" => long_name = Some(ext.value_bytes().to_vec()),
+                                    b"linkpath" => long_link = Some(ext.value_bytes().to_vec()),
+                                    _ => {}
+                                }
+                            }
+                        }
+                        _ => {}
+                    }
+                    continue;
+                }
+                tar::EntryType::Regular | tar::EntryType::Continuous => "reg",
+                tar::EntryType::Directory => "dir",
+                tar::EntryType::Symlink => "symlink",
+                tar::EntryType::Link => "hardlink",
+                tar::EntryType::Char => "char",
+                tar::EntryType::Block => "block",
+                tar::EntryType::Fifo => "fifo",
+                t => anyhow::bail!("Unsupported tar entry type {t:?}"),
+            };
+            let name = long_name
+                .take()
+                .unwrap_or_else(|| header.path_bytes().into_owned());
+            let name = toc_name(&name);
+            let link_name = long_link
+                .take()
+                .or_else(|| header.link_name_bytes().map(|l| l.into_owned()))
+                .filter(|_| matches!(kind, "symlink" | "hardlink"))
+                .map(|l| String::from_utf8_lossy(&l).into_owned());
+            let device = matches!(kind, "char" | "block");
+            let mut toc = TocEntry {
+                name: name.clone(),
+                kind,
+                size: if kind == "reg" { size } else { 0 },
+                modtime: Utc
+                    .timestamp_opt(header.mtime()?.try_into()?, 0)
+                    .single()
+                    .map(|t| t.to_rfc3339_opts(SecondsFormat::Secs, true)),
+                link_name,
+                mode: header.mode()?.into(),
+                uid: header.uid()?,
+                gid: header.gid()?,
+                user_name: header.username()?.map(ToOwned::to_owned),
+                group_name: header.groupname()?.map(ToOwned::to_owned),
+                ..Default::default()
+            };
+            if device {
+                toc.dev_major = header.device_major()?;
+                toc.dev_minor = header.device_minor()?;
+            }
+            if kind != "reg" || size == 0 {
+                if kind == "reg" {
+                    toc.digest = Some(format!("sha256:{}", hash::sha256_hex(&[])?));
+                }
+                let mut buf = Vec::new();
+                entry.read_to_end(&mut buf)?;
+                self.write_uncompressed(&buf)?;
+                self.write_padding(size)?;
+                self.entries.push(toc);
+                continue;
+            }
+
+            // Each chunk of file content starts a new gzip member.
+            let first = self.entries.len();
+            let mut file_hash = Sha256::new()?;
+            let mut chunk_offset = 0;
+            let mut toc = Some(toc);
+            while chunk_offset < size {
+                let n = (size - chunk_offset).min(self.chunk_size);
+                let mut chunk = Vec::with_capacity(n as usize);
+                (&mut entry).take(n).read_to_end(&mut chunk)?;
+                if chunk.len() as u64 != n {
+                    anyhow::bail!("Unexpected end of file content for {name}");
+                }
+                self.close_member()?;
+                file_hash.update(&chunk)?;
+                let mut e = toc.take().unwrap_or_else(|| TocEntry {
+                    name: name.clone(),
+                    kind: "chunk",
+                    ..Default::default()
+                });
+                e.offset = self.bw.size;
+                e.chunk_offset = chunk_offset;
+                if size > self.chunk_size {
+                    e.chunk_size = n;
+                }
+                e.chunk_digest = Some(format!("sha256:{}", hash::sha256_hex(&chunk)?));
+                self.write_uncompressed(&chunk)?;
+                self.entries.push(e);
+                chunk_offset += n;
+            }
+            self.entries[first].digest = Some(format!("sha256:{}", file_hash.finish_hex()?));
+            self.write_padding(size)?;
+        }
+        Ok(())
+    }
+
+    /// Write the TOC and footer, and put the blob in place.
+    #[context("Completing eStargz layer")]
+    pub fn complete(mut self) -> Result<StargzLayer> {
+        self.close_member()?;
+        let toc_offset = self.bw.size;
+        let toc = serde_json::to_vec(&Toc {
+            version: 1,
+            entries: std::mem::take(&mut self.entries),
+        })?;
+        let mut h = tar::Header::new_ustar();
+        h.set_path(TOC_TAR_NAME)?;
+        h.set_entry_type(tar::EntryType::Regular);
+        h.set_size(toc.len() as u64);
+        h.set_mode(0o644);
+        h.set_cksum();
+        self.write_uncompressed(h.as_bytes())?;
+        self.write_uncompressed(&toc)?;
+        self.write_padding(toc.len() as u64)?;
+        // The end of the tar archive
+        self.write_uncompressed(&[0; 2 * BLOCK_SIZE as usize])?;
+        self.close_member()?;
+        self.bw.write_all(&footer(toc_offset))?;
+
+        let blob = self.bw.complete()?;
+        let uncompressed_sha256 = self.uncompressed_hash.finish_hex()?;
+        Ok(StargzLayer {
+            layer: Layer {
+                blob,
+                uncompressed_sha256,
+                media_type: MediaType::ImageLayerGzip,
+            },
+            toc_digest: format!("sha256:{}", hash::sha256_hex(&toc)?),
+            uncompressed_size: self.uncompressed_size,
+        })
+    }
+}
+
+impl OciDir {
+    /// Create a writer for a new eStargz layer; tar streams are added with
+    /// [`StargzLayerWriter::append_tar`].
+    pub fn create_stargz_layer(&self, opts: &StargzLayerOptions) -> Result<StargzLayerWriter<'_>> {
+        Ok(StargzLayerWriter {
+            bw: BlobWriter::new(&*self.store, &self.progress)?,
+            uncompressed_hash: Sha256::new()?,
+            uncompressed_size: 0,
+            compression: opts.compression.unwrap_or_default(),
+            chunk_size: opts
+                .chunk_size
+                .map(NonZeroU64::get)
+                .unwrap_or(DEFAULT_CHUNK_SIZE),
+            member: None,
+            entries: Vec::new(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+    use std::io::Seek;
+
+    /// Parse the TOC offset from an eStargz footer.
+    fn parse_footer(footer: &[u8]) -> Result<u64> {
+        let subfield = footer
+            .get(16..38)
+            .filter(|s| s.ends_with(b"STARGZ"))
+            .ok_or_else(|| anyhow!("Invalid eStargz footer"))?;
+        Ok(u64::from_str_radix(
+            std::str::from_utf8(&subfield[..16])?,
+            16,
+        )?)
+    }
+
+    #[test]
+    fn stargz() -> Result<()> {
+        let w = OciDir::new_in_memory()?;
+        let big: Vec<u8> = (0..3000u32).map(|i| (i % 251) as u8).collect();
+        let long_name = format!("usr/{}/file", "d".repeat(120));
+        let header = |kind, mode| {
+            let mut h = tar::Header::new_gnu();
+            h.set_entry_type(kind);
+            h.set_mode(mode);
+            h.set_uid(0);
+            h.set_gid(0);
+            h.set_mtime(1700000000);
+            h.set_size(0);
+            h
+        };
+        let mut b = tar::Builder::new(Vec::new());
+        let mut h = header(tar::EntryType::Directory, 0o755);
+        b.append_data(&mut h, "usr/", std::io::empty())?;
+        let mut h = header(tar::EntryType::Regular, 0o644);
+        h.set_size(5);
+        b.append_data(&mut h, "usr/small", &b"hello"[..])?;
+        h.set_size(big.len() as u64);
+        b.append_data(&mut h, "usr/big", big.as_slice())?;
+        h.set_size(0);
+        b.append_data(&mut h, &long_name, std::io::empty())?;
+        let mut h = header(tar::EntryType::Symlink, 0o777);
+        b.append_link(&mut h, "usr/link", "small")?;
+        let input = b.into_inner()?;
+
+        let opts = StargzLayerOptions {
+            chunk_size: NonZeroU64::new(1024),
+            ..Default::default()
+        };
+        let mut sw = w.create_stargz_layer(&opts)?;
+        sw.append_tar(input.as_slice())?;
+        let layer = sw.complete()?;
+        let desc = layer.descriptor().build()?;
+        assert_eq!(
+            desc.annotations().as_ref().unwrap()[TOC_DIGEST_ANNOTATION],
+            layer.toc_digest
+        );
+        assert_eq!(w.compute_diffid(&desc)?, layer.layer.diff_id());
+
+        // The whole layer is a tarball with the original entries and the TOC
+        let (_, r) = w.open_blob_decompressed(&desc)?;
+        let names = tar::Archive::new(r)
+            .entries()?
+            .map(|e| Ok(e?.path()?.to_string_lossy().into_owned()))
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(
+            names,
+            [
+                "usr/",
+                "usr/small",
+                "usr/big",
+                long_name.as_str(),
+                "usr/link",
+                TOC_TAR_NAME
+            ]
+        );
+
+        // Find the TOC via the footer
+        let mut blob = Vec::new();
+        w.read_blob(&desc)?.read_to_end(&mut blob)?;
+        let toc_offset = parse_footer(&blob[blob.len() - FOOTER_SIZE..])?;
+        let toc_member = flate2::read::GzDecoder::new(&blob[toc_offset as usize..]);
+        let mut archive = tar::Archive::new(toc_member);
+        let mut entry = archive.entries()?.next().unwrap()?;
+        assert_eq!(entry.path()?.to_str(), Some(TOC_TAR_NAME));
+        let mut toc = Vec::new();
+        entry.read_to_end(&mut toc)?;
+        assert_eq!(
+            format!("sha256:{}", hash::sha256_hex(&toc)?),
+            layer.toc_digest
+        );
+        let toc: serde_json::Value = serde_json::from_slice(&toc)?;
+        let entries = toc["entries"].as_array().unwrap();
+        let kinds: Vec<_> = entries
+            .iter()
+            .map(|e| e["type"].as_str().unwrap())
+            .collect();
+        assert_eq!(
+            kinds,
+            ["dir", "reg", "reg", "chunk", "chunk", "reg", "symlink"]
+        );
+        assert_eq!(entries[0]["name"], "usr");
+        assert_eq!(entries[5]["name"], long_name.as_str());
+        assert_eq!(entries[6]["linkName"], "small");
+        assert_eq!(entries[2]["size"], 3000);
+        assert_eq!(
+            entries[2]["digest"],
+            format!("sha256:{}", hash::sha256_hex(&big)?)
+        );
+
+        // Each chunk can be read independently from its offset
+        for (i, e) in entries[2..5].iter().enumerate() {
+            let offset = e["offset"].as_u64().unwrap();
+            let size = e["chunkSize"].as_u64().unwrap();
+            let mut chunk = Vec::new();
+            let mut r = std::io::Cursor::new(&blob);
+            r.seek(std::io::SeekFrom::Start(offset))?;
+            flate2::read::GzDecoder::new(r)
+                .take(size)
+                .read_to_end(&mut chunk)?;
+            let start = i * 1024;
+            assert_eq!(chunk, big[start..start + size as usize]);
+        }
+        Ok(())
+    }
+}
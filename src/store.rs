@@ -0,0 +1,733 @@
+//! Storage backends for blobs and layout metadata files.
+//!
+//! The default backend is a [`Dir`] which is the root of an OCI image layout;
+//! there is also an in-memory backend, see [`crate::OciDir::new_in_memory`].
+//! Other backends, such as object storage, can implement [`BlobStore`] and be
+//! used with [`crate::OciDir::with_store`].
+
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use cap_std::fs::Dir;
+use cap_std_ext::cap_std;
+use cap_std_ext::cap_tempfile;
+use cap_std_ext::dirext::CapStdExtDirExt;
+
+use crate::{parse_one_filename, BlobReader, BLOBS};
+
+/// The directory under `blobs/` holding partially written resumable blobs,
+/// which is not a digest algorithm.
+pub(crate) const STAGING_DIR: &str = "staging";
+/// The prefix of temporary files created by [`StagingDir`].
+pub(crate) const STAGED_TEMP_PREFIX: &str = ".tmp-blob-";
+
+/// A blob which is being written, and can be committed under its final digest.
+pub trait StagedBlob: Write + Send + Debug {
+    /// Make the blob visible under the provided `algorithm:encoded` digest.
+    fn commit(self: Box<Self>, digest: &str) -> Result<()>;
+}
+
+/// Storage for content-addressed blobs and the small set of named metadata
+/// files (`oci-layout`, `index.json`) at the root of a layout.
+///
+/// Digests are always passed in `algorithm:encoded` form; see [`split_digest`].
+///
+/// Operations which only make sense on disk, such as extraction with
+/// reflinks or resumable writes, are unavailable unless [`Self::as_dir`]
+/// returns a directory. A read-only store may return an error from the
+/// methods which modify it.
+pub trait BlobStore: Debug + Send + Sync {
+    /// Open the blob with the given digest, if it exists. Stores which are not
+    /// backed by files should stream content through [`BlobReader::Other`].
+    fn get(&self, digest: &str) -> Result<Option<BlobReader>>;
+    /// Begin writing a new blob.
+    fn put(&self) -> Result<Box<dyn StagedBlob + '_>>;
+    /// Returns true if a blob with the given digest exists.
+    fn has(&self, digest: &str) -> Result<bool>;
+    /// List the digests of all blobs.
+    fn list(&self) -> Result<Vec<String>>;
+    /// Remove a blob, returning true if it existed.
+    fn delete(&self, digest: &str) -> Result<bool>;
+    /// Read a metadata file at the root of the layout.
+    fn read_meta(&self, name: &str) -> Result<Option<Vec<u8>>>;
+    /// Atomically replace a metadata file at the root of the layout.
+    fn write_meta(&self, name: &str, contents: &[u8]) -> Result<()>;
+    /// Append to a metadata file at the root of the layout, creating it if needed.
+    fn append_meta(&self, name: &str, contents: &[u8]) -> Result<()>;
+    /// The underlying directory, for operations which only make sense on disk.
+    fn as_dir(&self) -> Option<&Dir> {
+        None
+    }
+    /// Returns true if this store refuses all modifications; operations which
+    /// write to [`Self::as_dir`] directly are then refused as well.
+    fn is_read_only(&self) -> bool {
+        false
+    }
+}
+
+/// Split a digest into its algorithm and encoded parts, validating that both are
+/// usable as a single path component.
+pub fn split_digest(digest: &str) -> Result<(&str, &str)> {
+    let (alg, encoded) = digest
+        .split_once(':')
+        .ok_or_else(|| anyhow!("Invalid digest {digest}"))?;
+    let alg = parse_one_filename(alg)?;
+    let encoded = parse_one_filename(encoded)?;
+    Ok((alg, encoded))
+}
+
+/// Returns true if blobs with this digest can be verified; only sha256 is
+/// supported, but layouts written by other tools may contain other algorithms.
+pub(crate) fn is_supported_digest(digest: &str) -> bool {
+    digest.starts_with("sha256:")
+}
+
+/// The error returned when reading a blob whose digest algorithm is not
+/// supported; it can be retrieved with [`anyhow::Error::downcast_ref`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnsupportedDigest {
+    /// The full digest of the blob.
+    pub digest: String,
+}
+
+impl std::fmt::Display for UnsupportedDigest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Unsupported digest algorithm {}", self.digest)
+    }
+}
+
+impl std::error::Error for UnsupportedDigest {}
+
+/// The path to a blob relative to the layout root.
+pub(crate) fn blob_path(digest: &str) -> Result<Utf8PathBuf> {
+    let (alg, encoded) = split_digest(digest)?;
+    Ok(Utf8Path::new(BLOBS).join(alg).join(encoded))
+}
+
+/// The path to a blob in a sharded layout, such as `blobs/sha256/ab/abcdef…`;
+/// see [`ShardedDir`]. Blobs whose encoded digest is too short to shard use the
+/// flat path.
+pub(crate) fn sharded_blob_path(digest: &str) -> Result<Utf8PathBuf> {
+    let (alg, encoded) = split_digest(digest)?;
+    let Some(prefix) = encoded.get(..2).filter(|p| p.len() < encoded.len()) else {
+        return blob_path(digest);
+    };
+    Ok(Utf8Path::new(BLOBS).join(alg).join(prefix).join(encoded))
+}
+
+/// The path at which a blob is stored, in either the flat or sharded form.
+pub(crate) fn find_blob_path(dir: &Dir, digest: &str) -> Result<Option<Utf8PathBuf>> {
+    let flat = blob_path(digest)?;
+    if dir.try_exists(&flat)? {
+        return Ok(Some(flat));
+    }
+    let sharded = sharded_blob_path(digest)?;
+    if sharded != flat && dir.try_exists(&sharded)? {
+        return Ok(Some(sharded));
+    }
+    Ok(None)
+}
+
+/// The path for a new blob, in the sharded form if `sharded` is set.
+pub(crate) fn new_blob_path(digest: &str, sharded: bool) -> Result<Utf8PathBuf> {
+    if sharded {
+        sharded_blob_path(digest)
+    } else {
+        blob_path(digest)
+    }
+}
+
+#[derive(Debug)]
+struct DirStagedBlob<'a> {
+    dir: &'a Dir,
+    tmpf: cap_tempfile::TempFile<'a>,
+    sharded: bool,
+}
+
+impl<'a> Write for DirStagedBlob<'a> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.tmpf.as_file_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.tmpf.as_file_mut().flush()
+    }
+}
+
+/// Create the directory for a blob path.
+pub(crate) fn ensure_blob_parent(dir: &Dir, path: &Utf8Path) -> Result<()> {
+    // The sha256 directory is created by `ensure`, but other algorithms may not exist yet.
+    if let Some(parent) = path.parent() {
+        let db = crate::dir_builder();
+        dir.ensure_dir_with(parent, &db)?;
+    }
+    Ok(())
+}
+
+impl<'a> StagedBlob for DirStagedBlob<'a> {
+    fn commit(self: Box<Self>, digest: &str) -> Result<()> {
+        let path = new_blob_path(digest, self.sharded)?;
+        ensure_blob_parent(self.dir, &path)?;
+        // Another writer may have completed the same blob concurrently; since
+        // blobs are content addressed, its copy is as good as ours.
+        match self.tmpf.replace(&path) {
+            Err(_) if self.dir.try_exists(&path)? => Ok(()),
+            r => Ok(r?),
+        }
+    }
+}
+
+fn put_dir(dir: &Dir, sharded: bool) -> Result<Box<dyn StagedBlob + '_>> {
+    Ok(Box::new(DirStagedBlob {
+        dir,
+        tmpf: cap_tempfile::TempFile::new(dir)?,
+        sharded,
+    }))
+}
+
+/// Blobs are read from both the flat and the sharded form, see [`ShardedDir`];
+/// new blobs are stored flat.
+impl BlobStore for Dir {
+    fn get(&self, digest: &str) -> Result<Option<BlobReader>> {
+        let Some(path) = find_blob_path(self, digest)? else {
+            return Ok(None);
+        };
+        Ok(self
+            .open_optional(path)?
+            .map(|f| BlobReader::File(f.into_std())))
+    }
+
+    fn put(&self) -> Result<Box<dyn StagedBlob + '_>> {
+        put_dir(self, false)
+    }
+
+    fn has(&self, digest: &str) -> Result<bool> {
+        Ok(find_blob_path(self, digest)?.is_some())
+    }
+
+    fn list(&self) -> Result<Vec<String>> {
+        let mut r = Vec::new();
+        let Some(blobs) = self.open_dir_optional(BLOBS)? else {
+            return Ok(r);
+        };
+        for algdir in blobs.entries()? {
+            let algdir = algdir?;
+            if !algdir.file_type()?.is_dir() {
+                continue;
+            }
+            let Some(alg) = algdir.file_name().to_str().map(ToOwned::to_owned) else {
+                continue;
+            };
+            if alg == STAGING_DIR {
+                continue;
+            }
+            list_blobs(&algdir.open_dir()?, &alg, true, &mut r)?;
+        }
+        r.sort();
+        r.dedup();
+        Ok(r)
+    }
+
+    fn delete(&self, digest: &str) -> Result<bool> {
+        let mut found = false;
+        for path in [blob_path(digest)?, sharded_blob_path(digest)?] {
+            found |= self.remove_file_optional(path)?;
+        }
+        Ok(found)
+    }
+
+    fn read_meta(&self, name: &str) -> Result<Option<Vec<u8>>> {
+        Ok(self.read_optional(parse_one_filename(name)?)?)
+    }
+
+    fn write_meta(&self, name: &str, contents: &[u8]) -> Result<()> {
+        crate::atomic_write(self, parse_one_filename(name)?, contents)
+    }
+
+    fn append_meta(&self, name: &str, contents: &[u8]) -> Result<()> {
+        let mut opts = cap_std::fs::OpenOptions::new();
+        opts.append(true).create(true);
+        let mut f = self.open_with(parse_one_filename(name)?, &opts)?;
+        f.write_all(contents)?;
+        Ok(())
+    }
+
+    fn as_dir(&self) -> Option<&Dir> {
+        Some(self)
+    }
+}
+
+/// A layout directory whose new blobs are written to temporary files in a
+/// separate staging directory, rather than the layout root; see
+/// [`crate::OciDirOptions::staging_dir`].
+#[derive(Debug)]
+pub struct StagingDir {
+    dir: Dir,
+    staging: Dir,
+    sharded: bool,
+}
+
+impl StagingDir {
+    /// Stage new blobs of the layout `dir` in `staging`, a path relative to the
+    /// layout which is created if necessary. It must be on the same filesystem
+    /// as the blob directories, since blobs are renamed into place.
+    pub fn new(dir: Dir, staging: &Utf8Path) -> Result<Self> {
+        let db = crate::dir_builder();
+        dir.ensure_dir_with(staging, &db)?;
+        let staging = dir.open_dir(staging)?;
+        Ok(Self {
+            dir,
+            staging,
+            sharded: false,
+        })
+    }
+
+    /// Store new blobs in shard subdirectories, as [`ShardedDir`] does.
+    pub fn sharded(mut self, sharded: bool) -> Self {
+        self.sharded = sharded;
+        self
+    }
+}
+
+/// A unique name for a temporary file.
+fn temp_name() -> String {
+    use std::hash::{BuildHasher, Hasher};
+    let mut h = std::collections::hash_map::RandomState::new().build_hasher();
+    h.write_u32(std::process::id());
+    format!("{STAGED_TEMP_PREFIX}{:016x}", h.finish())
+}
+
+/// Add the digests of the blobs in the directory of an algorithm to `r`,
+/// including those in two-character shard subdirectories.
+fn list_blobs(dir: &Dir, alg: &str, shards: bool, r: &mut Vec<String>) -> Result<()> {
+    for ent in dir.entries()? {
+        let ent = ent?;
+        let Some(name) = ent.file_name().to_str().map(ToOwned::to_owned) else {
+            continue;
+        };
+        // Hidden files, such as temporary files, are never blobs.
+        if name.starts_with('.') {
+            continue;
+        }
+        let file_type = ent.file_type()?;
+        if file_type.is_file() {
+            r.push(format!("{alg}:{name}"));
+        } else if shards && file_type.is_dir() && name.len() == 2 {
+            let mut sharded = Vec::new();
+            list_blobs(&ent.open_dir()?, alg, false, &mut sharded)?;
+            r.extend(
+                sharded
+                    .into_iter()
+                    .filter(|d| d.split_once(':').is_some_and(|(_, e)| e.starts_with(&name))),
+            );
+        }
+    }
+    Ok(())
+}
+
+/// A layout directory whose new blobs are stored in two-character prefix
+/// subdirectories, such as `blobs/sha256/ab/abcdef…`, which keeps directories
+/// small for filesystems which are slow with many entries; see
+/// [`crate::OciDirOptions::shard_blobs`].
+///
+/// This is not part of the OCI image layout specification, so other tools cannot
+/// read such layouts; [`crate::OciDir::migrate_to_flat`] converts them back. All
+/// directory-backed stores read both forms.
+#[derive(Debug)]
+pub struct ShardedDir(Dir);
+
+impl ShardedDir {
+    /// Wrap the provided layout directory.
+    pub fn new(dir: Dir) -> Self {
+        Self(dir)
+    }
+}
+
+impl BlobStore for ShardedDir {
+    fn get(&self, digest: &str) -> Result<Option<BlobReader>> {
+        self.0.get(digest)
+    }
+
+    fn put(&self) -> Result<Box<dyn StagedBlob + '_>> {
+        put_dir(&self.0, true)
+    }
+
+    fn has(&self, digest: &str) -> Result<bool> {
+        self.0.has(digest)
+    }
+
+    fn list(&self) -> Result<Vec<String>> {
+        self.0.list()
+    }
+
+    fn delete(&self, digest: &str) -> Result<bool> {
+        self.0.delete(digest)
+    }
+
+    fn read_meta(&self, name: &str) -> Result<Option<Vec<u8>>> {
+        self.0.read_meta(name)
+    }
+
+    fn write_meta(&self, name: &str, contents: &[u8]) -> Result<()> {
+        self.0.write_meta(name, contents)
+    }
+
+    fn append_meta(&self, name: &str, contents: &[u8]) -> Result<()> {
+        self.0.append_meta(name, contents)
+    }
+
+    fn as_dir(&self) -> Option<&Dir> {
+        Some(&self.0)
+    }
+}
+
+#[derive(Debug)]
+struct NamedStagedBlob<'a> {
+    dir: &'a Dir,
+    staging: &'a Dir,
+    name: String,
+    file: cap_std::fs::File,
+    committed: bool,
+    sharded: bool,
+}
+
+impl<'a> Write for NamedStagedBlob<'a> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl<'a> StagedBlob for NamedStagedBlob<'a> {
+    fn commit(mut self: Box<Self>, digest: &str) -> Result<()> {
+        let path = new_blob_path(digest, self.sharded)?;
+        ensure_blob_parent(self.dir, &path)?;
+        self.staging.rename(&self.name, self.dir, &path)?;
+        self.committed = true;
+        Ok(())
+    }
+}
+
+impl<'a> Drop for NamedStagedBlob<'a> {
+    fn drop(&mut self) {
+        if !self.committed {
+            let _ = self.staging.remove_file(&self.name);
+        }
+    }
+}
+
+impl BlobStore for StagingDir {
+    fn get(&self, digest: &str) -> Result<Option<BlobReader>> {
+        self.dir.get(digest)
+    }
+
+    fn put(&self) -> Result<Box<dyn StagedBlob + '_>> {
+        let mut opts = cap_std::fs::OpenOptions::new();
+        opts.write(true).create_new(true);
+        loop {
+            let name = temp_name();
+            match self.staging.open_with(&name, &opts) {
+                Ok(file) => {
+                    return Ok(Box::new(NamedStagedBlob {
+                        dir: &self.dir,
+                        staging: &self.staging,
+                        name,
+                        file,
+                        committed: false,
+                        sharded: self.sharded,
+                    }))
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    fn has(&self, digest: &str) -> Result<bool> {
+        self.dir.has(digest)
+    }
+
+    fn list(&self) -> Result<Vec<String>> {
+        self.dir.list()
+    }
+
+    fn delete(&self, digest: &str) -> Result<bool> {
+        self.dir.delete(digest)
+    }
+
+    fn read_meta(&self, name: &str) -> Result<Option<Vec<u8>>> {
+        self.dir.read_meta(name)
+    }
+
+    fn write_meta(&self, name: &str, contents: &[u8]) -> Result<()> {
+        self.dir.write_meta(name, contents)
+    }
+
+    fn append_meta(&self, name: &str, contents: &[u8]) -> Result<()> {
+        self.dir.append_meta(name, contents)
+    }
+
+    fn as_dir(&self) -> Option<&Dir> {
+        Some(&self.dir)
+    }
+}
+
+/// A layout directory which is never modified, such as one on read-only media.
+///
+/// All modifications fail with an error instead of attempting to create
+/// temporary files; see [`crate::OciDir::open_readonly`].
+#[derive(Debug)]
+pub struct ReadOnlyDir(Dir);
+
+impl ReadOnlyDir {
+    /// Wrap the provided layout directory.
+    pub fn new(dir: Dir) -> Self {
+        Self(dir)
+    }
+}
+
+fn read_only_error() -> anyhow::Error {
+    anyhow!("Layout is read-only")
+}
+
+impl BlobStore for ReadOnlyDir {
+    fn get(&self, digest: &str) -> Result<Option<BlobReader>> {
+        self.0.get(digest)
+    }
+
+    fn put(&self) -> Result<Box<dyn StagedBlob + '_>> {
+        Err(read_only_error())
+    }
+
+    fn has(&self, digest: &str) -> Result<bool> {
+        self.0.has(digest)
+    }
+
+    fn list(&self) -> Result<Vec<String>> {
+        self.0.list()
+    }
+
+    fn delete(&self, _digest: &str) -> Result<bool> {
+        Err(read_only_error())
+    }
+
+    fn read_meta(&self, name: &str) -> Result<Option<Vec<u8>>> {
+        self.0.read_meta(name)
+    }
+
+    fn write_meta(&self, _name: &str, _contents: &[u8]) -> Result<()> {
+        Err(read_only_error())
+    }
+
+    fn append_meta(&self, _name: &str, _contents: &[u8]) -> Result<()> {
+        Err(read_only_error())
+    }
+
+    fn as_dir(&self) -> Option<&Dir> {
+        Some(&self.0)
+    }
+
+    fn is_read_only(&self) -> bool {
+        true
+    }
+}
+
+/// An in-memory blob store.
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    blobs: Mutex<BTreeMap<String, Arc<[u8]>>>,
+    meta: Mutex<BTreeMap<String, Vec<u8>>>,
+}
+
+#[derive(Debug)]
+struct MemoryStagedBlob<'a> {
+    store: &'a MemoryStore,
+    buf: Vec<u8>,
+}
+
+impl<'a> Write for MemoryStagedBlob<'a> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.buf.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<'a> StagedBlob for MemoryStagedBlob<'a> {
+    fn commit(self: Box<Self>, digest: &str) -> Result<()> {
+        split_digest(digest)?;
+        self.store
+            .blobs
+            .lock()
+            .unwrap()
+            .insert(digest.to_owned(), self.buf.into());
+        Ok(())
+    }
+}
+
+impl BlobStore for MemoryStore {
+    fn get(&self, digest: &str) -> Result<Option<BlobReader>> {
+        let blobs = self.blobs.lock().unwrap();
+        Ok(blobs
+            .get(digest)
+            .map(|b| BlobReader::Memory(std::io::Cursor::new(Arc::clone(b)))))
+    }
+
+    fn put(&self) -> Result<Box<dyn StagedBlob + '_>> {
+        Ok(Box::new(MemoryStagedBlob {
+            store: self,
+            buf: Vec::new(),
+        }))
+    }
+
+    fn has(&self, digest: &str) -> Result<bool> {
+        Ok(self.blobs.lock().unwrap().contains_key(digest))
+    }
+
+    fn list(&self) -> Result<Vec<String>> {
+        Ok(self.blobs.lock().unwrap().keys().cloned().collect())
+    }
+
+    fn delete(&self, digest: &str) -> Result<bool> {
+        Ok(self.blobs.lock().unwrap().remove(digest).is_some())
+    }
+
+    fn read_meta(&self, name: &str) -> Result<Option<Vec<u8>>> {
+        Ok(self.meta.lock().unwrap().get(name).cloned())
+    }
+
+    fn write_meta(&self, name: &str, contents: &[u8]) -> Result<()> {
+        self.meta
+            .lock()
+            .unwrap()
+            .insert(name.to_owned(), contents.to_vec());
+        Ok(())
+    }
+
+    fn append_meta(&self, name: &str, contents: &[u8]) -> Result<()> {
+        self.meta
+            .lock()
+            .unwrap()
+            .entry(name.to_owned())
+            .or_default()
+            .extend_from_slice(contents);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use crate::OciDir;
+
+    /// A store which counts writes, delegating to memory and streaming reads.
+    #[derive(Debug, Default)]
+    struct CountingStore {
+        inner: MemoryStore,
+        puts: AtomicUsize,
+    }
+
+    impl BlobStore for CountingStore {
+        fn get(&self, digest: &str) -> Result<Option<BlobReader>> {
+            Ok(self
+                .inner
+                .get(digest)?
+                .map(|r| BlobReader::Other(Box::new(std::io::BufReader::new(r)))))
+        }
+        fn put(&self) -> Result<Box<dyn StagedBlob + '_>> {
+            self.puts.fetch_add(1, Ordering::SeqCst);
+            self.inner.put()
+        }
+        fn has(&self, digest: &str) -> Result<bool> {
+            self.inner.has(digest)
+        }
+        fn list(&self) -> Result<Vec<String>> {
+            self.inner.list()
+        }
+        fn delete(&self, digest: &str) -> Result<bool> {
+            self.inner.delete(digest)
+        }
+        fn read_meta(&self, name: &str) -> Result<Option<Vec<u8>>> {
+            self.inner.read_meta(name)
+        }
+        fn write_meta(&self, name: &str, contents: &[u8]) -> Result<()> {
+            self.inner.write_meta(name, contents)
+        }
+        fn append_meta(&self, name: &str, contents: &[u8]) -> Result<()> {
+            self.inner.append_meta(name, contents)
+        }
+    }
+
+    #[test]
+    fn custom_store() -> Result<()> {
+        let store = Arc::new(CountingStore::default());
+        store.write_meta("oci-layout", crate::OCI_LAYOUT_DEFAULT.as_bytes())?;
+        let w = OciDir::with_store(store.clone(), &Default::default())?;
+        assert!(w.dir().is_none());
+        let mut layer = w.create_gzip_layer(None)?;
+        layer.write_all(b"content")?;
+        let layer = layer.complete()?;
+        let manifest = crate::new_empty_manifest().build().unwrap();
+        w.insert_manifest(manifest, Some("latest"), Default::default())?;
+        assert_eq!(store.puts.load(Ordering::SeqCst), 2);
+        assert!(w.store().has(&layer.blob.digest_id())?);
+        assert!(w.find_manifest_with_tag("latest")?.is_some());
+        let r = w.read_blob(&layer.descriptor().build()?)?;
+        assert!(matches!(r, BlobReader::Other(_)));
+        assert_eq!(
+            std::io::read_to_string(flate2::read::GzDecoder::new(r))?,
+            "content"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn staging_dir() -> Result<()> {
+        use cap_std_ext::cap_tempfile;
+        use std::time::Duration;
+
+        let td = cap_tempfile::tempdir(cap_std::ambient_authority())?;
+        let opts = crate::OciDirOptions {
+            staging_dir: Some("blobs/tmp".into()),
+            ..Default::default()
+        };
+        let w = OciDir::ensure_with(&td, &opts)?;
+        let staged = || -> Result<Vec<String>> {
+            td.open_dir("blobs/tmp")?
+                .entries()?
+                .map(|e| Ok(e?.file_name().to_string_lossy().into_owned()))
+                .collect()
+        };
+        let mut layer = w.create_gzip_layer(None)?;
+        layer.write_all(b"content")?;
+        let names = staged()?;
+        assert_eq!(names.len(), 1);
+        assert!(names[0].starts_with(STAGED_TEMP_PREFIX));
+        assert_eq!(td.entries()?.count(), 2);
+        let layer = layer.complete()?;
+        assert!(staged()?.is_empty());
+        assert!(w.store().has(&layer.blob.digest_id())?);
+        assert_eq!(w.store().list()?, [layer.blob.digest_id()]);
+
+        // Dropped writers clean up, leaked ones are recovered
+        drop(w.create_gzip_layer(None)?);
+        assert!(staged()?.is_empty());
+        std::mem::forget(w.create_gzip_layer(None)?);
+        let name = staged()?.remove(0);
+        assert_eq!(w.recover(Duration::ZERO)?, [format!("blobs/tmp/{name}")]);
+        assert!(staged()?.is_empty());
+        w.fsck()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,237 @@
+//! Incremental synchronization of images between layouts.
+
+use std::collections::BTreeSet;
+
+use anyhow::Result;
+use fn_error_context::context;
+use oci_spec::image::{self as oci_image, Descriptor};
+
+use crate::{OciDir, OCI_TAG_ANNOTATION};
+
+/// What [`OciDir::sync_from`] does when a tag refers to different manifests
+/// in the two layouts.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum SyncConflict {
+    /// Fail before anything is copied.
+    #[default]
+    Error,
+    /// Keep the local entry.
+    Ours,
+    /// Replace the local entry with the one from the source.
+    Theirs,
+}
+
+/// Options for [`OciDir::sync_from`].
+#[derive(Debug, Clone, Default)]
+pub struct SyncOptions {
+    /// How tag collisions are resolved.
+    pub conflict: SyncConflict,
+}
+
+/// The result of [`OciDir::sync_from`].
+#[derive(Debug, Clone, Default)]
+#[non_exhaustive]
+pub struct SyncReport {
+    /// The digests of the blobs which were copied.
+    pub copied: Vec<String>,
+    /// The total size of the copied blobs.
+    pub copied_bytes: u64,
+    /// The number of needed blobs which were already present.
+    pub existing: u64,
+    /// The index entries which were added.
+    pub added: Vec<Descriptor>,
+    /// Tags which referred to different manifests, whether or not they were replaced.
+    pub conflicts: Vec<String>,
+}
+
+fn tag_of(desc: &Descriptor) -> Option<&str> {
+    desc.annotations()
+        .as_ref()
+        .and_then(|a| a.get(OCI_TAG_ANNOTATION))
+        .map(|s| s.as_str())
+}
+
+impl OciDir {
+    /// Copy the index entries of `src` into this layout along with the blobs they
+    /// reference, skipping blobs which are already present by digest.
+    ///
+    /// Copied blobs are verified against their digest and size before being added.
+    /// Entries which are already present with the same tag and digest are left
+    /// alone, and tag collisions are resolved according to [`SyncOptions::conflict`].
+    #[context("Syncing layout")]
+    pub fn sync_from(&self, src: &OciDir, opts: &SyncOptions) -> Result<SyncReport> {
+        let mut r = SyncReport::default();
+        let _lock = self.lock_index()?;
+        let index = self.read_index()?;
+        let mut manifests = index
+            .as_ref()
+            .map(|i| i.manifests().clone())
+            .unwrap_or_default();
+        let theirs = src
+            .read_index()?
+            .map(|i| i.manifests().clone())
+            .unwrap_or_default();
+
+        let mut add = Vec::new();
+        for desc in theirs {
+            let Some(tag) = tag_of(&desc) else {
+                if !manifests.iter().any(|d| d.digest() == desc.digest()) {
+                    add.push(desc);
+                }
+                continue;
+            };
+            match manifests.iter().find(|d| tag_of(d) == Some(tag)) {
+                Some(ours) if ours.digest() == desc.digest() => {}
+                Some(ours) => {
+                    if opts.conflict == SyncConflict::Error {
+                        anyhow::bail!(
+                            "Tag {tag} refers to {} locally but {} in the source",
+                            ours.digest(),
+                            desc.digest()
+                        );
+                    }
+                    r.conflicts.push(tag.to_owned());
+                    if opts.conflict == SyncConflict::Theirs {
+                        add.push(desc);
+                    }
+                }
+                None => add.push(desc),
+            }
+        }
+
+        let mut needed = BTreeSet::new();
+        for desc in &add {
+            src.collect_reachable(desc, &mut needed)?;
+        }
+        let dirs = src.store.as_dir().zip(self.writable_dir().ok().flatten());
+        for digest in needed {
+            if self.store.has(&digest)? {
+                r.existing += 1;
+                continue;
+            }
+            let (mut f, size) = src.open_blob_sized(&digest)?;
+            if let Some((srcdir, destdir)) = dirs {
+                if crate::clone::copy_blob_fast(
+                    srcdir,
+                    destdir,
+                    &digest,
+                    self.opts.shard_blobs,
+                    true,
+                )? {
+                    self.blob_written_directly(&digest)?;
+                    r.copied.push(digest);
+                    r.copied_bytes += size;
+                    continue;
+                }
+            }
+            let mut w = self.create_blob_with_expected(&digest, size)?;
+            std::io::copy(&mut f, &mut w)?;
+            w.complete()?;
+            r.copied.push(digest);
+            r.copied_bytes += size;
+        }
+
+        if add.is_empty() {
+            return Ok(r);
+        }
+        for desc in &add {
+            if let Some(tag) = tag_of(desc) {
+                manifests.retain(|d| tag_of(d) != Some(tag));
+            }
+        }
+        manifests.extend(add.iter().cloned());
+        let mut index = match index {
+            Some(index) => index,
+            None => oci_image::ImageIndexBuilder::default()
+                .schema_version(oci_image::SCHEMA_VERSION)
+                .manifests(Vec::new())
+                .build()?,
+        };
+        index.set_manifests(manifests);
+        self.write_index(&index, "sync", None, None)?;
+        r.added = add;
+        Ok(r)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cap_std_ext::{cap_std, cap_tempfile};
+    use std::io::Write;
+
+    fn push_image(w: &OciDir, contents: &[u8], tag: &str) -> Result<Descriptor> {
+        let mut manifest = crate::new_empty_manifest().build()?;
+        let mut config = oci_image::ImageConfigurationBuilder::default().build()?;
+        let mut layer = w.create_gzip_layer(None)?;
+        layer.write_all(contents)?;
+        let layer = layer.complete()?;
+        w.push_layer(&mut manifest, &mut config, layer, "layer", None);
+        w.insert_manifest_and_config(manifest, config, Some(tag), Default::default())
+    }
+
+    #[test]
+    fn sync() -> Result<()> {
+        let ours = OciDir::new_in_memory()?;
+        let td = cap_tempfile::tempdir(cap_std::ambient_authority())?;
+        let theirs = OciDir::ensure(&td)?;
+        for w in [&ours, &theirs] {
+            push_image(w, b"shared", "shared")?;
+        }
+        let mine = push_image(&ours, b"mine", "conflict")?;
+        let other = push_image(&theirs, b"other", "conflict")?;
+        let new = push_image(&theirs, b"new", "new")?;
+
+        let err = ours.sync_from(&theirs, &Default::default()).unwrap_err();
+        assert!(format!("{err:#}").contains("conflict"), "{err:#}");
+        assert_eq!(ours.manifests()?.len(), 2);
+
+        let opts = SyncOptions {
+            conflict: SyncConflict::Ours,
+        };
+        let r = ours.sync_from(&theirs, &opts)?;
+        assert_eq!(r.added, std::slice::from_ref(&new));
+        assert_eq!(r.conflicts, ["conflict"]);
+        // The manifest, config and layer
+        assert_eq!(r.copied.len(), 3);
+        assert_eq!(r.existing, 0);
+        assert_eq!(ours.resolve("conflict")?, mine);
+        ours.fsck()?;
+
+        // Nothing left to do
+        let r = ours.sync_from(&theirs, &opts)?;
+        assert!(r.added.is_empty() && r.copied.is_empty());
+
+        let opts = SyncOptions {
+            conflict: SyncConflict::Theirs,
+        };
+        let r = ours.sync_from(&theirs, &opts)?;
+        assert_eq!(r.added, std::slice::from_ref(&other));
+        assert_eq!(ours.resolve("conflict")?, other);
+        assert_eq!(ours.manifests()?.len(), 3);
+        ours.fsck()?;
+        // Blobs referenced by a new tag for a known manifest are not copied again
+        let shared = theirs.resolve("shared")?;
+        let mut tagged = shared.clone();
+        tagged.set_annotations(Some(
+            [(OCI_TAG_ANNOTATION.to_owned(), "alias".to_owned())].into(),
+        ));
+        theirs.insert_descriptor(tagged, Some("alias"))?;
+        let r = ours.sync_from(&theirs, &opts)?;
+        assert_eq!(r.added.len(), 1);
+        assert!(r.copied.is_empty());
+        assert_eq!(r.existing, 3);
+
+        // Corrupted source blobs are not copied
+        let target = OciDir::new_in_memory()?;
+        let layer: oci_image::ImageManifest = theirs.read_json_blob(&new)?;
+        let layer = &layer.layers()[0];
+        let path = crate::store::blob_path(layer.digest())?;
+        td.remove_file(&path)?;
+        td.write(&path, b"corrupted")?;
+        assert!(target.sync_from(&theirs, &Default::default()).is_err());
+        assert!(!target.store.has(layer.digest())?);
+        Ok(())
+    }
+}
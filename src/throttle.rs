@@ -0,0 +1,79 @@
+//! Bandwidth limiting for blob I/O.
+
+use std::num::NonZeroU64;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::OciDir;
+
+/// Transfers may run ahead of the limit by this much before blocking, so
+/// that small reads and writes do not each sleep.
+const BURST: Duration = Duration::from_millis(100);
+
+/// A limiter shared by all operations on a layout handle.
+#[derive(Debug)]
+pub(crate) struct RateLimiter {
+    bytes_per_sec: NonZeroU64,
+    /// The time at which all bytes consumed so far are within the limit.
+    next: Mutex<Option<Instant>>,
+}
+
+impl RateLimiter {
+    fn new(bytes_per_sec: NonZeroU64) -> Self {
+        Self {
+            bytes_per_sec,
+            next: Mutex::new(None),
+        }
+    }
+
+    /// Account for `n` transferred bytes, sleeping if the limit was exceeded.
+    pub(crate) fn consume(&self, n: u64) {
+        let delay = {
+            let mut next = self.next.lock().unwrap();
+            let now = Instant::now();
+            let start = next.filter(|t| *t > now).unwrap_or(now);
+            let cost = Duration::from_secs_f64(n as f64 / self.bytes_per_sec.get() as f64);
+            let t = start + cost;
+            *next = Some(t);
+            t.saturating_duration_since(now)
+        };
+        if delay > BURST {
+            std::thread::sleep(delay - BURST);
+        }
+    }
+}
+
+impl OciDir {
+    /// Return a handle to this layout whose blob reads and writes are limited to
+    /// `bytes_per_sec` in total, shared by all operations on the returned handle and
+    /// its clones. This covers writers created from it as well as copying, cloning
+    /// and verification.
+    ///
+    /// Blobs shared via reflinks or hard links when cloning do not count toward the limit.
+    pub fn with_rate_limit(&self, bytes_per_sec: NonZeroU64) -> Self {
+        Self {
+            progress: self.progress.with_limit(RateLimiter::new(bytes_per_sec)),
+            ..self.clone()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use std::io::Write;
+
+    #[test]
+    fn rate_limit() -> Result<()> {
+        let w = OciDir::new_in_memory()?.with_rate_limit(NonZeroU64::new(1_000_000).unwrap());
+        let start = Instant::now();
+        let mut layerw = w.create_uncompressed_layer()?;
+        for _ in 0..30 {
+            layerw.write_all(&[0u8; 10_000])?;
+        }
+        layerw.complete()?;
+        assert!(start.elapsed() >= Duration::from_millis(150));
+        Ok(())
+    }
+}
@@ -0,0 +1,611 @@
+//! Checking layouts against the OCI image specification.
+
+use std::collections::BTreeSet;
+use std::io::Read;
+
+use anyhow::Result;
+use fn_error_context::context;
+use oci_spec::image::{
+    Arch, Descriptor, ImageConfiguration, ImageIndex, ImageManifest, MediaType, Os, Platform,
+};
+
+use crate::layout::{parse_layout, OCI_LAYOUT_FILE};
+use crate::{CompressionFormat, OciDir};
+
+/// How thoroughly [`OciDir::validate`] checks a layout.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+#[non_exhaustive]
+pub enum ValidationLevel {
+    /// The required files, JSON structure, media types, descriptor fields and
+    /// the presence and size of all referenced blobs.
+    #[default]
+    Basic,
+    /// Additionally annotation keys, platform values, consistency between
+    /// index entries, manifests and configs, and the digests of all blobs.
+    Strict,
+}
+
+/// The kind of a [`Violation`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[non_exhaustive]
+pub enum ViolationKind {
+    /// A required file is missing.
+    MissingFile,
+    /// A file or blob is not valid JSON of the expected structure.
+    InvalidJson,
+    /// A descriptor has an invalid digest or size.
+    InvalidDescriptor,
+    /// A media type is malformed or not allowed in its position.
+    InvalidMediaType,
+    /// A referenced blob is missing.
+    MissingBlob,
+    /// A blob does not match the size or digest of its descriptor.
+    BlobMismatch,
+    /// An annotation key does not follow the specification.
+    InvalidAnnotation,
+    /// A platform has an unknown operating system or architecture.
+    InvalidPlatform,
+    /// Related objects disagree, such as a manifest and its config.
+    Inconsistent,
+    /// A JSON blob exceeds the [`crate::OciDirOptions::json_limits`] for its media type.
+    TooLarge,
+}
+
+/// A problem found by [`OciDir::validate`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Violation {
+    /// The kind of problem.
+    pub kind: ViolationKind,
+    /// Where the problem was found, such as `index.json/manifests/0/config`.
+    pub location: String,
+    /// A description of the problem.
+    pub message: String,
+}
+
+impl std::fmt::Display for Violation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.location, self.message)
+    }
+}
+
+/// Annotations the specification defines in the reserved `org.opencontainers` namespace.
+const RESERVED_ANNOTATIONS: &[&str] = &[
+    "org.opencontainers.image.created",
+    "org.opencontainers.image.authors",
+    "org.opencontainers.image.url",
+    "org.opencontainers.image.documentation",
+    "org.opencontainers.image.source",
+    "org.opencontainers.image.version",
+    "org.opencontainers.image.revision",
+    "org.opencontainers.image.vendor",
+    "org.opencontainers.image.licenses",
+    "org.opencontainers.image.ref.name",
+    "org.opencontainers.image.title",
+    "org.opencontainers.image.description",
+    "org.opencontainers.image.base.digest",
+    "org.opencontainers.image.base.name",
+];
+/// Annotations defined by the ocicrypt specification.
+const ENCRYPTION_ANNOTATION_PREFIX: &str = "org.opencontainers.image.enc.";
+const RESERVED_PREFIX: &str = "org.opencontainers.";
+const EMPTY_MEDIA_TYPE: &str = "application/vnd.oci.empty.v1+json";
+/// The media type suffix of encrypted layers, see the `encrypt` feature.
+const ENCRYPTED_SUFFIX: &str = "+encrypted";
+
+/// A restricted name per RFC 6838.
+fn valid_restricted_name(s: &str) -> bool {
+    s.len() <= 127
+        && s.chars().next().is_some_and(|c| c.is_ascii_alphanumeric())
+        && s.chars()
+            .all(|c| c.is_ascii_alphanumeric() || "!#$&-^_.+".contains(c))
+}
+
+fn valid_media_type(s: &str) -> bool {
+    s.split_once('/')
+        .is_some_and(|(t, sub)| valid_restricted_name(t) && valid_restricted_name(sub))
+}
+
+/// Check a digest against the grammar of the specification, with the
+/// registered algorithms checked more precisely.
+fn valid_digest(s: &str) -> bool {
+    let Some((alg, encoded)) = s.split_once(':') else {
+        return false;
+    };
+    let alg_ok = alg.split(['+', '.', '_', '-']).all(|c| {
+        !c.is_empty()
+            && c.bytes()
+                .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit())
+    });
+    let hex_len = match alg {
+        "sha256" => Some(64),
+        "sha512" => Some(128),
+        _ => None,
+    };
+    let encoded_ok = match hex_len {
+        Some(len) => {
+            encoded.len() == len
+                && encoded
+                    .bytes()
+                    .all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+        }
+        None => {
+            !encoded.is_empty()
+                && encoded
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b"=_-".contains(&b))
+        }
+    };
+    alg_ok && encoded_ok
+}
+
+/// Annotation keys should use reverse domain notation, and keys in the reserved
+/// namespace must be defined by the specification.
+fn annotation_key_problem(key: &str) -> Option<String> {
+    if key.starts_with(RESERVED_PREFIX) {
+        if RESERVED_ANNOTATIONS.contains(&key) || key.starts_with(ENCRYPTION_ANNOTATION_PREFIX) {
+            return None;
+        }
+        return Some(format!("Unknown key {key:?} in the reserved namespace"));
+    }
+    let domain = key.split(['/', '_']).next().unwrap_or_default();
+    let labels: Vec<_> = domain.split('.').collect();
+    let valid = labels.len() >= 2
+        && labels
+            .iter()
+            .all(|l| !l.is_empty() && l.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-'))
+        && key.bytes().all(|b| b.is_ascii_graphic());
+    (!valid).then(|| format!("Key {key:?} does not use reverse domain notation"))
+}
+
+fn is_layer_media_type(media_type: &MediaType) -> bool {
+    CompressionFormat::from_media_type(media_type).is_some()
+        || media_type.to_string().ends_with(ENCRYPTED_SUFFIX)
+}
+
+struct Validator<'a> {
+    dir: &'a OciDir,
+    level: ValidationLevel,
+    violations: Vec<Violation>,
+    /// Blobs which have already been checked.
+    seen: BTreeSet<String>,
+}
+
+impl<'a> Validator<'a> {
+    fn strict(&self) -> bool {
+        self.level >= ValidationLevel::Strict
+    }
+
+    fn push(&mut self, kind: ViolationKind, location: &str, message: impl Into<String>) {
+        self.violations.push(Violation {
+            kind,
+            location: location.to_owned(),
+            message: message.into(),
+        });
+    }
+
+    fn check_annotations(
+        &mut self,
+        loc: &str,
+        annotations: &Option<std::collections::HashMap<String, String>>,
+    ) {
+        if !self.strict() {
+            return;
+        }
+        let mut keys: Vec<_> = annotations.iter().flatten().map(|(k, _)| k).collect();
+        keys.sort();
+        for key in keys {
+            if let Some(problem) = annotation_key_problem(key) {
+                self.push(ViolationKind::InvalidAnnotation, loc, problem);
+            }
+        }
+    }
+
+    fn check_platform(&mut self, loc: &str, os: &Os, arch: &Arch) {
+        if !self.strict() {
+            return;
+        }
+        if let Os::Other(os) = os {
+            self.push(
+                ViolationKind::InvalidPlatform,
+                loc,
+                format!("Unknown operating system {os:?}"),
+            );
+        }
+        if let Arch::Other(arch) = arch {
+            self.push(
+                ViolationKind::InvalidPlatform,
+                loc,
+                format!("Unknown architecture {arch:?}"),
+            );
+        }
+    }
+
+    /// Check the fields of a descriptor and, if `required`, its blob. Returns the
+    /// blob contents when it exists and matches the descriptor.
+    fn check_descriptor(
+        &mut self,
+        loc: &str,
+        desc: &Descriptor,
+        required: bool,
+        read: bool,
+    ) -> Result<Option<Vec<u8>>> {
+        let digest = desc.digest().as_str();
+        if !valid_digest(digest) {
+            self.push(
+                ViolationKind::InvalidDescriptor,
+                loc,
+                format!("Invalid digest {digest:?}"),
+            );
+            return Ok(None);
+        }
+        if desc.size() < 0 {
+            self.push(
+                ViolationKind::InvalidDescriptor,
+                loc,
+                format!("Negative size {}", desc.size()),
+            );
+            return Ok(None);
+        }
+        let media_type = desc.media_type().to_string();
+        if !valid_media_type(&media_type) {
+            self.push(
+                ViolationKind::InvalidMediaType,
+                loc,
+                format!("Invalid media type {media_type:?}"),
+            );
+        }
+        self.check_annotations(loc, desc.annotations());
+        if let Some(p) = desc.platform() {
+            self.check_platform(loc, p.os(), p.architecture());
+        }
+        if !required {
+            return Ok(None);
+        }
+        if !self.dir.store.has(digest)? {
+            self.push(
+                ViolationKind::MissingBlob,
+                loc,
+                format!("Missing blob {digest}"),
+            );
+            return Ok(None);
+        }
+        let (f, size) = self.dir.open_blob_sized(digest)?;
+        if size != desc.size() as u64 {
+            self.push(
+                ViolationKind::BlobMismatch,
+                loc,
+                format!(
+                    "Blob {digest} has size {size} but the descriptor has {}",
+                    desc.size()
+                ),
+            );
+            return Ok(None);
+        }
+        if self.strict() && self.seen.insert(digest.to_owned()) {
+            if let Err(e) = self.dir.verify_blob(digest) {
+                self.push(ViolationKind::BlobMismatch, loc, format!("{e:#}"));
+                return Ok(None);
+            }
+        }
+        if !read {
+            return Ok(None);
+        }
+        let limit = self.dir.opts.json_limits.for_media_type(desc.media_type());
+        if size > limit {
+            self.push(
+                ViolationKind::TooLarge,
+                loc,
+                format!("Blob {digest} has size {size}, above the limit of {limit}"),
+            );
+            return Ok(None);
+        }
+        let mut buf = Vec::new();
+        f.take(size).read_to_end(&mut buf)?;
+        Ok(Some(buf))
+    }
+
+    /// Check the `schemaVersion` and `mediaType` fields of a JSON document.
+    fn check_json_header(&mut self, loc: &str, buf: &[u8], media_type: &MediaType) -> bool {
+        let v: serde_json::Value = match serde_json::from_slice(buf) {
+            Ok(v) => v,
+            Err(e) => {
+                self.push(
+                    ViolationKind::InvalidJson,
+                    loc,
+                    format!("Invalid JSON: {e}"),
+                );
+                return false;
+            }
+        };
+        if v.get("schemaVersion").and_then(|v| v.as_u64()) != Some(2) {
+            self.push(ViolationKind::InvalidJson, loc, "schemaVersion must be 2");
+        }
+        if let Some(found) = v.get("mediaType") {
+            if found.as_str() != Some(media_type.to_string().as_str()) {
+                self.push(
+                    ViolationKind::InvalidMediaType,
+                    loc,
+                    format!("mediaType {found} does not match {media_type}"),
+                );
+            }
+        }
+        true
+    }
+
+    fn check_index(&mut self, loc: &str, buf: &[u8]) -> Result<()> {
+        if !self.check_json_header(loc, buf, &MediaType::ImageIndex) {
+            return Ok(());
+        }
+        let index: ImageIndex = match serde_json::from_slice(buf) {
+            Ok(v) => v,
+            Err(e) => {
+                self.push(
+                    ViolationKind::InvalidJson,
+                    loc,
+                    format!("Invalid index: {e}"),
+                );
+                return Ok(());
+            }
+        };
+        self.check_annotations(loc, index.annotations());
+        for (i, desc) in index.manifests().iter().enumerate() {
+            let loc = format!("{loc}/manifests/{i}");
+            let kind = desc.media_type();
+            if kind != &MediaType::ImageManifest && kind != &MediaType::ImageIndex {
+                self.push(
+                    ViolationKind::InvalidMediaType,
+                    &loc,
+                    format!("Unexpected media type {kind} in an index"),
+                );
+                self.check_descriptor(&loc, desc, true, false)?;
+                continue;
+            }
+            let Some(buf) = self.check_descriptor(&loc, desc, true, true)? else {
+                continue;
+            };
+            if kind == &MediaType::ImageIndex {
+                self.check_index(&loc, &buf)?;
+            } else {
+                self.check_manifest(&loc, desc.platform().as_ref(), &buf)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn check_manifest(&mut self, loc: &str, platform: Option<&Platform>, buf: &[u8]) -> Result<()> {
+        if !self.check_json_header(loc, buf, &MediaType::ImageManifest) {
+            return Ok(());
+        }
+        let manifest: ImageManifest = match serde_json::from_slice(buf) {
+            Ok(v) => v,
+            Err(e) => {
+                self.push(
+                    ViolationKind::InvalidJson,
+                    loc,
+                    format!("Invalid manifest: {e}"),
+                );
+                return Ok(());
+            }
+        };
+        self.check_annotations(loc, manifest.annotations());
+        if let Some(subject) = manifest.subject() {
+            self.check_descriptor(&format!("{loc}/subject"), subject, false, false)?;
+        }
+        let config_loc = format!("{loc}/config");
+        let config_desc = manifest.config();
+        let is_image = config_desc.media_type() == &MediaType::ImageConfig;
+        if config_desc.media_type().to_string() == EMPTY_MEDIA_TYPE
+            && manifest.artifact_type().is_none()
+        {
+            self.push(
+                ViolationKind::Inconsistent,
+                loc,
+                "artifactType must be set when the config is empty",
+            );
+        }
+        let config = self.check_descriptor(&config_loc, config_desc, true, is_image)?;
+        for (i, layer) in manifest.layers().iter().enumerate() {
+            let layer_loc = format!("{loc}/layers/{i}");
+            if self.strict() && is_image && !is_layer_media_type(layer.media_type()) {
+                self.push(
+                    ViolationKind::InvalidMediaType,
+                    &layer_loc,
+                    format!("{} is not a layer media type", layer.media_type()),
+                );
+            }
+            self.check_descriptor(&layer_loc, layer, true, false)?;
+        }
+        if let Some(buf) = config {
+            self.check_config(&config_loc, &manifest, platform, &buf);
+        }
+        Ok(())
+    }
+
+    fn check_config(
+        &mut self,
+        loc: &str,
+        manifest: &ImageManifest,
+        platform: Option<&Platform>,
+        buf: &[u8],
+    ) {
+        let config: ImageConfiguration = match serde_json::from_slice(buf) {
+            Ok(v) => v,
+            Err(e) => {
+                self.push(
+                    ViolationKind::InvalidJson,
+                    loc,
+                    format!("Invalid config: {e}"),
+                );
+                return;
+            }
+        };
+        if config.rootfs().typ() != "layers" {
+            self.push(
+                ViolationKind::InvalidJson,
+                loc,
+                format!(
+                    "rootfs.type must be \"layers\", found {:?}",
+                    config.rootfs().typ()
+                ),
+            );
+        }
+        self.check_platform(loc, config.os(), config.architecture());
+        if !self.strict() {
+            return;
+        }
+        let (layers, diff_ids) = (manifest.layers().len(), config.rootfs().diff_ids().len());
+        if layers != diff_ids {
+            self.push(
+                ViolationKind::Inconsistent,
+                loc,
+                format!("Manifest has {layers} layers but config has {diff_ids} diff_ids"),
+            );
+        }
+        if let Some(p) = platform {
+            if p.os() != config.os() || p.architecture() != config.architecture() {
+                self.push(
+                    ViolationKind::Inconsistent,
+                    loc,
+                    format!(
+                        "Index platform {}/{} does not match config {}/{}",
+                        p.os(),
+                        p.architecture(),
+                        config.os(),
+                        config.architecture()
+                    ),
+                );
+            }
+        }
+    }
+}
+
+impl OciDir {
+    /// Check the layout against the OCI image specification, returning all
+    /// violations found. Errors are only returned for failures to read the layout.
+    ///
+    /// All content reachable from `index.json` is checked; unreferenced blobs are not.
+    #[context("Validating layout")]
+    pub fn validate(&self, level: ValidationLevel) -> Result<Vec<Violation>> {
+        let mut v = Validator {
+            dir: self,
+            level,
+            violations: Vec::new(),
+            seen: BTreeSet::new(),
+        };
+        match self.store.read_meta(OCI_LAYOUT_FILE)? {
+            Some(buf) => {
+                if let Err(e) = parse_layout(&buf) {
+                    v.push(
+                        ViolationKind::InvalidJson,
+                        OCI_LAYOUT_FILE,
+                        format!("{e:#}"),
+                    );
+                }
+            }
+            None => v.push(ViolationKind::MissingFile, OCI_LAYOUT_FILE, "Missing file"),
+        }
+        match self.store.read_meta("index.json")? {
+            Some(buf) => v.check_index("index.json", &buf)?,
+            None => v.push(ViolationKind::MissingFile, "index.json", "Missing file"),
+        }
+        Ok(v.violations)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use oci_spec::image as oci_image;
+    use std::collections::HashMap;
+    use std::io::Write;
+
+    #[test]
+    fn syntax() {
+        assert!(valid_digest(&format!("sha256:{}", "a".repeat(64))));
+        assert!(!valid_digest(&format!("sha256:{}", "A".repeat(64))));
+        assert!(!valid_digest("sha256:abc"));
+        assert!(valid_digest(
+            "multihash+base58:QmRZxt2b1FVZPNqd8hsiykDL3TdBDeTSPX9Kv46HmX4Gx8"
+        ));
+        assert!(!valid_digest("Sha256:abc"));
+        assert!(valid_media_type(
+            "application/vnd.oci.image.layer.v1.tar+gzip"
+        ));
+        assert!(!valid_media_type("application"));
+        assert!(!valid_media_type("application/ foo"));
+        assert!(annotation_key_problem("org.opencontainers.image.source").is_none());
+        assert!(annotation_key_problem("org.opencontainers.image.unknown").is_some());
+        assert!(annotation_key_problem("containerd.io/snapshot/stargz/toc.digest").is_none());
+        assert!(annotation_key_problem("ostree.commit").is_none());
+        assert!(annotation_key_problem("nodomain").is_some());
+    }
+
+    #[test]
+    fn validate() -> Result<()> {
+        let w = OciDir::new_in_memory()?;
+        assert_eq!(w.validate(ValidationLevel::Strict)?.len(), 1);
+
+        let mut manifest = crate::new_empty_manifest().build()?;
+        let mut config = oci_image::ImageConfigurationBuilder::default().build()?;
+        let mut layer = w.create_gzip_layer(None)?;
+        layer.write_all(b"not a tarball")?;
+        let layer = layer.complete()?;
+        w.push_layer(&mut manifest, &mut config, layer, "layer", None);
+        let good =
+            w.insert_manifest_and_config(manifest, config, Some("good"), Default::default())?;
+        assert_eq!(w.validate(ValidationLevel::Strict)?, []);
+
+        // A manifest with problems only found in strict mode
+        let mut manifest = crate::new_empty_manifest().build()?;
+        manifest.set_annotations(Some(HashMap::from([(
+            "org.opencontainers.image.bogus".to_owned(),
+            "x".to_owned(),
+        )])));
+        let config = oci_image::ImageConfigurationBuilder::default()
+            .architecture(Arch::Other("pdp11".into()))
+            .build()?;
+        let mut layers = manifest.layers().clone();
+        layers.push(
+            w.write_blob_with_type(b"data", MediaType::Other("text/plain".into()))?
+                .build()?,
+        );
+        manifest.set_layers(layers);
+        w.insert_manifest_and_config(manifest, config, Some("strict"), Default::default())?;
+        assert_eq!(w.validate(ValidationLevel::Basic)?, []);
+        let kinds: Vec<_> = w
+            .validate(ValidationLevel::Strict)?
+            .into_iter()
+            .map(|v| v.kind)
+            .collect();
+        assert_eq!(
+            kinds,
+            [
+                ViolationKind::InvalidAnnotation,
+                ViolationKind::InvalidMediaType,
+                ViolationKind::InvalidPlatform,
+                ViolationKind::Inconsistent,
+                ViolationKind::Inconsistent,
+            ]
+        );
+
+        // A missing blob and a wrong size
+        let mut manifest: ImageManifest = w.read_json_blob(&good)?;
+        let mut layers = manifest.layers().clone();
+        let mut missing = layers[0].clone();
+        missing.set_digest(format!("sha256:{}", "0".repeat(64)));
+        let mut wrong_size = layers[0].clone();
+        wrong_size.set_size(1);
+        layers.extend([missing, wrong_size]);
+        manifest.set_layers(layers);
+        w.insert_manifest(manifest, Some("broken"), Default::default())?;
+        let violations = w.validate(ValidationLevel::Basic)?;
+        let kinds: Vec<_> = violations.iter().map(|v| v.kind).collect();
+        assert_eq!(
+            kinds,
+            [ViolationKind::MissingBlob, ViolationKind::BlobMismatch]
+        );
+        assert_eq!(violations[0].location, "index.json/manifests/2/layers/1");
+        Ok(())
+    }
+}
@@ -0,0 +1,52 @@
+//! Policies for verifying blob digests when reading.
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::num::NonZeroU32;
+
+/// Controls whether [`crate::OciDir::read_blob`] verifies the digest of blobs from
+/// storage before returning them, see [`crate::OciDirOptions::verify`].
+///
+/// Verification reads the whole blob an additional time. Blob contents embedded
+/// in descriptors are always verified.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum VerifyPolicy {
+    /// Never verify on read; integrity can still be checked with [`crate::OciDir::fsck`].
+    #[default]
+    Never,
+    /// Verify every read.
+    Always,
+    /// Verify a random sample of one in the given number of reads.
+    Sample(NonZeroU32),
+}
+
+impl VerifyPolicy {
+    /// Decide whether the current read should be verified.
+    pub(crate) fn should_verify(&self) -> bool {
+        match self {
+            VerifyPolicy::Never => false,
+            VerifyPolicy::Always => true,
+            VerifyPolicy::Sample(n) => {
+                // RandomState is randomly seeded per instance, which is good enough for sampling.
+                let r = RandomState::new().build_hasher().finish();
+                r <= u64::MAX / u64::from(n.get())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn policy() {
+        assert!(!VerifyPolicy::Never.should_verify());
+        assert!(VerifyPolicy::Always.should_verify());
+        assert!(VerifyPolicy::Sample(NonZeroU32::MIN).should_verify());
+        let sample = VerifyPolicy::Sample(NonZeroU32::new(4).unwrap());
+        let n = (0..1000).filter(|_| sample.should_verify()).count();
+        assert!(n > 100 && n < 500, "{n}");
+    }
+}
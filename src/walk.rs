@@ -0,0 +1,193 @@
+//! Traversal of everything referenced by the index.
+
+use anyhow::Result;
+use fn_error_context::context;
+use oci_spec::image::{Descriptor, ImageIndex, ImageManifest, MediaType};
+
+use crate::OciDir;
+
+/// A node visited by [`OciDir::walk`], along with its descriptor.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum WalkNode<'a> {
+    /// An image index, which is followed by its manifests.
+    Index(&'a ImageIndex),
+    /// An image manifest, which is followed by its config and layers.
+    Manifest(&'a ImageManifest),
+    /// The config of the preceding manifest. It is not read; for images,
+    /// [`OciDir::read_json_blob`] can be used to parse it.
+    Config,
+    /// A layer of the preceding manifest, which is not read.
+    Layer,
+    /// An index or manifest whose blob is not present.
+    Missing,
+    /// An entry of an index with another media type, which is not read.
+    Other,
+}
+
+/// How [`OciDir::walk`] continues after a node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WalkControl {
+    /// Visit the children of this node, if any.
+    #[default]
+    Continue,
+    /// Do not visit the children of this node, such as when it was seen before.
+    SkipChildren,
+    /// Stop the walk.
+    Stop,
+}
+
+type Visitor<'v> = dyn FnMut(&Descriptor, WalkNode<'_>) -> Result<WalkControl> + 'v;
+
+impl OciDir {
+    /// Visit `desc`, returning false if the walk was stopped.
+    fn walk_desc(&self, desc: &Descriptor, visitor: &mut Visitor<'_>) -> Result<bool> {
+        let is_manifest = desc.media_type() == &MediaType::ImageManifest;
+        let is_index = desc.media_type() == &MediaType::ImageIndex;
+        if (is_manifest || is_index) && !self.has_blob(desc)? {
+            return Ok(visitor(desc, WalkNode::Missing)? != WalkControl::Stop);
+        }
+        if is_manifest {
+            let manifest: ImageManifest = self.read_json_blob_lenient(desc)?;
+            match visitor(desc, WalkNode::Manifest(&manifest))? {
+                WalkControl::Continue => {}
+                WalkControl::SkipChildren => return Ok(true),
+                WalkControl::Stop => return Ok(false),
+            }
+            if visitor(manifest.config(), WalkNode::Config)? == WalkControl::Stop {
+                return Ok(false);
+            }
+            for layer in manifest.layers() {
+                if visitor(layer, WalkNode::Layer)? == WalkControl::Stop {
+                    return Ok(false);
+                }
+            }
+        } else if is_index {
+            let index: ImageIndex = self.read_json_blob_lenient(desc)?;
+            match visitor(desc, WalkNode::Index(&index))? {
+                WalkControl::Continue => {}
+                WalkControl::SkipChildren => return Ok(true),
+                WalkControl::Stop => return Ok(false),
+            }
+            for child in index.manifests() {
+                if !self.walk_desc(child, visitor)? {
+                    return Ok(false);
+                }
+            }
+        } else {
+            return Ok(visitor(desc, WalkNode::Other)? != WalkControl::Stop);
+        }
+        Ok(true)
+    }
+
+    /// Visit everything referenced by the index depth-first: each entry, then the
+    /// manifests of nested indexes, and the config and layers of each manifest.
+    ///
+    /// Blobs referenced more than once are visited each time; a visitor which only
+    /// needs each blob once can return [`WalkControl::SkipChildren`] for nodes it
+    /// has seen. The `subject` of artifacts is not followed. Indexes and manifests
+    /// whose digest algorithm is not supported are parsed without being verified.
+    #[context("Walking layout")]
+    pub fn walk(
+        &self,
+        mut visitor: impl FnMut(&Descriptor, WalkNode<'_>) -> Result<WalkControl>,
+    ) -> Result<()> {
+        let Some(index) = self.read_index()? else {
+            return Ok(());
+        };
+        for desc in index.manifests() {
+            if !self.walk_desc(desc, &mut visitor)? {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Like [`Self::walk`], starting from a single descriptor.
+    #[context("Walking {}", desc.digest())]
+    pub fn walk_from(
+        &self,
+        desc: &Descriptor,
+        mut visitor: impl FnMut(&Descriptor, WalkNode<'_>) -> Result<WalkControl>,
+    ) -> Result<()> {
+        self.walk_desc(desc, &mut visitor).map(drop)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use oci_spec::image::{ImageConfigurationBuilder, ImageIndexBuilder, SCHEMA_VERSION};
+    use std::io::Write;
+
+    fn kind(node: &WalkNode) -> &'static str {
+        match node {
+            WalkNode::Index(_) => "index",
+            WalkNode::Manifest(_) => "manifest",
+            WalkNode::Config => "config",
+            WalkNode::Layer => "layer",
+            WalkNode::Missing => "missing",
+            WalkNode::Other => "other",
+        }
+    }
+
+    #[test]
+    fn walk() -> Result<()> {
+        let w = OciDir::new_in_memory()?;
+        let mut manifest = crate::new_empty_manifest().build()?;
+        let mut config = ImageConfigurationBuilder::default().build()?;
+        let mut lw = w.create_gzip_layer(None)?;
+        lw.write_all(b"layer")?;
+        w.push_layer(&mut manifest, &mut config, lw.complete()?, "layer", None);
+        let image = w.insert_manifest_and_config(manifest, config, None, Default::default())?;
+        let index = ImageIndexBuilder::default()
+            .schema_version(SCHEMA_VERSION)
+            .manifests(vec![image.clone()])
+            .build()?;
+        let index = crate::write_json_blob_to_store(
+            &*w.store,
+            &w.progress,
+            &index,
+            MediaType::ImageIndex,
+            &Default::default(),
+        )?
+        .build()?;
+        w.insert_descriptor(index.clone(), None)?;
+        let mut missing = image.clone();
+        missing.set_digest(format!("sha256:{}", "0".repeat(64)));
+        w.insert_descriptor(missing, None)?;
+
+        let mut seen = Vec::new();
+        w.walk(|d, node| {
+            seen.push(format!("{} {}", kind(&node), &d.digest()[7..11]));
+            Ok(WalkControl::Continue)
+        })?;
+        let kinds: Vec<_> = seen.iter().map(|s| s.split(' ').next().unwrap()).collect();
+        assert_eq!(
+            kinds,
+            ["manifest", "config", "layer", "index", "manifest", "config", "layer", "missing"]
+        );
+        assert_eq!(seen[0], seen[4]);
+
+        // Skipping children of the index, and stopping
+        let mut n = 0;
+        w.walk(|_, node| {
+            n += 1;
+            Ok(match node {
+                WalkNode::Index(_) => WalkControl::SkipChildren,
+                WalkNode::Missing => unreachable!(),
+                WalkNode::Layer => WalkControl::Stop,
+                _ => WalkControl::Continue,
+            })
+        })?;
+        assert_eq!(n, 3);
+        let mut n = 0;
+        w.walk_from(&index, |_, node| {
+            n += 1;
+            assert!(!matches!(node, WalkNode::Missing));
+            Ok(WalkControl::SkipChildren)
+        })?;
+        assert_eq!(n, 1);
+        Ok(())
+    }
+}